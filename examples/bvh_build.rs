@@ -0,0 +1,37 @@
+//! Times building a BVH over 100000 random triangles. Run with
+//! `cargo run --release --example bvh_build`.
+
+use std::time::Instant;
+
+#[macro_use]
+extern crate rust_raytracer;
+
+use rust_raytracer::acceleration::Bvh;
+use rust_raytracer::raytracer::*;
+use rust_raytracer::rng::Rng;
+
+const TRIANGLES: usize = 100_000;
+const RUNS: usize = 5;
+
+fn triangles() -> Vec<Box<dyn Geometry>> {
+    let mut rng = Rng::new(1);
+    let mut random_vector = || v3!(rng.next_f64(), rng.next_f64(), rng.next_f64());
+    (0..TRIANGLES).map(|_| {
+        let v0 = random_vector()*100.0;
+        let (v1, v2) = (v0 + random_vector(), v0 + random_vector());
+        Box::new(Triangle::new(v0, v1, v2, basic_material(v3!(1.0, 1.0, 1.0))))
+            as Box<dyn Geometry>
+    }).collect()
+}
+
+fn main() {
+    let mut best = f64::INFINITY;
+    for _ in 0..RUNS {
+        let objects = triangles();
+        let start = Instant::now();
+        let bvh = Bvh::new(objects);
+        best = best.min(start.elapsed().as_secs_f64());
+        drop(bvh);
+    }
+    println!("{} triangles, best of {} {:8.1} ms", TRIANGLES, RUNS, best*1000.0);
+}
@@ -0,0 +1,43 @@
+//! Measures how fast the BVH finds the closest hit of a million random
+//! rays through `random_scene` with 10000 spheres. Run with
+//! `cargo run --release --example bvh_traversal`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+#[macro_use]
+extern crate rust_raytracer;
+
+use rust_raytracer::acceleration::Bvh;
+use rust_raytracer::raytracer::*;
+use rust_raytracer::rng::Rng;
+use rust_raytracer::scenes::random_scene;
+
+const RAYS: usize = 1_000_000;
+const RUNS: usize = 5;
+
+fn main() {
+    let scene = random_scene(10000, 1);
+    let objects: Vec<Box<dyn Geometry>> = scene.objects.into_iter()
+        .filter(|o| o.bounding_box().is_some())
+        .collect();
+    let bvh = Bvh::new(objects);
+
+    let mut rng = Rng::new(2);
+    let rays: Vec<Ray> = (0..RAYS).map(|_| {
+        let origin = v3!(rng.next_f64()*30.0 - 15.0, rng.next_f64()*4.0, rng.next_f64()*30.0 - 15.0);
+        let dir = v3!(rng.next_f64()*2.0 - 1.0, rng.next_f64()*2.0 - 1.0,
+                      rng.next_f64()*2.0 - 1.0).normalize();
+        Ray { origin, dir, t_max: f64::INFINITY }
+    }).collect();
+
+    let mut best = f64::INFINITY;
+    let mut hits = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        hits = rays.iter().filter(|ray| black_box(bvh.intersect(ray, f64::INFINITY)).is_some()).count();
+        best = best.min(start.elapsed().as_secs_f64());
+    }
+    println!("{} rays, {} hits", RAYS, hits);
+    println!("best of {} {:8.1} ms {:12.0} rays/s", RUNS, best*1000.0, RAYS as f64 / best);
+}
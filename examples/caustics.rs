@@ -0,0 +1,46 @@
+//! Renders a glass ball on a checkered floor, lit from the side so that the
+//! light it focuses lands in its shadow. Whitted tracing leaves the shadow
+//! dark; with a photon map the caustic shows up in it. Run with
+//! `cargo run --release --example caustics [out.png]`.
+
+use std::env;
+use std::fs::File;
+
+#[macro_use]
+extern crate rust_raytracer;
+
+use rust_raytracer::output::writer_for_format;
+use rust_raytracer::photon::PhotonMap;
+use rust_raytracer::raytracer::*;
+
+fn main() {
+    let mut scene = scene();
+    scene.camera = Camera::look_at(v3!(0.0, 4.0, -6.0), v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0),
+                                   40.0, 4.0 / 3.0);
+    scene.add(Plane { pos: v3!(0.0, -1.0, 0.0),
+                      normal: v3!(0.0, 1.0, 0.0),
+                      material: basic_material(v3!(0.8, 0.8, 0.8)).into(),
+                      uv_scale: 1.0 });
+    scene.add(Sphere { pos: v3!(0.0, 0.2, 0.0),
+                       radius: 0.8,
+                       material: basic_material(v3!(1.0, 1.0, 1.0)).with_transparency(0.95, 1.5).into(),
+                       motion: None });
+    // Photons fall off with the square of the distance, so the light has
+    // to as well for the caustic to match the direct light.
+    scene.add_light(Light { pos: v3!(-3.0, 3.0, 3.0),
+                            color: v3!(1.0, 1.0, 1.0),
+                            intensity: 35.0,
+                            attenuation: (0.0, 0.0, 1.0) });
+
+    let photons = PhotonMap::new(&scene, 2_000_000, 0.05, 1);
+    println!("{} caustic photons stored", photons.len());
+    scene.photon_map = Some(photons);
+
+    let config = RenderConfig::default();
+    let pixels = raytrace(&scene, &config);
+    let path = env::args().nth(1).unwrap_or_else(|| "caustics.png".to_string());
+    let mut out = File::create(&path).expect("can't create output file");
+    writer_for_format("png").unwrap()
+        .write(&pixels, config.width, config.height, &mut out)
+        .expect("can't write output file");
+}
@@ -0,0 +1,25 @@
+//! Renders `cornell_box` with the default settings and prints the image's
+//! checksum; the `cornell_box_matches_its_checksum` test compares it with
+//! the reference. Run with `cargo run --release --example cornell_box`;
+//! pass a file name to also save the image as PNG.
+
+use std::env;
+use std::fs::File;
+
+extern crate rust_raytracer;
+
+use rust_raytracer::output::writer_for_format;
+use rust_raytracer::raytracer::*;
+use rust_raytracer::scenes::{cornell_box, image_checksum};
+
+fn main() {
+    let config = RenderConfig::default();
+    let pixels = raytrace(&cornell_box(), &config);
+    if let Some(path) = env::args().nth(1) {
+        let mut out = File::create(&path).expect("can't create output file");
+        writer_for_format("png").unwrap()
+            .write(&pixels, config.width, config.height, &mut out)
+            .expect("can't write output file");
+    }
+    println!("checksum {}", image_checksum(&pixels));
+}
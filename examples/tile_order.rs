@@ -0,0 +1,37 @@
+//! Compares rendering by scan-lines with rendering in 16x16 tiles on
+//! `random_scene` with 500 spheres. Run with `cargo run --release --example tile_order`.
+
+use std::time::Instant;
+
+extern crate rust_raytracer;
+
+use rust_raytracer::raytracer::*;
+use rust_raytracer::scenes::random_scene;
+
+const RUNS: usize = 5;
+
+/// Best of `RUNS` single-threaded renders, in milliseconds.
+fn time(scene: &Scene, config: &RenderConfig) -> (f64, Vec<rust_raytracer::vector::Vector3>) {
+    let mut best = f64::INFINITY;
+    let mut pixels = Vec::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        pixels = raytrace(scene, config);
+        best = best.min(start.elapsed().as_secs_f64() * 1000.0);
+    }
+    (best, pixels)
+}
+
+fn main() {
+    let mut scene = random_scene(500, 1);
+    scene.build_bvh();
+    let lines = RenderConfig { width: 800, height: 600, ..RenderConfig::default() };
+    let tiles = RenderConfig { tile_size: 16, ..lines.clone() };
+
+    let (lines_ms, lines_pixels) = time(&scene, &lines);
+    let (tiles_ms, tiles_pixels) = time(&scene, &tiles);
+    assert!(lines_pixels == tiles_pixels, "tile order changed the image");
+
+    println!("scan-lines:  {:8.1} ms", lines_ms);
+    println!("16x16 tiles: {:8.1} ms", tiles_ms);
+}
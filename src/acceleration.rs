@@ -0,0 +1,959 @@
+use std;
+use std::cell::RefCell;
+
+use raytracer::{Geometry, Intersection, Ray};
+#[cfg(feature = "simd")]
+use simd::SpherePacket;
+use vector::Vector3;
+
+const SAH_BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+/// Deepest a BVH gets, which sizes the traversal stack. Anything still
+/// unsplit at this depth stays in one leaf.
+const MAX_DEPTH: usize = 64;
+/// Subtrees over fewer primitives than this are built on one thread.
+const PARALLEL_BUILD_MIN: usize = 4096;
+/// Upper limit for the number of grid cells along each axis.
+const MAX_GRID_DIM: usize = 64;
+
+/// Axis-aligned bounding box.
+#[derive(Copy, Clone)]
+pub struct Aabb {
+    pub min: Vector3,
+    pub max: Vector3,
+}
+
+impl Aabb {
+    /// Smallest box containing both corners, in any order.
+    pub fn from_points(a: Vector3, b: Vector3) -> Aabb {
+        Aabb { min: a.component_min(b), max: a.component_max(b) }
+    }
+
+    pub fn merge(&self, other: &Aabb) -> Aabb {
+        Aabb { min: self.min.component_min(other.min),
+               max: self.max.component_max(other.max) }
+    }
+
+    /// Whether `p` is inside the box or on its boundary.
+    pub fn contains(&self, p: Vector3) -> bool {
+        p.x >= self.min.x && p.y >= self.min.y && p.z >= self.min.z
+            && p.x <= self.max.x && p.y <= self.max.y && p.z <= self.max.z
+    }
+
+    /// Whether the box and the sphere of `radius` around `center` share
+    /// any point.
+    pub fn overlaps_sphere(&self, center: Vector3, radius: f64) -> bool {
+        let closest = center.component_max(self.min).component_min(self.max);
+        (center - closest).length_squared() <= radius*radius
+    }
+
+    pub fn centroid(&self) -> Vector3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        2.0 * (d.x*d.y + d.y*d.z + d.z*d.x)
+    }
+
+    /// Slab test. Returns the parametric interval `(tnear, tfar)` where the
+    /// ray is inside the box, or None if the ray misses it.
+    pub fn intersects_ray(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let mut tnear = -f64::INFINITY;
+        let mut tfar = f64::INFINITY;
+        for axis in 0..3 {
+            let inv_dir = 1.0 / ray.dir[axis];
+            let mut t1 = (self.min[axis] - ray.origin[axis]) * inv_dir;
+            let mut t2 = (self.max[axis] - ray.origin[axis]) * inv_dir;
+            if t1 > t2 { std::mem::swap(&mut t1, &mut t2); }
+            // NaN (origin on a slab boundary of a flat axis) leaves the
+            // interval unchanged.
+            if t1 > tnear { tnear = t1; }
+            if t2 < tfar { tfar = t2; }
+            if tnear > tfar || tfar < 0.0 { return None; }
+        }
+        Some((tnear, tfar))
+    }
+}
+
+enum BvhNode {
+    Leaf { bounds: Aabb, first: usize, count: usize },
+    Interior { bounds: Aabb, left: usize, right: usize },
+}
+
+impl BvhNode {
+    fn bounds(&self) -> &Aabb {
+        match *self {
+            BvhNode::Leaf { ref bounds, .. } => bounds,
+            BvhNode::Interior { ref bounds, .. } => bounds,
+        }
+    }
+}
+
+struct Primitive {
+    index: usize,
+    bounds: Aabb,
+    centroid: Vector3,
+}
+
+/// Bounding volume hierarchy over objects with finite bounding boxes,
+/// built with binned surface area heuristic splits.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    objects: Vec<Box<dyn Geometry>>,
+    /// For each node, the spheres of each run of four objects in it if it
+    /// is a leaf.
+    #[cfg(feature = "simd")]
+    packets: Vec<Vec<SpherePacket>>,
+}
+
+impl Bvh {
+    /// Builds a BVH over `objects`. Every object must have a bounding box.
+    pub fn new(objects: Vec<Box<dyn Geometry>>) -> Bvh {
+        let mut prims: Vec<Primitive> = objects.iter().enumerate().map(|(index, o)| {
+            let bounds = o.bounding_box().expect("BVH objects must be bounded");
+            Primitive { index, bounds, centroid: bounds.centroid() }
+        }).collect();
+
+        let nodes = if prims.is_empty() { Vec::new() } else { build_parallel(&mut prims, 0, 0) };
+
+        // Reorder the objects so that each leaf refers to a contiguous range.
+        let mut slots: Vec<Option<Box<dyn Geometry>>> = objects.into_iter().map(Some).collect();
+        let objects: Vec<Box<dyn Geometry>> =
+            prims.iter().map(|p| slots[p.index].take().unwrap()).collect();
+        #[cfg(feature = "simd")]
+        let packets = nodes.iter().map(|node| match *node {
+            BvhNode::Leaf { first, count, .. } =>
+                objects[first..first + count].chunks(4).map(SpherePacket::new).collect(),
+            BvhNode::Interior { .. } => Vec::new(),
+        }).collect();
+        Bvh { nodes,
+              objects,
+              #[cfg(feature = "simd")]
+              packets }
+    }
+
+    pub fn objects(&self) -> &[Box<dyn Geometry>] {
+        &self.objects
+    }
+
+    /// Gives back the objects the hierarchy was built from.
+    pub fn into_objects(self) -> Vec<Box<dyn Geometry>> {
+        self.objects
+    }
+
+    /// Indices into `objects` of the objects whose bounding boxes overlap
+    /// the sphere of `radius` around `center`, in increasing order.
+    pub fn objects_within_radius(&self, center: Vector3, radius: f64) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if !node.bounds().overlaps_sphere(center, radius) {
+                continue;
+            }
+            match *node {
+                BvhNode::Leaf { first, count, .. } => found.extend(first..first + count),
+                BvhNode::Interior { left, right, .. } => {
+                    stack.push(left);
+                    stack.push(right);
+                }
+            }
+        }
+        finish_query(&self.objects, found, center, radius)
+    }
+
+    /// Returns the closest intersection nearer than `t_max`.
+    pub fn intersect(&self, ray: &Ray, t_max: f64) -> Option<Intersection> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        match self.nodes[0].bounds().intersects_ray(ray) {
+            Some((tnear, _)) if tnear < t_max => {},
+            _ => return None,
+        }
+        // Nodes still to visit with the distance where the ray enters
+        // them, nearest on top. The tree is at most MAX_DEPTH deep and only
+        // the farther child of each node on the current path waits here,
+        // so the stack never overflows.
+        let mut stack = [(0, 0.0); MAX_DEPTH];
+        let mut len = 1;
+        let mut closest = t_max;
+        let mut isect = None;
+        while len > 0 {
+            len -= 1;
+            let (node, tnear) = stack[len];
+            if tnear >= closest {
+                continue;
+            }
+            match self.nodes[node] {
+                BvhNode::Leaf { first, count, .. } => {
+                    for (chunk, objects) in self.objects[first..first+count].chunks(4).enumerate() {
+                        let may_hit = self.may_hit(node, chunk, ray, closest);
+                        for (o, _) in objects.iter().zip(may_hit).filter(|&(_, may_hit)| may_hit) {
+                            if let Some(i) = o.intersects(ray) {
+                                if i.dist < closest {
+                                    closest = i.dist;
+                                    isect = Some(i);
+                                }
+                            }
+                        }
+                    }
+                },
+                BvhNode::Interior { left, right, .. } => {
+                    let entry = |child: usize| match self.nodes[child].bounds().intersects_ray(ray) {
+                        Some((tnear, _)) => tnear,
+                        None => f64::INFINITY,
+                    };
+                    // Visit the nearer child first so its hits can prune the other.
+                    let (mut near, mut far) = ((left, entry(left)), (right, entry(right)));
+                    if far.1 < near.1 {
+                        std::mem::swap(&mut near, &mut far);
+                    }
+                    for &child in [far, near].iter() {
+                        if child.1 < closest {
+                            stack[len] = child;
+                            len += 1;
+                        }
+                    }
+                }
+            }
+        }
+        isect
+    }
+
+    /// Which of the four objects from `4 * chunk` on in leaf `node` the ray
+    /// might hit nearer than `t_max`, culling spheres with `SpherePacket`.
+    #[cfg(feature = "simd")]
+    fn may_hit(&self, node: usize, chunk: usize, ray: &Ray, t_max: f64) -> [bool; 4] {
+        self.packets[node][chunk].may_hit(ray, t_max)
+    }
+
+    #[cfg(not(feature = "simd"))]
+    fn may_hit(&self, _node: usize, _chunk: usize, _ray: &Ray, _t_max: f64) -> [bool; 4] {
+        [true; 4]
+    }
+}
+
+/// Builds the subtree for `prims`, which start at `offset` in the full
+/// list, at `depth` and appends it to `nodes`. Returns the index of its
+/// root node.
+fn build(nodes: &mut Vec<BvhNode>, prims: &mut [Primitive], offset: usize,
+         depth: usize) -> usize {
+    let bounds = bounds_of(prims);
+    let node = nodes.len();
+    nodes.push(BvhNode::Leaf { bounds, first: offset, count: prims.len() });
+    if prims.len() <= 1 || depth + 1 >= MAX_DEPTH {
+        return node;
+    }
+    if let Some(mid) = split(prims, &bounds) {
+        let (left_prims, right_prims) = prims.split_at_mut(mid);
+        let left = build(nodes, left_prims, offset, depth + 1);
+        let right = build(nodes, right_prims, offset + mid, depth + 1);
+        nodes[node] = BvhNode::Interior { bounds, left, right };
+    }
+    node
+}
+
+/// Like `build`, but builds the two children of each split in parallel
+/// until fewer than `PARALLEL_BUILD_MIN` primitives remain, and returns
+/// the subtree's nodes. They are laid out exactly as `build` lays them
+/// out, so the tree doesn't depend on the thread count.
+fn build_parallel(prims: &mut [Primitive], offset: usize, depth: usize) -> Vec<BvhNode> {
+    let mut nodes = Vec::new();
+    if prims.len() < PARALLEL_BUILD_MIN || depth + 1 >= MAX_DEPTH {
+        build(&mut nodes, prims, offset, depth);
+        return nodes;
+    }
+    let bounds = bounds_of(prims);
+    let mid = match split(prims, &bounds) {
+        Some(mid) => mid,
+        None => return vec![BvhNode::Leaf { bounds, first: offset, count: prims.len() }],
+    };
+    let (left_prims, right_prims) = prims.split_at_mut(mid);
+    let (left, right) = rayon::join(|| build_parallel(left_prims, offset, depth + 1),
+                                    || build_parallel(right_prims, offset + mid, depth + 1));
+    // The children's node indices are relative to their own lists.
+    let shift = |node: BvhNode, by: usize| match node {
+        BvhNode::Interior { bounds, left, right } =>
+            BvhNode::Interior { bounds, left: left + by, right: right + by },
+        leaf => leaf,
+    };
+    let right_start = 1 + left.len();
+    nodes.reserve(right_start + right.len());
+    nodes.push(BvhNode::Interior { bounds, left: 1, right: right_start });
+    nodes.extend(left.into_iter().map(|n| shift(n, 1)));
+    nodes.extend(right.into_iter().map(|n| shift(n, right_start)));
+    nodes
+}
+
+fn bounds_of(prims: &[Primitive]) -> Aabb {
+    prims.iter().skip(1).fold(prims[0].bounds, |b, p| b.merge(&p.bounds))
+}
+
+/// Sorts the candidate indices of a radius query, drops duplicates and
+/// keeps the objects whose own bounding boxes overlap the sphere.
+fn finish_query(objects: &[Box<dyn Geometry>], mut found: Vec<usize>,
+                center: Vector3, radius: f64) -> Vec<usize> {
+    found.sort_unstable();
+    found.dedup();
+    found.retain(|&i| objects[i].bounding_box().is_some_and(|b| b.overlaps_sphere(center, radius)));
+    found
+}
+
+/// Partitions `prims` along the binned SAH split with the lowest cost and
+/// returns where the second half starts, or None if they are better left
+/// in one leaf.
+fn split(prims: &mut [Primitive], bounds: &Aabb) -> Option<usize> {
+    let count = prims.len();
+    let centroid_bounds = prims.iter()
+        .fold(Aabb { min: prims[0].centroid, max: prims[0].centroid },
+              |b, p| b.merge(&Aabb { min: p.centroid, max: p.centroid }));
+    let extent = centroid_bounds.max - centroid_bounds.min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 }
+               else if extent.y >= extent.z { 1 } else { 2 };
+    let lo = centroid_bounds.min[axis];
+    let hi = centroid_bounds.max[axis];
+    if hi - lo <= 0.0 {
+        // All centroids coincide, splitting can't separate anything.
+        return None;
+    }
+
+    let bin_of = |p: &Primitive| {
+        let b = ((p.centroid[axis] - lo) / (hi - lo) * SAH_BINS as f64) as usize;
+        b.min(SAH_BINS - 1)
+    };
+    let mut bin_counts = [0usize; SAH_BINS];
+    let mut bin_bounds: [Option<Aabb>; SAH_BINS] = [None; SAH_BINS];
+    for p in prims.iter() {
+        let b = bin_of(p);
+        bin_counts[b] += 1;
+        bin_bounds[b] = Some(match bin_bounds[b] {
+            Some(bb) => bb.merge(&p.bounds),
+            None => p.bounds,
+        });
+    }
+
+    // Cost of splitting after each bin, relative to intersecting every
+    // primitive in a single leaf.
+    let mut best_cost = f64::INFINITY;
+    let mut best_split = 0;
+    for split in 0..SAH_BINS-1 {
+        let side_cost = |bins: std::ops::Range<usize>| {
+            let mut n = 0;
+            let mut bb: Option<Aabb> = None;
+            for i in bins {
+                n += bin_counts[i];
+                if let Some(b) = bin_bounds[i] {
+                    bb = Some(bb.map_or(b, |x| x.merge(&b)));
+                }
+            }
+            bb.map_or(0.0, |b| b.surface_area() * n as f64)
+        };
+        let cost = 0.125 + (side_cost(0..split+1) + side_cost(split+1..SAH_BINS))
+            / bounds.surface_area();
+        if cost < best_cost {
+            best_cost = cost;
+            best_split = split;
+        }
+    }
+    if count <= MAX_LEAF_SIZE && best_cost >= count as f64 {
+        return None;
+    }
+
+    let mut mid = 0;
+    for i in 0..count {
+        if bin_of(&prims[i]) <= best_split {
+            prims.swap(i, mid);
+            mid += 1;
+        }
+    }
+    if mid == 0 || mid == count {
+        mid = count / 2;
+    }
+    Some(mid)
+}
+
+/// Regular grid of cells over objects with finite bounding boxes. Cheaper
+/// to build than a `Bvh` and as fast or faster when the objects are spread
+/// evenly, but slow for scenes with dense clusters.
+pub struct UniformGrid {
+    /// Indices into `objects` of everything overlapping each cell, with x
+    /// varying fastest.
+    cells: Vec<Vec<usize>>,
+    dim: [usize; 3],
+    bounds: Aabb,
+    objects: Vec<Box<dyn Geometry>>,
+}
+
+thread_local! {
+    /// Mailboxes for `UniformGrid::intersect` and `KdTree::intersect`: the
+    /// ray number each object was last tested against, so objects spanning
+    /// several cells are only tested once per ray. The ray counter is
+    /// shared by every grid and tree on the thread, so one mailbox array
+    /// serves them all.
+    static MAILBOX: RefCell<(u64, Vec<u64>)> = const { RefCell::new((0, Vec::new())) };
+}
+
+impl UniformGrid {
+    /// Builds a grid over `objects`, with about three cells per cube root
+    /// of the object count along the longest axis. Every object must have
+    /// a bounding box.
+    pub fn new(objects: Vec<Box<dyn Geometry>>) -> UniformGrid {
+        let boxes: Vec<Aabb> = objects.iter()
+            .map(|o| o.bounding_box().expect("grid objects must be bounded"))
+            .collect();
+        let bounds = match boxes.first() {
+            Some(first) => boxes.iter().fold(*first, |b, o| b.merge(o)),
+            None => Aabb { min: v3!(0.0, 0.0, 0.0), max: v3!(0.0, 0.0, 0.0) },
+        };
+        let extent = bounds.max - bounds.min;
+        let max_extent = extent.max_component();
+        let cells_per_unit = if max_extent > 0.0 {
+            3.0 * (objects.len() as f64).cbrt() / max_extent
+        } else {
+            0.0
+        };
+        let mut dim = [1; 3];
+        for (axis, d) in dim.iter_mut().enumerate() {
+            let cells = (extent[axis] * cells_per_unit).round() as usize;
+            *d = cells.clamp(1, MAX_GRID_DIM);
+        }
+
+        let mut grid = UniformGrid { cells: vec![Vec::new(); dim[0]*dim[1]*dim[2]],
+                                     dim,
+                                     bounds,
+                                     objects: Vec::new() };
+        for (index, b) in boxes.iter().enumerate() {
+            let lo = grid.cell_of(b.min);
+            let hi = grid.cell_of(b.max);
+            for z in lo[2]..=hi[2] {
+                for y in lo[1]..=hi[1] {
+                    for x in lo[0]..=hi[0] {
+                        let cell = grid.cell_index([x, y, z]);
+                        grid.cells[cell].push(index);
+                    }
+                }
+            }
+        }
+        grid.objects = objects;
+        grid
+    }
+
+    pub fn objects(&self) -> &[Box<dyn Geometry>] {
+        &self.objects
+    }
+
+    /// Gives back the objects the grid was built from.
+    pub fn into_objects(self) -> Vec<Box<dyn Geometry>> {
+        self.objects
+    }
+
+    /// Indices into `objects` of the objects whose bounding boxes overlap
+    /// the sphere of `radius` around `center`, in increasing order.
+    pub fn objects_within_radius(&self, center: Vector3, radius: f64) -> Vec<usize> {
+        let query = Aabb { min: center - v3!(radius, radius, radius),
+                           max: center + v3!(radius, radius, radius) };
+        if self.objects.is_empty() || !self.bounds.overlaps_sphere(center, radius) {
+            return Vec::new();
+        }
+        let (lo, hi) = (self.cell_of(query.min), self.cell_of(query.max));
+        let mut found = Vec::new();
+        for z in lo[2]..=hi[2] {
+            for y in lo[1]..=hi[1] {
+                for x in lo[0]..=hi[0] {
+                    found.extend(&self.cells[self.cell_index([x, y, z])]);
+                }
+            }
+        }
+        finish_query(&self.objects, found, center, radius)
+    }
+
+    fn cell_size(&self, axis: usize) -> f64 {
+        (self.bounds.max[axis] - self.bounds.min[axis]) / self.dim[axis] as f64
+    }
+
+    /// Cell containing `p`, clamped to the grid.
+    fn cell_of(&self, p: Vector3) -> [usize; 3] {
+        let mut cell = [0; 3];
+        for (axis, c) in cell.iter_mut().enumerate() {
+            let size = self.cell_size(axis);
+            if size > 0.0 {
+                let i = ((p[axis] - self.bounds.min[axis]) / size).floor();
+                *c = (i.max(0.0) as usize).min(self.dim[axis] - 1);
+            }
+        }
+        cell
+    }
+
+    fn cell_index(&self, cell: [usize; 3]) -> usize {
+        (cell[2]*self.dim[1] + cell[1])*self.dim[0] + cell[0]
+    }
+
+    /// Returns the closest intersection nearer than `t_max`, walking the
+    /// cells along the ray with a 3D DDA and stopping at the first cell
+    /// that contains a hit.
+    pub fn intersect(&self, ray: &Ray, t_max: f64) -> Option<Intersection> {
+        if self.objects.is_empty() {
+            return None;
+        }
+        let t_start = match self.bounds.intersects_ray(ray) {
+            Some((tnear, _)) if tnear < t_max => tnear.max(0.0),
+            _ => return None,
+        };
+        let start = ray.origin + ray.dir*t_start;
+        let mut cell = self.cell_of(start);
+        let mut step = [0isize; 3];
+        let mut next_t = [f64::INFINITY; 3];
+        let mut delta_t = [f64::INFINITY; 3];
+        for axis in 0..3 {
+            let dir = ray.dir[axis];
+            let size = self.cell_size(axis);
+            let min = self.bounds.min[axis];
+            let pos = start[axis];
+            if dir > 0.0 {
+                step[axis] = 1;
+                next_t[axis] = t_start + (min + (cell[axis] + 1) as f64 * size - pos) / dir;
+                delta_t[axis] = size / dir;
+            } else if dir < 0.0 {
+                step[axis] = -1;
+                next_t[axis] = t_start + (min + cell[axis] as f64 * size - pos) / dir;
+                delta_t[axis] = -size / dir;
+            }
+        }
+
+        MAILBOX.with(|mailbox| {
+            let mut mailbox = mailbox.borrow_mut();
+            let (ref mut ray_id, ref mut tested) = *mailbox;
+            *ray_id += 1;
+            if tested.len() < self.objects.len() {
+                tested.resize(self.objects.len(), 0);
+            }
+
+            let mut closest = t_max;
+            let mut isect = None;
+            loop {
+                for &index in &self.cells[self.cell_index(cell)] {
+                    if tested[index] == *ray_id {
+                        continue;
+                    }
+                    tested[index] = *ray_id;
+                    if let Some(i) = self.objects[index].intersects(ray) {
+                        if i.dist < closest {
+                            closest = i.dist;
+                            isect = Some(i);
+                        }
+                    }
+                }
+                let axis = if next_t[0] < next_t[1] {
+                    if next_t[0] < next_t[2] { 0 } else { 2 }
+                } else if next_t[1] < next_t[2] { 1 } else { 2 };
+                // Hits in later cells are all farther than the exit from
+                // this one.
+                if closest <= next_t[axis] {
+                    break;
+                }
+                let next = cell[axis] as isize + step[axis];
+                if next < 0 || next >= self.dim[axis] as isize {
+                    break;
+                }
+                cell[axis] = next as usize;
+                next_t[axis] += delta_t[axis];
+            }
+            isect
+        })
+    }
+}
+
+/// k-d tree over objects with finite bounding boxes. Each node splits
+/// its box in two at the median of its objects' centers along the box's
+/// longest axis; objects crossing the split are in both halves. Nodes stop
+/// splitting at `max_depth` or with `min_objects_per_leaf` objects or
+/// fewer. Traversal follows the ray front to back through the cells, so
+/// it stops at the first cell with a hit. Median cuts don't weigh how
+/// much surface each side gets, so on the terrain of the `kd_vs_bvh`
+/// example it only keeps up with the `Bvh` up to about 1000 triangles.
+pub struct KdTree {
+    nodes: Vec<KdNode>,
+    /// Indices into `objects` of each leaf's objects.
+    indices: Vec<usize>,
+    bounds: Aabb,
+    max_depth: usize,
+    min_objects_per_leaf: usize,
+    objects: Vec<Box<dyn Geometry>>,
+}
+
+enum KdNode {
+    Leaf { first: usize, count: usize },
+    /// `below` holds everything with coordinates up to `split` along
+    /// `axis`, `above` everything from it up.
+    Interior { axis: usize, split: f64, below: usize, above: usize },
+}
+
+impl KdTree {
+    /// Builds a k-d tree over `objects`. Every object must have a bounding
+    /// box. `max_depth` is capped at 64.
+    pub fn new(objects: Vec<Box<dyn Geometry>>, max_depth: usize,
+               min_objects_per_leaf: usize) -> KdTree {
+        let boxes: Vec<Aabb> = objects.iter()
+            .map(|o| o.bounding_box().expect("k-d tree objects must be bounded"))
+            .collect();
+        let bounds = match boxes.first() {
+            Some(first) => boxes.iter().fold(*first, |b, o| b.merge(o)),
+            None => Aabb { min: v3!(0.0, 0.0, 0.0), max: v3!(0.0, 0.0, 0.0) },
+        };
+        let mut tree = KdTree { nodes: Vec::new(),
+                                indices: Vec::new(),
+                                bounds,
+                                max_depth: max_depth.min(MAX_DEPTH - 1),
+                                min_objects_per_leaf,
+                                objects };
+        tree.build((0..boxes.len()).collect(), &boxes, bounds, 0);
+        tree
+    }
+
+    pub fn objects(&self) -> &[Box<dyn Geometry>] {
+        &self.objects
+    }
+
+    /// Gives back the objects the tree was built from.
+    pub fn into_objects(self) -> Vec<Box<dyn Geometry>> {
+        self.objects
+    }
+
+    /// Indices into `objects` of the objects whose bounding boxes overlap
+    /// the sphere of `radius` around `center`, in increasing order.
+    pub fn objects_within_radius(&self, center: Vector3, radius: f64) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() && self.bounds.overlaps_sphere(center, radius) {
+            stack.push(0);
+        }
+        while let Some(node) = stack.pop() {
+            match self.nodes[node] {
+                KdNode::Leaf { first, count } =>
+                    found.extend(&self.indices[first..first + count]),
+                KdNode::Interior { axis, split, below, above } => {
+                    if center[axis] - radius <= split {
+                        stack.push(below);
+                    }
+                    if center[axis] + radius >= split {
+                        stack.push(above);
+                    }
+                }
+            }
+        }
+        finish_query(&self.objects, found, center, radius)
+    }
+
+    /// Adds the subtree over `items`, which lie in `bounds`, and returns the
+    /// index of its root node.
+    fn build(&mut self, mut items: Vec<usize>, boxes: &[Aabb], bounds: Aabb, depth: usize) -> usize {
+        let node = self.nodes.len();
+        let first = self.indices.len();
+        self.nodes.push(KdNode::Leaf { first, count: items.len() });
+        if depth >= self.max_depth || items.len() <= self.min_objects_per_leaf.max(1) {
+            self.indices.extend(items);
+            return node;
+        }
+
+        let extent = bounds.max - bounds.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 }
+                   else if extent.y >= extent.z { 1 } else { 2 };
+        let center = |i: &usize| boxes[*i].centroid()[axis];
+        let median = items.len() / 2;
+        items.select_nth_unstable_by(median, |a, b| center(a).total_cmp(&center(b)));
+        let split = center(&items[median]);
+        let below: Vec<usize> = items.iter().cloned().filter(|&i| boxes[i].min[axis] <= split).collect();
+        let above: Vec<usize> = items.iter().cloned().filter(|&i| boxes[i].max[axis] >= split).collect();
+        if split <= bounds.min[axis] || split >= bounds.max[axis]
+            || (below.len() == items.len() && above.len() == items.len()) {
+            // Splitting wouldn't separate anything.
+            self.indices.extend(items);
+            return node;
+        }
+
+        let (mut below_bounds, mut above_bounds) = (bounds, bounds);
+        below_bounds.max[axis] = split;
+        above_bounds.min[axis] = split;
+        let below = self.build(below, boxes, below_bounds, depth + 1);
+        let above = self.build(above, boxes, above_bounds, depth + 1);
+        self.nodes[node] = KdNode::Interior { axis, split, below, above };
+        node
+    }
+
+    /// Returns the closest intersection nearer than `t_max`.
+    pub fn intersect(&self, ray: &Ray, t_max: f64) -> Option<Intersection> {
+        let (mut tmin, mut tmax) = match self.bounds.intersects_ray(ray) {
+            Some((tnear, tfar)) if !self.nodes.is_empty() => (tnear.max(0.0), tfar.min(t_max)),
+            _ => return None,
+        };
+        MAILBOX.with(|mailbox| {
+            let mut mailbox = mailbox.borrow_mut();
+            let (ref mut ray_id, ref mut tested) = *mailbox;
+            *ray_id += 1;
+            if tested.len() < self.objects.len() {
+                tested.resize(self.objects.len(), 0);
+            }
+
+            // Far children still to visit, with the part of the ray inside
+            // them. At most one waits per level of the tree.
+            let mut stack = [(0, 0.0, 0.0); MAX_DEPTH];
+            let mut len = 0;
+            let mut node = 0;
+            let mut closest = t_max;
+            let mut isect = None;
+            while tmin <= tmax {
+                match self.nodes[node] {
+                    KdNode::Interior { axis, split, below, above } => {
+                        let (origin, dir) = (ray.origin[axis], ray.dir[axis]);
+                        let below_first = origin < split || (origin == split && dir <= 0.0);
+                        let (near, far) = if below_first { (below, above) } else { (above, below) };
+                        let t_split = (split - origin) / dir;
+                        if t_split.is_nan() || t_split > tmax || t_split <= 0.0 {
+                            node = near;
+                        } else if t_split < tmin {
+                            node = far;
+                        } else {
+                            stack[len] = (far, t_split, tmax);
+                            len += 1;
+                            node = near;
+                            tmax = t_split;
+                        }
+                        continue;
+                    },
+                    KdNode::Leaf { first, count } => {
+                        for &index in &self.indices[first..first+count] {
+                            if tested[index] == *ray_id {
+                                continue;
+                            }
+                            tested[index] = *ray_id;
+                            if let Some(hit) = self.objects[index].intersects(ray) {
+                                if hit.dist < closest {
+                                    closest = hit.dist;
+                                    isect = Some(hit);
+                                }
+                            }
+                        }
+                        // Hits past this cell can still be beaten by objects
+                        // in the cells after it.
+                        if closest <= tmax {
+                            break;
+                        }
+                    },
+                }
+                if len == 0 {
+                    break;
+                }
+                len -= 1;
+                let (next, next_min, next_max) = stack[len];
+                node = next;
+                tmin = next_min;
+                tmax = next_max.min(closest);
+            }
+            isect
+        })
+    }
+}
+
+/// Kind of acceleration structure for `Scene::build_acceleration`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AccelerationStructure {
+    Bvh,
+    Grid,
+    KdTree { max_depth: usize, min_objects_per_leaf: usize },
+}
+
+impl AccelerationStructure {
+    /// Builds this kind of structure over `objects`, which must all be
+    /// bounded.
+    pub fn build(self, objects: Vec<Box<dyn Geometry>>) -> Accelerator {
+        match self {
+            AccelerationStructure::Bvh => Accelerator::Bvh(Bvh::new(objects)),
+            AccelerationStructure::Grid => Accelerator::Grid(UniformGrid::new(objects)),
+            AccelerationStructure::KdTree { max_depth, min_objects_per_leaf } =>
+                Accelerator::KdTree(KdTree::new(objects, max_depth, min_objects_per_leaf)),
+        }
+    }
+}
+
+/// Acceleration structure of a scene.
+pub enum Accelerator {
+    Bvh(Bvh),
+    Grid(UniformGrid),
+    KdTree(KdTree),
+}
+
+impl Accelerator {
+    pub fn objects(&self) -> &[Box<dyn Geometry>] {
+        match *self {
+            Accelerator::Bvh(ref bvh) => bvh.objects(),
+            Accelerator::Grid(ref grid) => grid.objects(),
+            Accelerator::KdTree(ref tree) => tree.objects(),
+        }
+    }
+
+    pub fn into_objects(self) -> Vec<Box<dyn Geometry>> {
+        match self {
+            Accelerator::Bvh(bvh) => bvh.into_objects(),
+            Accelerator::Grid(grid) => grid.into_objects(),
+            Accelerator::KdTree(tree) => tree.into_objects(),
+        }
+    }
+
+    /// Returns the closest intersection nearer than `t_max`.
+    pub fn intersect(&self, ray: &Ray, t_max: f64) -> Option<Intersection> {
+        match *self {
+            Accelerator::Bvh(ref bvh) => bvh.intersect(ray, t_max),
+            Accelerator::Grid(ref grid) => grid.intersect(ray, t_max),
+            Accelerator::KdTree(ref tree) => tree.intersect(ray, t_max),
+        }
+    }
+
+    /// Indices into `objects()` of the objects whose bounding boxes
+    /// overlap the sphere of `radius` around `center`, in increasing order.
+    pub fn objects_within_radius(&self, center: Vector3, radius: f64) -> Vec<usize> {
+        match *self {
+            Accelerator::Bvh(ref bvh) => bvh.objects_within_radius(center, radius),
+            Accelerator::Grid(ref grid) => grid.objects_within_radius(center, radius),
+            Accelerator::KdTree(ref tree) => tree.objects_within_radius(center, radius),
+        }
+    }
+
+    /// Replaces object `index` of `objects()` with `f` applied to it. The
+    /// structure itself isn't updated, so it has to be rebuilt if the
+    /// object's bounds change.
+    pub fn replace_object<F>(&mut self, index: usize, f: F)
+        where F: FnOnce(Box<dyn Geometry>) -> Box<dyn Geometry>
+    {
+        let objects = match *self {
+            Accelerator::Bvh(ref mut bvh) => &mut bvh.objects,
+            Accelerator::Grid(ref mut grid) => &mut grid.objects,
+            Accelerator::KdTree(ref mut tree) => &mut tree.objects,
+        };
+        // Swap the object out and its replacement back in, keeping the
+        // indices of the others.
+        let object = objects.swap_remove(index);
+        objects.push(f(object));
+        let last = objects.len() - 1;
+        objects.swap(index, last);
+    }
+
+    /// What kind of structure this is, for building the same kind over
+    /// new objects.
+    pub fn kind(&self) -> AccelerationStructure {
+        match *self {
+            Accelerator::Bvh(_) => AccelerationStructure::Bvh,
+            Accelerator::Grid(_) => AccelerationStructure::Grid,
+            Accelerator::KdTree(ref tree) =>
+                AccelerationStructure::KdTree { max_depth: tree.max_depth,
+                                                min_objects_per_leaf: tree.min_objects_per_leaf },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raytracer::{basic_material, Sphere, Triangle};
+    use rng::Rng;
+
+    fn random_vector(rng: &mut Rng) -> Vector3 {
+        v3!(rng.next_f64()*2.0 - 1.0, rng.next_f64()*2.0 - 1.0, rng.next_f64()*2.0 - 1.0)
+    }
+
+    /// Spheres and triangles of varying sizes in a 20 unit cube.
+    fn random_objects(n: usize, rng: &mut Rng) -> Vec<Box<dyn Geometry>> {
+        let white = basic_material(v3!(1.0, 1.0, 1.0));
+        (0..n).map(|i| {
+            let pos = random_vector(rng)*10.0;
+            if i % 2 == 0 {
+                Box::new(Sphere { pos, radius: 0.05 + rng.next_f64()*0.5, material: white.clone().into(),
+                                  motion: None }) as Box<dyn Geometry>
+            } else {
+                Box::new(Triangle::new(pos, pos + random_vector(rng), pos + random_vector(rng),
+                                       white.clone()))
+            }
+        }).collect()
+    }
+
+    /// Nearest hit by testing every object.
+    fn brute_force(objects: &[Box<dyn Geometry>], ray: &Ray, t_max: f64) -> Option<f64> {
+        objects.iter()
+            .filter_map(|o| o.intersects(ray))
+            .map(|i| i.dist)
+            .filter(|&t| t < t_max)
+            .fold(None, |best, t| Some(best.map_or(t, |b: f64| b.min(t))))
+    }
+
+    #[test]
+    fn bvh_finds_the_nearest_hit() {
+        let mut rng = Rng::new(11);
+        let bvh = Bvh::new(random_objects(2000, &mut rng));
+        let mut hits = 0;
+        for i in 0..2000 {
+            // From outside and from among the objects.
+            let origin = random_vector(&mut rng) * if i % 2 == 0 { 30.0 } else { 5.0 };
+            let dir = (random_vector(&mut rng)*5.0 - origin).normalize();
+            let ray = Ray { origin, dir, t_max: f64::INFINITY };
+            let t_max = if i % 3 == 0 { 10.0 } else { f64::INFINITY };
+            let expected = brute_force(bvh.objects(), &ray, t_max);
+            assert_eq!(bvh.intersect(&ray, t_max).map(|i| i.dist), expected, "ray {:?}", ray);
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 500, "only {} hits", hits);
+        assert!(Bvh::new(Vec::new()).intersect(&Ray { origin: v3!(0.0, 0.0, 0.0), dir: v3!(0.0, 0.0, 1.0),
+                                                      t_max: f64::INFINITY }, f64::INFINITY).is_none());
+    }
+
+    /// Nodes as plain values that can be compared, bounds bit for bit.
+    fn node_layout(nodes: &[BvhNode]) -> Vec<(bool, usize, usize, [u64; 6])> {
+        nodes.iter().map(|node| {
+            let b = node.bounds();
+            let mut bits = [0; 6];
+            for (i, c) in b.min.as_array().iter().chain(b.max.as_array().iter()).enumerate() {
+                bits[i] = c.to_bits();
+            }
+            match *node {
+                BvhNode::Leaf { first, count, .. } => (true, first, count, bits),
+                BvhNode::Interior { left, right, .. } => (false, left, right, bits),
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn parallel_build_matches_serial_build() {
+        let mut rng = Rng::new(17);
+        let white = basic_material(v3!(1.0, 1.0, 1.0));
+        let triangles: Vec<Box<dyn Geometry>> = (0..20000).map(|_| {
+            let pos = random_vector(&mut rng)*10.0;
+            Box::new(Triangle::new(pos, pos + random_vector(&mut rng)*0.2,
+                                   pos + random_vector(&mut rng)*0.2, white.clone()))
+                as Box<dyn Geometry>
+        }).collect();
+        let primitives = || -> Vec<Primitive> {
+            triangles.iter().enumerate().map(|(index, o)| {
+                let bounds = o.bounding_box().unwrap();
+                Primitive { index, bounds, centroid: bounds.centroid() }
+            }).collect()
+        };
+
+        let mut serial_prims = primitives();
+        let mut serial = Vec::new();
+        build(&mut serial, &mut serial_prims, 0, 0);
+        let order = |prims: &[Primitive]| prims.iter().map(|p| p.index).collect::<Vec<_>>();
+        assert!(serial.len() > 2 * 20000 / MAX_LEAF_SIZE, "only {} nodes", serial.len());
+
+        for threads in [1, 4] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let mut prims = primitives();
+            let nodes = pool.install(|| build_parallel(&mut prims, 0, 0));
+            assert!(node_layout(&nodes) == node_layout(&serial),
+                    "nodes differ on {} threads", threads);
+            assert_eq!(order(&prims), order(&serial_prims), "primitives differ on {} threads", threads);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use output::writer_for_format;
+use raytracer::{raytrace_full, raytrace_parallel, Camera, RenderConfig, RenderStats, Scene};
+use scene_file::SceneFile;
+use vector::Vector3;
+
+/// Sequence of scenes rendered one after another as the frames of an
+/// animation.
+pub struct Animation {
+    pub frames: Vec<Scene>,
+}
+
+impl Animation {
+    /// `frames` scenes interpolated linearly from `start` to `end` with
+    /// `lerp_scenes`, including both ends. The BVH is built for each frame.
+    pub fn lerp(start: &Scene, end: &Scene, frames: usize) -> Animation {
+        let frames = (0..frames).map(|i| {
+            let t = if frames > 1 { i as f64 / (frames - 1) as f64 } else { 0.0 };
+            let mut scene = lerp_scenes(start, end, t);
+            scene.build_bvh();
+            scene
+        }).collect();
+        Animation { frames }
+    }
+}
+
+/// Scene between `a` at `t = 0` and `b` at `t = 1`. Every number in the
+/// scenes' descriptions is interpolated, so camera, light and object
+/// positions move and material colors blend. Only floating point values
+/// present in both scenes are interpolated; anything else, such as
+/// objects only in one of the scenes or sample counts, is taken from `a`.
+/// Like `Scene::to_json`, this leaves out what scene files can't hold.
+pub fn lerp_scenes(a: &Scene, b: &Scene, t: f64) -> Scene {
+    let to_value = |scene| serde_json::to_value(SceneFile::from_scene(scene))
+        .expect("scene descriptions always serialize");
+    let value = lerp_value(&to_value(a), &to_value(b), t);
+    serde_json::from_value::<SceneFile>(value)
+        .expect("interpolated scene descriptions always deserialize")
+        .into_scene()
+}
+
+fn lerp_value(a: &Value, b: &Value, t: f64) -> Value {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) if x.is_f64() && y.is_f64() => {
+            let (x, y) = (x.as_f64().unwrap(), y.as_f64().unwrap());
+            serde_json::Number::from_f64(x + (y - x)*t).map_or(a.clone(), Value::Number)
+        },
+        (Value::Array(xs), Value::Array(ys)) => Value::Array(
+            xs.iter().enumerate()
+                .map(|(i, x)| ys.get(i).map_or(x.clone(), |y| lerp_value(x, y, t)))
+                .collect()),
+        (Value::Object(xs), Value::Object(ys)) => Value::Object(
+            xs.iter()
+                .map(|(k, x)| (k.clone(), ys.get(k).map_or(x.clone(), |y| lerp_value(x, y, t))))
+                .collect()),
+        _ => a.clone(),
+    }
+}
+
+/// Renders every frame of `anim` into `output_dir` as numbered images,
+/// `frame_0001.png` and so on for the format "png", ready for an image
+/// viewer or ffmpeg. Returns the paths of the written files.
+pub fn render_animation(anim: &Animation, config: &RenderConfig, output_dir: &Path,
+                        format: &str) -> io::Result<Vec<PathBuf>> {
+    let writer = writer_for_format(format).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported format '{}'", format))
+    })?;
+    fs::create_dir_all(output_dir)?;
+    let mut paths = Vec::with_capacity(anim.frames.len());
+    for (i, scene) in anim.frames.iter().enumerate() {
+        let pixels = raytrace_parallel(scene, config);
+        let path = output_dir.join(format!("frame_{:04}.{}", i + 1, format));
+        let mut out = BufWriter::new(File::create(&path)?);
+        writer.write(&pixels, config.width, config.height, &mut out)?;
+        out.flush()?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Cameras at `frames` equally spaced angles on a circle around `target`,
+/// `radius` away and `elevation` radians above its horizontal plane, all
+/// looking at `target`. The first is on the -z side and they go round
+/// once, so the last stops one step short of the first. The field of view
+/// and aspect ratio are taken from the scene's camera.
+pub fn turntable_cameras(scene: &Scene, target: Vector3, radius: f64, elevation: f64,
+                         frames: usize) -> Vec<Camera> {
+    let camera = &scene.camera;
+    let half_height = camera.up.length() / camera.dist;
+    let vfov = 2.0 * half_height.atan().to_degrees();
+    let aspect = camera.right.length() / camera.up.length();
+    (0..frames).map(|i| {
+        let angle = 2.0 * PI * i as f64 / frames as f64;
+        let offset = v3!(elevation.cos() * angle.sin(), elevation.sin(), -elevation.cos() * angle.cos());
+        Camera::look_at(target + offset*radius, target, v3!(0.0, 1.0, 0.0), vfov, aspect)
+    }).collect()
+}
+
+/// Renders `scene` from each of `turntable_cameras` into `output_dir` as
+/// `turntable_0001.png` and so on, for showing an object from every side.
+/// Progress is printed to stderr. Returns the `RenderStats` of a frame
+/// averaged over all of them.
+pub fn render_turntable(scene: &Scene, target: Vector3, radius: f64, elevation: f64,
+                        frames: usize, config: &RenderConfig,
+                        output_dir: &Path) -> io::Result<RenderStats> {
+    let writer = writer_for_format("png").expect("png is supported");
+    fs::create_dir_all(output_dir)?;
+    let mut total = RenderStats::default();
+    let cameras = turntable_cameras(scene, target, radius, elevation, frames);
+    for (i, camera) in cameras.into_iter().enumerate() {
+        eprint!("\rframe {}/{}", i + 1, frames);
+        let config = RenderConfig { camera: Some(camera), print_stats: false, ..config.clone() };
+        let buffer = raytrace_full(scene, &config);
+        let path = output_dir.join(format!("turntable_{:04}.png", i + 1));
+        let mut out = BufWriter::new(File::create(&path)?);
+        writer.write(&buffer.color, config.width, config.height, &mut out)?;
+        out.flush()?;
+        let stats = buffer.stats;
+        total.primary_rays += stats.primary_rays;
+        total.shadow_rays += stats.shadow_rays;
+        total.reflection_rays += stats.reflection_rays;
+        total.total_intersections += stats.total_intersections;
+        total.elapsed_ms += stats.elapsed_ms;
+    }
+    eprintln!();
+    let n = frames.max(1) as u64;
+    Ok(RenderStats { primary_rays: total.primary_rays / n,
+                     shadow_rays: total.shadow_rays / n,
+                     reflection_rays: total.reflection_rays / n,
+                     total_intersections: total.total_intersections / n,
+                     elapsed_ms: total.elapsed_ms / n })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raytracer::{basic_material, SceneBuilder};
+
+    #[test]
+    fn moving_sphere_renders_distinct_frames() {
+        let at = |x| SceneBuilder::new()
+            .sphere(v3!(x, 0.0, 5.0), 1.0, basic_material(v3!(1.0, 0.0, 0.0)))
+            .light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0))
+            .build();
+        let anim = Animation::lerp(&at(-2.0), &at(2.0), 3);
+        assert_eq!(anim.frames[1].to_json(), at(0.0).to_json());
+
+        let dir = std::env::temp_dir().join(format!("rust_raytracer_animation_{}", std::process::id()));
+        let config = RenderConfig { width: 16, height: 12, print_stats: false, ..RenderConfig::default() };
+        let paths = render_animation(&anim, &config, &dir, "ppm").unwrap();
+        let images: Vec<Vec<u8>> = paths.iter().map(|path| fs::read(path).unwrap()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<_> = paths.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["frame_0001.ppm", "frame_0002.ppm", "frame_0003.ppm"]);
+        assert!(images[0] != images[1] && images[1] != images[2] && images[0] != images[2]);
+    }
+
+    #[test]
+    fn turntable_goes_round_once() {
+        let scene = SceneBuilder::new()
+            .sphere(v3!(0.0, 0.0, 0.0), 1.0, basic_material(v3!(1.0, 0.0, 0.0)))
+            .sphere(v3!(1.2, 0.0, 0.0), 0.3, basic_material(v3!(0.0, 1.0, 0.0)))
+            .light(v3!(0.0, 10.0, -10.0), v3!(1.0, 1.0, 1.0))
+            .build();
+        let target = v3!(0.0, 0.0, 0.0);
+        let cameras = turntable_cameras(&scene, target, 5.0, 0.3, 12);
+        let angle = |c: &Camera| c.pos.x.atan2(-c.pos.z).rem_euclid(2.0 * PI);
+        // Every step, including the one from the last frame back to the
+        // first, is 30 degrees, so 12 steps are exactly 360.
+        let mut total = 0.0;
+        for i in 0..12 {
+            let step = (angle(&cameras[(i + 1) % 12]) - angle(&cameras[i])).rem_euclid(2.0 * PI);
+            assert!((step.to_degrees() - 30.0).abs() < 1e-9, "step {} is {}", i, step.to_degrees());
+            total += step;
+        }
+        assert!((total.to_degrees() - 360.0).abs() < 1e-9);
+        for camera in &cameras {
+            assert!(((camera.pos - target).length() - 5.0).abs() < 1e-9);
+            assert!((camera.pos.y - 5.0 * 0.3f64.sin()).abs() < 1e-9);
+        }
+
+        let dir = std::env::temp_dir().join(format!("rust_raytracer_turntable_{}", std::process::id()));
+        let config = RenderConfig { width: 16, height: 12, print_stats: false, ..RenderConfig::default() };
+        let stats = render_turntable(&scene, target, 5.0, 0.3, 12, &config, &dir).unwrap();
+        let images: Vec<Vec<u8>> = (1..=12)
+            .map(|i| fs::read(dir.join(format!("turntable_{:04}.png", i))).unwrap())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(images[0] != images[11], "the last frame repeats the first");
+        assert_eq!(stats.primary_rays, 16 * 12);
+    }
+}
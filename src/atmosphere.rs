@@ -0,0 +1,171 @@
+//! Single scattering sky, after Nishita et al. "Display of the Earth
+//! Taking into Account Atmospheric Scattering". Sunlight is scattered
+//! once towards the viewer by air molecules (Rayleigh scattering, much
+//! stronger for blue than for red, which makes the sky blue) and by
+//! aerosols (Mie scattering, the same for every wavelength, which makes
+//! the glow around the sun white). The viewer stands on the ground of a
+//! spherical planet; distances are in meters.
+
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use vector::Vector3;
+
+const PLANET_RADIUS: f64 = 6_360e3;
+const ATMOSPHERE_RADIUS: f64 = 6_420e3;
+/// Heights over which the density of air molecules and of aerosols falls
+/// by a factor of e.
+const RAYLEIGH_SCALE_HEIGHT: f64 = 7_994.0;
+const MIE_SCALE_HEIGHT: f64 = 1_200.0;
+/// Mean cosine of the Mie scattering angle: aerosols mostly scatter
+/// forwards.
+const MIE_G: f64 = 0.76;
+/// Points along the view ray, and along the ray from each of them to
+/// the sun, at which the integrals are evaluated.
+const STEPS: usize = 16;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AtmosphereModel {
+    /// Rayleigh scattering coefficient at sea level, per meter, for red,
+    /// green and blue.
+    pub beta_rayleigh: Vector3,
+    /// Mie scattering coefficient at sea level, per meter.
+    pub beta_mie: f64,
+    /// Unit direction towards the sun.
+    pub sun_dir: Vector3,
+    /// Radiance of the sunlight entering the atmosphere.
+    pub sun_intensity: f64,
+}
+
+impl Default for AtmosphereModel {
+    /// The Earth's atmosphere with the sun 30 degrees above the horizon.
+    fn default() -> AtmosphereModel {
+        AtmosphereModel { beta_rayleigh: v3!(5.8e-6, 13.5e-6, 33.1e-6),
+                          beta_mie: 21e-6,
+                          sun_dir: v3!(0.0, 0.5, 3.0f64.sqrt() / 2.0),
+                          sun_intensity: 20.0 }
+    }
+}
+
+impl AtmosphereModel {
+    /// Radiance scattered towards a viewer on the ground looking along
+    /// `dir`. Rays below the horizon stop at the ground, which is black.
+    pub fn radiance(&self, dir: Vector3) -> Vector3 {
+        let dir = dir.normalize();
+        let sun_dir = self.sun_dir.normalize();
+        let origin = v3!(0.0, PLANET_RADIUS + 1.0, 0.0);
+        let length = match sphere_hit(origin, dir, PLANET_RADIUS) {
+            Some(t) => t,
+            None => sphere_hit(origin, dir, ATMOSPHERE_RADIUS).unwrap_or(0.0),
+        };
+        let step = length / STEPS as f64;
+
+        // Optical depth from the viewer, and the light scattered at each
+        // point weighted by the density there.
+        let (mut depth_rayleigh, mut depth_mie) = (0.0, 0.0);
+        let (mut sum_rayleigh, mut sum_mie) = (v3!(0.0, 0.0, 0.0), v3!(0.0, 0.0, 0.0));
+        for i in 0..STEPS {
+            let p = origin + dir*(step*(i as f64 + 0.5));
+            let height = p.length() - PLANET_RADIUS;
+            let density_rayleigh = (-height / RAYLEIGH_SCALE_HEIGHT).exp() * step;
+            let density_mie = (-height / MIE_SCALE_HEIGHT).exp() * step;
+            depth_rayleigh += density_rayleigh;
+            depth_mie += density_mie;
+            // The sun is hidden by the planet at this point.
+            let (sun_rayleigh, sun_mie) = match self.depth_to_sun(p, sun_dir) {
+                Some(depths) => depths,
+                None => continue,
+            };
+            let tau = self.beta_rayleigh*(depth_rayleigh + sun_rayleigh)
+                + v3!(1.0, 1.0, 1.0)*(1.1*self.beta_mie*(depth_mie + sun_mie));
+            let transmittance = tau.map(|t| (-t).exp());
+            sum_rayleigh += transmittance*density_rayleigh;
+            sum_mie += transmittance*density_mie;
+        }
+
+        let mu = dir.dot(sun_dir);
+        let phase_rayleigh = 3.0 / (16.0*PI) * (1.0 + mu*mu);
+        let g2 = MIE_G*MIE_G;
+        let phase_mie = 3.0 / (8.0*PI) * ((1.0 - g2)*(1.0 + mu*mu))
+            / ((2.0 + g2)*(1.0 + g2 - 2.0*MIE_G*mu).powf(1.5));
+        let rayleigh = Vector3::zip_with(sum_rayleigh, self.beta_rayleigh, |s, b| s*b) * phase_rayleigh;
+        (rayleigh + sum_mie*(self.beta_mie*phase_mie)) * self.sun_intensity
+    }
+
+    /// Rayleigh and Mie optical depths, before the scattering coefficients,
+    /// from `p` to the top of the atmosphere towards the sun, or None if
+    /// the planet is in the way.
+    fn depth_to_sun(&self, p: Vector3, sun_dir: Vector3) -> Option<(f64, f64)> {
+        if sphere_hit(p, sun_dir, PLANET_RADIUS).is_some() {
+            return None;
+        }
+        let step = sphere_hit(p, sun_dir, ATMOSPHERE_RADIUS).unwrap_or(0.0) / STEPS as f64;
+        let (mut rayleigh, mut mie) = (0.0, 0.0);
+        for j in 0..STEPS {
+            let height = (p + sun_dir*(step*(j as f64 + 0.5))).length() - PLANET_RADIUS;
+            rayleigh += (-height / RAYLEIGH_SCALE_HEIGHT).exp() * step;
+            mie += (-height / MIE_SCALE_HEIGHT).exp() * step;
+        }
+        Some((rayleigh, mie))
+    }
+}
+
+/// Distance along the ray to its first crossing, in front of `origin`,
+/// of the sphere of `radius` around the planet's center.
+fn sphere_hit(origin: Vector3, dir: Vector3, radius: f64) -> Option<f64> {
+    let b = origin.dot(dir);
+    let c = origin.dot(origin) - radius*radius;
+    let discriminant = b*b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let (t0, t1) = (-b - root, -b + root);
+    if t0 > 0.0 {
+        Some(t0)
+    } else if t1 > 0.0 {
+        Some(t1)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clear_sky_is_blue_at_noon_and_red_at_sunset() {
+        let noon = AtmosphereModel { sun_dir: v3!(0.0, 1.0, 0.0), ..AtmosphereModel::default() };
+        let up = noon.radiance(v3!(0.0, 1.0, 0.0));
+        assert!(up.z > up.y && up.y > up.x && up.x > 0.0, "noon sky {:?}", up);
+        let side = noon.radiance(v3!(1.0, 0.2, 0.0));
+        assert!(side.z > side.x, "noon sky towards the horizon {:?}", side);
+
+        // Looking at the setting sun, the light has come through so much
+        // air that red wins.
+        let sunset = AtmosphereModel { sun_dir: v3!(0.0, 0.02, 1.0).normalize(), ..AtmosphereModel::default() };
+        let towards_sun = sunset.radiance(v3!(0.0, 0.05, 1.0));
+        assert!(towards_sun.x > towards_sun.z, "sunset sky {:?}", towards_sun);
+        // And the sky overhead is darker than at noon.
+        assert!(sunset.radiance(v3!(0.0, 1.0, 0.0)).z < up.z);
+    }
+
+    #[test]
+    fn glow_around_the_sun_is_brighter_than_the_opposite_sky() {
+        let model = AtmosphereModel::default();
+        let near_sun = model.radiance(model.sun_dir + v3!(0.05, 0.0, 0.0));
+        let opposite = model.radiance(v3!(-model.sun_dir.x, model.sun_dir.y, -model.sun_dir.z));
+        assert!(near_sun.y > 3.0*opposite.y, "{:?} near the sun, {:?} opposite", near_sun, opposite);
+    }
+
+    #[test]
+    fn ground_and_night_are_dark() {
+        let model = AtmosphereModel::default();
+        // Only the little air between the viewer and the ground scatters.
+        assert!(model.radiance(v3!(0.0, -1.0, 0.0)).max_component() < 1e-3);
+        let night = AtmosphereModel { sun_dir: v3!(0.0, -1.0, 0.0), ..model };
+        assert_eq!(night.radiance(v3!(0.0, 1.0, 0.0)), v3!(0.0, 0.0, 0.0));
+    }
+}
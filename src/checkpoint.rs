@@ -0,0 +1,147 @@
+//! Checkpoint files for resuming long renders, see `raytrace_resumable`.
+//! The format is the magic bytes `RTCKPT02`, then as little-endian 64-bit
+//! values the width, height, sample count, the fingerprint of the scene
+//! and settings being rendered and the five `RenderStats` counters, then for each pixel its color and depth as floats and its
+//! normal as a presence byte followed by three floats. Floats are stored
+//! bit for bit, so a buffer comes back exactly as it was saved.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+
+use raytracer::{RenderBuffer, RenderStats};
+use vector::Vector3;
+
+const MAGIC: &[u8; 8] = b"RTCKPT02";
+
+/// Writes `buffer`, the number of samples accumulated into it and the
+/// `fingerprint` of what is being rendered to `path`, replacing the file if there is one. The file is written next
+/// to `path` first and renamed over it, so an interrupted save leaves the
+/// previous checkpoint intact.
+pub fn save_checkpoint(path: &str, buffer: &RenderBuffer, samples_so_far: usize,
+                       fingerprint: u64) -> io::Result<()> {
+    let partial = format!("{}.partial", path);
+    {
+        let mut out = BufWriter::new(File::create(&partial)?);
+        out.write_all(MAGIC)?;
+        let stats = &buffer.stats;
+        for n in [buffer.width as u64, buffer.height as u64, samples_so_far as u64, fingerprint,
+                  stats.primary_rays, stats.shadow_rays, stats.reflection_rays,
+                  stats.total_intersections, stats.elapsed_ms] {
+            out.write_all(&n.to_le_bytes())?;
+        }
+        for i in 0..buffer.width*buffer.height {
+            write_vector(&mut out, buffer.color[i])?;
+            out.write_all(&buffer.depth[i].to_le_bytes())?;
+            match buffer.normal[i] {
+                Some(normal) => {
+                    out.write_all(&[1])?;
+                    write_vector(&mut out, normal)?;
+                },
+                None => out.write_all(&[0])?,
+            }
+        }
+        out.flush()?;
+    }
+    std::fs::rename(&partial, path)
+}
+
+/// Reads a checkpoint written by `save_checkpoint`, returning the buffer,
+/// the number of samples in it and its fingerprint.
+pub fn load_checkpoint(path: &str) -> io::Result<(RenderBuffer, usize, u64)> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a render checkpoint"));
+    }
+    let mut header = [0; 9];
+    for n in header.iter_mut() {
+        *n = read_u64(&mut input)?;
+    }
+    let [width, height, samples, fingerprint, primary_rays, shadow_rays, reflection_rays,
+         total_intersections, elapsed_ms] = header;
+    let (width, height) = (width as usize, height as usize);
+    let pixels = width.checked_mul(height)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "image size overflows"))?;
+
+    let (mut color, mut depth, mut normal) = (Vec::new(), Vec::new(), Vec::new());
+    for _ in 0..pixels {
+        color.push(read_vector(&mut input)?);
+        depth.push(f64::from_bits(read_u64(&mut input)?));
+        let mut present = [0];
+        input.read_exact(&mut present)?;
+        normal.push(match present[0] {
+            0 => None,
+            1 => Some(read_vector(&mut input)?),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad normal flag")),
+        });
+    }
+    let stats = RenderStats { primary_rays, shadow_rays, reflection_rays, total_intersections,
+                              elapsed_ms };
+    Ok((RenderBuffer { color, depth, normal, width, height, stats }, samples as usize, fingerprint))
+}
+
+fn write_vector(out: &mut impl Write, v: Vector3) -> io::Result<()> {
+    for c in v.as_array() {
+        out.write_all(&c.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_vector(input: &mut impl Read) -> io::Result<Vector3> {
+    let mut c = [0.0; 3];
+    for c in c.iter_mut() {
+        *c = f64::from_bits(read_u64(input)?);
+    }
+    Ok(Vector3::from_array(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_roundtrip_is_exact() {
+        let (width, height) = (7, 5);
+        let buffer = RenderBuffer {
+            color: (0..width*height).map(|i| v3!(i as f64 / 3.0, -0.0, 1e300 * i as f64)).collect(),
+            depth: (0..width*height)
+                .map(|i| if i % 4 == 0 { f64::INFINITY } else { (i as f64).sqrt() })
+                .collect(),
+            normal: (0..width*height)
+                .map(|i| if i % 3 == 0 { None } else { Some(v3!(0.1 * i as f64, 1.0 / 7.0, -2.5)) })
+                .collect(),
+            width,
+            height,
+            stats: RenderStats { primary_rays: 1, shadow_rays: 2, reflection_rays: 3,
+                                 total_intersections: u64::MAX, elapsed_ms: 5 },
+        };
+        let path = std::env::temp_dir().join(format!("rust_raytracer_checkpoint_{}.bin",
+                                                     std::process::id()));
+        let path = path.to_str().unwrap();
+        save_checkpoint(path, &buffer, 42, u64::MAX - 7).unwrap();
+        let saved = std::fs::read(path).unwrap();
+        let (loaded, samples, fingerprint) = load_checkpoint(path).unwrap();
+        save_checkpoint(path, &loaded, samples, fingerprint).unwrap();
+        let resaved = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!((samples, fingerprint), (42, u64::MAX - 7));
+        assert_eq!((loaded.width, loaded.height, loaded.stats), (width, height, buffer.stats));
+        let bits = |v: Vector3| v.as_array().map(f64::to_bits);
+        for i in 0..width*height {
+            assert_eq!(bits(loaded.color[i]), bits(buffer.color[i]));
+            assert_eq!(loaded.depth[i].to_bits(), buffer.depth[i].to_bits());
+            assert_eq!(loaded.normal[i].map(bits), buffer.normal[i].map(bits));
+        }
+        assert!(saved == resaved, "saving the loaded checkpoint changed the file");
+    }
+}
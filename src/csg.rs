@@ -0,0 +1,168 @@
+use acceleration::Aabb;
+use raytracer::{Geometry, Intersection, Material, MaterialId, Ray};
+
+/// Constructive solid geometry: a solid built from two others. The children
+/// should be closed solids. Bounded children are entered from outside their
+/// bounding boxes, so rays may start anywhere; for unbounded ones the ray
+/// origin is assumed to be outside.
+pub enum Csg {
+    /// Points in either solid.
+    Union(Box<dyn Geometry>, Box<dyn Geometry>),
+    /// Points in both solids.
+    Intersection(Box<dyn Geometry>, Box<dyn Geometry>),
+    /// Points in the first solid but not in the second.
+    Difference(Box<dyn Geometry>, Box<dyn Geometry>),
+}
+
+impl Csg {
+    fn children(&self) -> (&dyn Geometry, &dyn Geometry) {
+        match *self {
+            Csg::Union(ref a, ref b) => (a.as_ref(), b.as_ref()),
+            Csg::Intersection(ref a, ref b) => (a.as_ref(), b.as_ref()),
+            Csg::Difference(ref a, ref b) => (a.as_ref(), b.as_ref()),
+        }
+    }
+
+    fn contains(&self, in_a: bool, in_b: bool) -> bool {
+        match *self {
+            Csg::Union(..) => in_a || in_b,
+            Csg::Intersection(..) => in_a && in_b,
+            Csg::Difference(..) => in_a && !in_b,
+        }
+    }
+}
+
+/// Every surface crossing of `g` along the line of `ray`, including those
+/// behind the origin, so that crossings alternate between entering and
+/// leaving the solid.
+fn crossings(g: &dyn Geometry, ray: &Ray) -> Vec<Intersection> {
+    let start = match g.bounding_box() {
+        Some(bounds) => match bounds.intersects_ray(ray) {
+            Some((tnear, _)) => tnear.min(0.0) - 1e-4,
+            None => return vec![],
+        },
+        None => 0.0,
+    };
+    let outside = Ray { origin: ray.origin + ray.dir*start, dir: ray.dir, t_max: ray.t_max - start };
+    g.intersect_all(&outside).into_iter().map(|mut isect| {
+        isect.dist += start;
+        isect
+    }).collect()
+}
+
+impl Geometry for Csg {
+    fn material(&self) -> Material {
+        self.children().0.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.children().0.material_id()
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let (a, b) = self.children();
+        match *self {
+            Csg::Union(..) => match (a.bounding_box(), b.bounding_box()) {
+                (Some(ba), Some(bb)) => Some(ba.merge(&bb)),
+                _ => None,
+            },
+            Csg::Intersection(..) => a.bounding_box().or_else(|| b.bounding_box()),
+            Csg::Difference(..) => a.bounding_box(),
+        }
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        self.intersect_all(ray).into_iter().next()
+    }
+    fn intersect_all(&self, ray: &Ray) -> Vec<Intersection> {
+        let (a, b) = self.children();
+        let mut events: Vec<(Intersection, bool)> = crossings(a, ray).into_iter()
+            .map(|i| (i, true))
+            .chain(crossings(b, ray).into_iter().map(|i| (i, false)))
+            .collect();
+        events.sort_by(|x, y| x.0.dist.partial_cmp(&y.0.dist).unwrap_or(std::cmp::Ordering::Equal));
+
+        // Walk the crossings keeping track of which solids the ray is in;
+        // wherever that changes the combined solid's inside, it's a surface.
+        let (mut in_a, mut in_b, mut inside) = (false, false, false);
+        let mut hits = Vec::new();
+        for (mut isect, from_a) in events {
+            if from_a { in_a = !in_a; } else { in_b = !in_b; }
+            let now = self.contains(in_a, in_b);
+            if now == inside {
+                continue;
+            }
+            inside = now;
+            // Outward facing normal: against the ray when entering.
+            let facing = isect.normal.dot(ray.dir);
+            if (inside && facing > 0.0) || (!inside && facing < 0.0) {
+                isect.normal = -isect.normal;
+            }
+            if isect.dist > 1e-9 {
+                hits.push(isect);
+            }
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raytracer::{basic_material, Sphere};
+    use vector::Vector3;
+
+    fn sphere(x: f64, radius: f64) -> Box<dyn Geometry> {
+        Box::new(Sphere { pos: v3!(x, 0.0, 0.0), radius, material: basic_material(v3!(1.0, 1.0, 1.0)).into(),
+                          motion: None })
+    }
+
+    /// Distances and normals of every hit of `csg` along `dir` from `origin`.
+    fn hits(csg: &Csg, origin: Vector3, dir: Vector3) -> Vec<(f64, Vector3)> {
+        let ray = Ray { origin, dir, t_max: f64::INFINITY };
+        csg.intersect_all(&ray).into_iter().map(|i| (i.dist, i.normal)).collect()
+    }
+
+    fn assert_hits(found: &[(f64, Vector3)], expected: &[(f64, Vector3)]) {
+        assert_eq!(found.len(), expected.len(), "{:?}", found);
+        for (&(dist, normal), &(d, n)) in found.iter().zip(expected) {
+            assert!((dist - d).abs() < 1e-9 && normal == n, "{:?} instead of {:?}", found, expected);
+        }
+    }
+
+    #[test]
+    fn difference_has_a_hollow_inside() {
+        // A ball of radius 2 with a cavity of radius 1, both at the origin.
+        let hollow = Csg::Difference(sphere(0.0, 2.0), sphere(0.0, 1.0));
+        let (x, minus_x) = (v3!(1.0, 0.0, 0.0), v3!(-1.0, 0.0, 0.0));
+        // In through the shell, out into the cavity, and the same again. At
+        // the cavity walls the normals face out of the shell, so away from
+        // the cavity's own.
+        assert_hits(&hits(&hollow, v3!(-5.0, 0.0, 0.0), x),
+                    &[(3.0, minus_x), (4.0, x), (6.0, minus_x), (7.0, x)]);
+        // From within the cavity the first hit is its wall, facing the ray.
+        assert_hits(&hits(&hollow, v3!(0.0, 0.0, 0.0), x), &[(1.0, minus_x), (2.0, x)]);
+        let ray = Ray { origin: v3!(0.0, 0.0, 0.0), dir: x, t_max: f64::INFINITY };
+        assert_eq!(hollow.intersects(&ray).unwrap().normal, minus_x);
+    }
+
+    #[test]
+    fn intersection_is_entered_where_both_solids_are() {
+        // Unit spheres overlapping from x = -0.5 to 0.5.
+        let lens = Csg::Intersection(sphere(-0.5, 1.0), sphere(0.5, 1.0));
+        let (x, minus_x) = (v3!(1.0, 0.0, 0.0), v3!(-1.0, 0.0, 0.0));
+        assert_hits(&hits(&lens, v3!(-5.0, 0.0, 0.0), x), &[(4.5, minus_x), (5.5, x)]);
+        assert_hits(&hits(&lens, v3!(5.0, 0.0, 0.0), minus_x), &[(4.5, x), (5.5, minus_x)]);
+        // Through the first sphere only, above the overlap.
+        let ray = Ray { origin: v3!(-1.0, -5.0, 0.0), dir: v3!(0.0, 1.0, 0.0), t_max: f64::INFINITY };
+        assert!(lens.intersects(&ray).is_none());
+    }
+
+    #[test]
+    fn union_hides_the_inner_surfaces() {
+        let both = Csg::Union(sphere(-0.5, 1.0), sphere(0.5, 1.0));
+        let (x, minus_x) = (v3!(1.0, 0.0, 0.0), v3!(-1.0, 0.0, 0.0));
+        assert_hits(&hits(&both, v3!(-5.0, 0.0, 0.0), x), &[(3.5, minus_x), (6.5, x)]);
+        // Starting inside, where both overlap, the only hit is on the way
+        // out, facing along the ray.
+        assert_hits(&hits(&both, v3!(0.0, 0.0, 0.0), x), &[(1.5, x)]);
+        assert_hits(&hits(&both, v3!(0.0, 0.0, 0.0), minus_x), &[(1.5, minus_x)]);
+    }
+}
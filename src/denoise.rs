@@ -0,0 +1,220 @@
+use rayon::prelude::*;
+#[cfg(feature = "denoiser-fft")]
+use rustfft::FftPlanner;
+#[cfg(feature = "denoiser-fft")]
+use rustfft::num_complex::Complex;
+
+use raytracer::RenderBuffer;
+use vector::Vector3;
+
+/// Relative depth difference at which a neighbor's weight falls to
+/// `exp(-1/2)`.
+const DEPTH_SIGMA: f64 = 0.1;
+/// Power the cosine between normals is raised to, so that neighbors on
+/// differently oriented surfaces count for little.
+const NORMAL_EXPONENT: i32 = 8;
+
+/// Smooths noise in `buffer.color` with a bilateral filter. Each pixel
+/// becomes a weighted average of its neighborhood. The weights fall off
+/// with screen distance (`spatial_sigma` pixels), with color difference
+/// (`color_sigma`), and across depth and normal discontinuities, so edges
+/// between objects stay sharp. Returns the filtered colors in the same
+/// order as `buffer.color`. A sigma that isn't positive leaves nothing to
+/// average over, and the colors come back unchanged.
+pub fn denoise_bilateral(buffer: &RenderBuffer, spatial_sigma: f64,
+                         color_sigma: f64) -> Vec<Vector3> {
+    if !(spatial_sigma > 0.0 && color_sigma > 0.0) {
+        return buffer.color.clone();
+    }
+    let (width, height) = (buffer.width, buffer.height);
+    let radius = (2.0 * spatial_sigma).ceil() as isize;
+    let spatial = -0.5 / (spatial_sigma * spatial_sigma);
+    let range = -0.5 / (color_sigma * color_sigma);
+    (0..width*height).into_par_iter().map(|i| {
+        let (x, y) = ((i % width) as isize, (i / width) as isize);
+        let color = buffer.color[i];
+        let mut sum = v3!(0.0, 0.0, 0.0);
+        let mut total = 0.0;
+        for ny in (y - radius).max(0)..(y + radius + 1).min(height as isize) {
+            for nx in (x - radius).max(0)..(x + radius + 1).min(width as isize) {
+                let j = ny as usize * width + nx as usize;
+                let (dx, dy) = ((nx - x) as f64, (ny - y) as f64);
+                let weight = ((dx*dx + dy*dy) * spatial).exp()
+                    * ((buffer.color[j] - color).length_squared() * range).exp()
+                    * geometry_weight(buffer, i, j);
+                sum += buffer.color[j] * weight;
+                total += weight;
+            }
+        }
+        // The pixel itself always has a weight of 1.
+        sum / total
+    }).collect()
+}
+
+/// How much pixel `j` is on the same surface as pixel `i`, judging by
+/// their depths and normals.
+fn geometry_weight(buffer: &RenderBuffer, i: usize, j: usize) -> f64 {
+    let (di, dj) = (buffer.depth[i], buffer.depth[j]);
+    if di.is_infinite() || dj.is_infinite() {
+        // Background only blends with background.
+        return if di.is_infinite() && dj.is_infinite() { 1.0 } else { 0.0 };
+    }
+    let relative = (di - dj) / di;
+    let depth = (-0.5 * relative * relative / (DEPTH_SIGMA * DEPTH_SIGMA)).exp();
+    let normal = match (buffer.normal[i], buffer.normal[j]) {
+        (Some(a), Some(b)) => a.dot(b).max(0.0).powi(NORMAL_EXPONENT),
+        _ => 1.0,
+    };
+    depth * normal
+}
+
+/// Smooths noise in `pixels`, a `width` x `height` image in row-major
+/// order such as `RenderBuffer::color`, with a Wiener filter in the
+/// frequency domain. Each channel is Fourier transformed, and every
+/// frequency is scaled down by how much of its power the noise could
+/// account for, which removes the high frequencies where noise dominates
+/// and keeps the strong low ones that make up the image. The noise is
+/// assumed white, with a standard deviation per channel estimated from
+/// the differences between neighboring pixels; where most of the image is
+/// flat, e.g. an empty background, the estimate is too low to remove
+/// anything. `strength` scales the noise estimate: 1 is the plain Wiener
+/// filter, and 2-4 removes more noise at the cost of fine detail. The
+/// transform treats the image as repeating, so opposite edges bleed into
+/// each other slightly. Runs in O(n log n) for n pixels, whatever the
+/// amount of smoothing, where `denoise_bilateral` grows with the square
+/// of its radius.
+#[cfg(feature = "denoiser-fft")]
+pub fn denoise_fft(pixels: &[Vector3], width: usize, height: usize,
+                   strength: f64) -> Vec<Vector3> {
+    assert_eq!(pixels.len(), width*height, "pixels don't match the image size");
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+    let channels: Vec<Vec<f64>> = (0..3).into_par_iter().map(|c| {
+        let channel: Vec<f64> = pixels.iter().map(|p| p[c]).collect();
+        let sigma = noise_sigma(&channel, width);
+        wiener_filter(&channel, width, height, sigma * sigma * strength)
+    }).collect();
+    (0..width*height).map(|i| v3!(channels[0][i], channels[1][i], channels[2][i])).collect()
+}
+
+/// Standard deviation of white noise in a channel, from the median
+/// absolute difference of horizontally adjacent pixels, which is robust
+/// to the edges of the image content.
+#[cfg(feature = "denoiser-fft")]
+fn noise_sigma(channel: &[f64], width: usize) -> f64 {
+    let mut differences: Vec<f64> = channel.chunks(width)
+        .flat_map(|row| row.windows(2).map(|w| (w[1] - w[0]).abs()))
+        .collect();
+    if differences.is_empty() {
+        return 0.0;
+    }
+    let middle = differences.len() / 2;
+    let (_, median, _) = differences.select_nth_unstable_by(middle, f64::total_cmp);
+    // The difference of two samples has twice the variance, and the
+    // median absolute deviation of a normal distribution is 0.6745 sigma.
+    *median / (0.6745 * std::f64::consts::SQRT_2)
+}
+
+/// Filters a channel with per-frequency gains `1 - noise / power`,
+/// clamped at 0, where `noise` is the variance of the noise per pixel.
+#[cfg(feature = "denoiser-fft")]
+fn wiener_filter(channel: &[f64], width: usize, height: usize, noise: f64) -> Vec<f64> {
+    let mut planner = FftPlanner::new();
+    let (rows, columns) = (planner.plan_fft_forward(width), planner.plan_fft_forward(height));
+    let (rows_inverse, columns_inverse) =
+        (planner.plan_fft_inverse(width), planner.plan_fft_inverse(height));
+    let mut data: Vec<Complex<f64>> = channel.iter().map(|&c| Complex::new(c, 0.0)).collect();
+    rows.process(&mut data);
+    let mut data = transpose(&data, width, height);
+    columns.process(&mut data);
+
+    // White noise has the same expected power at every frequency: the
+    // variance times the number of pixels, as the transform isn't
+    // normalized.
+    let noise_power = noise * (width*height) as f64;
+    for f in data.iter_mut() {
+        let power = f.norm_sqr();
+        if power > 0.0 {
+            *f *= (1.0 - noise_power / power).max(0.0);
+        }
+    }
+
+    columns_inverse.process(&mut data);
+    let mut data = transpose(&data, height, width);
+    rows_inverse.process(&mut data);
+    let scale = 1.0 / (width*height) as f64;
+    data.iter().map(|f| f.re * scale).collect()
+}
+
+/// `data`, `width` x `height` in row-major order, with rows and columns
+/// swapped.
+#[cfg(feature = "denoiser-fft")]
+fn transpose(data: &[Complex<f64>], width: usize, height: usize) -> Vec<Complex<f64>> {
+    let mut out = Vec::with_capacity(data.len());
+    for x in 0..width {
+        out.extend((0..height).map(|y| data[y*width + x]));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raytracer::RenderStats;
+    use rng::Rng;
+
+    /// Root mean square distance of `pixels` from `color`.
+    fn error(pixels: &[Vector3], color: Vector3) -> f64 {
+        let sum: f64 = pixels.iter().map(|p| (*p - color).length_squared()).sum();
+        (sum / pixels.len() as f64).sqrt()
+    }
+
+    /// A `width` x `height` buffer of a flat wall facing the camera.
+    fn wall(color: Vec<Vector3>, width: usize, height: usize) -> RenderBuffer {
+        RenderBuffer { color, depth: vec![2.0; width*height],
+                       normal: vec![Some(v3!(0.0, 0.0, -1.0)); width*height],
+                       width, height, stats: RenderStats::default() }
+    }
+
+    #[test]
+    fn bilateral_keeps_solid_image() {
+        let color = v3!(0.6, 0.3, 0.1);
+        let buffer = wall(vec![color; 16*12], 16, 12);
+        for (spatial, range) in [(3.0, 0.2), (0.0, 0.2), (3.0, 0.0), (-1.0, -1.0)] {
+            let denoised = denoise_bilateral(&buffer, spatial, range);
+            assert!(denoised.iter().all(|p| (*p - color).length() < 1e-12),
+                    "sigmas {} and {} changed the image", spatial, range);
+        }
+    }
+
+    #[test]
+    fn bilateral_smooths_noise() {
+        let (width, height) = (32, 24);
+        let color = v3!(0.6, 0.3, 0.1);
+        let mut rng = Rng::new(3);
+        let mut noise = || (rng.next_f64() - 0.5) * 0.2;
+        let buffer = wall((0..width*height).map(|_| color + v3!(noise(), noise(), noise())).collect(),
+                          width, height);
+        let denoised = denoise_bilateral(&buffer, 2.0, 0.5);
+        let (before, after) = (error(&buffer.color, color), error(&denoised, color));
+        assert!(after < before / 2.0, "noise only went from {} to {}", before, after);
+    }
+
+    #[cfg(feature = "denoiser-fft")]
+    #[test]
+    fn fft_denoises_constant_image_to_its_color() {
+        let (width, height) = (96, 64);
+        let color = v3!(0.6, 0.3, 0.1);
+        let mut rng = Rng::new(7);
+        let mut noise = || (rng.next_f64() - 0.5) * 0.4;
+        let noisy: Vec<Vector3> = (0..width*height)
+            .map(|_| color + v3!(noise(), noise(), noise()))
+            .collect();
+        let denoised = denoise_fft(&noisy, width, height, 3.0);
+        assert!(error(&noisy, color) > 0.15, "noisy pixels are {} off", error(&noisy, color));
+        assert!(error(&denoised, color) < 0.02, "denoised pixels are {} off", error(&denoised, color));
+        let mean = denoised.iter().fold(v3!(0.0, 0.0, 0.0), |sum, &p| sum + p) / denoised.len() as f64;
+        assert!((mean - color).length() < 0.005, "denoised mean is {:?}", mean);
+    }
+}
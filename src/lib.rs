@@ -0,0 +1,32 @@
+extern crate exr;
+extern crate image;
+extern crate rayon;
+#[cfg(feature = "denoiser-fft")]
+extern crate rustfft;
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
+
+#[macro_use]
+pub mod vector;
+
+pub mod raytracer;
+pub mod acceleration;
+pub mod animation;
+pub mod atmosphere;
+pub mod checkpoint;
+pub mod csg;
+pub mod denoise;
+pub mod mesh;
+pub mod output;
+pub mod photon;
+pub mod rng;
+pub mod sampling;
+pub mod scene_file;
+pub mod scenes;
+pub mod shadow_map;
+#[cfg(feature = "simd")]
+pub mod simd;
+pub mod texture;
+pub mod tonemap;
+pub mod transform;
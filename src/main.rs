@@ -1,57 +1,371 @@
-use std::io::prelude::*;
+use std::env;
+use std::fs;
 use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufWriter, IsTerminal, Write};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
 
-const WIDTH: usize = 800;
-const HEIGHT: usize = 600;
-
 #[macro_use]
-mod vector;
+extern crate rust_raytracer;
+#[macro_use]
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
 
-mod raytracer;
+use rust_raytracer::output::{writer_for_format, ImageWriter};
+use rust_raytracer::raytracer::*;
+use rust_raytracer::tonemap::ToneMapper;
+use rust_raytracer::vector::Vector3;
 
-use vector::Vector3;
+const USAGE: &str = "usage: rust_raytracer [--width N] [--height N] [--output PATH] [--format ppm|png|bmp|exr] [--scene PATH] [--stereo SEPARATION]
+       rust_raytracer --daemon";
 
-use raytracer::*;
+struct Options {
+    width: usize,
+    height: usize,
+    output: String,
+    format: String,
+    /// JSON scene file to render instead of the built-in scene.
+    scene: Option<String>,
+    /// Render left and right eye images this far apart into
+    /// `left_<output>` and `right_<output>`.
+    stereo: Option<f64>,
+    /// Read render jobs from stdin instead, see `run_daemon`.
+    daemon: bool,
+}
 
+fn parse_size(name: &str, value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{} must be a positive integer, got '{}'", name, value)),
+    }
+}
 
-fn main() {
-    let mut scene = scene();
-    
-    scene.camera = Camera {
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut width = 800;
+    let mut height = 600;
+    let mut output = "raytracing.ppm".to_string();
+    let mut format = None;
+    let mut scene = None;
+    let mut stereo = None;
+    let mut daemon = false;
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "--daemon" => {
+                daemon = true;
+                continue;
+            }
+            "--width" | "--height" | "--output" | "--format" | "--scene" | "--stereo" =>
+                args.next().ok_or(format!("{} requires a value", arg))?,
+            _ => return Err(format!("unknown argument '{}'", arg)),
+        };
+        match arg.as_str() {
+            "--width" => width = parse_size(&arg, &value)?,
+            "--height" => height = parse_size(&arg, &value)?,
+            "--output" => output = value,
+            "--scene" => scene = Some(value),
+            "--stereo" => stereo = Some(value.parse::<f64>().ok().filter(|s| *s >= 0.0)
+                .ok_or(format!("--stereo must be a non-negative number, got '{}'", value))?),
+            _ => format = Some(value),
+        }
+    }
+    // Without --format, go by the output file extension.
+    let format = format.unwrap_or_else(|| {
+        Path::new(&output).extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("ppm")
+            .to_string()
+    });
+    Ok(Options { width, height, output, format, scene, stereo, daemon })
+}
+
+/// Draws a progress bar on stderr, unless it's redirected.
+fn progress_bar() -> Option<ProgressCallback> {
+    if !io::stderr().is_terminal() {
+        return None;
+    }
+    Some(Arc::new(|done, total| {
+        const WIDTH: usize = 40;
+        let filled = done * WIDTH / total;
+        eprint!("\r[{}>{}] {:3}%", "=".repeat(filled), " ".repeat(WIDTH - filled), done * 100 / total);
+        if done == total {
+            eprintln!();
+        }
+    }))
+}
+
+/// Loads a JSON scene file, or a TOML one if the name ends in `.toml`.
+fn load_scene(path: &str) -> Result<Scene, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    if Path::new(path).extension().is_some_and(|e| e == "toml") {
+        Scene::from_toml(&text).map_err(|e| e.to_string())
+    } else {
+        Scene::from_json(&text).map_err(|e| e.to_string())
+    }
+}
+
+fn default_scene(width: usize, height: usize) -> Scene {
+    let camera = Camera {
         pos: v3!(0.5, 2.5, -1.0),
         up: v3!(0.0, 1.0, 0.2).normalize(),
-        right: v3!(1.33, 0.0, 0.0),
+        right: v3!(width as f64 / height as f64, 0.0, 0.0),
         dist: 2.0,
+        aperture: 0.0,
+        focus_distance: 1.0,
+        projection: Projection::Perspective,
     };
 
-    let mut red = basic_material(v3!(1.0, 0.0, 0.0));
-    red.shininess = 64.0;
+    let red = basic_material(v3!(1.0, 0.0, 0.0)).with_shininess(64.0);
     let blue = basic_material(v3!(0.0, 0.0, 1.0));
     let green = basic_material(v3!(0.0, 1.0, 0.0));
-    let mirror = Material { shininess: 32.0,
-                            spec_color: v3!(1.0, 1.0, 1.0),
-                            color: v3!(1.0, 1.0, 1.0),
-                            reflection: 0.7 };
-
-    scene.add(Sphere { pos: v3!(-2.0, 1.5, 7.0), radius: 0.5, material: red });
-    scene.add(Sphere { pos: v3!(-1.0, -0.5, 8.0), radius: 0.5, material: blue });
-    scene.add(Sphere { pos: v3!(-3.0, -0.5, 5.0), radius: 0.5, material: green });
-    scene.add(Plane { pos: v3!(0.0, -1.0, 0.0), normal: v3!(0.0, 1.0, 0.0), material: red });
-    scene.add(BBox { v1: v3!(-2.5, -1.0, 6.0), v2: v3!(-1.5, 1.0, 10.0), material: mirror });
-    scene.add(BBox { v1: v3!(2.0, -1.0, 5.0), v2: v3!(3.0, 1.0, 6.0), material: green });
-    scene.add(Sphere { pos: v3!(1.0, 0.0, 8.0), radius: 1.0, material: mirror });
-    scene.lights = vec![Light { pos: v3!(20.0, 20.0, -20.0), color: v3!(1.0, 1.0, 1.0)}];
-                                      
-    let pixels = raytrace(&scene, WIDTH, HEIGHT);
-
-    let mut f = File::create("raytracing.ppm").expect("Could not create file."); 
-    let mut ppm: Vec<String> = Vec::new();
-    ppm.push(format!("P3 {} {} 255", WIDTH, HEIGHT));
-    for y in 0..HEIGHT {
-        for x in 0..WIDTH {
-            let (r, g, b) = pixels[y*WIDTH+x].to_rgb();
-            ppm.push(format!("{} {} {}", r, g, b));
+    let mirror = basic_material(v3!(1.0, 1.0, 1.0)).with_shininess(32.0).with_reflection(0.7);
+
+    SceneBuilder::new()
+        .camera(camera)
+        .sphere(v3!(-2.0, 1.5, 7.0), 0.5, red.clone())
+        .sphere(v3!(-1.0, -0.5, 8.0), 0.5, blue)
+        .sphere(v3!(-3.0, -0.5, 5.0), 0.5, green.clone())
+        .plane(v3!(0.0, -1.0, 0.0), v3!(0.0, 1.0, 0.0), red)
+        .bbox(v3!(-2.5, -1.0, 6.0), v3!(-1.5, 1.0, 10.0), mirror.clone())
+        .bbox(v3!(2.0, -1.0, 5.0), v3!(3.0, 1.0, 6.0), green)
+        .sphere(v3!(1.0, 0.0, 8.0), 1.0, mirror)
+        .add_light(point_light(v3!(20.0, 20.0, -20.0), v3!(1.0, 1.0, 1.0), 1600.0))
+        .build()
+}
+
+/// Renders `scene` into `output`, in the format `writer` picks.
+fn render_to_file(scene: &Scene, config: &RenderConfig, writer: &dyn ImageWriter, output: &str)
+                  -> Result<(), String> {
+    write_image(&raytrace_parallel(scene, config), config, writer, output)
+}
+
+fn write_image(pixels: &[Vector3], config: &RenderConfig, writer: &dyn ImageWriter, output: &str)
+               -> Result<(), String> {
+    let mut f = BufWriter::new(File::create(output).map_err(|e| e.to_string())?);
+    writer.write(pixels, config.width, config.height, &mut f)
+        .and_then(|_| f.flush())
+        .map_err(|e| e.to_string())
+}
+
+/// Render settings of a daemon job. Missing fields take the defaults of
+/// the command line.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RenderOptions {
+    width: usize,
+    height: usize,
+    /// Image format; without it the output extension decides.
+    format: Option<String>,
+    max_depth: u32,
+    /// Random rays per pixel. 1 is a single ray through the corner.
+    samples: usize,
+    /// Path trace with this many paths per camera ray instead of Whitted
+    /// ray tracing.
+    path_samples: Option<usize>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        let config = RenderConfig::default();
+        RenderOptions { width: config.width,
+                        height: config.height,
+                        format: None,
+                        max_depth: config.max_depth,
+                        samples: 1,
+                        path_samples: None }
+    }
+}
+
+/// One line of the daemon's input.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    /// The scene, in the format of `Scene::to_json`.
+    scene: serde_json::Value,
+    output: String,
+    #[serde(default)]
+    options: RenderOptions,
+}
+
+fn run_job(line: &str) -> Result<String, String> {
+    let job: Job = serde_json::from_str(line).map_err(|e| format!("invalid job: {}", e))?;
+    let (opts, output) = (job.options, job.output);
+    if opts.width == 0 || opts.height == 0 || opts.samples == 0 {
+        return Err("width, height and samples must be positive".to_string());
+    }
+    let format = opts.format.unwrap_or_else(|| {
+        Path::new(&output).extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("ppm")
+            .to_string()
+    });
+    let writer = writer_for_format(&format)
+        .ok_or(format!("unsupported format '{}'", format))?;
+    let mut scene = Scene::from_json(&job.scene.to_string())
+        .map_err(|e| format!("invalid scene: {}", e))?;
+    for warning in scene.validate() {
+        eprintln!("warning: {}", warning);
+    }
+    scene.build_bvh();
+
+    let mut config = RenderConfig { width: opts.width,
+                                    height: opts.height,
+                                    max_depth: opts.max_depth,
+                                    print_stats: false,
+                                    ..RenderConfig::default() };
+    if opts.samples > 1 {
+        config.anti_alias = AntiAlias::Stochastic(opts.samples);
+    }
+    if let Some(paths) = opts.path_samples {
+        config.integrator = IntegratorMode::PathTrace(paths);
+    }
+    if writer.is_hdr() {
+        config.tone_mapper = Some(ToneMapper::Linear);
+        config.gamma = 1.0;
+    }
+    render_to_file(&scene, &config, &*writer, &output)
+        .map_err(|e| format!("could not write '{}': {}", output, e))?;
+    Ok(output)
+}
+
+/// Renders the jobs on `input`, one JSON object per line, until it ends,
+/// so that scripts can render many frames without starting a process for
+/// each. A job is `{"scene": {..}, "output": "frame.png", "options":
+/// {"width": 640, "samples": 4}}`, with every option optional. For each
+/// job one line is written to `out`: `{"status": "ok", "output": ..}`, or
+/// `{"status": "error", "line": n, "error": ..}` if it failed, in which
+/// case the daemon carries on with the next job.
+fn run_daemon<R: BufRead, W: Write>(input: R, mut out: W) -> io::Result<()> {
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
         }
+        let response = match run_job(&line) {
+            Ok(output) => json!({ "status": "ok", "output": output }),
+            Err(e) => json!({ "status": "error", "line": i + 1, "error": e }),
+        };
+        writeln!(out, "{}", response)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// `output` with `<eye>_` in front of its file name.
+fn eye_path(output: &str, eye: &str) -> String {
+    let path = Path::new(output);
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or(output);
+    path.with_file_name(format!("{}_{}", eye, name)).to_string_lossy().into_owned()
+}
+
+fn main() {
+    let opts = parse_args(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("error: {}\n{}", e, USAGE);
+        process::exit(1);
+    });
+    if opts.daemon {
+        let stdin = io::stdin();
+        if let Err(e) = run_daemon(stdin.lock(), io::stdout()) {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let writer = writer_for_format(&opts.format).unwrap_or_else(|| {
+        eprintln!("error: unsupported format '{}'\n{}", opts.format, USAGE);
+        process::exit(1);
+    });
+
+    let mut scene = match opts.scene {
+        Some(ref path) => load_scene(path).unwrap_or_else(|e| {
+            eprintln!("error: could not load scene '{}': {}", path, e);
+            process::exit(1);
+        }),
+        None => default_scene(opts.width, opts.height),
+    };
+    for warning in scene.validate() {
+        eprintln!("warning: {}", warning);
+    }
+    scene.build_bvh();
+
+    let mut config = RenderConfig { width: opts.width,
+                                    height: opts.height,
+                                    progress_callback: progress_bar(),
+                                    ..RenderConfig::default() };
+    if writer.is_hdr() {
+        config.tone_mapper = Some(ToneMapper::Linear);
+        config.gamma = 1.0;
+    }
+    let images = match opts.stereo {
+        Some(separation) => {
+            let (left, right) = stereo_render(&scene, &scene.camera, separation, &config);
+            vec![(left, eye_path(&opts.output, "left")), (right, eye_path(&opts.output, "right"))]
+        },
+        None => vec![(raytrace_parallel(&scene, &config), opts.output.clone())],
+    };
+    for (pixels, output) in images {
+        write_image(&pixels, &config, &*writer, &output).unwrap_or_else(|e| {
+            eprintln!("error: could not write '{}': {}", output, e);
+            process::exit(1);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_scene_survives_json() {
+        let scene = default_scene(64, 48);
+        let json = scene.to_json();
+        let loaded = Scene::from_json(&json).unwrap();
+        assert_eq!(loaded.to_json(), json);
+        let config = RenderConfig { width: 64, height: 48, print_stats: false,
+                                    ..RenderConfig::default() };
+        let (original, reloaded) = (raytrace(&scene, &config), raytrace(&loaded, &config));
+        // Bit for bit, not just within `Vector3`'s tolerance.
+        let bits = |pixels: &[Vector3]| -> Vec<[u64; 3]> {
+            pixels.iter().map(|p| p.as_array().map(f64::to_bits)).collect()
+        };
+        assert!(bits(&original) == bits(&reloaded), "reloaded scene renders differently");
+    }
+
+    #[test]
+    fn daemon_renders_jobs_and_reports_errors() {
+        let dir = std::env::temp_dir().join(format!("rust_raytracer_daemon_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("frame.ppm").to_str().unwrap().to_string();
+        let scene: serde_json::Value = serde_json::from_str(&default_scene(8, 6).to_json()).unwrap();
+        let jobs = [
+            json!({ "scene": scene, "output": output, "options": { "width": 8, "height": 6 } }).to_string(),
+            String::new(),
+            "not json".to_string(),
+            json!({ "scene": scene, "output": output, "options": { "format": "gif" } }).to_string(),
+        ].join("\n");
+
+        let mut out = Vec::new();
+        run_daemon(jobs.as_bytes(), &mut out).unwrap();
+        let responses: Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0], json!({ "status": "ok", "output": output }));
+        assert_eq!(responses[1]["status"], "error");
+        assert_eq!(responses[1]["line"], 3);
+        assert_eq!(responses[2]["error"], "unsupported format 'gif'");
+        assert_eq!(fs::read(&output).unwrap().len(), "P6 8 6 255\n".len() + 8*6*3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stereo_images_are_named_after_the_output() {
+        let opts = parse_args(["--stereo", "0.065", "--output", "out/frame.png"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(opts.stereo, Some(0.065));
+        assert_eq!(eye_path(&opts.output, "left"), "out/left_frame.png");
+        assert_eq!(eye_path("frame.png", "right"), "right_frame.png");
+        assert!(parse_args(["--stereo", "-1"].iter().map(|s| s.to_string())).is_err());
     }
-    f.write_fmt(format_args!("{}\n", ppm.join(" "))).ok();    
 }
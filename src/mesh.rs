@@ -0,0 +1,620 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use raytracer::{basic_material, Material, MaterialRef, Triangle, TriangleBuilder};
+use texture::load_png_texture;
+use vector::Vector3;
+
+/// Error from loading a Wavefront OBJ or MTL file.
+#[derive(Debug)]
+pub enum ObjError {
+    Io(io::Error),
+    /// Malformed line, with its 1-based line number.
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjError::Io(ref e) => write!(f, "{}", e),
+            ObjError::Parse { line, ref message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for ObjError {}
+
+impl From<io::Error> for ObjError {
+    fn from(e: io::Error) -> ObjError {
+        ObjError::Io(e)
+    }
+}
+
+/// Loads the triangles of an OBJ file. Faces after a `usemtl` line get
+/// that material from the file's `mtllib` libraries, and the others
+/// `material`. With `smooth_normals`, the normals are computed with
+/// `compute_smooth_normals` in place of any the file gives.
+pub fn load_obj(path: &str, material: Material,
+                smooth_normals: bool) -> Result<Vec<Triangle>, ObjError> {
+    let materials = load_obj_materials(path)?.materials.into_iter()
+        .map(|(name, material)| (name, material.into()))
+        .collect();
+    load_obj_with_materials(path, material.into(), &materials, smooth_normals)
+}
+
+/// Like `load_obj`, but `usemtl` names are looked up in `materials`, so
+/// that the caller decides how they are shared; `mtllib` lines are
+/// skipped.
+pub fn load_obj_with_materials(path: &str, material: MaterialRef,
+                               materials: &HashMap<String, MaterialRef>,
+                               smooth_normals: bool) -> Result<Vec<Triangle>, ObjError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut triangles = parse_obj_with_materials(reader, material, materials)?;
+    if smooth_normals {
+        compute_smooth_normals(&mut triangles);
+    }
+    Ok(triangles)
+}
+
+/// The materials of the MTL files named by the `mtllib` lines of the OBJ
+/// file at `path`, found relative to its directory. A name defined in
+/// several of them takes the last definition.
+pub fn load_obj_materials(path: &str) -> Result<MaterialLibrary, ObjError> {
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut library = MaterialLibrary::default();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let mut tokens = line.split_whitespace();
+        if tokens.next() == Some("mtllib") {
+            for file in tokens {
+                library.materials.extend(load_mtl(&dir.join(file))?.materials);
+            }
+        }
+    }
+    Ok(library)
+}
+
+/// Gives the triangles smooth shading: the normal at each vertex becomes
+/// the average of the face normals of the triangles that share it, so the
+/// shading blends across edges instead of showing every face. Triangles
+/// share a vertex where their corners are at exactly the same position;
+/// see `weld_vertices` for meshes whose seams don't quite meet.
+pub fn compute_smooth_normals(triangles: &mut [Triangle]) {
+    let key = |v: Vector3| v.as_array().map(f64::to_bits);
+    let finite = |v: &Vector3| v.as_array().iter().all(|c| c.is_finite());
+    let mut sums: HashMap<[u64; 3], Vector3> = HashMap::new();
+    for t in triangles.iter() {
+        let normal = t.normal();
+        if !finite(&normal) {
+            continue;
+        }
+        for v in [t.v0, t.v1, t.v2] {
+            *sums.entry(key(v)).or_insert(v3!(0.0, 0.0, 0.0)) += normal;
+        }
+    }
+    for t in triangles.iter_mut() {
+        let normal = |v: Vector3| sums.get(&key(v)).map(|n| n.normalize()).filter(finite);
+        t.vertex_normals = match (normal(t.v0), normal(t.v1), normal(t.v2)) {
+            (Some(n0), Some(n1), Some(n2)) => [Some(n0), Some(n1), Some(n2)],
+            _ => [None; 3],
+        };
+    }
+}
+
+/// Merges triangle corners less than `epsilon` apart into one vertex, at
+/// the position of the first of them, and returns how many distinct
+/// vertices are left. OBJ files often repeat a vertex along UV seams and
+/// hard edges, which stops `compute_smooth_normals` from blending across
+/// them; welding first makes it treat the copies as one.
+pub fn weld_vertices(triangles: &mut [Triangle], epsilon: f64) -> usize {
+    let epsilon = epsilon.max(0.0);
+    // Vertices kept so far, bucketed by grid cells `epsilon` across, so
+    // that each only has to be compared with those in neighboring cells.
+    let cell_size = if epsilon > 0.0 { epsilon } else { 1.0 };
+    let cell = |v: Vector3| v.as_array().map(|c| (c / cell_size).floor() as i64);
+    let mut grid: HashMap<[i64; 3], Vec<Vector3>> = HashMap::new();
+    let mut count = 0;
+    let mut weld = |v: Vector3| {
+        let [x, y, z] = cell(v);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let near = grid.get(&[x + dx, y + dy, z + dz]).and_then(|kept| {
+                        kept.iter().find(|&&k| (k - v).length() <= epsilon)
+                    });
+                    if let Some(&kept) = near {
+                        return kept;
+                    }
+                }
+            }
+        }
+        grid.entry([x, y, z]).or_default().push(v);
+        count += 1;
+        v
+    };
+    for t in triangles.iter_mut() {
+        let (v0, v1, v2) = (weld(t.v0), weld(t.v1), weld(t.v2));
+        let mut welded = Triangle::new(v0, v1, v2, t.material.clone());
+        welded.vertex_normals = t.vertex_normals;
+        welded.vertex_uvs = t.vertex_uvs;
+        *t = welded;
+    }
+    count
+}
+
+/// Parses OBJ data, with every face getting `material`. Only vertices
+/// (`v`), texture coordinates (`vt`), normals (`vn`) and faces (`f`) are
+/// used; polygons are split into a triangle fan around their first vertex.
+/// Other directives are skipped with a warning. Texture v is flipped so
+/// that v = 0 is the top row of an image, as elsewhere in the crate.
+pub fn parse_obj<R: BufRead>(reader: R, material: Material) -> Result<Vec<Triangle>, ObjError> {
+    parse_obj_with_materials(reader, material.into(), &HashMap::new())
+}
+
+/// Like `parse_obj`, with the faces after a `usemtl` line given the
+/// material of that name in `materials`. Names it doesn't have fall back
+/// to `material`, with a warning.
+pub fn parse_obj_with_materials<R: BufRead>(reader: R, material: MaterialRef,
+                                            materials: &HashMap<String, MaterialRef>)
+                                            -> Result<Vec<Triangle>, ObjError> {
+    let mut current = material.clone();
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut triangles = Vec::new();
+    let mut warned = HashSet::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_no = i + 1;
+        let err = |message: String| ObjError::Parse { line: line_no, message };
+
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+        match keyword {
+            "v" | "vn" => {
+                if args.len() < 3 {
+                    return Err(err(format!("expected 3 coordinates, got {}", args.len())));
+                }
+                let mut c = [0.0; 3];
+                for (c, a) in c.iter_mut().zip(&args) {
+                    *c = a.parse().map_err(|e| err(format!("invalid coordinate {:?}: {}", a, e)))?;
+                }
+                let v = v3!(c[0], c[1], c[2]);
+                if keyword == "v" { positions.push(v); } else { normals.push(v); }
+            },
+            "vt" => {
+                if args.len() < 2 {
+                    return Err(err(format!("expected 2 texture coordinates, got {}", args.len())));
+                }
+                let mut c = [0.0; 2];
+                for (c, a) in c.iter_mut().zip(&args) {
+                    *c = a.parse().map_err(|e| err(format!("invalid coordinate {:?}: {}", a, e)))?;
+                }
+                uvs.push((c[0], 1.0 - c[1]));
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return Err(err(format!("face needs at least 3 vertices, got {}", args.len())));
+                }
+                let mut vertices = Vec::with_capacity(args.len());
+                for a in &args {
+                    vertices.push(parse_face_vertex(a, &positions, &uvs, &normals).map_err(&err)?);
+                }
+                for k in 1..vertices.len()-1 {
+                    let (a, b, c) = (vertices[0], vertices[k], vertices[k+1]);
+                    let mut builder = TriangleBuilder::new(a.position, b.position, c.position,
+                                                           current.clone());
+                    if let (Some(ta), Some(tb), Some(tc)) = (a.uv, b.uv, c.uv) {
+                        builder = builder.vertex_uvs(ta, tb, tc);
+                    }
+                    if let (Some(na), Some(nb), Some(nc)) = (a.normal, b.normal, c.normal) {
+                        builder = builder.vertex_normals(na, nb, nc);
+                    }
+                    triangles.push(builder.build());
+                }
+            },
+            "usemtl" => {
+                let name = args.join(" ");
+                current = match materials.get(&name) {
+                    Some(m) => m.clone(),
+                    None => {
+                        if warned.insert(format!("usemtl {}", name)) {
+                            eprintln!("warning: unknown OBJ material {:?}", name);
+                        }
+                        material.clone()
+                    },
+                };
+            },
+            "mtllib" => {},
+            _ => {
+                if warned.insert(keyword.to_string()) {
+                    eprintln!("warning: ignoring unsupported OBJ directive {:?}", keyword);
+                }
+            },
+        }
+    }
+    Ok(triangles)
+}
+
+/// One corner of a face.
+#[derive(Copy, Clone)]
+struct FaceVertex {
+    position: Vector3,
+    uv: Option<(f64, f64)>,
+    normal: Option<Vector3>,
+}
+
+/// Resolves one `v`, `v/vt`, `v//vn` or `v/vt/vn` face vertex into its
+/// position and, if given, texture coordinates and normal.
+fn parse_face_vertex(s: &str, positions: &[Vector3], uvs: &[(f64, f64)], normals: &[Vector3])
+                     -> Result<FaceVertex, String> {
+    let mut parts = s.split('/');
+    let position = resolve_index(parts.next().unwrap_or(""), positions)?;
+    let uv = match parts.next() {
+        Some(t) if !t.is_empty() => Some(resolve_index(t, uvs)?),
+        _ => None,
+    };
+    let normal = match parts.next() {
+        Some(n) if !n.is_empty() => Some(resolve_index(n, normals)?),
+        _ => None,
+    };
+    Ok(FaceVertex { position, uv, normal })
+}
+
+/// Looks up a 1-based index, or an index relative to the end if negative.
+fn resolve_index<T: Copy>(s: &str, items: &[T]) -> Result<T, String> {
+    let i: i64 = s.parse().map_err(|_| format!("invalid index {:?}", s))?;
+    let index = if i < 0 { items.len() as i64 + i } else { i - 1 };
+    if index < 0 || index >= items.len() as i64 {
+        return Err(format!("index {} out of range", i));
+    }
+    Ok(items[index as usize])
+}
+
+/// Materials by name, as read from Wavefront MTL files for the `usemtl`
+/// lines of OBJ files.
+#[derive(Clone, Debug, Default)]
+pub struct MaterialLibrary {
+    pub materials: HashMap<String, Material>,
+}
+
+/// Loads a Wavefront MTL file, with texture images found relative to its
+/// directory.
+pub fn load_mtl(path: &Path) -> Result<MaterialLibrary, ObjError> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    parse_mtl(BufReader::new(File::open(path)?), dir)
+}
+
+/// Parses the materials of an MTL file. Each `newmtl` starts from a white
+/// `basic_material`, then `Kd` sets its color, `Ks` the specular color,
+/// `Ns` the shininess, `d` the opacity or `Tr` the transparency, `Ni` the
+/// index of refraction, `Ke` the emission and `map_Kd` the texture, an
+/// image loaded from `dir`. Other directives are skipped with a warning.
+pub fn parse_mtl<R: BufRead>(reader: R, dir: &Path) -> Result<MaterialLibrary, ObjError> {
+    let mut library = MaterialLibrary::default();
+    let mut current: Option<(String, Material)> = None;
+    let mut warned = HashSet::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_no = i + 1;
+        let err = |message: String| ObjError::Parse { line: line_no, message };
+
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+        if keyword == "newmtl" {
+            let name = args.first().ok_or_else(|| err("newmtl needs a name".to_string()))?;
+            let started = (name.to_string(), basic_material(v3!(1.0, 1.0, 1.0)));
+            if let Some((name, material)) = current.replace(started) {
+                library.materials.insert(name, material);
+            }
+            continue;
+        }
+        let material = match current {
+            Some((_, ref mut material)) => material,
+            None => return Err(err(format!("{} before the first newmtl", keyword))),
+        };
+        let number = || parse_mtl_numbers(&args, 1).map(|n| n[0]).map_err(&err);
+        match keyword {
+            "Kd" | "Ks" | "Ke" => {
+                // A single value is a gray.
+                let c = parse_mtl_numbers(&args, 1).map_err(&err)?;
+                let color = if c.len() >= 3 { v3!(c[0], c[1], c[2]) } else { v3!(c[0], c[0], c[0]) };
+                match keyword {
+                    "Kd" => material.color = color,
+                    "Ks" => material.spec_color = color,
+                    _ => material.emission = color,
+                }
+            },
+            "Ns" => material.shininess = number()?,
+            "d" => material.transparency = 1.0 - number()?,
+            "Tr" => material.transparency = number()?,
+            "Ni" => material.refraction = number()?,
+            "map_Kd" => {
+                // Options such as `-s` come before the file name.
+                let file = args.last().ok_or_else(|| err("map_Kd needs a file".to_string()))?;
+                let path = dir.join(file);
+                let texture = load_png_texture(&path.to_string_lossy())
+                    .map_err(|e| err(format!("can't load {:?}: {}", path, e)))?;
+                material.texture = Some(Arc::new(texture));
+            },
+            _ => {
+                if warned.insert(keyword.to_string()) {
+                    eprintln!("warning: ignoring unsupported MTL directive {:?}", keyword);
+                }
+            },
+        }
+    }
+    if let Some((name, material)) = current {
+        library.materials.insert(name, material);
+    }
+    Ok(library)
+}
+
+/// The numbers of an MTL line, at least `min` of them.
+fn parse_mtl_numbers(args: &[&str], min: usize) -> Result<Vec<f64>, String> {
+    if args.len() < min {
+        return Err(format!("expected {} numbers, got {}", min, args.len()));
+    }
+    args.iter()
+        .map(|a| a.parse().map_err(|e| format!("invalid number {:?}: {}", a, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    use image;
+    use raytracer::{basic_material, scene, Geometry, MaterialId, Ray};
+    use rng::Rng;
+
+    fn parse(obj: &str) -> Result<Vec<Triangle>, ObjError> {
+        parse_obj(obj.as_bytes(), basic_material(v3!(1.0, 1.0, 1.0)))
+    }
+
+    #[test]
+    fn parses_faces_with_uvs_and_normals() {
+        let quad = "# a unit square facing +z
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+o square
+f 1/1/1 2/2/1 3/3/1 4/4/1
+";
+        let triangles = parse(quad).unwrap();
+        // The quad is split into a fan around its first vertex.
+        assert_eq!(triangles.len(), 2);
+        assert_eq!((triangles[0].v0, triangles[0].v1, triangles[0].v2),
+                   (v3!(0.0, 0.0, 0.0), v3!(1.0, 0.0, 0.0), v3!(1.0, 1.0, 0.0)));
+        assert_eq!((triangles[1].v0, triangles[1].v1, triangles[1].v2),
+                   (v3!(0.0, 0.0, 0.0), v3!(1.0, 1.0, 0.0), v3!(0.0, 1.0, 0.0)));
+        // Texture v is flipped.
+        assert_eq!(triangles[1].vertex_uvs, [Some((0.0, 1.0)), Some((1.0, 0.0)), Some((0.0, 0.0))]);
+        assert_eq!(triangles[0].vertex_normals[2], Some(v3!(0.0, 0.0, 1.0)));
+        // The mesh is hit where the square is.
+        let ray = Ray { origin: v3!(0.25, 0.75, -1.0), dir: v3!(0.0, 0.0, 1.0), t_max: f64::INFINITY };
+        let hits: Vec<_> = triangles.iter().filter_map(|t| t.intersects(&ray)).collect();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].uv, (0.25, 0.25));
+    }
+
+    #[test]
+    fn resolves_relative_and_partial_indices() {
+        let triangles = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 -1\nf -3//1 -2//1 -1//1\nf 1 2 3\n").unwrap();
+        assert_eq!(triangles.len(), 2);
+        assert_eq!(triangles[0].v2, v3!(0.0, 1.0, 0.0));
+        assert_eq!(triangles[0].vertex_normals[0], Some(v3!(0.0, 0.0, -1.0)));
+        assert_eq!(triangles[1].vertex_normals, [None; 3]);
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let line_of = |obj: &str| match parse(obj) {
+            Err(ObjError::Parse { line, .. }) => line,
+            other => panic!("parsed {:?}", other.map(|t| t.len())),
+        };
+        assert_eq!(line_of("v 0 0 0\nv 1 x 0\n"), 2);
+        assert_eq!(line_of("v 0 0\n"), 1);
+        assert_eq!(line_of("v 0 0 0\nv 1 0 0\nf 1 2\n"), 3);
+        assert_eq!(line_of("v 0 0 0\nv 1 0 0\nv 0 1 0\n\nf 1 2 4\n"), 5);
+        assert_eq!(line_of("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1/1 2/1 3/1\n"), 4);
+        let message = parse("v 0 0\n").err().unwrap().to_string();
+        assert_eq!(message, "line 1: expected 3 coordinates, got 2");
+    }
+
+    /// OBJ of a unit sphere made of `rings` rings of `segments` faces.
+    fn sphere_obj(rings: usize, segments: usize) -> String {
+        let mut obj = String::new();
+        for i in 0..=rings {
+            let theta = PI * i as f64 / rings as f64;
+            for j in 0..segments {
+                let phi = 2.0 * PI * j as f64 / segments as f64;
+                let (x, z) = (theta.sin()*phi.cos(), theta.sin()*phi.sin());
+                obj += &format!("v {} {} {}\n", x, theta.cos(), z);
+            }
+        }
+        let index = |i: usize, j: usize| i*segments + j % segments + 1;
+        for i in 0..rings {
+            for j in 0..segments {
+                obj += &format!("f {} {} {} {}\n", index(i, j), index(i, j + 1), index(i + 1, j + 1),
+                                index(i + 1, j));
+            }
+        }
+        obj
+    }
+
+    #[test]
+    fn smooth_normals_make_a_sphere_round() {
+        let path = std::env::temp_dir()
+            .join(format!("rust_raytracer_sphere_{}.obj", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, sphere_obj(12, 24)).unwrap();
+        let white = basic_material(v3!(1.0, 1.0, 1.0));
+        let (flat, smooth) = (load_obj(path, white.clone(), false), load_obj(path, white, true));
+        std::fs::remove_file(path).unwrap();
+        let (flat, smooth) = (flat.unwrap(), smooth.unwrap());
+        assert!(smooth.iter().all(|t| t.vertex_normals.iter().all(Option::is_some)));
+
+        // Worst angle between the shading normal and the true sphere's, over
+        // rays at the middle band from all around.
+        let worst_error = |triangles: &[Triangle]| {
+            (0..360).map(|k| {
+                let angle = (k as f64 + 0.37).to_radians();
+                let origin = v3!(angle.cos(), 0.13, angle.sin()) * 3.0;
+                let dir = (v3!(0.0, 0.13, 0.0) - origin).normalize();
+                let ray = Ray { origin, dir, t_max: f64::INFINITY };
+                let hit = triangles.iter().filter_map(|t| t.intersects(&ray))
+                    .min_by(|a, b| a.dist.total_cmp(&b.dist))
+                    .unwrap();
+                hit.normal.dot(hit.pos.normalize()).min(1.0).acos()
+            }).fold(0.0, f64::max)
+        };
+        let (flat_error, smooth_error) = (worst_error(&flat), worst_error(&smooth));
+        assert!(flat_error > 0.1, "flat shading is only {} off", flat_error);
+        assert!(smooth_error < 0.03, "smooth shading is {} off", smooth_error);
+    }
+
+
+    #[test]
+    fn welding_joins_a_cube_into_eight_corners() {
+        // Each face has its own four corners, nudged a little apart, as in
+        // an OBJ file with a seam along every edge.
+        let mut rng = Rng::new(8);
+        let mut triangles = Vec::new();
+        for axis in 0..3 {
+            for side in [-1.0, 1.0] {
+                let mut corner = |a: f64, b: f64| {
+                    let mut c = [0.0; 3];
+                    c[axis] = side;
+                    c[(axis + 1) % 3] = a;
+                    c[(axis + 2) % 3] = b;
+                    Vector3::from_array(c) + v3!(rng.next_f64(), rng.next_f64(), rng.next_f64()) * 1e-7
+                };
+                let mut quad = [corner(-1.0, -1.0), corner(1.0, -1.0), corner(1.0, 1.0), corner(-1.0, 1.0)];
+                if side < 0.0 {
+                    // Wound to face outwards.
+                    quad.reverse();
+                }
+                let white = basic_material(v3!(1.0, 1.0, 1.0));
+                triangles.push(Triangle::new(quad[0], quad[1], quad[2], white.clone()));
+                triangles.push(Triangle::new(quad[0], quad[2], quad[3], white));
+            }
+        }
+        let distinct = |triangles: &[Triangle]| {
+            let mut corners: Vec<[u64; 3]> = triangles.iter()
+                .flat_map(|t| [t.v0, t.v1, t.v2])
+                .map(|v| v.as_array().map(f64::to_bits))
+                .collect();
+            corners.sort();
+            corners.dedup();
+            corners.len()
+        };
+        assert_eq!(distinct(&triangles), 24);
+        assert_eq!(weld_vertices(&mut triangles, 1e-5), 8);
+        assert_eq!(distinct(&triangles), 8);
+
+        // Now the smooth normals span the seams: each corner's points out
+        // along its diagonal.
+        compute_smooth_normals(&mut triangles);
+        for t in &triangles {
+            for (v, n) in [t.v0, t.v1, t.v2].iter().zip(&t.vertex_normals) {
+                let diagonal = v.normalize();
+                assert!(n.unwrap().dot(diagonal) > 0.999, "{:?} at {:?}", n, v);
+            }
+        }
+    }
+
+
+    #[test]
+    fn parses_mtl_materials() {
+        let mtl = "# two materials\n\
+                   newmtl red\nKd 0.8 0.1 0.1\nKs 0.5\nNs 96\nillum 2\n\
+                   newmtl glass\nKd 1 1 1\nd 0.25\nNi 1.5\n\
+                   newmtl lamp\nKe 4 3 2\nTr 0.1\n";
+        let library = parse_mtl(mtl.as_bytes(), Path::new("")).unwrap();
+        assert_eq!(library.materials.len(), 3);
+        let red = &library.materials["red"];
+        assert_eq!(red.color, v3!(0.8, 0.1, 0.1));
+        assert_eq!(red.spec_color, v3!(0.5, 0.5, 0.5));
+        assert_eq!(red.shininess, 96.0);
+        assert_eq!(red.transparency, 0.0);
+        let glass = &library.materials["glass"];
+        assert_eq!((glass.transparency, glass.refraction), (0.75, 1.5));
+        let lamp = &library.materials["lamp"];
+        assert_eq!(lamp.emission, v3!(4.0, 3.0, 2.0));
+        assert_eq!(lamp.transparency, 0.1);
+
+        let line_of = |mtl: &str| match parse_mtl(mtl.as_bytes(), Path::new("")) {
+            Err(ObjError::Parse { line, .. }) => line,
+            other => panic!("parsed {:?}", other.map(|l| l.materials.len())),
+        };
+        assert_eq!(line_of("Kd 1 1 1\n"), 1);
+        assert_eq!(line_of("newmtl a\nNs x\n"), 2);
+        assert_eq!(line_of("newmtl a\n\nKd\n"), 3);
+        assert_eq!(line_of("newmtl a\nmap_Kd missing.png\n"), 2);
+    }
+
+    #[test]
+    fn obj_faces_take_their_mtl_materials() {
+        // No Utah teapot to hand offline, so a small OBJ and MTL pair: two
+        // triangles with different materials and one before any `usemtl`.
+        let dir = std::env::temp_dir().join(format!("rust_raytracer_mtl_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbImage::from_pixel(2, 2, image::Rgb([0, 255, 0])).save(dir.join("green.png")).unwrap();
+        std::fs::write(dir.join("pair.mtl"), "newmtl red\nKd 1 0 0\n\
+                                              newmtl grass\nmap_Kd green.png\n").unwrap();
+        std::fs::write(dir.join("pair.obj"), "mtllib pair.mtl\n\
+                                              v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+                                              f 1 2 3\n\
+                                              usemtl red\nf 1 2 3\n\
+                                              usemtl grass\nf 1 2 3\n\
+                                              usemtl unknown\nf 1 2 3\n").unwrap();
+        let path = dir.join("pair.obj").to_string_lossy().into_owned();
+        let triangles = load_obj(&path, basic_material(v3!(0.0, 0.0, 1.0)), false);
+        let mut scene = scene();
+        scene.register_material("red", basic_material(v3!(1.0, 1.0, 0.0)));
+        let added = scene.add_mesh(&path, basic_material(v3!(0.0, 0.0, 1.0)));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let colors: Vec<Vector3> = triangles.unwrap().iter().map(|t| t.material().color).collect();
+        assert_eq!(colors, [v3!(0.0, 0.0, 1.0), v3!(1.0, 0.0, 0.0),
+                            v3!(1.0, 1.0, 1.0), v3!(0.0, 0.0, 1.0)]);
+
+        // The scene keeps its own "red" and registers "grass", which the
+        // faces share by id.
+        added.unwrap();
+        assert_eq!(scene.materials().len(), 2);
+        assert_eq!(scene.materials()[0].1.color, v3!(1.0, 1.0, 0.0));
+        let grass = &scene.materials()[1];
+        assert_eq!(grass.0, "grass");
+        let texture = grass.1.texture.as_ref().unwrap();
+        assert_eq!(texture.sample(v3!(0.0, 0.0, 0.0), v3!(0.0, 0.0, 1.0), (0.5, 0.5)), v3!(0.0, 1.0, 0.0));
+        let ids: Vec<Option<MaterialId>> = scene.objects.iter().map(|o| o.material_id()).collect();
+        assert_eq!(ids, [None, Some(MaterialId(0)), Some(MaterialId(1)), None]);
+    }
+}
@@ -0,0 +1,165 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+
+use exr::prelude::{Encoding, Image, SpecificChannels, Vec2, WritableImage};
+use image::{ExtendedColorType, ImageEncoder};
+use image::codecs::png::PngEncoder;
+
+use vector::Vector3;
+
+/// Encodes a rendered image. `pixels` is in row-major order starting from
+/// the top row and already gamma encoded, as returned by `raytrace`.
+pub trait ImageWriter {
+    fn write(&self, pixels: &[Vector3], width: usize, height: usize,
+             out: &mut dyn Write) -> io::Result<()>;
+
+    /// Whether the format stores linear, unclamped radiance, in which case
+    /// the image should be rendered without tone mapping and with a gamma
+    /// of 1.
+    fn is_hdr(&self) -> bool {
+        false
+    }
+}
+
+/// Binary (P6) portable pixmap.
+pub struct PpmWriter;
+
+pub struct PngWriter;
+
+/// Uncompressed 24-bit Windows bitmap.
+pub struct BmpWriter;
+
+/// Uncompressed OpenEXR image with a 32-bit float per channel. The pixels
+/// are stored as they are, so they should be linear radiance.
+pub struct ExrWriter;
+
+/// Returns the writer for a format name ("ppm", "png", "bmp" or "exr"), or None
+/// if the format isn't supported.
+pub fn writer_for_format(format: &str) -> Option<Box<dyn ImageWriter>> {
+    match format.to_lowercase().as_str() {
+        "ppm" => Some(Box::new(PpmWriter)),
+        "png" => Some(Box::new(PngWriter)),
+        "bmp" => Some(Box::new(BmpWriter)),
+        "exr" => Some(Box::new(ExrWriter)),
+        _ => None,
+    }
+}
+
+/// Quantizes the pixels into packed 8-bit RGB.
+fn to_rgb8(pixels: &[Vector3]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(pixels.len() * 3);
+    for p in pixels {
+        let (r, g, b) = p.to_rgb_gamma(1.0);
+        buf.extend_from_slice(&[r, g, b]);
+    }
+    buf
+}
+
+impl ImageWriter for PpmWriter {
+    fn write(&self, pixels: &[Vector3], width: usize, height: usize,
+             out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "P6 {} {} 255", width, height)?;
+        out.write_all(&to_rgb8(pixels))
+    }
+}
+
+impl ImageWriter for PngWriter {
+    fn write(&self, pixels: &[Vector3], width: usize, height: usize,
+             out: &mut dyn Write) -> io::Result<()> {
+        PngEncoder::new(out)
+            .write_image(&to_rgb8(pixels), width as u32, height as u32, ExtendedColorType::Rgb8)
+            .map_err(io::Error::other)
+    }
+}
+
+impl ImageWriter for BmpWriter {
+    fn write(&self, pixels: &[Vector3], width: usize, height: usize,
+             out: &mut dyn Write) -> io::Result<()> {
+        // Rows are stored bottom-up as BGR, each padded to 4 bytes.
+        let row_size = (width * 3 + 3) & !3;
+        let image_size = row_size * height;
+        let header_size = 14 + 40;
+
+        let mut header = Vec::with_capacity(header_size);
+        header.extend_from_slice(b"BM");
+        header.extend_from_slice(&((header_size + image_size) as u32).to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&(header_size as u32).to_le_bytes());
+        header.extend_from_slice(&40u32.to_le_bytes());
+        header.extend_from_slice(&(width as i32).to_le_bytes());
+        header.extend_from_slice(&(height as i32).to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // color planes
+        header.extend_from_slice(&24u16.to_le_bytes()); // bits per pixel
+        header.extend_from_slice(&0u32.to_le_bytes()); // no compression
+        header.extend_from_slice(&(image_size as u32).to_le_bytes());
+        header.extend_from_slice(&2835i32.to_le_bytes()); // 72 DPI
+        header.extend_from_slice(&2835i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        out.write_all(&header)?;
+
+        let rgb = to_rgb8(pixels);
+        let mut row = vec![0u8; row_size];
+        for y in (0..height).rev() {
+            for x in 0..width {
+                let i = (y * width + x) * 3;
+                row[x*3] = rgb[i+2];
+                row[x*3+1] = rgb[i+1];
+                row[x*3+2] = rgb[i];
+            }
+            out.write_all(&row)?;
+        }
+        Ok(())
+    }
+}
+
+impl ImageWriter for ExrWriter {
+    fn write(&self, pixels: &[Vector3], width: usize, height: usize,
+             out: &mut dyn Write) -> io::Result<()> {
+        let channels = SpecificChannels::rgb(|pos: Vec2<usize>| {
+            let p = pixels[pos.y() * width + pos.x()];
+            (p.x as f32, p.y as f32, p.z as f32)
+        });
+        let image = Image::from_encoded_channels((width, height), Encoding::UNCOMPRESSED, channels);
+        // The encoder needs to seek, so the file is built in memory first.
+        let mut buf = Cursor::new(Vec::new());
+        image.write().to_buffered(&mut buf).map_err(io::Error::other)?;
+        out.write_all(buf.get_ref())
+    }
+
+    fn is_hdr(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exr::prelude::{read, ReadChannels, ReadLayers};
+
+    #[test]
+    fn exr_roundtrips_unclamped_radiance() {
+        let (width, height) = (3, 2);
+        let pixels = vec![v3!(2.0, 0.5, 0.0), v3!(0.1, 0.2, 0.3), v3!(-0.25, 100.0, 1e-3),
+                          v3!(0.0, 0.0, 0.0), v3!(1.0, 1.0, 1.0), v3!(7.5, 0.75, 0.075)];
+        let mut file = Vec::new();
+        ExrWriter.write(&pixels, width, height, &mut file).unwrap();
+
+        let image = read().no_deep_data().largest_resolution_level()
+            .rgb_channels(
+                |size, _| vec![v3!(0.0, 0.0, 0.0); size.area()],
+                move |loaded: &mut Vec<Vector3>, pos: Vec2<usize>, (r, g, b): (f32, f32, f32)| {
+                    loaded[pos.y() * width + pos.x()] = v3!(r as f64, g as f64, b as f64);
+                })
+            .first_valid_layer().all_attributes()
+            .from_buffered(Cursor::new(file)).unwrap();
+        assert_eq!(image.layer_data.size, Vec2(width, height));
+        let loaded = image.layer_data.channel_data.pixels;
+        for (p, q) in pixels.iter().zip(&loaded) {
+            // Only rounded to f32.
+            assert!((*p - *q).length() < 1e-4 * p.length().max(1.0), "{:?} came back as {:?}", p, q);
+        }
+        assert!((loaded[0] - v3!(2.0, 0.5, 0.0)).length() < 1e-4);
+    }
+}
@@ -0,0 +1,236 @@
+//! Photon map for caustics, after Henrik Wann Jensen's "Realistic Image
+//! Synthesis Using Photon Mapping". Photons leave the scene's lights and
+//! bounce off mirrors and through glass until they land on a diffuse
+//! surface, where they are stored in a k-d tree. Shading a diffuse point
+//! then estimates the light focused onto it from the density of the
+//! photons around it. Only photons that were reflected or refracted on
+//! the way are kept: direct light is already handled by the shadow rays.
+
+use std::f64::consts::PI;
+
+use raytracer::{fresnel_schlick, Ray, Scene};
+use rng::Rng;
+use vector::Vector3;
+
+/// Bounces after which a photon still going is dropped.
+const MAX_BOUNCES: u32 = 16;
+
+/// Light arriving at a diffuse surface.
+#[derive(Copy, Clone, Debug)]
+pub struct Photon {
+    pub pos: Vector3,
+    /// Unit direction the photon was travelling in.
+    pub dir: Vector3,
+    pub power: Vector3,
+}
+
+/// Caustic photons of a scene in a balanced k-d tree, for `Scene::photon_map`.
+pub struct PhotonMap {
+    /// The tree, implicit in the order: the median of each range is its
+    /// node, splitting it along `axes` at the same index into the photons
+    /// before and after it.
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    /// How many of the nearest photons `irradiance` averages over.
+    pub gather_count: usize,
+    /// Farthest a photon can be from the shaded point and still count.
+    pub gather_radius: f64,
+}
+
+impl PhotonMap {
+    /// Shoots `count` photons into `scene`, shared evenly between the
+    /// lights that emit them, and keeps the caustic ones. Directional
+    /// lights don't emit any. The same `seed` always gives the same map.
+    /// `irradiance` averages over the nearest 64 photons within
+    /// `gather_radius`.
+    pub fn new(scene: &Scene, count: usize, gather_radius: f64, seed: u64) -> PhotonMap {
+        let mut rng = Rng::new(seed);
+        let mut photons = Vec::new();
+        let emitting: Vec<_> = scene.lights.iter().filter(|light| light.emits_photons()).collect();
+        let per_light = count / emitting.len().max(1);
+        for light in emitting {
+            for _ in 0..per_light {
+                if let Some((ray, power)) = light.emit_photon(&mut rng) {
+                    trace_photon(scene, ray, power / per_light as f64, &mut photons, &mut rng);
+                }
+            }
+        }
+        let mut axes = vec![0; photons.len()];
+        balance(&mut photons, &mut axes);
+        PhotonMap { photons, axes, gather_count: 64, gather_radius }
+    }
+
+    /// The stored photons, in tree order.
+    pub fn photons(&self) -> &[Photon] {
+        &self.photons
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Up to `n` photons within `radius` of `pos`, nearest first, with
+    /// their squared distances.
+    pub fn nearest(&self, pos: Vector3, n: usize, radius: f64) -> Vec<(f64, &Photon)> {
+        let mut found = Vec::with_capacity(n + 1);
+        if n > 0 {
+            self.gather(0, self.photons.len(), pos, n, radius*radius, &mut found);
+        }
+        found.into_iter().map(|(d2, i)| (d2, &self.photons[i])).collect()
+    }
+
+    /// Adds the photons of the subtree over `start..end` that are nearer
+    /// than the farthest of `found` to it, keeping it sorted and at most
+    /// `n` long.
+    fn gather(&self, start: usize, end: usize, pos: Vector3, n: usize, max_d2: f64,
+              found: &mut Vec<(f64, usize)>) {
+        if start >= end {
+            return;
+        }
+        let mid = start + (end - start) / 2;
+        let axis = self.axes[mid] as usize;
+        let delta = pos[axis] - self.photons[mid].pos[axis];
+        let (near, far) = if delta < 0.0 { ((start, mid), (mid + 1, end)) }
+                          else { ((mid + 1, end), (start, mid)) };
+        self.gather(near.0, near.1, pos, n, max_d2, found);
+
+        let limit = |found: &Vec<(f64, usize)>| {
+            if found.len() < n { max_d2 } else { found[n - 1].0 }
+        };
+        let d2 = (self.photons[mid].pos - pos).length_squared();
+        if d2 < limit(found) {
+            let at = found.partition_point(|&(d, _)| d <= d2);
+            found.insert(at, (d2, mid));
+            found.truncate(n);
+        }
+        if delta*delta < limit(found) {
+            self.gather(far.0, far.1, pos, n, max_d2, found);
+        }
+    }
+
+    /// Light per unit area arriving at `pos` on a surface facing `normal`,
+    /// estimated from the nearest `gather_count` photons within
+    /// `gather_radius` with a cone filter, which weighs photons less the
+    /// farther they are and so blurs the edges of caustics less than a
+    /// plain average. Photons arriving from behind the surface don't count.
+    pub fn irradiance(&self, pos: Vector3, normal: Vector3) -> Vector3 {
+        let found = self.nearest(pos, self.gather_count, self.gather_radius);
+        // Fewer photons than asked for means the density is low out to the
+        // gather radius, so estimate over all of it.
+        let r2 = match found.last() {
+            Some(&(d2, _)) if found.len() == self.gather_count => d2,
+            Some(_) => self.gather_radius * self.gather_radius,
+            None => return v3!(0.0, 0.0, 0.0),
+        };
+        if r2 <= 0.0 {
+            return v3!(0.0, 0.0, 0.0);
+        }
+        let r = r2.sqrt();
+        let mut sum = v3!(0.0, 0.0, 0.0);
+        for &(d2, photon) in &found {
+            if photon.dir.dot(normal) < 0.0 {
+                sum += photon.power * (1.0 - d2.sqrt() / r);
+            }
+        }
+        // A cone filter integrates to a third of the disc it covers.
+        sum / (PI * r2 / 3.0)
+    }
+}
+
+/// Follows a photon through the scene, choosing at every surface between
+/// mirror reflection, refraction and landing on it with the material's
+/// reflection and transparency as probabilities. Landing ends the path,
+/// and the photon is stored if it was reflected or refracted before.
+fn trace_photon(scene: &Scene, mut ray: Ray, power: Vector3, photons: &mut Vec<Photon>,
+                rng: &mut Rng) {
+    let mut specular = false;
+    for _ in 0..MAX_BOUNCES {
+        let isect = match scene.intersect(&ray) {
+            Some(isect) => isect,
+            None => return,
+        };
+        let material = &isect.material;
+        let cos_i = -ray.dir.dot(isect.normal);
+        // Normal on the side the photon came from.
+        let normal = if cos_i < 0.0 { -isect.normal } else { isect.normal };
+        let reflection_dir = ray.dir.reflect(normal);
+        let choice = rng.next_f64();
+        let dir = if choice < material.reflection {
+            reflection_dir
+        } else if choice < material.reflection + material.transparency {
+            let ior = material.refraction;
+            let eta = if cos_i < 0.0 { ior } else { 1.0 / ior };
+            let f0 = ((1.0 - ior) / (1.0 + ior)).powi(2);
+            match ray.dir.refract(normal, eta) {
+                Some(dir) if rng.next_f64() >= fresnel_schlick(cos_i.abs(), f0) => dir,
+                _ => reflection_dir,
+            }
+        } else {
+            if specular {
+                photons.push(Photon { pos: isect.pos, dir: ray.dir, power });
+            }
+            return;
+        };
+        specular = true;
+        ray = Ray { origin: isect.pos + dir*0.001, dir, t_max: f64::INFINITY };
+    }
+}
+
+/// Orders `photons` into a balanced k-d tree, each range split at its
+/// median along the axis it is widest in, and records the axes.
+fn balance(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let first = photons[0].pos;
+    let (min, max) = photons.iter().fold((first, first), |(min, max), p| {
+        (min.component_min(p.pos), max.component_max(p.pos))
+    });
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 }
+               else if extent.y >= extent.z { 1 } else { 2 };
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| a.pos[axis].total_cmp(&b.pos[axis]));
+    axes[mid] = axis as u8;
+    let (photons_below, photons_rest) = photons.split_at_mut(mid);
+    let (axes_below, axes_rest) = axes.split_at_mut(mid);
+    balance(photons_below, axes_below);
+    balance(&mut photons_rest[1..], &mut axes_rest[1..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let mut rng = Rng::new(23);
+        let random_point = |rng: &mut Rng| v3!(rng.next_f64(), rng.next_f64(), rng.next_f64()*0.1);
+        let mut photons: Vec<Photon> = (0..5000).map(|i| {
+            // Some photons share a position, to exercise ties.
+            let pos = if i % 10 == 0 { v3!(0.5, 0.5, 0.05) } else { random_point(&mut rng) };
+            Photon { pos, dir: v3!(0.0, 0.0, -1.0), power: v3!(1.0, 1.0, 1.0) }
+        }).collect();
+        let mut axes = vec![0; photons.len()];
+        balance(&mut photons, &mut axes);
+        let map = PhotonMap { photons, axes, gather_count: 64, gather_radius: 0.1 };
+
+        for i in 0..500 {
+            let pos = if i % 50 == 0 { v3!(0.5, 0.5, 0.05) } else { random_point(&mut rng) * 1.2 };
+            let n = [0, 1, 10, 64, 600][i % 5];
+            let radius = [0.01, 0.05, 0.2, 2.0][i % 4];
+            let mut expected: Vec<f64> = map.photons().iter()
+                .map(|p| (p.pos - pos).length_squared())
+                .filter(|&d2| d2 < radius*radius)
+                .collect();
+            expected.sort_by(f64::total_cmp);
+            expected.truncate(n);
+            let found: Vec<f64> = map.nearest(pos, n, radius).iter().map(|&(d2, _)| d2).collect();
+            assert_eq!(found, expected, "{} nearest within {} of {:?}", n, radius, pos);
+        }
+    }
+}
@@ -1,266 +1,5701 @@
 use std;
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+#[cfg(feature = "debug_api")]
+use std::collections::HashMap;
 
-use vector::Vector3;
+use image::ImageReader;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use toml;
+
+use acceleration::{Aabb, AccelerationStructure, Accelerator};
+use atmosphere::AtmosphereModel;
+use checkpoint::{load_checkpoint, save_checkpoint};
+use mesh::{load_obj_materials, load_obj_with_materials, ObjError};
+use photon::PhotonMap;
+use rng::Rng;
+use sampling::{cosine_hemisphere_sample, local_to_world, uniform_cone_sample, uniform_sphere_sample};
+use scene_file::{LightDesc, ObjectDesc, SceneFile};
+use shadow_map::ShadowMap;
+use texture::{CheckerboardTexture, Texture, TextureFootprint};
+use tonemap::ToneMapper;
+use transform::{Instance, InstanceHandle, SceneNode, Transform};
+use vector::{smoothstep_f64, Matrix4, Vector3};
 
 
 pub struct Scene {
     pub camera: Camera,
-    pub lights: Vec<Light>,
-    pub objects: Vec<Box<Geometry>>,
+    pub lights: Vec<Box<dyn LightSource>>,
+    /// Objects intersected one by one. `build_bvh` and `build_grid` move
+    /// every bounded object from here into the acceleration structure.
+    pub objects: Vec<Box<dyn Geometry>>,
+    accel: Option<Accelerator>,
+    /// Bumped by `mark_dirty`; `accel` is only valid while it matches
+    /// `accel_generation`.
+    generation: u64,
+    accel_generation: u64,
+    tone_mapper: ToneMapper,
+    /// Color seen by rays that miss every object.
+    pub background: Background,
+    /// Light reaching every surface regardless of the lights, multiplied
+    /// by the material color.
+    pub ambient: Vector3,
+    /// Haze blended into the Whitted and ambient occlusion integrators'
+    /// colors by distance.
+    pub fog: Option<Fog>,
+    /// Caustics added to diffuse surfaces by the Whitted and ambient
+    /// occlusion integrators. Built from the scene as it was, so it has to
+    /// be built again after the scene changes.
+    pub photon_map: Option<PhotonMap>,
+    /// Named materials, indexed by `MaterialId`.
+    materials: Vec<(String, Material)>,
+    /// Openings the path tracer samples the background through, see
+    /// `LightPortal`. Empty for scenes open to the background.
+    pub light_portals: Vec<LightPortal>,
+    /// How the Whitted and ambient occlusion integrators decide whether a
+    /// light is blocked.
+    pub shadowing_mode: ShadowMode,
+    /// Objects placed in a hierarchy. `build_bvh`, `build_grid` and the
+    /// other acceleration structures flatten it into the scene's objects,
+    /// leaving an empty root behind.
+    pub root: SceneNode,
+}
+
+/// Exponential distance fog: a fraction `1 - exp(-dist * density)` of what
+/// is seen at distance `dist` is replaced by `color`.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Fog {
+    pub color: Vector3,
+    pub density: f64,
+}
+
+/// How `Scene::shadowing_mode` finds shadows.
+#[derive(Clone, Default)]
+pub enum ShadowMode {
+    /// A shadow ray towards every light sample.
+    #[default]
+    RayTraced,
+    /// Looks shadows up in the maps, one per light in `Scene::lights` order,
+    /// and casts shadow rays for lights without one. Built from the scene
+    /// as it was, see `Scene::build_shadow_maps`. The path tracer always
+    /// casts shadow rays.
+    ShadowMap(Vec<Option<ShadowMap>>),
+}
+
+/// Distance used for fog on rays that hit nothing.
+const FOG_MISS_DISTANCE: f64 = 1e4;
+
+impl Fog {
+    /// `color` as seen through `dist` units of fog.
+    pub fn apply(&self, color: Vector3, dist: f64) -> Vector3 {
+        color.lerp(self.color, 1.0 - (-dist * self.density).exp())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Background {
+    Solid(Vector3),
+    /// Blends from `bottom` for rays pointing straight down to `top` for
+    /// rays pointing straight up.
+    Gradient { top: Vector3, bottom: Vector3 },
+    /// Color for a given (normalized) ray direction. Can't be saved in
+    /// scene files.
+    #[serde(skip)]
+    Custom(Box<dyn Fn(Vector3) -> Vector3 + Send + Sync>),
+    /// Image covering every direction, such as one loaded with
+    /// `load_hdr`. Can't be saved in scene files.
+    #[serde(skip)]
+    EnvMap(EnvMap),
+    /// Sky lit by the sun through a scattering atmosphere.
+    Atmosphere(AtmosphereModel),
+}
+
+/// Equirectangular image lighting the scene from every direction. Pixels
+/// are in row-major order from the top row, which is straight up. An
+/// image with no pixels is black.
+pub struct EnvMap {
+    pixels: Vec<Vector3>,
+    width: usize,
+    height: usize,
+    /// Multiplies every lookup, to match the exposure of the rest of the
+    /// scene.
+    pub power: f64,
+    /// Divides every lookup by `mean_luminance`, so that the average
+    /// luminance over all directions is `power`.
+    pub auto_normalize: bool,
+    mean_luminance: OnceLock<f64>,
+}
+
+impl EnvMap {
+    pub fn new(pixels: Vec<Vector3>, width: usize, height: usize) -> EnvMap {
+        EnvMap { pixels, width, height, power: 1.0, auto_normalize: false, mean_luminance: OnceLock::new() }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Radiance arriving from direction `dir`, scaled by `power`.
+    pub fn sample(&self, dir: Vector3) -> Vector3 {
+        let scale = if !self.auto_normalize {
+            self.power
+        } else if self.mean_luminance() > 0.0 {
+            self.power / self.mean_luminance()
+        } else {
+            0.0
+        };
+        sample_env_map(&self.pixels, self.width, self.height, dir) * scale
+    }
+
+    /// Luminance of the image averaged over the sphere of directions,
+    /// before `power`. Rows near the poles cover less solid angle and
+    /// count for less. Computed on first use.
+    pub fn mean_luminance(&self) -> f64 {
+        use std::f64::consts::PI;
+        *self.mean_luminance.get_or_init(|| {
+            let (mut sum, mut weight) = (0.0, 0.0);
+            for (y, row) in self.pixels.chunks(self.width.max(1)).take(self.height).enumerate() {
+                let w = (PI * (y as f64 + 0.5) / self.height as f64).sin();
+                sum += w * row.iter().map(|p| p.dot(v3!(0.2126, 0.7152, 0.0722))).sum::<f64>();
+                weight += w * row.len() as f64;
+            }
+            if weight > 0.0 { sum / weight } else { 0.0 }
+        })
+    }
+}
+
+/// Loads a Radiance `.hdr` image as an environment map background.
+pub fn load_hdr(path: &str) -> Result<Background, io::Error> {
+    let image = ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()
+        .map_err(io::Error::other)?
+        .into_rgb32f();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let pixels = image.pixels()
+        .map(|p| v3!(p[0] as f64, p[1] as f64, p[2] as f64))
+        .collect();
+    Ok(Background::EnvMap(EnvMap::new(pixels, width, height)))
+}
+
+/// Bilinearly filtered lookup of an equirectangular image in direction
+/// `dir`. Longitude wraps around and latitude is clamped at the poles. An
+/// image with no pixels is black everywhere.
+fn sample_env_map(pixels: &[Vector3], width: usize, height: usize, dir: Vector3) -> Vector3 {
+    use std::f64::consts::PI;
+    if width == 0 || height == 0 {
+        return v3!(0.0, 0.0, 0.0);
+    }
+    let u = 0.5 + dir.x.atan2(dir.z) / (2.0*PI);
+    let v = dir.y.clamp(-1.0, 1.0).acos() / PI;
+    let x = u*width as f64 - 0.5;
+    let y = (v*height as f64 - 0.5).clamp(0.0, (height - 1) as f64);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let column = |i: f64| (i as i64).rem_euclid(width as i64) as usize;
+    let (xa, xb) = (column(x0), column(x0 + 1.0));
+    let (ya, yb) = (y0 as usize, (y0 as usize + 1).min(height - 1));
+    let top = pixels[ya*width + xa].lerp(pixels[ya*width + xb], fx);
+    let bottom = pixels[yb*width + xa].lerp(pixels[yb*width + xb], fx);
+    top.lerp(bottom, fy)
 }
 
-#[derive(Copy,Clone)]
+impl Background {
+    pub fn color(&self, dir: Vector3) -> Vector3 {
+        match *self {
+            Background::Solid(color) => color,
+            Background::Gradient { top, bottom } => bottom.lerp(top, (dir.y + 1.0) * 0.5),
+            Background::Custom(ref f) => f(dir),
+            Background::EnvMap(ref env) => env.sample(dir),
+            Background::Atmosphere(ref model) => model.radiance(dir),
+        }
+    }
+}
+
+impl Default for Background {
+    fn default() -> Background {
+        Background::Solid(v3!(0.0, 0.4, 1.0))
+    }
+}
+
+impl fmt::Debug for Background {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Background::Solid(color) => f.debug_tuple("Solid").field(&color).finish(),
+            Background::Gradient { top, bottom } =>
+                f.debug_struct("Gradient").field("top", &top).field("bottom", &bottom).finish(),
+            Background::Custom(_) => f.write_str("Custom(..)"),
+            Background::Atmosphere(ref model) => f.debug_tuple("Atmosphere").field(model).finish(),
+            Background::EnvMap(ref env) =>
+                f.debug_struct("EnvMap").field("width", &env.width).field("height", &env.height)
+                    .field("power", &env.power).field("auto_normalize", &env.auto_normalize).finish(),
+        }
+    }
+}
+
+/// Geometry and lights are trait objects, so only their counts are shown.
+impl fmt::Debug for Scene {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let accel_objects = self.accel.as_ref().map_or(0, |accel| accel.objects().len());
+        f.debug_struct("Scene")
+            .field("camera", &self.camera)
+            .field("lights", &self.lights.len())
+            .field("objects", &(self.objects.len() + accel_objects))
+            .field("tone_mapper", &self.tone_mapper)
+            .field("background", &self.background)
+            .field("ambient", &self.ambient)
+            .field("fog", &self.fog)
+            .field("materials", &self.materials.iter().map(|m| &m.0).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Material {
     pub shininess: f64,
     pub spec_color: Vector3,
     pub color: Vector3,
     pub reflection: f64,
+    /// Index of refraction, e.g. 1.5 for glass.
+    pub refraction: f64,
+    /// Fraction of the surface color replaced by refracted/reflected light,
+    /// from 0 (opaque) to 1 (clear).
+    pub transparency: f64,
+    /// Light given off by the surface itself, regardless of the lights.
+    pub emission: Vector3,
+    /// Replaces `color` with a color looked up at each hit point. Not
+    /// saved in scene files.
+    #[serde(skip)]
+    pub texture: Option<Arc<dyn Texture>>,
+    /// Tangent-space normals as colors, such as a `NormalMap`, used to
+    /// perturb the shading normal of shapes with texture coordinates. Not
+    /// saved in scene files.
+    #[serde(skip)]
+    pub normal_map: Option<Arc<dyn Texture>>,
+    /// How direct light is reflected.
+    #[serde(default)]
+    pub brdf: BrdfModel,
+    /// Shade both sides of the surface, by turning the normal towards the
+    /// ray when the back is hit. For thin surfaces and views from inside
+    /// closed objects; refraction needs the outward normal, so leave it
+    /// off for transparent solids.
+    #[serde(default)]
+    pub two_sided: bool,
+}
+
+/// Chainable setters, for starting from `basic_material` and changing a
+/// few fields, e.g.
+/// `basic_material(red).with_reflection(0.7).with_shininess(64.0)`.
+impl Material {
+    pub fn with_reflection(mut self, reflection: f64) -> Material {
+        self.reflection = reflection;
+        self
+    }
+
+    pub fn with_shininess(mut self, shininess: f64) -> Material {
+        self.shininess = shininess;
+        self
+    }
+
+    pub fn with_spec_color(mut self, spec_color: Vector3) -> Material {
+        self.spec_color = spec_color;
+        self
+    }
+
+    pub fn with_emission(mut self, emission: Vector3) -> Material {
+        self.emission = emission;
+        self
+    }
+
+    /// Makes the material `transparency` clear, refracting with the index
+    /// of refraction `refraction`.
+    pub fn with_transparency(mut self, transparency: f64, refraction: f64) -> Material {
+        self.transparency = transparency;
+        self.refraction = refraction;
+        self
+    }
+}
+
+/// Reflection model used for direct lighting.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum BrdfModel {
+    /// Lambertian diffuse plus a `shininess` and `spec_color` highlight
+    /// around the half vector.
+    #[default]
+    BlinnPhong,
+    /// Like `BlinnPhong`, with the highlight around the reflected light
+    /// direction instead.
+    Phong,
+    /// GGX microfacet model. `roughness` goes from 0 (polished) to 1, and
+    /// `metalness` blends from a dielectric with 4% specular reflectance
+    /// and a `color` diffuse to a metal that reflects `color` specularly.
+    /// `shininess` and `spec_color` are not used.
+    CookTorrance { roughness: f64, metalness: f64 },
+}
+
+/// Index of a material registered with `Scene::register_material`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MaterialId(pub usize);
+
+/// A geometry's material: its own copy, or one shared through the scene's
+/// registry. Scene files write inline materials as before and named ones
+/// as their id.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MaterialRef {
+    Inline(Material),
+    Named(MaterialId),
+}
+
+impl MaterialRef {
+    /// The inline material. Named materials are only known to the scene, so
+    /// for them this is a white placeholder, which `cast_ray` replaces.
+    pub fn material(&self) -> Material {
+        match *self {
+            MaterialRef::Inline(ref material) => material.clone(),
+            MaterialRef::Named(_) => basic_material(v3!(1.0, 1.0, 1.0)),
+        }
+    }
+
+    pub fn id(&self) -> Option<MaterialId> {
+        match *self {
+            MaterialRef::Inline(_) => None,
+            MaterialRef::Named(id) => Some(id),
+        }
+    }
+}
+
+impl From<Material> for MaterialRef {
+    fn from(material: Material) -> MaterialRef {
+        MaterialRef::Inline(material)
+    }
 }
 
+impl From<MaterialId> for MaterialRef {
+    fn from(id: MaterialId) -> MaterialRef {
+        MaterialRef::Named(id)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Sphere {
     pub pos: Vector3,
     pub radius: f64,
-    pub material: Material
+    pub material: MaterialRef,
+    /// How far the sphere moves while the shutter is open, for
+    /// `raytrace_motion_blur`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<Vector3>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Plane {
     pub pos: Vector3,
     pub normal: Vector3,
-    pub material: Material,
+    pub material: MaterialRef,
+    /// Texture coordinates per unit of world x and z.
+    #[serde(default = "default_uv_scale")]
+    pub uv_scale: f64,
+}
+
+fn default_uv_scale() -> f64 {
+    1.0
+}
+
+/// Part of a plane inside the box from `min` to `max`. Unlike `Plane` it
+/// is bounded, so it can go in the BVH.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BoundedPlane {
+    pub pos: Vector3,
+    pub normal: Vector3,
+    pub min: Vector3,
+    pub max: Vector3,
+    pub material: MaterialRef,
+}
+
+/// Flat disk of `radius` around `center`, facing along `normal`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Disk {
+    pub center: Vector3,
+    pub normal: Vector3,
+    pub radius: f64,
+    pub material: MaterialRef,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BBox {
     pub v1: Vector3,
     pub v2: Vector3,
-    pub material: Material,
+    pub material: MaterialRef,
+    /// How far the box moves while the shutter is open, for
+    /// `raytrace_motion_blur`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<Vector3>,
+}
+
+pub struct Triangle {
+    pub v0: Vector3,
+    pub v1: Vector3,
+    pub v2: Vector3,
+    pub material: MaterialRef,
+    /// Shading normals at v0, v1 and v2. Interpolated across the face when
+    /// all three are given, otherwise the face normal is used.
+    pub vertex_normals: [Option<Vector3>; 3],
+    /// Texture coordinates at v0, v1 and v2. Interpolated across the face
+    /// when all three are given, otherwise the barycentric coordinates are
+    /// used as uv.
+    pub vertex_uvs: [Option<(f64, f64)>; 3],
+    normal: Vector3,
+}
+
+/// Builds a `Triangle`, optionally with vertex normals for smooth shading.
+pub struct TriangleBuilder {
+    v0: Vector3,
+    v1: Vector3,
+    v2: Vector3,
+    material: MaterialRef,
+    vertex_normals: [Option<Vector3>; 3],
+    vertex_uvs: [Option<(f64, f64)>; 3],
+}
+
+/// Cylinder around the segment from `axis_start` to `axis_end`, optionally
+/// closed with flat end-caps.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Cylinder {
+    pub axis_start: Vector3,
+    pub axis_end: Vector3,
+    pub radius: f64,
+    pub capped: bool,
+    pub material: MaterialRef,
+}
+
+/// Cylinder around the segment from `start` to `end`, closed with
+/// hemispherical end-caps.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Capsule {
+    pub start: Vector3,
+    pub end: Vector3,
+    pub radius: f64,
+    pub material: MaterialRef,
+}
+
+/// Cone with its tip at `apex`, opening along `axis` with `half_angle`
+/// (in radians) between the axis and the surface, cut off `height` from
+/// the apex. The base is optionally closed with a disk.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Cone {
+    pub apex: Vector3,
+    pub axis: Vector3,
+    pub half_angle: f64,
+    pub height: f64,
+    pub capped: bool,
+    pub material: MaterialRef,
+}
+
+/// Torus lying in the xz-plane around `center`. `major_radius` is the
+/// distance from the center to the middle of the tube, `minor_radius` the
+/// radius of the tube.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Torus {
+    pub center: Vector3,
+    pub major_radius: f64,
+    pub minor_radius: f64,
+    pub material: MaterialRef,
 }
 
+/// Sphere of `radius` around `center` seen from inside, showing `texture`
+/// as a sky behind everything else in the scene: it glows with the
+/// texture's color, isn't lit and casts no shadows. Rays leaving the dome
+/// see the background instead. Its texture coordinates are those of a
+/// `Sphere`, so an equirectangular image maps onto it as longitude and
+/// latitude, and procedural textures are looked up at the hit point. Not
+/// saved in scene files.
+#[derive(Clone)]
+pub struct SkyDome {
+    pub center: Vector3,
+    pub radius: f64,
+    pub texture: Arc<dyn Texture>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Camera {
     pub pos: Vector3,
     pub up: Vector3,
     pub right: Vector3,
-    pub dist: f64
+    pub dist: f64,
+    /// Radius of the thin lens. 0 gives a pinhole camera where everything
+    /// is in focus.
+    pub aperture: f64,
+    /// Distance from `pos` to the plane that is in perfect focus.
+    pub focus_distance: f64,
+    pub projection: Projection,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Projection {
+    /// Rays spread out from `pos` through the image plane `dist` ahead.
+    #[default]
+    Perspective,
+    /// Parallel rays along the view direction, starting from the plane
+    /// through `pos` spanned by `right` and `up`. `dist` and the lens are
+    /// ignored.
+    Orthographic,
 }
 
+/// Point light. Its intensity falls off with distance `d` as
+/// `intensity / (a + b*d + c*d^2)`, where `(a, b, c)` is `attenuation`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Light {
     pub pos: Vector3,
-    pub color: Vector3
+    pub color: Vector3,
+    pub intensity: f64,
+    pub attenuation: (f64, f64, f64),
 }
 
-pub struct Intersection {
-    pos: Vector3,
-    normal: Vector3,
-    dist: f64,
-    material: Material,
+/// Infinitely distant light shining along `dir`, such as sunlight. Its
+/// intensity doesn't fall off with distance.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DirectionalLight {
+    pub dir: Vector3,
+    pub color: Vector3,
 }
 
-pub struct Ray {
-    origin: Vector3,
-    dir: Vector3,
+/// Cone of light from `pos` along `dir`. Full intensity within
+/// `inner_angle` of the axis, fading smoothly to nothing at `outer_angle`
+/// (both in radians).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Spotlight {
+    pub pos: Vector3,
+    pub dir: Vector3,
+    pub color: Vector3,
+    pub inner_angle: f64,
+    pub outer_angle: f64,
 }
 
-pub trait Geometry {
-    fn material(&self) -> Material;
-    fn intersects(&self, ray: &Ray) -> Option<Intersection>;
+/// Rectangular light with corner `pos` and edges `u` and `v`. Shadows are
+/// estimated from `samples` stratified points on the rectangle, giving
+/// soft penumbrae.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AreaLight {
+    pub pos: Vector3,
+    pub u: Vector3,
+    pub v: Vector3,
+    pub color: Vector3,
+    pub samples: usize,
 }
 
-impl Scene {
-    pub fn add<T: Geometry + 'static>(&mut self, g: T) {
-        self.objects.push(Box::new(g));
-    }
+/// Opening, such as a window, through which the background lights an
+/// otherwise closed scene. With portals in `Scene::light_portals` the path
+/// tracer samples the background only through them, instead of waiting for
+/// diffuse bounces to find their way out. Every direction in which the
+/// background can be seen must pass through a portal.
+#[derive(Copy, Clone)]
+pub struct LightPortal {
+    pub bounds: Aabb,
 }
 
-impl Geometry for BBox {
-    fn material(&self) -> Material {
-        self.material
+impl LightPortal {
+    /// Random direction from `from` through the portal, with its
+    /// probability density per unit solid angle. A point uniformly picked
+    /// on the faces turned towards `from` is aimed at; those faces cover
+    /// the portal's outline once. The density is 0 if `from` is inside.
+    pub fn sample(&self, from: Vector3, rng: &mut Rng) -> (Vector3, f64) {
+        let faces = self.front_faces(from);
+        let total: f64 = faces.iter().map(|f| f.2).sum();
+        let mut pick = rng.next_f64() * total;
+        let (u, v) = (rng.next_f64(), rng.next_f64());
+        for &(axis, plane, area) in &faces {
+            if pick > area {
+                pick -= area;
+                continue;
+            }
+            let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+            let mut p = [0.0; 3];
+            p[axis] = plane;
+            p[a] = self.bounds.min[a] + u*(self.bounds.max[a] - self.bounds.min[a]);
+            p[b] = self.bounds.min[b] + v*(self.bounds.max[b] - self.bounds.min[b]);
+            let dir = (v3!(p[0], p[1], p[2]) - from).normalize();
+            return (dir, self.pdf(from, dir));
+        }
+        (v3!(0.0, 1.0, 0.0), 0.0)
     }
-    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
-        let mut tnear = -(std::f64::INFINITY);
-        let mut tfar = std::f64::INFINITY;
 
-        if ray.dir.x == 0.0 && ray.origin.x < self.v1.x && ray.origin.x > self.v2.x {
-            return None;
+    /// Density of `sample` picking the unit direction `dir` from `from`.
+    pub fn pdf(&self, from: Vector3, dir: Vector3) -> f64 {
+        let faces = self.front_faces(from);
+        let total: f64 = faces.iter().map(|f| f.2).sum();
+        // The ray enters through the front face it reaches last.
+        let entry = faces.iter()
+            .map(|&(axis, plane, _)| (axis, (plane - from[axis]) / dir[axis]))
+            .filter(|&(_, t)| t > 0.0)
+            .fold(None, |best: Option<(usize, f64)>, f| match best {
+                Some(b) if b.1 >= f.1 => Some(b),
+                _ => Some(f),
+            });
+        match entry {
+            Some((axis, t)) if total > 0.0 && self.grown().contains(from + dir*t) =>
+                t*t / (dir[axis].abs() * total),
+            _ => 0.0,
         }
+    }
 
-        let mut t1 = (self.v1.x - ray.origin.x) / ray.dir.x;
-        let mut t2 = (self.v2.x - ray.origin.x) / ray.dir.x;
-        let mut n = v3!(1.0, 0.0, 0.0);
+    /// The faces whose outside `from` is on, as (axis, coordinate, area).
+    fn front_faces(&self, from: Vector3) -> Vec<(usize, f64, f64)> {
+        let (min, max) = (self.bounds.min, self.bounds.max);
+        let size = max - min;
+        let mut faces = Vec::new();
+        for axis in 0..3 {
+            let area = size[(axis + 1) % 3] * size[(axis + 2) % 3];
+            if from[axis] < min[axis] {
+                faces.push((axis, min[axis], area));
+            } else if from[axis] > max[axis] {
+                faces.push((axis, max[axis], area));
+            }
+        }
+        faces
+    }
 
-        if t1 > t2 { std::mem::swap(&mut t1, &mut t2);  }
-        if t1 > tnear { tnear = t1; n = v3!(1.0, 0.0, 0.0); }
-        if t2 < tfar { tfar = t2; }
-        if tnear > tfar || tfar < 0.0 { return None; }
+    /// The bounds with a little slack for rounding at the edges.
+    fn grown(&self) -> Aabb {
+        let eps = v3!(1e-9, 1e-9, 1e-9);
+        Aabb { min: self.bounds.min - eps, max: self.bounds.max + eps }
+    }
+}
 
-        t1 = (self.v1.y - ray.origin.y) / ray.dir.y;
-        t2 = (self.v2.y - ray.origin.y) / ray.dir.y;
+#[derive(Debug)]
+pub struct Intersection {
+    pub pos: Vector3,
+    pub normal: Vector3,
+    pub dist: f64,
+    pub material: Material,
+    /// Registered material to look up in the scene in place of `material`.
+    pub material_id: Option<MaterialId>,
+    /// Barycentric coordinates (u, v) of the hit for triangles, where the
+    /// hit is `(1-u-v)*v0 + u*v1 + v*v2`.
+    pub barycentric: Option<(f64, f64)>,
+    /// Texture coordinates of the hit, or (0, 0) for shapes without them.
+    pub uv: (f64, f64),
+    /// Direction along the surface in which u grows, for normal mapping.
+    /// None for shapes without texture coordinates.
+    pub tangent: Option<Vector3>,
+    /// Hit point in the object space of the innermost `Transform` or
+    /// `Instance` placing the shape, for textures that should move with
+    /// it. None for shapes that aren't transformed.
+    pub object_pos: Option<Vector3>,
+}
 
-        if t1 > t2 { std::mem::swap(&mut t1, &mut t2);  }
-        if t1 > tnear { tnear = t1; n = v3!(0.0, 1.0, 0.0);
-        }
-        if t2 < tfar { tfar = t2; }
-        if tnear > tfar || tfar < 0.0 { return None; }
+#[derive(Debug)]
+pub struct Ray {
+    pub origin: Vector3,
+    pub dir: Vector3,
+    /// Hits farther than this along the ray are ignored by the renderer,
+    /// as if the ray had missed. `f64::INFINITY` keeps every hit.
+    pub t_max: f64,
+}
 
-        t1 = (self.v1.z - ray.origin.z) / ray.dir.z;
-        t2 = (self.v2.z - ray.origin.z) / ray.dir.z;
+/// Rays through the next pixel to the right (`origin_dx`, `dir_dx`) and
+/// the next one up (`origin_dy`, `dir_dy`) of a camera ray. The Whitted
+/// and ambient occlusion integrators follow them to the surface the
+/// camera ray hits to find how much of it the pixel covers, and filter
+/// its texture over that. Reflected and refracted rays don't carry them.
+#[derive(Copy, Clone, Debug)]
+pub struct RayDifferential {
+    pub origin_dx: Vector3,
+    pub dir_dx: Vector3,
+    pub origin_dy: Vector3,
+    pub dir_dy: Vector3,
+}
 
-        if t1 > t2 { std::mem::swap(&mut t1, &mut t2);  }
-        if t1 > tnear { tnear = t1; n = v3!(0.0, 0.0, -1.0); }
-        if t2 < tfar { tfar = t2; }
-        if tnear > tfar || tfar < 0.0 { return None; }
+impl RayDifferential {
+    /// The rays through the pixels next to (x, y), through the same point
+    /// on the lens as `lens` picks for the camera ray.
+    fn for_pixel(camera: &Camera, x: f64, y: f64, width: usize, height: usize, t_max: f64,
+                 lens: &Rng) -> RayDifferential {
+        let offset_ray = |x, y| primary_ray(camera, x, y, width, height, t_max, &mut lens.clone());
+        let (ray_dx, ray_dy) = (offset_ray(x + 1.0, y), offset_ray(x, y + 1.0));
+        RayDifferential { origin_dx: ray_dx.origin, dir_dx: ray_dx.dir,
+                          origin_dy: ray_dy.origin, dir_dy: ray_dy.dir }
+    }
 
-        Some(Intersection { pos: ray.origin + ray.dir * tnear,
-                            normal: n,
-                            dist: tnear,
-                            material: self.material() })
+    /// Change in the origin of `ray` from one pixel to the next to the
+    /// right. Zero for a pinhole camera.
+    pub fn dpdx(&self, ray: &Ray) -> Vector3 {
+        self.origin_dx - ray.origin
     }
-}
 
-impl Geometry for Sphere {
-    fn material(&self) -> Material {
-        self.material
+    /// Change in the origin of `ray` from one pixel to the next one up.
+    pub fn dpdy(&self, ray: &Ray) -> Vector3 {
+        self.origin_dy - ray.origin
     }
-    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
-        let l = self.pos - ray.origin;
-        let tca = l.dot(ray.dir);
-        if tca < 0.0 {
-            return None;
-        }
-        let d = (l.dot(l)-tca*tca).sqrt();
-        let d2 = d*d;
-        let radius2 = self.radius*self.radius;
-        if d2 > radius2 {
-            return None;
-        }
-        let thc = (radius2 - d2).sqrt();
-        let t0 = tca - thc;
-        let t1 = tca + thc;
-        if t0 < 0.0 && t1 < 0.0 { return None; }
-        let t = if t0 < 0.0 { t1} else { t0.min(t1) };
 
-        let p = ray.origin+ray.dir*t;
-        let n = (p-self.pos).normalize();
-        Some (Intersection {pos: p, normal: n, dist: t, material: self.material() })
+    /// Change in the direction of `ray` from one pixel to the next to the
+    /// right. Zero for an orthographic camera.
+    pub fn dddx(&self, ray: &Ray) -> Vector3 {
+        self.dir_dx - ray.dir
+    }
+
+    /// Change in the direction of `ray` from one pixel to the next one up.
+    pub fn dddy(&self, ray: &Ray) -> Vector3 {
+        self.dir_dy - ray.dir
     }
 }
 
-impl Geometry for Plane {
-    fn material(&self) -> Material {
-        self.material
+/// Intermediate values of an intersection test, keyed by variable name.
+#[cfg(feature = "debug_api")]
+pub type DebugIntersectionResult = HashMap<&'static str, f64>;
+
+/// Light arriving at a shaded point from one sample of a light source.
+pub struct LightSample {
+    /// Unit vector from the shaded point towards the light.
+    pub dir: Vector3,
+    /// Distance to the sampled point on the light. Shadow rays ignore
+    /// anything farther away.
+    pub dist: f64,
+    pub color: Vector3,
+    /// Scale of this sample's contribution, e.g. spot falloff or the
+    /// weight of one of several area light samples.
+    pub intensity: f64,
+}
+
+/// Anything that illuminates the scene. Like `Geometry`, lights are shared
+/// between render threads.
+pub trait LightSource: Send + Sync {
+    /// Samples of the light as seen from `pos`. Samples that can't
+    /// contribute may be left out.
+    fn sample(&self, pos: Vector3, rng: &mut Rng) -> Vec<LightSample>;
+
+    /// Description of the light for scene files, or None if it can't be
+    /// saved.
+    fn to_desc(&self) -> Option<LightDesc> {
+        None
     }
-    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
-        let t = (self.pos - ray.origin).dot(self.normal)/(ray.dir.dot(self.normal));
-        if t > 0.0 {
-            let p = ray.origin+ray.dir*t;
-            let mut material = self.material();
-            let c = ((((2.0*p.z).floor() + (2.0*p.x).floor()) as i32) % 2) as f64;
-            material.color = material.color * c.abs();
-            Some(Intersection {
-                dist: t,
-                normal: self.normal,
-                pos: p,
-                material: material,
-            })
-        } else {
-            None
-        }
+
+    /// Whether `emit_photon` starts photons, so that `PhotonMap` can share
+    /// them out between the lights that do.
+    fn emits_photons(&self) -> bool {
+        false
     }
-}
 
-fn cast_ray(scene: &Scene, ray: &Ray) -> Option<Intersection> {
-    let mut closest: f64 = std::f64::INFINITY;
-    let mut isect: Option<Intersection> = None;
-    for o in scene.objects.iter() {
-        match o.intersects(&ray) {
-            Some(i) => {
-                if i.dist < closest {
-                    closest = i.dist;
-                    isect = Some(i);
-                }
-            },
-            _ => {}
-        }
+    /// Starts a photon for `PhotonMap`: a ray leaving the light, and the
+    /// power the light would emit if this were its only photon. None for
+    /// lights that don't emit photons.
+    fn emit_photon(&self, _rng: &mut Rng) -> Option<(Ray, Vector3)> {
+        None
     }
-    isect
 }
 
-fn blinn_phong(light_dir: Vector3, isect: &Intersection) -> Vector3 {
-    let material = isect.material;
-    let diffuse = light_dir.dot(isect.normal).max(0.0);
-    let mut specular = 0.0;
-    if diffuse > 0.0 {
-        let view_dir = (isect.pos * (-1.0)).normalize();
-        let half_dir = (light_dir + view_dir).normalize();
-        let spec_angle = half_dir.dot(isect.normal).max(0.0);
-        specular = spec_angle.powf(material.shininess);
+/// Scenes are shared between render threads, so geometry must be
+/// `Send + Sync`.
+pub trait Geometry: Send + Sync {
+    /// The object's own material, or a placeholder if it uses a
+    /// registered one, see `material_id`.
+    fn material(&self) -> Material;
+    /// Registered material the object is shaded with in place of
+    /// `material`, looked up with `Scene::get_material`.
+    fn material_id(&self) -> Option<MaterialId> {
+        None
     }
-    return material.color * diffuse + material.spec_color * specular;
-}
+    fn intersects(&self, ray: &Ray) -> Option<Intersection>;
+    /// World space bounds, or None for unbounded geometry such as planes.
+    fn bounding_box(&self) -> Option<Aabb>;
 
-fn shade_pixel(scene: &Scene, ray: &Ray, trace_depth: i32) -> Vector3 {
-    let mut pixel = v3!(0.0, 0.0, 0.0);
-    match cast_ray(&scene, &ray) {
-        None => { pixel = v3!(0.0, 0.4, 1.0); }, // background color
-        Some(isect) => {
-            for ref light in &scene.lights {
-                let light_dir = (light.pos - isect.pos).normalize();
-                let shadow_ray = Ray { origin: isect.pos+light_dir*0.001,
-                                       dir: light_dir };
-                match cast_ray(&scene, &shadow_ray) {
-                    Some(..) => { },
-                    None => { pixel = pixel + blinn_phong(light_dir, &isect); }
-                }
-                pixel = pixel + isect.material.color * 0.1; // ambient
-                    
-                if isect.material.reflection > 0.0 {                        
-                    let reflection_dir = ray.dir - isect.normal*ray.dir.dot(isect.normal)*2.0;
-                    let reflection_ray = Ray { origin: isect.pos+reflection_dir*0.001,
-                                               dir: reflection_dir };
-                    if trace_depth > 0 {
-                        pixel = shade_pixel(&scene, &reflection_ray, trace_depth - 1)
-                            * isect.material.reflection;
-                    }
-                }
+    /// Maps the uniform random numbers `u1`, `u2` in [0, 1) to a point
+    /// uniformly distributed over the surface, returned as
+    /// `(point, normal, pdf)` with the pdf measured per unit area.
+    fn sample_surface(&self, _u1: f64, _u2: f64) -> (Vector3, Vector3, f64) {
+        panic!("sample_surface is not implemented for this geometry");
+    }
+
+    /// Solid angle pdf, as seen from `from`, of having sampled the surface
+    /// point `to` with `sample_surface`: `dist^2 / (cos_theta * area)`.
+    fn pdf_surface(&self, _from: Vector3, _to: Vector3) -> f64 {
+        panic!("pdf_surface is not implemented for this geometry");
+    }
+
+    /// Every hit along `ray`, nearest first. The default walks the ray by
+    /// calling `intersects` again just past each hit, which finds all of
+    /// them as long as `intersects` also works from inside the object.
+    fn intersect_all(&self, ray: &Ray) -> Vec<Intersection> {
+        const MAX_HITS: usize = 64;
+        let mut hits: Vec<Intersection> = Vec::new();
+        let mut offset = 0.0;
+        while hits.len() < MAX_HITS {
+            let rest = Ray { origin: ray.origin + ray.dir*offset, dir: ray.dir, t_max: ray.t_max - offset };
+            match self.intersects(&rest) {
+                Some(mut isect) => {
+                    isect.dist += offset;
+                    offset = isect.dist + 1e-6;
+                    hits.push(isect);
+                },
+                None => break,
             }
         }
+        hits
+    }
+
+    /// Description of the object for scene files, or None if it can't be
+    /// saved.
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        None
+    }
+
+    /// Whether the object blocks shadow rays. Backdrops such as `SkyDome`
+    /// don't, so that the lights inside them still reach the scene.
+    fn casts_shadows(&self) -> bool {
+        true
+    }
+
+    /// Copy of the object where it is at time `t` in [0, 1) of the
+    /// shutter interval, or None if it doesn't move.
+    fn at_time(&self, _t: f64) -> Option<Box<dyn Geometry>> {
+        None
+    }
+
+    /// Moves the object by `offset` in place, returning false if it can't,
+    /// in which case `Scene::move_object` wraps it in a `Transform`.
+    fn translate(&mut self, _offset: Vector3) -> bool {
+        false
+    }
+
+    /// Records the intermediate values computed by `intersects`.
+    /// Geometries that don't support introspection return an empty map.
+    #[cfg(feature = "debug_api")]
+    fn debug_intersect(&self, _ray: &Ray) -> DebugIntersectionResult {
+        DebugIntersectionResult::new()
     }
-    return pixel;
 }
 
-pub fn raytrace(scene: &Scene, width: usize, height: usize) -> Vec<Vector3> {
-    let mut pixels: Vec<Vector3> = vec![v3!(0.0, 0.0, 0.0); width*height];
-    for y in 0..height {
-        for x in 0..width {
-            let u = (x as f64) * 2.0 / (width as f64) - 1.0;
-            let v = (y as f64) * 2.0 / (height as f64) - 1.0;
-            let camera = &scene.camera;
-            let forward = camera.right.cross(&camera.up).normalize();
-            let pos =
-                camera.pos
-                + forward*camera.dist
-                + camera.right*u
-                + camera.up*v;
-            let ray_dir: Vector3 = (pos-camera.pos).normalize();
-            let ray = Ray { origin: camera.pos.clone(), dir: ray_dir };
-            pixels[(height-1-y)*width+x] = shade_pixel(&scene, &ray, 3);
+impl Scene {
+    pub fn add<T: Geometry + 'static>(&mut self, g: T) {
+        self.objects.push(Box::new(g));
+    }
+
+    /// Places the shared geometry of `handle` in the scene with `matrix`.
+    /// Pass an `Arc<dyn Geometry>` the first time and the returned handle
+    /// for further copies. Panics if `matrix` isn't invertible.
+    pub fn add_instance<H: Into<InstanceHandle>>(&mut self, handle: H, matrix: Matrix4) -> InstanceHandle {
+        let handle = handle.into();
+        self.add(Instance::new(&handle, matrix));
+        handle
+    }
+
+    /// Loads a Wavefront OBJ file and adds its triangles to the scene. The
+    /// materials of its `mtllib` files are registered under their names,
+    /// except where the scene already has a material of that name, and the
+    /// faces after a `usemtl` line share the registered material, so
+    /// meshes can also use materials from a scene file. Other faces get
+    /// `material`.
+    pub fn add_mesh(&mut self, path: &str, material: Material) -> Result<(), ObjError> {
+        // Sorted, so that the ids don't depend on the hash map's order.
+        let mut library: Vec<(String, Material)> =
+            load_obj_materials(path)?.materials.into_iter().collect();
+        library.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, m) in library {
+            if self.material_id(&name).is_none() {
+                self.register_material(&name, m);
+            }
         }
+        let materials = self.materials.iter().enumerate()
+            .map(|(i, m)| (m.0.clone(), MaterialId(i).into()))
+            .collect();
+        for t in load_obj_with_materials(path, material.into(), &materials, false)? {
+            self.add(t);
+        }
+        Ok(())
     }
-    pixels
-}
 
-pub fn scene() -> Scene {
-    let cam = Camera {
-        pos: v3!(0.0, 0.0, -1.0),
-        up: v3!(0.0, 1.0, 0.0),
-        right: v3!(1.33, 0.0, 0.0),
-        dist: 2.0,
-    };
-    Scene { camera: cam, lights: vec![], objects: vec![] }
-}
+    /// Reads a scene saved with `to_json`. The BVH isn't built.
+    pub fn from_json(s: &str) -> Result<Scene, serde_json::Error> {
+        serde_json::from_str::<SceneFile>(s).map(SceneFile::into_scene)
+    }
 
+    /// Reads a scene in the same format as `from_json`, written as TOML.
+    /// Objects and lights are arrays of tables, `[[objects]]` and
+    /// `[[lights]]`, each with a `type` key. The BVH isn't built.
+    pub fn from_toml(s: &str) -> Result<Scene, toml::de::Error> {
+        toml::from_str::<SceneFile>(s).map(SceneFile::into_scene)
+    }
 
-pub fn basic_material(color: Vector3) -> Material {
-    Material { shininess: 16.0,
-               spec_color: v3!(1.0, 1.0, 1.0),
-               color: color,
-               reflection: 0.0  }
+    /// Saves the scene as JSON. Textures, custom and image backgrounds, and
+    /// objects or lights without a `to_desc` are left out.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&SceneFile::from_scene(self))
+            .expect("scene descriptions always serialize")
+    }
+
+    /// Every object in the scene, whether or not it's in the BVH or grid.
+    pub fn all_objects(&self) -> impl Iterator<Item = &dyn Geometry> {
+        let accel_objects = self.accel.as_ref().map_or(&[][..], |accel| accel.objects());
+        self.objects.iter().chain(accel_objects).map(|o| o.as_ref())
+    }
+
+    /// Closest hit along `ray`, as the renderer sees it.
+    pub fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        cast_ray(self, ray)
+    }
+
+    /// Indices in `all_objects` order of the objects whose bounding boxes
+    /// overlap the sphere of `radius` around `center`, in increasing order.
+    /// Unbounded objects such as planes are always included. Objects in
+    /// an up to date acceleration structure are found through it, the
+    /// rest by testing each one.
+    pub fn objects_within_radius(&self, center: Vector3, radius: f64) -> Vec<usize> {
+        let overlaps = |o: &dyn Geometry| {
+            o.bounding_box().is_none_or(|b| b.overlaps_sphere(center, radius))
+        };
+        let mut found: Vec<usize> = self.objects.iter().enumerate()
+            .filter(|&(_, o)| overlaps(o.as_ref()))
+            .map(|(i, _)| i)
+            .collect();
+        let offset = self.objects.len();
+        match self.accel {
+            Some(ref accel) if self.accel_generation == self.generation => found.extend(
+                accel.objects_within_radius(center, radius).into_iter().map(|i| offset + i)),
+            Some(ref accel) => found.extend(
+                accel.objects().iter().enumerate()
+                    .filter(|&(_, o)| overlaps(o.as_ref()))
+                    .map(|(i, _)| offset + i)),
+            None => {},
+        }
+        found
+    }
+
+    /// Looks for common setup mistakes. The warnings are advisory: the
+    /// scene still renders, just probably not as intended.
+    pub fn validate(&self) -> Vec<SceneWarning> {
+        let mut warnings = Vec::new();
+        let objects: Vec<&dyn Geometry> = self.all_objects().collect();
+        if objects.is_empty() {
+            warnings.push(SceneWarning::NoObjects);
+        }
+        let materials: Vec<Material> = objects.iter().map(|o| self.object_material(*o)).collect();
+        let glowing = materials.iter().any(|m| m.emission.max_component() > 0.0);
+        if self.lights.is_empty() && !glowing {
+            warnings.push(SceneWarning::NoLights);
+        }
+        for (i, light) in self.lights.iter().enumerate() {
+            let dark = match light.to_desc() {
+                Some(LightDesc::Light(l)) => l.color.max_component() <= 0.0 || l.intensity <= 0.0,
+                Some(LightDesc::DirectionalLight(l)) => l.color.max_component() <= 0.0,
+                Some(LightDesc::Spotlight(l)) => l.color.max_component() <= 0.0,
+                Some(LightDesc::AreaLight(l)) => l.color.max_component() <= 0.0 || l.samples == 0,
+                None => false,
+            };
+            if dark {
+                warnings.push(SceneWarning::LightWithZeroColor(i));
+            }
+        }
+        for (i, object) in objects.iter().enumerate() {
+            let negative = match object.to_desc() {
+                Some(ObjectDesc::Sphere(o)) => o.radius < 0.0,
+                Some(ObjectDesc::Disk(o)) => o.radius < 0.0,
+                Some(ObjectDesc::Cylinder(o)) => o.radius < 0.0,
+                Some(ObjectDesc::Capsule(o)) => o.radius < 0.0,
+                Some(ObjectDesc::Torus(o)) => o.major_radius < 0.0 || o.minor_radius < 0.0,
+                _ => false,
+            };
+            if negative {
+                warnings.push(SceneWarning::NegativeRadius(i));
+            }
+            if camera_inside(*object, &materials[i], self.camera.pos) {
+                warnings.push(SceneWarning::CameraInsideGeometry(i));
+            }
+        }
+        if !materials.is_empty() && materials.iter().all(|m| m.reflection >= 1.0) {
+            warnings.push(SceneWarning::OnlyMirrors);
+        }
+        warnings
+    }
+
+    /// Adds a material that objects can share by holding its id as a
+    /// `MaterialRef::Named`. Registering a name again replaces that
+    /// material, for every object using it, and returns the same id.
+    pub fn register_material(&mut self, name: &str, material: Material) -> MaterialId {
+        match self.material_id(name) {
+            Some(id) => {
+                self.materials[id.0].1 = material;
+                id
+            },
+            None => {
+                self.materials.push((name.to_string(), material));
+                MaterialId(self.materials.len() - 1)
+            },
+        }
+    }
+
+    /// Panics if `id` wasn't returned by this scene's `register_material`.
+    pub fn get_material(&self, id: MaterialId) -> &Material {
+        &self.materials[id.0].1
+    }
+
+    /// The material `object` is shaded with: its registered material if it
+    /// has one, otherwise its own.
+    pub fn object_material(&self, object: &dyn Geometry) -> Material {
+        match object.material_id() {
+            Some(id) => self.get_material(id).clone(),
+            None => object.material(),
+        }
+    }
+
+    pub fn material_id(&self, name: &str) -> Option<MaterialId> {
+        self.materials.iter().position(|m| m.0 == name).map(MaterialId)
+    }
+
+    /// Registered materials as `(name, material)`, in `MaterialId` order.
+    pub fn materials(&self) -> &[(String, Material)] {
+        &self.materials
+    }
+
+    pub fn tone_mapper(&self) -> ToneMapper {
+        self.tone_mapper
+    }
+
+    pub fn add_light<T: LightSource + 'static>(&mut self, l: T) {
+        self.lights.push(Box::new(l));
+    }
+
+    /// Sets how rendered colors are brought into [0, 1]. Defaults to
+    /// `ToneMapper::Clamp`.
+    pub fn set_tone_mapper(&mut self, tone_mapper: ToneMapper) {
+        self.tone_mapper = tone_mapper;
+    }
+
+    /// Builds a bounding volume hierarchy over all bounded objects so that
+    /// `cast_ray` can skip whole groups of them. Objects added afterwards
+    /// are still rendered, but are only put in the BVH by rebuilding it.
+    pub fn build_bvh(&mut self) {
+        self.build_acceleration(AccelerationStructure::Bvh);
+    }
+
+    /// Like `build_bvh`, but puts the bounded objects in a `UniformGrid`,
+    /// which suits objects spread evenly through the scene better.
+    pub fn build_grid(&mut self) {
+        self.build_acceleration(AccelerationStructure::Grid);
+    }
+
+    /// Puts all bounded objects in an acceleration structure of the given
+    /// kind, replacing any the scene already has.
+    pub fn build_acceleration(&mut self, kind: AccelerationStructure) {
+        let (bounded, unbounded): (Vec<_>, Vec<_>) =
+            self.take_objects().into_iter().partition(|o| o.bounding_box().is_some());
+        self.objects = unbounded;
+        self.accel = Some(kind.build(bounded));
+        self.accel_generation = self.generation;
+    }
+
+    /// Records that objects in the BVH or grid have moved, so it has to be
+    /// rebuilt with `rebuild_acceleration`. Until then rays test its
+    /// objects one by one rather than missing the moved ones, which is
+    /// correct but slow.
+    pub fn mark_dirty(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Rebuilds the BVH or grid, whichever the scene has, over the current
+    /// objects.
+    pub fn rebuild_acceleration(&mut self) {
+        if let Some(kind) = self.accel.as_ref().map(|accel| accel.kind()) {
+            self.build_acceleration(kind);
+        }
+    }
+
+    /// Moves object `id`, counted in `all_objects` order, so that the center
+    /// of its bounding box is at `new_pos`, and marks the scene dirty.
+    /// Moving an object again composes into the same `Transform`.
+    /// Panics if the object has no bounding box.
+    pub fn move_object(&mut self, id: usize, new_pos: Vector3) {
+        let move_to = |mut object: Box<dyn Geometry>| -> Box<dyn Geometry> {
+            let bounds = object.bounding_box().expect("only bounded objects can be moved");
+            let offset = new_pos - bounds.centroid();
+            if object.translate(offset) {
+                object
+            } else {
+                Box::new(Transform::new(Matrix4::translate(offset), object))
+            }
+        };
+        let count = self.objects.len();
+        if id < count {
+            let object = self.objects.swap_remove(id);
+            self.objects.push(move_to(object));
+            self.objects.swap(id, count - 1);
+        } else {
+            self.accel.as_mut().expect("object id out of range").replace_object(id - count, move_to);
+        }
+        self.mark_dirty();
+    }
+
+    /// Removes every object from the scene, including those in the BVH or
+    /// grid.
+    /// Switches to `ShadowMode::ShadowMap`, with a map of `resolution` by
+    /// `resolution` texels for each directional light. Other lights keep
+    /// casting shadow rays. The maps have to be built again after the
+    /// scene changes.
+    pub fn build_shadow_maps(&mut self, resolution: usize) {
+        let maps = self.lights.iter().map(|light| match light.to_desc() {
+            Some(LightDesc::DirectionalLight(ref light)) => Some(ShadowMap::build(self, light, resolution)),
+            _ => None,
+        }).collect();
+        self.shadowing_mode = ShadowMode::ShadowMap(maps);
+    }
+
+    /// Takes the geometry out of the scene graph under `root`, with the
+    /// transforms of its nodes applied, for the renderer to intersect.
+    pub fn build_flat_objects(&mut self) -> Vec<Box<dyn Geometry>> {
+        let root = std::mem::take(&mut self.root);
+        root.flatten(&Matrix4::identity()).into_iter()
+            .map(|instance| Box::new(instance) as Box<dyn Geometry>)
+            .collect()
+    }
+
+    fn take_objects(&mut self) -> Vec<Box<dyn Geometry>> {
+        let mut objects: Vec<Box<dyn Geometry>> = self.objects.drain(..).collect();
+        objects.extend(self.build_flat_objects());
+        if let Some(accel) = self.accel.take() {
+            objects.extend(accel.into_objects());
+        }
+        objects
+    }
+}
+
+/// Whether `pos` is inside the closed `object`, shaded with `material`,
+/// and the object is opaque: `pos` is within its bounds and enclosed by it
+/// along all six axis directions.
+fn camera_inside(object: &dyn Geometry, material: &Material, pos: Vector3) -> bool {
+    if material.transparency > 0.0
+        || !object.bounding_box().is_some_and(|b| b.contains(pos)) {
+        return false;
+    }
+    (0..6).all(|i| {
+        let mut dir = v3!(0.0, 0.0, 0.0);
+        dir[i / 2] = if i % 2 == 0 { 1.0 } else { -1.0 };
+        object.intersects(&Ray { origin: pos, dir, t_max: f64::INFINITY }).is_some()
+    })
+}
+
+/// Likely mistake in a scene, found by `Scene::validate`. Objects are
+/// numbered in `Scene::all_objects` order and lights in `Scene::lights`
+/// order.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SceneWarning {
+    NoObjects,
+    /// Nothing is lit but the ambient term: there are no lights and no
+    /// emissive objects.
+    NoLights,
+    /// The camera is inside this opaque object, so it can only see the
+    /// object's inside.
+    CameraInsideGeometry(usize),
+    /// This light is black, or has no intensity or samples, so it lights
+    /// nothing.
+    LightWithZeroColor(usize),
+    NegativeRadius(usize),
+    /// Every object is a perfect mirror, so nothing is shaded directly and
+    /// rays bounce between them until `RenderConfig::max_depth`.
+    OnlyMirrors,
+}
+
+impl fmt::Display for SceneWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneWarning::NoObjects => write!(f, "the scene has no objects"),
+            SceneWarning::NoLights => write!(f, "the scene has no lights or emissive objects"),
+            SceneWarning::CameraInsideGeometry(i) => write!(f, "the camera is inside object {}", i),
+            SceneWarning::LightWithZeroColor(i) => write!(f, "light {} gives off no light", i),
+            SceneWarning::NegativeRadius(i) => write!(f, "object {} has a negative radius", i),
+            SceneWarning::OnlyMirrors => write!(f, "every object is a perfect mirror"),
+        }
+    }
+}
+
+/// One sample towards the point `light_pos`.
+fn point_sample(pos: Vector3, light_pos: Vector3, color: Vector3, intensity: f64) -> LightSample {
+    let to_light = light_pos - pos;
+    LightSample { dir: to_light.normalize(),
+                  dist: to_light.length(),
+                  color,
+                  intensity }
+}
+
+impl LightSource for Light {
+    fn sample(&self, pos: Vector3, _rng: &mut Rng) -> Vec<LightSample> {
+        let sample = point_sample(pos, self.pos, self.color, 1.0);
+        let (a, b, c) = self.attenuation;
+        let d = sample.dist;
+        // Clamped so that nearby lights can't push the surface past white.
+        let intensity = (self.intensity / (a + b*d + c*d*d)).min(1.0);
+        vec![LightSample { intensity, ..sample }]
+    }
+
+    fn to_desc(&self) -> Option<LightDesc> {
+        Some(LightDesc::Light(self.clone()))
+    }
+
+    fn emits_photons(&self) -> bool {
+        true
+    }
+
+    /// Photons fall off with the square of the distance, whatever the
+    /// light's `attenuation`.
+    fn emit_photon(&self, rng: &mut Rng) -> Option<(Ray, Vector3)> {
+        let dir = uniform_sphere_sample(rng.next_f64(), rng.next_f64());
+        Some((Ray { origin: self.pos, dir, t_max: f64::INFINITY },
+              self.color * (self.intensity * 4.0 * std::f64::consts::PI)))
+    }
+}
+
+impl Light {
+    /// Light at `pos` with the color of a black body at `temperature`
+    /// kelvin, as given by `Vector3::from_kelvin`, and no attenuation.
+    pub fn from_kelvin(pos: Vector3, temperature: f64, intensity: f64) -> Light {
+        Light { pos, color: Vector3::from_kelvin(temperature), intensity, attenuation: (1.0, 0.0, 0.0) }
+    }
+}
+
+impl LightSource for DirectionalLight {
+    fn sample(&self, _pos: Vector3, _rng: &mut Rng) -> Vec<LightSample> {
+        vec![LightSample { dir: -self.dir.normalize(),
+                           dist: f64::INFINITY,
+                           color: self.color,
+                           intensity: 1.0 }]
+    }
+
+    fn to_desc(&self) -> Option<LightDesc> {
+        Some(LightDesc::DirectionalLight(self.clone()))
+    }
+}
+
+impl LightSource for Spotlight {
+    fn sample(&self, pos: Vector3, _rng: &mut Rng) -> Vec<LightSample> {
+        let sample = point_sample(pos, self.pos, self.color, 1.0);
+        let angle = (-sample.dir).dot(self.dir.normalize()).clamp(-1.0, 1.0).acos();
+        let intensity = smoothstep_f64(self.outer_angle, self.inner_angle, angle);
+        if intensity > 0.0 {
+            vec![LightSample { intensity, ..sample }]
+        } else {
+            vec![]
+        }
+    }
+
+    fn to_desc(&self) -> Option<LightDesc> {
+        Some(LightDesc::Spotlight(self.clone()))
+    }
+
+    fn emits_photons(&self) -> bool {
+        true
+    }
+
+    fn emit_photon(&self, rng: &mut Rng) -> Option<(Ray, Vector3)> {
+        let cos_max = self.outer_angle.cos();
+        let local = uniform_cone_sample(rng.next_f64(), rng.next_f64(), cos_max);
+        let angle = local.z.clamp(-1.0, 1.0).acos();
+        let intensity = smoothstep_f64(self.outer_angle, self.inner_angle, angle);
+        let solid_angle = 2.0 * std::f64::consts::PI * (1.0 - cos_max);
+        let dir = local_to_world(local, self.dir.normalize());
+        Some((Ray { origin: self.pos, dir, t_max: f64::INFINITY },
+              self.color * (intensity * solid_angle)))
+    }
+}
+
+impl LightSource for AreaLight {
+    fn sample(&self, pos: Vector3, rng: &mut Rng) -> Vec<LightSample> {
+        let weight = 1.0 / self.samples.max(1) as f64;
+        self.sample_points(rng).into_iter()
+            .map(|(su, sv)| point_sample(pos, self.pos + self.u*su + self.v*sv, self.color, weight))
+            .collect()
+    }
+
+    fn to_desc(&self) -> Option<LightDesc> {
+        Some(LightDesc::AreaLight(self.clone()))
+    }
+
+    fn emits_photons(&self) -> bool {
+        true
+    }
+
+    /// The rectangle shines from both sides, like its `sample`s.
+    fn emit_photon(&self, rng: &mut Rng) -> Option<(Ray, Vector3)> {
+        let origin = self.pos + self.u*rng.next_f64() + self.v*rng.next_f64();
+        let side = if rng.next_f64() < 0.5 { 1.0 } else { -1.0 };
+        let normal = self.u.cross(&self.v);
+        let area = normal.length();
+        let dir = local_to_world(cosine_hemisphere_sample(rng.next_f64(), rng.next_f64()),
+                                 normal.normalize() * side);
+        Some((Ray { origin, dir, t_max: f64::INFINITY },
+              self.color * (area * 2.0 * std::f64::consts::PI)))
+    }
+}
+
+impl AreaLight {
+    /// Stratified sample positions on the light as (u, v) in [0, 1)^2. The
+    /// largest square grid that fits in `samples` gets one jittered sample
+    /// per cell, any remaining samples are uniformly random.
+    fn sample_points(&self, rng: &mut Rng) -> Vec<(f64, f64)> {
+        let grid = (self.samples as f64).sqrt().floor() as usize;
+        let mut points = Vec::with_capacity(self.samples);
+        for i in 0..self.samples {
+            if i < grid*grid {
+                let (cx, cy) = ((i % grid) as f64, (i / grid) as f64);
+                points.push(((cx + rng.next_f64()) / grid as f64,
+                             (cy + rng.next_f64()) / grid as f64));
+            } else {
+                points.push((rng.next_f64(), rng.next_f64()));
+            }
+        }
+        points
+    }
+}
+
+impl BBox {
+    /// Slab test: narrows down the part of `ray` inside the box one axis
+    /// at a time, and returns `(tnear, n, tfar, n_exit)`, where the ray
+    /// enters the box at `tnear` through the face with normal `n` and
+    /// leaves it at `tfar` through `n_exit`. The ray misses if `tnear >
+    /// tfar`. `visit` is called after each axis with
+    /// `(axis, t1, t2, tnear, tfar, n)`, where `t1` and `t2` are that
+    /// axis' own slab.
+    fn slabs(&self, ray: &Ray, mut visit: impl FnMut(usize, f64, f64, f64, f64, Vector3))
+             -> (f64, Vector3, f64, Vector3) {
+        let mut tnear = -f64::INFINITY;
+        let mut tfar = f64::INFINITY;
+        let mut n = v3!(1.0, 0.0, 0.0);
+        let mut n_exit = v3!(-1.0, 0.0, 0.0);
+        for axis in 0..3 {
+            let mut t1 = (self.v1[axis] - ray.origin[axis]) / ray.dir[axis];
+            let mut t2 = (self.v2[axis] - ray.origin[axis]) / ray.dir[axis];
+            if t1 > t2 { std::mem::swap(&mut t1, &mut t2); }
+            // The entered face faces against the ray.
+            let mut face = v3!(0.0, 0.0, 0.0);
+            face[axis] = -ray.dir[axis].signum();
+            if t1 > tnear { tnear = t1; n = face; }
+            if t2 < tfar { tfar = t2; n_exit = face; }
+            visit(axis, t1, t2, tnear, tfar, n);
+        }
+        (tnear, n, tfar, n_exit)
+    }
+
+    /// Texture coordinates of `pos` on the face with normal `n`, each face
+    /// covering [0, 1] x [0, 1], and the face's u direction. Faces across x
+    /// run u along z, the others along x; v runs along y, or z on the top
+    /// and bottom.
+    fn face_uv(&self, pos: Vector3, n: Vector3) -> ((f64, f64), Vector3) {
+        let b = Aabb::from_points(self.v1, self.v2);
+        let size = b.max - b.min;
+        let rel = pos - b.min;
+        if n.x != 0.0 {
+            ((rel.z / size.z, rel.y / size.y), v3!(0.0, 0.0, 1.0))
+        } else if n.y != 0.0 {
+            ((rel.x / size.x, rel.z / size.z), v3!(1.0, 0.0, 0.0))
+        } else {
+            ((rel.x / size.x, rel.y / size.y), v3!(1.0, 0.0, 0.0))
+        }
+    }
+}
+
+impl Geometry for BBox {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::BBox(self.clone()))
+    }
+    fn at_time(&self, t: f64) -> Option<Box<dyn Geometry>> {
+        self.motion.map(|m| Box::new(BBox { v1: self.v1 + m*t,
+                                             v2: self.v2 + m*t,
+                                             ..self.clone() }) as Box<dyn Geometry>)
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(self.v1, self.v2))
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let (mut tnear, mut n, tfar, n_exit) = self.slabs(ray, |_, _, _, _, _, _| {});
+        if tnear > tfar || tfar < 0.0 {
+            return None;
+        }
+
+        // The ray starts inside the box, so the first hit is on the exit
+        // face. Its normal points back into the box, towards the origin.
+        if tnear < 0.0 {
+            tnear = tfar;
+            n = n_exit;
+        }
+
+        let pos = ray.origin + ray.dir * tnear;
+        let (uv, tangent) = self.face_uv(pos, n);
+        Some(Intersection { pos,
+                            normal: n,
+                            dist: tnear,
+                            material: self.material(),
+                            material_id: self.material.id(),
+                            barycentric: None,
+                            uv,
+                            tangent: Some(tangent),
+                            object_pos: None })
+    }
+
+    #[cfg(feature = "debug_api")]
+    fn debug_intersect(&self, ray: &Ray) -> DebugIntersectionResult {
+        let keys = [["x_t1", "x_t2", "x_tnear", "x_tfar", "x_nx", "x_ny", "x_nz"],
+                    ["y_t1", "y_t2", "y_tnear", "y_tfar", "y_nx", "y_ny", "y_nz"],
+                    ["z_t1", "z_t2", "z_tnear", "z_tfar", "z_nx", "z_ny", "z_nz"]];
+        let mut values = DebugIntersectionResult::new();
+        self.slabs(ray, |axis, t1, t2, tnear, tfar, n| {
+            for (key, value) in keys[axis].iter().zip([t1, t2, tnear, tfar, n.x, n.y, n.z]) {
+                values.insert(key, value);
+            }
+        });
+        values
+    }
+}
+
+impl Sphere {
+    /// Longitude and latitude of the unit normal `n`, both in [0, 1], with
+    /// v = 0 at the top.
+    pub fn uv(n: Vector3) -> (f64, f64) {
+        use std::f64::consts::PI;
+        ((n.z.atan2(n.x) + PI) / (2.0*PI), n.y.clamp(-1.0, 1.0).acos() / PI)
+    }
+
+    /// Direction of growing longitude at the unit normal `n`. At the poles,
+    /// where it is undefined, any horizontal direction will do.
+    fn tangent(n: Vector3) -> Vector3 {
+        let t = v3!(-n.z, 0.0, n.x);
+        if t.length_squared() < 1e-12 { v3!(1.0, 0.0, 0.0) } else { t.normalize() }
+    }
+}
+
+impl Geometry for Sphere {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::Sphere(self.clone()))
+    }
+    fn at_time(&self, t: f64) -> Option<Box<dyn Geometry>> {
+        self.motion.map(|m| Box::new(Sphere { pos: self.pos + m*t, ..self.clone() }) as Box<dyn Geometry>)
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = v3!(self.radius, self.radius, self.radius);
+        Some(Aabb { min: self.pos - r, max: self.pos + r })
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let l = self.pos - ray.origin;
+        let tca = l.dot(ray.dir);
+        let radius2 = self.radius*self.radius;
+        // Pointing away from the sphere, unless starting inside it.
+        if tca < 0.0 && l.dot(l) > radius2 {
+            return None;
+        }
+        // Rounding can take this below zero for rays through the center.
+        let d2 = (l.dot(l)-tca*tca).max(0.0);
+        if d2 > radius2 {
+            return None;
+        }
+        let thc = (radius2 - d2).sqrt();
+        let t0 = tca - thc;
+        let t1 = tca + thc;
+        if t0 < 0.0 && t1 < 0.0 { return None; }
+        let t = if t0 < 0.0 { t1} else { t0.min(t1) };
+
+        let p = ray.origin+ray.dir*t;
+        let n = (p-self.pos).normalize();
+        Some (Intersection {pos: p, normal: n, dist: t, material: self.material(),
+                            material_id: self.material.id(),
+                            barycentric: None,
+                            uv: Sphere::uv(n),
+                            tangent: Some(Sphere::tangent(n)),
+                            object_pos: None })
+    }
+
+    fn sample_surface(&self, u1: f64, u2: f64) -> (Vector3, Vector3, f64) {
+        let z = 1.0 - 2.0*u1;
+        let r = (1.0 - z*z).max(0.0).sqrt();
+        let phi = 2.0*std::f64::consts::PI*u2;
+        let n = v3!(r*phi.cos(), r*phi.sin(), z);
+        let area = 4.0*std::f64::consts::PI*self.radius*self.radius;
+        (self.pos + n*self.radius, n, 1.0 / area)
+    }
+
+    fn pdf_surface(&self, from: Vector3, to: Vector3) -> f64 {
+        let d = from - to;
+        let dist2 = d.dot(d);
+        let n = (to - self.pos).normalize();
+        let cos_theta = n.dot(d.normalize()).abs();
+        let area = 4.0*std::f64::consts::PI*self.radius*self.radius;
+        dist2 / (cos_theta * area)
+    }
+
+    #[cfg(feature = "debug_api")]
+    fn debug_intersect(&self, ray: &Ray) -> DebugIntersectionResult {
+        let mut values = DebugIntersectionResult::new();
+        let l = self.pos - ray.origin;
+        let tca = l.dot(ray.dir);
+        let d = (l.dot(l)-tca*tca).sqrt();
+        let thc = (self.radius*self.radius - d*d).sqrt();
+        values.insert("l_x", l.x);
+        values.insert("l_y", l.y);
+        values.insert("l_z", l.z);
+        values.insert("tca", tca);
+        values.insert("d", d);
+        values.insert("thc", thc);
+        values.insert("t0", tca - thc);
+        values.insert("t1", tca + thc);
+        values
+    }
+}
+
+impl Geometry for Plane {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::Plane(self.clone()))
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let t = (self.pos - ray.origin).dot(self.normal)/(ray.dir.dot(self.normal));
+        if t > 0.0 {
+            let p = ray.origin+ray.dir*t;
+            let mut material = self.material();
+            if material.texture.is_none() {
+                let checkerboard = CheckerboardTexture { scale: 2.0,
+                                                         color_a: v3!(0.0, 0.0, 0.0),
+                                                         color_b: material.color };
+                material.color = checkerboard.sample(p, self.normal, (p.x, p.z));
+            }
+            // The world x axis projected onto the plane, or z if the plane
+            // faces along x.
+            let n = self.normal.normalize();
+            let axis = if n.x.abs() > 0.999 { v3!(0.0, 0.0, 1.0) } else { v3!(1.0, 0.0, 0.0) };
+            Some(Intersection {
+                dist: t,
+                normal: self.normal,
+                pos: p,
+                material,
+                material_id: self.material.id(),
+                barycentric: None,
+                uv: (p.x * self.uv_scale, p.z * self.uv_scale),
+                tangent: Some((axis - n*n.dot(axis)).normalize()),
+                object_pos: None,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl Geometry for Disk {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::Disk(self.clone()))
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let n = self.normal.normalize();
+        let e = v3!((1.0 - n.x*n.x).max(0.0).sqrt(),
+                    (1.0 - n.y*n.y).max(0.0).sqrt(),
+                    (1.0 - n.z*n.z).max(0.0).sqrt()) * self.radius;
+        Some(Aabb { min: self.center - e, max: self.center + e })
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let t = (self.center - ray.origin).dot(self.normal)/(ray.dir.dot(self.normal));
+        if t <= 0.0 {
+            return None;
+        }
+        let p = ray.origin+ray.dir*t;
+        let d = p - self.center;
+        if d.length_squared() > self.radius*self.radius {
+            return None;
+        }
+        Some(Intersection {
+            dist: t,
+            normal: self.normal,
+            pos: p,
+            material: self.material(),
+            material_id: self.material.id(),
+            barycentric: None,
+            uv: (0.0, 0.0),
+            tangent: None,
+            object_pos: None,
+        })
+    }
+    fn sample_surface(&self, u1: f64, u2: f64) -> (Vector3, Vector3, f64) {
+        // The square root spreads the samples evenly over the area.
+        let r = self.radius * u1.sqrt();
+        let phi = 2.0*std::f64::consts::PI*u2;
+        let n = self.normal.normalize();
+        let dir = local_to_world(v3!(phi.cos(), phi.sin(), 0.0), n);
+        let area = std::f64::consts::PI*self.radius*self.radius;
+        (self.center + dir*r, n, 1.0 / area)
+    }
+
+    fn pdf_surface(&self, from: Vector3, to: Vector3) -> f64 {
+        let d = from - to;
+        let dist2 = d.dot(d);
+        let cos_theta = self.normal.normalize().dot(d.normalize()).abs();
+        let area = std::f64::consts::PI*self.radius*self.radius;
+        dist2 / (cos_theta * area)
+    }
+}
+
+impl Geometry for BoundedPlane {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::BoundedPlane(self.clone()))
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(self.min, self.max))
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let t = (self.pos - ray.origin).dot(self.normal)/(ray.dir.dot(self.normal));
+        if t <= 0.0 {
+            return None;
+        }
+        let p = ray.origin+ray.dir*t;
+        // Slack for planes lying in a face of the box, where p is only
+        // approximately on it.
+        const EPS: f64 = 1e-9;
+        let b = Aabb::from_points(self.min, self.max);
+        if p.x < b.min.x - EPS || p.y < b.min.y - EPS || p.z < b.min.z - EPS
+            || p.x > b.max.x + EPS || p.y > b.max.y + EPS || p.z > b.max.z + EPS {
+            return None;
+        }
+
+        // u and v run from 0 to 1 across the box along the two axes the
+        // normal points least along.
+        let n = self.normal.normalize();
+        let (a, c, axis) = if n.x.abs() >= n.y.abs() && n.x.abs() >= n.z.abs() {
+            ((p.y, b.min.y, b.max.y), (p.z, b.min.z, b.max.z), v3!(0.0, 1.0, 0.0))
+        } else if n.y.abs() >= n.z.abs() {
+            ((p.x, b.min.x, b.max.x), (p.z, b.min.z, b.max.z), v3!(1.0, 0.0, 0.0))
+        } else {
+            ((p.x, b.min.x, b.max.x), (p.y, b.min.y, b.max.y), v3!(1.0, 0.0, 0.0))
+        };
+        let fraction = |(x, lo, hi): (f64, f64, f64)| {
+            if hi > lo { (x - lo) / (hi - lo) } else { 0.0 }
+        };
+        Some(Intersection {
+            dist: t,
+            normal: self.normal,
+            pos: p,
+            material: self.material(),
+            material_id: self.material.id(),
+            barycentric: None,
+            uv: (fraction(a), fraction(c)),
+            tangent: Some((axis - n*n.dot(axis)).normalize()),
+            object_pos: None,
+        })
+    }
+    fn sample_surface(&self, u1: f64, u2: f64) -> (Vector3, Vector3, f64) {
+        // Uniform across the box along the two axes the normal points
+        // least along, then onto the plane along the third, as in
+        // `intersects`.
+        let b = Aabb::from_points(self.min, self.max);
+        let (n, e) = (self.normal.normalize(), b.max - b.min);
+        let (p, major) = if n.x.abs() >= n.y.abs() && n.x.abs() >= n.z.abs() {
+            (v3!(b.min.x, b.min.y + e.y*u1, b.min.z + e.z*u2), v3!(1.0, 0.0, 0.0))
+        } else if n.y.abs() >= n.z.abs() {
+            (v3!(b.min.x + e.x*u1, b.min.y, b.min.z + e.z*u2), v3!(0.0, 1.0, 0.0))
+        } else {
+            (v3!(b.min.x + e.x*u1, b.min.y + e.y*u2, b.min.z), v3!(0.0, 0.0, 1.0))
+        };
+        let p = p + major*((self.pos - p).dot(n) / major.dot(n));
+        (p, n, 1.0 / self.area())
+    }
+
+    fn pdf_surface(&self, from: Vector3, to: Vector3) -> f64 {
+        let d = from - to;
+        let dist2 = d.dot(d);
+        let cos_theta = self.normal.normalize().dot(d.normalize()).abs();
+        dist2 / (cos_theta * self.area())
+    }
+}
+
+impl BoundedPlane {
+    /// Area of the rectangle `intersects` accepts hits on, assuming the
+    /// plane crosses the box only through the faces along its normal.
+    fn area(&self) -> f64 {
+        let n = self.normal.normalize().abs();
+        let e = (self.max - self.min).abs();
+        if n.x >= n.y && n.x >= n.z {
+            e.y*e.z / n.x
+        } else if n.y >= n.z {
+            e.x*e.z / n.y
+        } else {
+            e.x*e.y / n.z
+        }
+    }
+}
+
+impl Triangle {
+    pub fn new(v0: Vector3, v1: Vector3, v2: Vector3,
+               material: impl Into<MaterialRef>) -> Triangle {
+        let normal = (v1 - v0).cross(&(v2 - v0)).normalize();
+        Triangle { v0, v1, v2, material: material.into(),
+                   vertex_normals: [None; 3], vertex_uvs: [None; 3], normal }
+    }
+
+    /// Face normal.
+    pub fn normal(&self) -> Vector3 {
+        self.normal
+    }
+
+    /// Shading normal at barycentric coordinates `(u, v)`.
+    fn shading_normal(&self, u: f64, v: f64) -> Vector3 {
+        match self.vertex_normals {
+            [Some(n0), Some(n1), Some(n2)] => (n0*(1.0 - u - v) + n1*u + n2*v).normalize(),
+            _ => self.normal,
+        }
+    }
+
+    /// Texture coordinates at barycentric coordinates `(u, v)`.
+    fn uv(&self, u: f64, v: f64) -> (f64, f64) {
+        match self.vertex_uvs {
+            [Some(t0), Some(t1), Some(t2)] =>
+                (t0.0*(1.0 - u - v) + t1.0*u + t2.0*v, t0.1*(1.0 - u - v) + t1.1*u + t2.1*v),
+            _ => (u, v),
+        }
+    }
+
+    /// Direction in which the texture u coordinate grows across the face.
+    fn tangent(&self) -> Vector3 {
+        let e1 = self.v1 - self.v0;
+        let e2 = self.v2 - self.v0;
+        if let [Some(t0), Some(t1), Some(t2)] = self.vertex_uvs {
+            let (du1, dv1) = (t1.0 - t0.0, t1.1 - t0.1);
+            let (du2, dv2) = (t2.0 - t0.0, t2.1 - t0.1);
+            let det = du1*dv2 - du2*dv1;
+            if det.abs() > 1e-12 {
+                return ((e1*dv2 - e2*dv1) * (1.0 / det)).normalize();
+            }
+        }
+        e1.normalize()
+    }
+}
+
+impl TriangleBuilder {
+    pub fn new(v0: Vector3, v1: Vector3, v2: Vector3,
+               material: impl Into<MaterialRef>) -> TriangleBuilder {
+        TriangleBuilder { v0, v1, v2, material: material.into(),
+                          vertex_normals: [None; 3], vertex_uvs: [None; 3] }
+    }
+
+    pub fn vertex_normals(mut self, n0: Vector3, n1: Vector3, n2: Vector3) -> TriangleBuilder {
+        self.vertex_normals = [Some(n0.normalize()), Some(n1.normalize()), Some(n2.normalize())];
+        self
+    }
+
+    pub fn vertex_uvs(mut self, uv0: (f64, f64), uv1: (f64, f64), uv2: (f64, f64)) -> TriangleBuilder {
+        self.vertex_uvs = [Some(uv0), Some(uv1), Some(uv2)];
+        self
+    }
+
+    pub fn build(self) -> Triangle {
+        let mut triangle = Triangle::new(self.v0, self.v1, self.v2, self.material);
+        triangle.vertex_normals = self.vertex_normals;
+        triangle.vertex_uvs = self.vertex_uvs;
+        triangle
+    }
+}
+
+impl Geometry for Triangle {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::Triangle { v0: self.v0,
+                                   v1: self.v1,
+                                   v2: self.v2,
+                                   vertex_normals: self.vertex_normals,
+                                   vertex_uvs: self.vertex_uvs,
+                                   material: self.material.clone() })
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(self.v0, self.v1).merge(&Aabb::from_points(self.v2, self.v2)))
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        // Möller-Trumbore
+        let e1 = self.v1 - self.v0;
+        let e2 = self.v2 - self.v0;
+        let p = ray.dir.cross(&e2);
+        let det = e1.dot(p);
+        // Also rejects degenerate, zero area triangles.
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let s = ray.origin - self.v0;
+        let u = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(&e1);
+        let v = ray.dir.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = e2.dot(q) * inv_det;
+        if t <= 0.0 {
+            return None;
+        }
+        Some(Intersection { pos: ray.origin + ray.dir*t,
+                            normal: self.shading_normal(u, v),
+                            dist: t,
+                            material: self.material(),
+                            material_id: self.material.id(),
+                            barycentric: Some((u, v)),
+                            uv: self.uv(u, v),
+                            tangent: Some(self.tangent()),
+                            object_pos: None })
+    }
+}
+
+impl Geometry for Cylinder {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::Cylinder(self.clone()))
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        // The end disks extend radius * sin(angle between axis and x/y/z).
+        let a = (self.axis_end - self.axis_start).normalize();
+        let e = v3!((1.0 - a.x*a.x).max(0.0).sqrt(),
+                    (1.0 - a.y*a.y).max(0.0).sqrt(),
+                    (1.0 - a.z*a.z).max(0.0).sqrt()) * self.radius;
+        let b = Aabb::from_points(self.axis_start, self.axis_end);
+        Some(Aabb { min: b.min - e, max: b.max + e })
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let ca = self.axis_end - self.axis_start;
+        let height = ca.length();
+        let a = ca * (1.0 / height);
+        let oc = ray.origin - self.axis_start;
+
+        // Infinite cylinder: solve |perp(origin + t*dir)|^2 = r^2, where perp
+        // removes the component along the axis.
+        let d_perp = ray.dir - a*ray.dir.dot(a);
+        let o_perp = oc - a*oc.dot(a);
+        let qa = d_perp.dot(d_perp);
+        let qb = 2.0*d_perp.dot(o_perp);
+        let qc = o_perp.dot(o_perp) - self.radius*self.radius;
+
+        let mut best: Option<(f64, Vector3)> = None;
+        let mut consider = |t: f64, n: Vector3| {
+            if t > 1e-9 && best.is_none_or(|(bt, _)| t < bt) {
+                best = Some((t, n));
+            }
+        };
+
+        let disc = qb*qb - 4.0*qa*qc;
+        if qa > 1e-12 && disc >= 0.0 {
+            let sq = disc.sqrt();
+            for &t in &[(-qb - sq) / (2.0*qa), (-qb + sq) / (2.0*qa)] {
+                // Clip to the finite segment.
+                let s = (oc + ray.dir*t).dot(a);
+                if (0.0..=height).contains(&s) {
+                    let n = (oc + ray.dir*t - a*s) * (1.0 / self.radius);
+                    consider(t, n);
+                }
+            }
+        }
+
+        if self.capped {
+            let denom = ray.dir.dot(a);
+            if denom.abs() > 1e-12 {
+                for &(center, n) in &[(self.axis_start, -a), (self.axis_end, a)] {
+                    let t = (center - ray.origin).dot(a) / denom;
+                    let p = ray.origin + ray.dir*t - center;
+                    if p.dot(p) <= self.radius*self.radius {
+                        consider(t, n);
+                    }
+                }
+            }
+        }
+
+        best.map(|(t, n)| Intersection { pos: ray.origin + ray.dir*t,
+                                         normal: n,
+                                         dist: t,
+                                         material: self.material(),
+                                         material_id: self.material.id(),
+                                         barycentric: None,
+                                         uv: (0.0, 0.0),
+                                         tangent: None,
+                                         object_pos: None })
+    }
+}
+
+impl Geometry for Capsule {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::Capsule(self.clone()))
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = v3!(self.radius, self.radius, self.radius);
+        let b = Aabb::from_points(self.start, self.end);
+        Some(Aabb { min: b.min - r, max: b.max + r })
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let ca = self.end - self.start;
+        let height = ca.length();
+        // A zero length capsule is a sphere; any axis will do.
+        let a = if height > 1e-12 { ca * (1.0 / height) } else { v3!(0.0, 1.0, 0.0) };
+        let oc = ray.origin - self.start;
+
+        let mut best: Option<(f64, Vector3)> = None;
+        let mut consider = |t: f64, n: Vector3| {
+            if t > 1e-9 && best.is_none_or(|(bt, _)| t < bt) {
+                best = Some((t, n));
+            }
+        };
+
+        // Body: the infinite cylinder clipped to the segment, as in Cylinder.
+        let d_perp = ray.dir - a*ray.dir.dot(a);
+        let o_perp = oc - a*oc.dot(a);
+        let qa = d_perp.dot(d_perp);
+        let qb = 2.0*d_perp.dot(o_perp);
+        let qc = o_perp.dot(o_perp) - self.radius*self.radius;
+        let disc = qb*qb - 4.0*qa*qc;
+        if qa > 1e-12 && disc >= 0.0 {
+            let sq = disc.sqrt();
+            for &t in &[(-qb - sq) / (2.0*qa), (-qb + sq) / (2.0*qa)] {
+                let s = (oc + ray.dir*t).dot(a);
+                if (0.0..=height).contains(&s) {
+                    let n = (oc + ray.dir*t - a*s) * (1.0 / self.radius);
+                    consider(t, n);
+                }
+            }
+        }
+
+        // End-caps: the outer half of a sphere at each end of the segment.
+        for &(center, out) in &[(self.start, -a), (self.end, a)] {
+            let oc = ray.origin - center;
+            let b = oc.dot(ray.dir);
+            let disc = b*b - (oc.dot(oc) - self.radius*self.radius);
+            if disc >= 0.0 {
+                let sq = disc.sqrt();
+                for &t in &[-b - sq, -b + sq] {
+                    let n = (oc + ray.dir*t) * (1.0 / self.radius);
+                    if n.dot(out) >= 0.0 {
+                        consider(t, n);
+                    }
+                }
+            }
+        }
+
+        best.map(|(t, n)| Intersection { pos: ray.origin + ray.dir*t,
+                                         normal: n,
+                                         dist: t,
+                                         material: self.material(),
+                                         material_id: self.material.id(),
+                                         barycentric: None,
+                                         uv: (0.0, 0.0),
+                                         tangent: None,
+                                         object_pos: None })
+    }
+}
+
+impl Geometry for Cone {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::Cone(self.clone()))
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let a = self.axis.normalize();
+        let base = self.apex + a*self.height;
+        let e = v3!((1.0 - a.x*a.x).max(0.0).sqrt(),
+                    (1.0 - a.y*a.y).max(0.0).sqrt(),
+                    (1.0 - a.z*a.z).max(0.0).sqrt()) * (self.height * self.half_angle.tan());
+        Some(Aabb { min: base - e, max: base + e }.merge(&Aabb::from_points(self.apex, self.apex)))
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let a = self.axis.normalize();
+        let k = self.half_angle.cos().powi(2);
+        let co = ray.origin - self.apex;
+
+        // Double cone: solve ((p - apex).a)^2 = cos^2(angle) |p - apex|^2.
+        let da = ray.dir.dot(a);
+        let oa = co.dot(a);
+        let qa = da*da - k*ray.dir.dot(ray.dir);
+        let qb = 2.0*(da*oa - k*ray.dir.dot(co));
+        let qc = oa*oa - k*co.dot(co);
+
+        let mut best: Option<(f64, Vector3)> = None;
+        let mut consider = |t: f64, n: Vector3| {
+            if t > 1e-9 && best.is_none_or(|(bt, _)| t < bt) {
+                best = Some((t, n));
+            }
+        };
+
+        let roots = if qa.abs() < 1e-12 {
+            // Ray parallel to the surface, hitting it once.
+            if qb.abs() < 1e-12 { vec![] } else { vec![-qc / qb] }
+        } else {
+            let disc = qb*qb - 4.0*qa*qc;
+            // A ray through the apex touches the cone there, with a zero
+            // discriminant that rounding can push just below zero.
+            if disc < -1e-9*qb*qb {
+                vec![]
+            } else {
+                let sq = disc.max(0.0).sqrt();
+                vec![(-qb - sq) / (2.0*qa), (-qb + sq) / (2.0*qa)]
+            }
+        };
+        for t in roots {
+            // Keep the nappe opening along the axis, up to the base.
+            let q = co + ray.dir*t;
+            let s = q.dot(a);
+            if (0.0..=self.height).contains(&s) {
+                let n = q*k - a*s;
+                // The normal is undefined at the apex itself.
+                let n = if n.length_squared() < 1e-24 { -a } else { n.normalize() };
+                consider(t, n);
+            }
+        }
+
+        if self.capped && da.abs() > 1e-12 {
+            let base = self.apex + a*self.height;
+            let radius = self.height * self.half_angle.tan();
+            let t = (base - ray.origin).dot(a) / da;
+            let p = ray.origin + ray.dir*t - base;
+            if p.length_squared() <= radius*radius {
+                consider(t, a);
+            }
+        }
+
+        best.map(|(t, n)| Intersection { pos: ray.origin + ray.dir*t,
+                                         normal: n,
+                                         dist: t,
+                                         material: self.material(),
+                                         material_id: self.material.id(),
+                                         barycentric: None,
+                                         uv: (0.0, 0.0),
+                                         tangent: None,
+                                         object_pos: None })
+    }
+}
+
+impl Geometry for Torus {
+    fn material(&self) -> Material {
+        self.material.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.material.id()
+    }
+    fn to_desc(&self) -> Option<ObjectDesc> {
+        Some(ObjectDesc::Torus(self.clone()))
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let (big_r, r) = (self.major_radius, self.minor_radius);
+        let e = v3!(big_r + r, r, big_r + r);
+        Some(Aabb { min: self.center - e, max: self.center + e })
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let (big_r, r) = (self.major_radius, self.minor_radius);
+        let dir = ray.dir.normalize();
+        let dir_len = ray.dir.length();
+
+        // Start the quartic from where the ray enters the bounding sphere,
+        // keeping the coefficients small for distant origins.
+        let oc = ray.origin - self.center;
+        let bound = big_r + r;
+        let b = oc.dot(dir);
+        let disc = b*b - (oc.dot(oc) - bound*bound);
+        if disc < 0.0 {
+            return None;
+        }
+        let t_start = (-b - disc.sqrt()).max(0.0);
+        if -b + disc.sqrt() < 0.0 {
+            return None;
+        }
+        let o = oc + dir*t_start;
+
+        // (|p|^2 - R^2 - r^2)^2 - 4R^2(r^2 - p.y^2) = 0 with p = o + t*dir
+        // and |dir| = 1.
+        let od = o.dot(dir);
+        let e = o.dot(o) - big_r*big_r - r*r;
+        let four_r2 = 4.0*big_r*big_r;
+        let coeffs = [e*e - four_r2*(r*r - o.y*o.y),
+                      4.0*od*e + 2.0*four_r2*o.y*dir.y,
+                      2.0*e + 4.0*od*od + four_r2*dir.y*dir.y,
+                      4.0*od,
+                      1.0];
+        let t = solve_quartic(coeffs).into_iter()
+            .filter(|&t| t_start + t > 1e-6)
+            .fold(f64::INFINITY, f64::min);
+        if !t.is_finite() {
+            return None;
+        }
+
+        let p = o + dir*t;
+        let ring = v3!(p.x, 0.0, p.z).normalize() * big_r;
+        let t = (t_start + t) / dir_len;
+        Some(Intersection { pos: ray.origin + ray.dir*t,
+                            normal: (p - ring).normalize(),
+                            dist: t,
+                            material: self.material(),
+                            material_id: self.material.id(),
+                            barycentric: None,
+                            uv: (0.0, 0.0),
+                            tangent: None,
+                            object_pos: None })
+    }
+}
+
+impl Geometry for SkyDome {
+    /// A placeholder: the color comes from the texture at each hit.
+    fn material(&self) -> Material {
+        emissive_material(v3!(0.0, 0.0, 0.0))
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+    fn casts_shadows(&self) -> bool {
+        false
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        // The far root: from inside, the only one ahead of the ray.
+        let oc = ray.origin - self.center;
+        let b = oc.dot(ray.dir);
+        let disc = b*b - (oc.dot(oc) - self.radius*self.radius);
+        if disc < 0.0 {
+            return None;
+        }
+        let t = -b + disc.sqrt();
+        if t <= 0.0 {
+            return None;
+        }
+        let pos = ray.origin + ray.dir*t;
+        let out = (pos - self.center).normalize();
+        let uv = Sphere::uv(out);
+        Some(Intersection { pos, normal: -out, dist: t,
+                            material: emissive_material(self.texture.sample(pos, out, uv)),
+                            material_id: None, barycentric: None, uv, tangent: None, object_pos: None })
+    }
+}
+
+const EQN_EPS: f64 = 1e-9;
+
+/// Real roots of `c[2]x^2 + c[1]x + c[0]`.
+fn solve_quadratic(c: [f64; 3]) -> Vec<f64> {
+    let p = c[1] / (2.0*c[2]);
+    let q = c[0] / c[2];
+    let d = p*p - q;
+    if d.abs() < EQN_EPS {
+        vec![-p]
+    } else if d < 0.0 {
+        vec![]
+    } else {
+        let sqrt_d = d.sqrt();
+        vec![sqrt_d - p, -sqrt_d - p]
+    }
+}
+
+/// Real roots of `c[3]x^3 + c[2]x^2 + c[1]x + c[0]` by Cardano's method.
+fn solve_cubic(c: [f64; 4]) -> Vec<f64> {
+    // Normal form x^3 + Ax^2 + Bx + C, then substitute x = y - A/3 to get
+    // y^3 + 3py + 2q = 0.
+    let a = c[2] / c[3];
+    let b = c[1] / c[3];
+    let cc = c[0] / c[3];
+    let sq_a = a*a;
+    let p = (-sq_a/3.0 + b) / 3.0;
+    let q = (2.0/27.0*a*sq_a - a*b/3.0 + cc) / 2.0;
+    let cb_p = p*p*p;
+    let d = q*q + cb_p;
+
+    let mut roots = if d.abs() < EQN_EPS {
+        if q.abs() < EQN_EPS {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0*u, -u]
+        }
+    } else if d < 0.0 {
+        // Three real roots.
+        let phi = (-q / (-cb_p).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0*(-p).sqrt();
+        let third = std::f64::consts::PI / 3.0;
+        vec![t*phi.cos(), -t*(phi + third).cos(), -t*(phi - third).cos()]
+    } else {
+        let sqrt_d = d.sqrt();
+        vec![(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()]
+    };
+    for r in roots.iter_mut() {
+        *r -= a / 3.0;
+    }
+    roots
+}
+
+/// Real roots of `c[4]x^4 + ... + c[0]` by Ferrari's method, each refined
+/// with a few Newton iterations on the original polynomial.
+fn solve_quartic(c: [f64; 5]) -> Vec<f64> {
+    // Normal form x^4 + Ax^3 + Bx^2 + Cx + D, then substitute x = y - A/4
+    // to eliminate the cubic term: y^4 + py^2 + qy + r = 0.
+    let a = c[3] / c[4];
+    let b = c[2] / c[4];
+    let cc = c[1] / c[4];
+    let d = c[0] / c[4];
+    let sq_a = a*a;
+    let p = -3.0/8.0*sq_a + b;
+    let q = sq_a*a/8.0 - a*b/2.0 + cc;
+    let r = -3.0/256.0*sq_a*sq_a + sq_a*b/16.0 - a*cc/4.0 + d;
+
+    let mut roots = if r.abs() < EQN_EPS {
+        // y(y^3 + py + q) = 0
+        let mut roots = solve_cubic([q, p, 0.0, 1.0]);
+        roots.push(0.0);
+        roots
+    } else {
+        // Take one root of the resolvent cubic and factor into two
+        // quadratics.
+        let z = solve_cubic([r*p/2.0 - q*q/8.0, -r, -p/2.0, 1.0])[0];
+        let u = z*z - r;
+        let v = 2.0*z - p;
+        let u = if u.abs() < EQN_EPS { 0.0 } else if u > 0.0 { u.sqrt() } else { return vec![] };
+        let v = if v.abs() < EQN_EPS { 0.0 } else if v > 0.0 { v.sqrt() } else { return vec![] };
+        let v = if q < 0.0 { -v } else { v };
+        let mut roots = solve_quadratic([z - u, v, 1.0]);
+        roots.extend(solve_quadratic([z + u, -v, 1.0]));
+        roots
+    };
+
+    let f = |x: f64| (((c[4]*x + c[3])*x + c[2])*x + c[1])*x + c[0];
+    let df = |x: f64| ((4.0*c[4]*x + 3.0*c[3])*x + 2.0*c[2])*x + c[1];
+    for root in roots.iter_mut() {
+        *root -= a / 4.0;
+        for _ in 0..3 {
+            let slope = df(*root);
+            if slope.abs() < EQN_EPS {
+                break;
+            }
+            *root -= f(*root) / slope;
+        }
+    }
+    roots
+}
+
+/// Returns the intermediate values `geometry` computes while intersecting
+/// `ray`, for inspecting intersection bugs.
+#[cfg(feature = "debug_api")]
+pub fn debug_intersect(geometry: &dyn Geometry, ray: &Ray) -> DebugIntersectionResult {
+    geometry.debug_intersect(ray)
+}
+
+fn cast_ray(scene: &Scene, ray: &Ray) -> Option<Intersection> {
+    let isect = nearest_hit(scene, ray);
+    if isect.is_some() {
+        count_rays(|c| c.hits += 1);
+    }
+    isect
+}
+
+/// `cast_ray` without counting the hit in `RenderStats`, for rays that
+/// aren't part of the render, such as the offset rays of a
+/// `RayDifferential`, which only measure a texture footprint.
+fn nearest_hit(scene: &Scene, ray: &Ray) -> Option<Intersection> {
+    // A little past `t_max`, so that hits right at it aren't lost to
+    // rounding.
+    let limit = ray.t_max + ray.t_max.abs()*1e-9 + 1e-9;
+    let mut closest: f64 = f64::INFINITY;
+    let mut isect: Option<Intersection> = None;
+    for o in scene.objects.iter() {
+        if let Some(i) = o.intersects(ray) {
+            if i.dist < closest && i.dist <= limit {
+                closest = i.dist;
+                isect = Some(i);
+            }
+        }
+    }
+    if let Some(i) = accel_intersect(scene, ray, closest.min(limit)) {
+        isect = Some(i);
+    }
+    if let Some(ref mut i) = isect {
+        if let Some(id) = i.material_id {
+            i.material = scene.get_material(id).clone();
+        }
+        if i.material.two_sided && ray.dir.dot(i.normal) > 0.0 {
+            i.normal = -i.normal;
+        }
+    }
+    isect
+}
+
+/// Returns true if any object blocks `ray` closer than `max_t`, i.e.
+/// between the shadow ray origin and the light.
+fn cast_shadow_ray(scene: &Scene, ray: &Ray, max_t: f64) -> bool {
+    let blocked = scene.objects.iter().any(|o| o.casts_shadows() && match o.intersects(ray) {
+        Some(i) => i.dist < max_t,
+        None => false,
+    }) || accel_intersect(scene, ray, max_t).is_some();
+    count_rays(|c| {
+        c.shadow += 1;
+        c.hits += blocked as u64;
+    });
+    blocked
+}
+
+/// The closest hit nearer than `t_max` among the objects in the scene's
+/// BVH or grid. If objects have moved since it was built it would miss
+/// them at their new positions, so they are tested one by one instead.
+fn accel_intersect(scene: &Scene, ray: &Ray, t_max: f64) -> Option<Intersection> {
+    let accel = scene.accel.as_ref()?;
+    if scene.accel_generation == scene.generation {
+        return accel.intersect(ray, t_max);
+    }
+    accel.objects().iter()
+        .filter_map(|o| o.intersects(ray))
+        .filter(|i| i.dist < t_max)
+        .min_by(|a, b| a.dist.total_cmp(&b.dist))
+}
+
+/// Lambertian diffuse plus a highlight around the half vector between
+/// the light and the viewer. Clamped to [0, 1] before the light color is
+/// applied.
+fn blinn_phong_shade(light_dir: Vector3, view_dir: Vector3, light_color: Vector3,
+                     isect: &Intersection) -> Vector3 {
+    let material = &isect.material;
+    let diffuse = light_dir.dot(isect.normal).max(0.0);
+    let mut specular = 0.0;
+    if diffuse > 0.0 {
+        let half_dir = (light_dir + view_dir).normalize();
+        let spec_angle = half_dir.dot(isect.normal).max(0.0);
+        specular = spec_angle.powf(material.shininess);
+    }
+    (material.color * diffuse + material.spec_color * specular).map(|c| c.clamp(0.0, 1.0)) * light_color
+}
+
+/// Like `blinn_phong_shade`, but the highlight is around the mirror
+/// reflection of the light, which makes it smaller for the same shininess.
+fn phong_shade(light_dir: Vector3, view_dir: Vector3, light_color: Vector3,
+               isect: &Intersection) -> Vector3 {
+    let material = &isect.material;
+    let n_dot_l = light_dir.dot(isect.normal);
+    let diffuse = n_dot_l.max(0.0);
+    let mut specular = 0.0;
+    if diffuse > 0.0 {
+        let reflected = (-light_dir).reflect(isect.normal);
+        specular = reflected.dot(view_dir).max(0.0).powf(material.shininess);
+    }
+    (material.color * diffuse + material.spec_color * specular).map(|c| c.clamp(0.0, 1.0)) * light_color
+}
+
+/// Cook-Torrance reflection with the GGX distribution, Smith shadowing
+/// and Schlick's Fresnel term. Like `blinn_phong_shade` the result is scaled by
+/// pi, so a white diffuse surface lit head-on returns `light_color`.
+fn cook_torrance(light_dir: Vector3, view_dir: Vector3, light_color: Vector3,
+                 isect: &Intersection, roughness: f64, metalness: f64) -> Vector3 {
+    let n = isect.normal;
+    let n_dot_l = n.dot(light_dir);
+    let n_dot_v = n.dot(view_dir);
+    if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+        return v3!(0.0, 0.0, 0.0);
+    }
+    let half_dir = (light_dir + view_dir).normalize();
+    let n_dot_h = n.dot(half_dir).max(0.0);
+    let v_dot_h = view_dir.dot(half_dir).max(0.0);
+
+    let alpha = (roughness * roughness).max(1e-4);
+    let alpha2 = alpha * alpha;
+    let d_denom = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    let distribution = alpha2 / (std::f64::consts::PI * d_denom * d_denom);
+    let k = alpha / 2.0;
+    let g1 = |cos: f64| cos / (cos * (1.0 - k) + k);
+    let geometry = g1(n_dot_l) * g1(n_dot_v);
+
+    let color = isect.material.color;
+    let f0 = v3!(0.04, 0.04, 0.04).lerp(color, metalness);
+    let schlick = |cos: f64| f0 + (v3!(1.0, 1.0, 1.0) - f0) * (1.0 - cos).powi(5);
+
+    let specular = schlick(v_dot_h)
+        * (std::f64::consts::PI * distribution * geometry / (4.0 * n_dot_l * n_dot_v));
+    // Light reflected at the surface from the viewing angle can't also be
+    // scattered diffusely, or grazing views would reflect more than 100%.
+    let diffuse = (v3!(1.0, 1.0, 1.0) - schlick(n_dot_v)) * color * (1.0 - metalness);
+    (diffuse + specular) * light_color * n_dot_l
+}
+
+/// Direct light arriving from `light_dir`, as seen from `view_dir`, with
+/// the reflection model `model`.
+fn shade_point(model: BrdfModel, light_dir: Vector3, view_dir: Vector3, light_color: Vector3,
+               isect: &Intersection) -> Vector3 {
+    match model {
+        BrdfModel::BlinnPhong => blinn_phong_shade(light_dir, view_dir, light_color, isect),
+        BrdfModel::Phong => phong_shade(light_dir, view_dir, light_color, isect),
+        BrdfModel::CookTorrance { roughness, metalness } =>
+            cook_torrance(light_dir, view_dir, light_color, isect, roughness, metalness),
+    }
+}
+
+/// Schlick's approximation of the Fresnel reflectance, where `f0` is the
+/// reflectance at normal incidence.
+pub fn fresnel_schlick(cos_theta: f64, f0: f64) -> f64 {
+    f0 + (1.0 - f0) * (1.0 - cos_theta).powi(5)
+}
+
+/// Light passing through a transparent surface: the refracted ray blended
+/// with the reflected one by their Fresnel weights.
+fn shade_transparent(scene: &Scene, ray: &Ray, isect: &Intersection, trace_depth: u32,
+                     integrator: IntegratorMode, rng: &mut Rng) -> Vector3 {
+    let ior = isect.material.refraction;
+    let mut cos_i = -ray.dir.dot(isect.normal);
+    // Leaving the object if the ray hits the back of the surface.
+    let (normal, eta) = if cos_i < 0.0 {
+        cos_i = -cos_i;
+        (-isect.normal, ior)
+    } else {
+        (isect.normal, 1.0 / ior)
+    };
+
+    let reflection_dir = ray.dir.reflect(normal);
+    let reflection_ray = Ray { origin: isect.pos+reflection_dir*0.001,
+                               dir: reflection_dir,
+                               t_max: ray.t_max };
+    count_rays(|c| c.reflection += 1);
+    let reflected = shade_pixel(scene, &reflection_ray, None, trace_depth - 1, integrator, rng);
+
+    let refraction_dir = match ray.dir.refract(normal, eta) {
+        Some(dir) => dir,
+        None => return reflected,
+    };
+    let refraction_ray = Ray { origin: isect.pos+refraction_dir*0.001,
+                               dir: refraction_dir,
+                               t_max: ray.t_max };
+    count_rays(|c| c.reflection += 1);
+    let refracted = shade_pixel(scene, &refraction_ray, None, trace_depth - 1, integrator, rng);
+
+    let f0 = ((1.0 - ior) / (1.0 + ior)).powi(2);
+    let kr = fresnel_schlick(cos_i, f0);
+    reflected*kr + refracted*(1.0 - kr)
+}
+
+/// Fraction of `samples` cosine weighted rays from the hit point that
+/// travel at least `radius` without hitting anything.
+fn ambient_occlusion(scene: &Scene, ray: &Ray, isect: &Intersection, samples: usize,
+                     radius: f64, rng: &mut Rng) -> f64 {
+    let samples = samples.max(1);
+    let normal = if ray.dir.dot(isect.normal) > 0.0 { -isect.normal } else { isect.normal };
+    let open = (0..samples).filter(|_| {
+        let dir = cosine_sample_hemisphere(normal, rng);
+        !cast_shadow_ray(scene, &Ray { origin: isect.pos+dir*0.001, dir, t_max: f64::INFINITY }, radius)
+    }).count();
+    open as f64 / samples as f64
+}
+
+/// Looks up the material's textures at the hit point, replacing its color
+/// with the color texture and bending the normal by the normal map.
+fn apply_textures(isect: &mut Intersection, footprint: Option<&TextureFootprint>) {
+    if let Some(ref texture) = isect.material.texture {
+        let pos = match isect.object_pos {
+            Some(object_pos) if texture.object_space() => object_pos,
+            _ => isect.pos,
+        };
+        isect.material.color = match footprint {
+            Some(footprint) => texture.sample_footprint(pos, isect.normal, isect.uv, footprint),
+            None => texture.sample(pos, isect.normal, isect.uv),
+        };
+    }
+    if let (Some(ref normal_map), Some(tangent)) = (&isect.material.normal_map, isect.tangent) {
+        let n = isect.normal;
+        let t = (tangent - n*n.dot(tangent)).normalize();
+        let b = n.cross(&t);
+        let local = normal_map.sample(isect.pos, n, isect.uv) * 2.0 - v3!(1.0, 1.0, 1.0);
+        isect.normal = (t*local.x + b*local.y + n*local.z).normalize();
+    }
+}
+
+/// How much of the textured surface hit at `isect` the pixel of
+/// `differential` covers, or None if the surface isn't textured. The
+/// offset rays are intersected with the surface's tangent plane for the
+/// positions, and cast into the scene for the texture coordinates, which
+/// only count if they land on a surface facing the same way.
+fn texture_footprint(scene: &Scene, ray: &Ray, differential: &RayDifferential,
+                     isect: &Intersection) -> Option<TextureFootprint> {
+    isect.material.texture.as_ref()?;
+    let n = isect.normal;
+    let offset = |origin: Vector3, dir: Vector3| {
+        let t = (isect.pos - origin).dot(n) / dir.dot(n);
+        if t.is_finite() && t > 0.0 { origin + dir*t - isect.pos } else { v3!(0.0, 0.0, 0.0) }
+    };
+    let uv_offset = |origin: Vector3, dir: Vector3| {
+        match nearest_hit(scene, &Ray { origin, dir, t_max: ray.t_max }) {
+            Some(hit) if hit.normal.dot(n) > 0.9 => {
+                // Texture coordinates wrap around closed shapes such as
+                // spheres, so a jump of about 1 is a step across the seam.
+                let (du, dv) = (hit.uv.0 - isect.uv.0, hit.uv.1 - isect.uv.1);
+                (du - du.round(), dv - dv.round())
+            },
+            _ => (0.0, 0.0),
+        }
+    };
+    Some(TextureFootprint { dpdx: offset(differential.origin_dx, differential.dir_dx),
+                            dpdy: offset(differential.origin_dy, differential.dir_dy),
+                            duvdx: uv_offset(differential.origin_dx, differential.dir_dx),
+                            duvdy: uv_offset(differential.origin_dy, differential.dir_dy) })
+}
+
+/// Whitted style shading. `integrator` is `Whitted`, or `AmbientOcclusion`
+/// to scale the ambient term by how occluded the surface is. Camera rays
+/// come with their `differential` for texture filtering.
+fn shade_pixel(scene: &Scene, ray: &Ray, differential: Option<&RayDifferential>,
+               trace_depth: u32, integrator: IntegratorMode, rng: &mut Rng) -> Vector3 {
+    let mut pixel = v3!(0.0, 0.0, 0.0);
+    match cast_ray(scene, ray) {
+        None => {
+            pixel = scene.background.color(ray.dir);
+            if let Some(fog) = scene.fog {
+                pixel = fog.apply(pixel, FOG_MISS_DISTANCE);
+            }
+        },
+        Some(mut isect) => {
+            let footprint = differential.and_then(|d| texture_footprint(scene, ray, d, &isect));
+            apply_textures(&mut isect, footprint.as_ref());
+            pixel += isect.material.emission;
+            let occlusion = match integrator {
+                IntegratorMode::AmbientOcclusion { samples, radius } if !scene.lights.is_empty() =>
+                    ambient_occlusion(scene, ray, &isect, samples, radius, rng),
+                _ => 1.0,
+            };
+            pixel += isect.material.color * scene.ambient * occlusion;
+            for (i, light) in scene.lights.iter().enumerate() {
+                let shadow_map = match scene.shadowing_mode {
+                    ShadowMode::ShadowMap(ref maps) => maps.get(i).and_then(Option::as_ref),
+                    ShadowMode::RayTraced => None,
+                };
+                for sample in light.sample(isect.pos, rng) {
+                    // A light behind the surface can't light it, so don't
+                    // spend a shadow ray on it.
+                    if sample.dir.dot(isect.normal) <= 0.0 {
+                        continue;
+                    }
+                    let shadowed = match shadow_map {
+                        Some(map) => map.test(isect.pos),
+                        None => {
+                            let shadow_ray = Ray { origin: isect.pos+sample.dir*0.001,
+                                                   dir: sample.dir,
+                                                   t_max: f64::INFINITY };
+                            cast_shadow_ray(scene, &shadow_ray, sample.dist - 0.001)
+                        },
+                    };
+                    if !shadowed {
+                        pixel += shade_point(isect.material.brdf, sample.dir, -ray.dir,
+                                             sample.color, &isect) * sample.intensity;
+                    }
+                }
+            }
+            if let Some(ref photons) = scene.photon_map {
+                pixel += isect.material.color * photons.irradiance(isect.pos, isect.normal);
+            }
+
+            if isect.material.reflection > 0.0 && trace_depth > 0 {
+                let reflection_dir = ray.dir.reflect(isect.normal);
+                let reflection_ray = Ray { origin: isect.pos+reflection_dir*0.001,
+                                           dir: reflection_dir,
+                                           t_max: ray.t_max };
+                // Reflectance rises towards 1 at grazing angles, and the
+                // reflection replaces that much of the surface's own color.
+                let kr = fresnel_schlick(ray.dir.dot(isect.normal).abs(), isect.material.reflection);
+                count_rays(|c| c.reflection += 1);
+                let reflected = shade_pixel(scene, &reflection_ray, None, trace_depth - 1, integrator, rng);
+                pixel = pixel * (1.0 - kr) + reflected * kr;
+            }
+
+            let transparency = isect.material.transparency;
+            if transparency > 0.0 && trace_depth > 0 {
+                pixel = pixel * (1.0 - transparency)
+                    + shade_transparent(scene, ray, &isect, trace_depth, integrator, rng) * transparency;
+            }
+
+            if let Some(fog) = scene.fog {
+                pixel = fog.apply(pixel, isect.dist);
+            }
+        }
+    }
+    pixel
+}
+
+/// Path depth from which paths are terminated by Russian roulette.
+const ROULETTE_DEPTH: u32 = 3;
+/// Hard limit on path depth, in case Russian roulette keeps surviving.
+const MAX_PATH_DEPTH: u32 = 64;
+
+/// Radiance along `ray` estimated from a single random path. Each bounce
+/// picks a mirror reflection, a refraction or a diffuse bounce with the
+/// material's reflection and transparency as probabilities. Diffuse bounces
+/// sample `scene.lights` directly and continue in a cosine weighted
+/// direction; emissive surfaces add light wherever a path hits them. With
+/// light portals, diffuse bounces also sample the background through them,
+/// so `after_diffuse` paths that reach it add nothing.
+fn path_trace_pixel(scene: &Scene, ray: &Ray, depth: u32, after_diffuse: bool, rng: &mut Rng) -> Vector3 {
+    let mut isect = match cast_ray(scene, ray) {
+        None if after_diffuse && !scene.light_portals.is_empty() => return v3!(0.0, 0.0, 0.0),
+        None => return scene.background.color(ray.dir),
+        Some(isect) => isect,
+    };
+    apply_textures(&mut isect, None);
+    let material = &isect.material;
+    let radiance = material.emission;
+    if depth >= MAX_PATH_DEPTH {
+        return radiance;
+    }
+
+    let mut weight = 1.0;
+    if depth >= ROULETTE_DEPTH {
+        let survival = material.color.max_component().max(material.reflection)
+            .max(material.transparency).clamp(0.05, 1.0);
+        if rng.next_f64() >= survival {
+            return radiance;
+        }
+        weight = 1.0 / survival;
+    }
+
+    let cos_i = -ray.dir.dot(isect.normal);
+    // Normal on the side the ray came from.
+    let normal = if cos_i < 0.0 { -isect.normal } else { isect.normal };
+    let reflection_dir = ray.dir.reflect(normal);
+    let choice = rng.next_f64();
+    count_rays(|c| c.reflection += 1);
+    let scattered = if choice < material.reflection {
+        let reflection_ray = Ray { origin: isect.pos+reflection_dir*0.001,
+                                   dir: reflection_dir,
+                                   t_max: ray.t_max };
+        path_trace_pixel(scene, &reflection_ray, depth + 1, false, rng)
+    } else if choice < material.reflection + material.transparency {
+        let ior = material.refraction;
+        let eta = if cos_i < 0.0 { ior } else { 1.0 / ior };
+        let f0 = ((1.0 - ior) / (1.0 + ior)).powi(2);
+        // Reflect with the Fresnel probability, or always on total internal
+        // reflection.
+        let dir = match ray.dir.refract(normal, eta) {
+            Some(dir) if rng.next_f64() >= fresnel_schlick(cos_i.abs(), f0) => dir,
+            _ => reflection_dir,
+        };
+        let refraction_ray = Ray { origin: isect.pos+dir*0.001, dir, t_max: ray.t_max };
+        path_trace_pixel(scene, &refraction_ray, depth + 1, false, rng)
+    } else {
+        let mut irradiance = v3!(0.0, 0.0, 0.0);
+        for light in &scene.lights {
+            for sample in light.sample(isect.pos, rng) {
+                let cos_l = sample.dir.dot(normal);
+                if cos_l <= 0.0 {
+                    continue;
+                }
+                let shadow_ray = Ray { origin: isect.pos+sample.dir*0.001,
+                                       dir: sample.dir,
+                                       t_max: f64::INFINITY };
+                if !cast_shadow_ray(scene, &shadow_ray, sample.dist - 0.001) {
+                    irradiance += sample.color * (cos_l * sample.intensity);
+                }
+            }
+        }
+        irradiance += sky_through_portals(scene, isect.pos, normal, rng);
+        let dir = cosine_sample_hemisphere(normal, rng);
+        let bounce = Ray { origin: isect.pos+dir*0.001, dir, t_max: ray.t_max };
+        irradiance += path_trace_pixel(scene, &bounce, depth + 1, true, rng);
+        material.color * irradiance
+    };
+    radiance + scattered*weight
+}
+
+/// Background light arriving at `pos` through a randomly picked light
+/// portal, weighted like a cosine sampled diffuse bounce. 0 without
+/// portals.
+fn sky_through_portals(scene: &Scene, pos: Vector3, normal: Vector3, rng: &mut Rng) -> Vector3 {
+    let portals = &scene.light_portals;
+    if portals.is_empty() {
+        return v3!(0.0, 0.0, 0.0);
+    }
+    let portal = &portals[((rng.next_f64() * portals.len() as f64) as usize).min(portals.len() - 1)];
+    let (dir, pdf) = portal.sample(pos, rng);
+    let cos = dir.dot(normal);
+    if pdf <= 0.0 || cos <= 0.0 {
+        return v3!(0.0, 0.0, 0.0);
+    }
+    // Portals may overlap as seen from `pos`.
+    let pdf = portals.iter().map(|p| p.pdf(pos, dir)).sum::<f64>() / portals.len() as f64;
+    let shadow_ray = Ray { origin: pos+dir*0.001, dir, t_max: f64::INFINITY };
+    if cast_shadow_ray(scene, &shadow_ray, f64::INFINITY) {
+        return v3!(0.0, 0.0, 0.0);
+    }
+    scene.background.color(dir) * (cos / (std::f64::consts::PI * pdf))
+}
+
+/// Random direction in the hemisphere around `normal`, with probability
+/// proportional to the cosine of its angle to `normal`.
+fn cosine_sample_hemisphere(normal: Vector3, rng: &mut Rng) -> Vector3 {
+    let (u1, u2) = (rng.next_f64(), rng.next_f64());
+    local_to_world(cosine_hemisphere_sample(u1, u2), normal)
+}
+
+impl Camera {
+    /// Pinhole camera at `from` looking towards `at`, with a vertical field
+    /// of view of `vfov_degrees` and `aspect` as width / height.
+    pub fn look_at(from: Vector3, at: Vector3, world_up: Vector3,
+                   vfov_degrees: f64, aspect: f64) -> Camera {
+        let half_height = (vfov_degrees.to_radians() / 2.0).tan();
+        let forward = (at - from).normalize();
+        // Image plane axes are ordered so that right x up = forward.
+        let right = world_up.cross(&forward).normalize();
+        let up = forward.cross(&right).normalize();
+        Camera { pos: from,
+                 up: up * half_height,
+                 right: right * (aspect * half_height),
+                 dist: 1.0,
+                 aperture: 0.0,
+                 focus_distance: (at - from).length(),
+                 projection: Projection::Perspective }
+    }
+
+    /// Thin lens camera described like a real one, with scene units taken
+    /// as meters: a lens of `focal_length_mm` stopped down to `f_stop`,
+    /// focused `focus_distance_m` away, in front of a sensor
+    /// `sensor_width_mm` wide. The sensor sets the horizontal field of
+    /// view; `aspect` is width / height as for `look_at`. For example 50
+    /// mm at f/1.8 on a 36 mm sensor, focused at 1.8 m, blurs the
+    /// background like a portrait photo.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_focal_length(focal_length_mm: f64, f_stop: f64, focus_distance_m: f64,
+                             sensor_width_mm: f64, from: Vector3, at: Vector3, world_up: Vector3,
+                             aspect: f64) -> Camera {
+        let half_width = sensor_width_mm / (2.0 * focal_length_mm);
+        let vfov_degrees = 2.0 * (half_width / aspect).atan().to_degrees();
+        // The entrance pupil is the focal length over the f-number across.
+        let aperture = focal_length_mm / f_stop / 2.0 / 1000.0;
+        Camera { aperture, focus_distance: focus_distance_m,
+                 ..Camera::look_at(from, at, world_up, vfov_degrees, aspect) }
+    }
+}
+
+/// How many rays are traced per pixel, and where within the pixel.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum AntiAlias {
+    /// A single ray through the pixel's corner.
+    #[default]
+    None,
+    /// n x n rays on a regular sub-pixel grid.
+    Grid(usize),
+    /// n rays through random points of the pixel.
+    Stochastic(usize),
+    /// n x n rays, one through a random point of each cell of a regular
+    /// sub-pixel grid. Covers the pixel more evenly than `Stochastic` with
+    /// the same number of rays.
+    Stratified(usize),
+    /// Rays through random points of the pixel until its color has
+    /// settled, so that flat regions take fewer rays than edges and
+    /// noise.
+    Adaptive(AdaptiveConfig),
+}
+
+/// When `AntiAlias::Adaptive` stops tracing rays through a pixel.
+///
+/// The stopping test is on the variance of the pixel's mean luminance,
+/// which is the square of the noise left in it: a `target_variance` of
+/// 1e-4 leaves noise of about 0.01, a few 8-bit steps, while 1e-5 is
+/// about one step and hard to see. Small samples underestimate the
+/// variance when most rays agree and a rare one doesn't, such as a
+/// pixel where a few rays find a small bright light. Stopping on such an
+/// estimate leaves pixels that are too dark next to converged ones,
+/// which shows up as blotches rather than grain. `warmup_spp` and
+/// `min_spp` guard against that.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AdaptiveConfig {
+    /// Rays traced before the variance is trusted at all.
+    pub warmup_spp: u32,
+    /// Rays every pixel gets, however flat, for algorithms that need a
+    /// minimum number of samples. Checked before the variance.
+    pub min_spp: u32,
+    /// Rays after which a pixel stops whatever its variance.
+    pub max_spp: u32,
+    /// Variance of the mean luminance at which a pixel is done.
+    pub target_variance: f64,
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> AdaptiveConfig {
+        AdaptiveConfig { warmup_spp: 8, min_spp: 4, max_spp: 256, target_variance: 1e-4 }
+    }
+}
+
+/// Called with `(done, total)` as scan-lines, or tiles when rendering in
+/// tiles, are finished.
+pub type ProgressCallback = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+/// Options for `raytrace` and `raytrace_parallel`.
+#[derive(Clone)]
+pub struct RenderConfig {
+    pub width: usize,
+    pub height: usize,
+    /// How many reflection and refraction bounces are followed. 0 shades
+    /// only what the camera sees directly. Not used by `PathTrace`.
+    pub max_depth: u32,
+    pub anti_alias: AntiAlias,
+    /// Overrides the scene's own tone mapper when set.
+    pub tone_mapper: Option<ToneMapper>,
+    /// Rendered colors are encoded with `1/gamma` after tone mapping. Use
+    /// 1.0 to get linear output.
+    pub gamma: f64,
+    pub integrator: IntegratorMode,
+    /// Called after every finished scan-line or tile. With
+    /// `raytrace_parallel` it is called from the worker threads, so they
+    /// may finish out of order, but `done` always counts up by one.
+    pub progress_callback: Option<ProgressCallback>,
+    /// Render the image in square tiles of this size, one after another,
+    /// instead of by scan-lines. Neighbouring rays then visit the same BVH
+    /// nodes, which is kinder to the cache. 0 renders by scan-lines.
+    pub tile_size: usize,
+    /// Only render the pixels in `(x_min, y_min, x_max, y_max)`, with the
+    /// maxima exclusive and y counted down from the top row. The rest of
+    /// the image is left black, so regions rendered separately, e.g. on
+    /// different machines, can be added together.
+    pub region: Option<(usize, usize, usize, usize)>,
+    /// Print `RenderStats` to stderr after `raytrace_full`.
+    pub print_stats: bool,
+    /// Objects farther than this from the camera, or from the surface a
+    /// reflected or refracted ray leaves, are ignored and the background
+    /// is seen instead. Shadow rays aren't limited.
+    pub max_ray_distance: f64,
+    /// Overrides the scene's camera when set.
+    pub camera: Option<Camera>,
+}
+
+impl Default for RenderConfig {
+    fn default() -> RenderConfig {
+        RenderConfig { width: 800,
+                       height: 600,
+                       max_depth: 3,
+                       anti_alias: AntiAlias::None,
+                       tone_mapper: None,
+                       gamma: 2.2,
+                       integrator: IntegratorMode::Whitted,
+                       progress_callback: None,
+                       tile_size: 0,
+                       region: None,
+                       print_stats: true,
+                       max_ray_distance: f64::INFINITY,
+                       camera: None }
+    }
+}
+
+impl fmt::Debug for RenderConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RenderConfig")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("max_depth", &self.max_depth)
+            .field("anti_alias", &self.anti_alias)
+            .field("tone_mapper", &self.tone_mapper)
+            .field("gamma", &self.gamma)
+            .field("integrator", &self.integrator)
+            .field("progress_callback", &self.progress_callback.is_some())
+            .field("tile_size", &self.tile_size)
+            .field("region", &self.region)
+            .field("print_stats", &self.print_stats)
+            .field("max_ray_distance", &self.max_ray_distance)
+            .field("camera", &self.camera)
+            .finish()
+    }
+}
+
+/// How the light arriving along each camera ray is computed.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum IntegratorMode {
+    /// Direct lighting plus recursive perfect reflection and refraction.
+    #[default]
+    Whitted,
+    /// Monte Carlo path tracing with the given number of paths per camera
+    /// ray, which also captures indirect diffuse light.
+    PathTrace(usize),
+    /// `Whitted`, with the ambient term scaled by the fraction of `samples`
+    /// hemisphere rays that escape within `radius`.
+    AmbientOcclusion { samples: usize, radius: f64 },
+}
+
+/// Ray through the image plane at continuous pixel coordinates (x, y).
+/// With a non-zero aperture the ray starts from a random point on the lens
+/// and passes through where the pinhole ray meets the focal plane. Hits
+/// beyond `t_max` are ignored.
+fn primary_ray(camera: &Camera, x: f64, y: f64, width: usize, height: usize, t_max: f64,
+               rng: &mut Rng) -> Ray {
+    let u = x * 2.0 / (width as f64) - 1.0;
+    let v = y * 2.0 / (height as f64) - 1.0;
+    let forward = camera.right.cross(&camera.up).normalize();
+    if camera.projection == Projection::Orthographic {
+        return Ray { origin: camera.pos + camera.right*u + camera.up*v, dir: forward, t_max };
+    }
+    let pos =
+        camera.pos
+        + forward*camera.dist
+        + camera.right*u
+        + camera.up*v;
+    let ray_dir: Vector3 = (pos-camera.pos).normalize();
+    if camera.aperture <= 0.0 {
+        return Ray { origin: camera.pos, dir: ray_dir, t_max };
+    }
+    let focus = camera.pos + ray_dir*(camera.focus_distance / ray_dir.dot(forward));
+    let r = camera.aperture * rng.next_f64().sqrt();
+    let theta = 2.0 * std::f64::consts::PI * rng.next_f64();
+    let origin = camera.pos
+        + camera.right.normalize()*(r*theta.cos())
+        + camera.up.normalize()*(r*theta.sin());
+    Ray { origin, dir: (focus - origin).normalize(), t_max }
+}
+
+/// Shades the pixel at (x, y), with y growing upwards from the bottom row.
+fn render_pixel(scene: &Scene, config: &RenderConfig, x: usize, y: usize) -> Vector3 {
+    encode_pixel(scene, config, sample_pixel(scene, config, x, y, 0))
+}
+
+/// Average linear color of the rays through the pixel at (x, y). Each
+/// `pass` picks different random samples, for adding passes together.
+fn sample_pixel(scene: &Scene, config: &RenderConfig, x: usize, y: usize, pass: usize) -> Vector3 {
+    let (width, height) = (config.width, config.height);
+    let mut rng = Rng::new((pass*width*height + y*width + x) as u64);
+    let offsets: Vec<(f64, f64)> = match config.anti_alias {
+        AntiAlias::Adaptive(adaptive) => return sample_adaptively(scene, config, x, y, &adaptive, &mut rng).0,
+        AntiAlias::None => vec![(0.0, 0.0)],
+        AntiAlias::Grid(n) => {
+            let n = n.max(1);
+            let step = 1.0 / n as f64;
+            (0..n*n).map(|i| (((i % n) as f64 + 0.5) * step,
+                              ((i / n) as f64 + 0.5) * step)).collect()
+        },
+        AntiAlias::Stochastic(n) =>
+            (0..n.max(1)).map(|_| (rng.next_f64(), rng.next_f64())).collect(),
+        AntiAlias::Stratified(n) => {
+            let n = n.max(1);
+            let step = 1.0 / n as f64;
+            (0..n*n).map(|i| (((i % n) as f64 + rng.next_f64()) * step,
+                              ((i / n) as f64 + rng.next_f64()) * step)).collect()
+        },
+    };
+    count_rays(|c| c.primary += offsets.len() as u64);
+    let mut color = v3!(0.0, 0.0, 0.0);
+    for &(dx, dy) in &offsets {
+        color += shade_sample(scene, config, x as f64 + dx, y as f64 + dy, &mut rng);
+    }
+    color / offsets.len() as f64
+}
+
+/// Random rays through the pixel at (x, y) until `adaptive` says it is
+/// done. Returns their average color and how many there were.
+fn sample_adaptively(scene: &Scene, config: &RenderConfig, x: usize, y: usize, adaptive: &AdaptiveConfig,
+                     rng: &mut Rng) -> (Vector3, u32) {
+    // The variance needs two samples.
+    let enough = adaptive.warmup_spp.max(adaptive.min_spp).max(2);
+    let (mut color, mut luminance, mut m2) = (v3!(0.0, 0.0, 0.0), 0.0, 0.0);
+    let mut n = 0;
+    while n < adaptive.max_spp.max(1) {
+        let (dx, dy) = (rng.next_f64(), rng.next_f64());
+        let sample = shade_sample(scene, config, x as f64 + dx, y as f64 + dy, rng);
+        count_rays(|c| c.primary += 1);
+        n += 1;
+        // Welford's running mean and variance.
+        color += (sample - color) / n as f64;
+        let l = sample.dot(v3!(0.2126, 0.7152, 0.0722));
+        let delta = l - luminance;
+        luminance += delta / n as f64;
+        m2 += delta * (l - luminance);
+        if n >= enough && m2 / ((n - 1) as f64 * n as f64) <= adaptive.target_variance {
+            break;
+        }
+    }
+    (color, n)
+}
+
+/// Linear color of one camera ray through continuous pixel coordinates
+/// (x, y).
+fn shade_sample(scene: &Scene, config: &RenderConfig, x: f64, y: f64, rng: &mut Rng) -> Vector3 {
+    let (width, height) = (config.width, config.height);
+    // The offset rays go through the same point on the lens.
+    let lens = rng.clone();
+    let camera = render_camera(scene, config);
+    let ray = primary_ray(camera, x, y, width, height, config.max_ray_distance, rng);
+    match config.integrator {
+        IntegratorMode::Whitted | IntegratorMode::AmbientOcclusion { .. } => {
+            let differential = RayDifferential::for_pixel(camera, x, y, width, height,
+                                                          config.max_ray_distance, &lens);
+            shade_pixel(scene, &ray, Some(&differential), config.max_depth, config.integrator, rng)
+        },
+        IntegratorMode::PathTrace(paths) => {
+            let paths = paths.max(1);
+            let mut sum = v3!(0.0, 0.0, 0.0);
+            for _ in 0..paths {
+                sum += path_trace_pixel(scene, &ray, 0, false, rng);
+            }
+            sum / paths as f64
+        },
+    }
+}
+
+/// The camera `config` renders through.
+fn render_camera<'a>(scene: &'a Scene, config: &'a RenderConfig) -> &'a Camera {
+    config.camera.as_ref().unwrap_or(&scene.camera)
+}
+
+/// Tone maps and gamma encodes a linear pixel color.
+fn encode_pixel(scene: &Scene, config: &RenderConfig, color: Vector3) -> Vector3 {
+    let tone_mapper = config.tone_mapper.unwrap_or(scene.tone_mapper);
+    let mapped = tone_mapper.apply(color);
+    if config.gamma == 1.0 {
+        mapped
+    } else {
+        mapped.map(|c| c.powf(1.0 / config.gamma))
+    }
+}
+
+/// Pixel rectangle `(x0, row0, x1, row1)`, with the ends exclusive and
+/// rows counted from the top.
+type Tile = (usize, usize, usize, usize);
+
+/// The part of the image to render: `config.region` cut to the image, or
+/// the whole image.
+fn render_area(config: &RenderConfig) -> Tile {
+    let (width, height) = (config.width, config.height);
+    match config.region {
+        Some((x0, row0, x1, row1)) => {
+            let (x1, row1) = (x1.min(width), row1.min(height));
+            (x0.min(x1), row0.min(row1), x1, row1)
+        },
+        None => (0, 0, width, height),
+    }
+}
+
+/// Tiles of `tile_size` covering `area`, row by row from the top left.
+/// Tiles on the right and bottom edges may be smaller.
+fn tiles(area: Tile, tile_size: usize) -> Vec<Tile> {
+    let (x_min, row_min, x_max, row_max) = area;
+    let mut tiles = Vec::new();
+    for row0 in (row_min..row_max).step_by(tile_size) {
+        for x0 in (x_min..x_max).step_by(tile_size) {
+            tiles.push((x0, row0, (x0 + tile_size).min(x_max), (row0 + tile_size).min(row_max)));
+        }
+    }
+    tiles
+}
+
+/// Colors of the pixels in `tile`, in row-major order.
+fn render_tile(scene: &Scene, config: &RenderConfig, tile: Tile) -> Vec<Vector3> {
+    let (x0, row0, x1, row1) = tile;
+    let mut colors = Vec::with_capacity((x1 - x0) * (row1 - row0));
+    for row in row0..row1 {
+        for x in x0..x1 {
+            colors.push(render_pixel(scene, config, x, config.height-1-row));
+        }
+    }
+    colors
+}
+
+/// Copies the colors of a rendered tile into the image.
+fn write_tile(pixels: &mut [Vector3], width: usize, tile: Tile, colors: &[Vector3]) {
+    let (x0, row0, x1, _) = tile;
+    for (i, line) in colors.chunks(x1 - x0).enumerate() {
+        let start = (row0 + i)*width + x0;
+        pixels[start..start + line.len()].copy_from_slice(line);
+    }
+}
+
+/// Renders the scene into `config.width * config.height` tone mapped and
+/// gamma encoded colors, in row-major order starting from the top row.
+pub fn raytrace(scene: &Scene, config: &RenderConfig) -> Vec<Vector3> {
+    let (width, height) = (config.width, config.height);
+    let mut pixels: Vec<Vector3> = vec![v3!(0.0, 0.0, 0.0); width*height];
+    let area = render_area(config);
+    if config.tile_size > 0 {
+        let tiles = tiles(area, config.tile_size);
+        for (i, &tile) in tiles.iter().enumerate() {
+            write_tile(&mut pixels, width, tile, &render_tile(scene, config, tile));
+            if let Some(ref callback) = config.progress_callback {
+                callback(i + 1, tiles.len());
+            }
+        }
+        return pixels;
+    }
+    let (x_min, row_min, x_max, row_max) = area;
+    for row in row_min..row_max {
+        for x in x_min..x_max {
+            pixels[row*width+x] = render_pixel(scene, config, x, height-1-row);
+        }
+        if let Some(ref callback) = config.progress_callback {
+            callback(row - row_min + 1, row_max - row_min);
+        }
+    }
+    pixels
+}
+
+/// Renders the image a scan-line at a time, top row first, and writes it
+/// to `writer` as a binary PPM, the same as `PpmWriter` would write the
+/// pixels of `raytrace`. Only one line of pixels is held at a time, so
+/// images too large for memory can be rendered straight to a file. Each
+/// line is rendered in parallel; `tile_size` is ignored.
+pub fn render_streaming<W: io::Write>(scene: &Scene, config: &RenderConfig,
+                                      writer: &mut W) -> io::Result<()> {
+    let (width, height) = (config.width, config.height);
+    let (x_min, row_min, x_max, row_max) = render_area(config);
+    writeln!(writer, "P6 {} {} 255", width, height)?;
+    let mut line = vec![0; width*3];
+    for row in 0..height {
+        let pixels: Vec<(u8, u8, u8)> = (0..width).into_par_iter().map(|x| {
+            if x < x_min || x >= x_max || row < row_min || row >= row_max {
+                return (0, 0, 0);
+            }
+            render_pixel(scene, config, x, height-1-row).to_rgb_gamma(1.0)
+        }).collect();
+        for (bytes, (r, g, b)) in line.chunks_mut(3).zip(pixels) {
+            bytes.copy_from_slice(&[r, g, b]);
+        }
+        writer.write_all(&line)?;
+        if row >= row_min && row < row_max {
+            if let Some(ref callback) = config.progress_callback {
+                callback(row - row_min + 1, row_max - row_min);
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// Same as `raytrace`, but renders scan-lines, or tiles, in parallel on
+/// the rayon thread pool. The scene is shared read-only, which is why
+/// `Geometry` requires `Send + Sync`. The output is identical to
+/// `raytrace`.
+pub fn raytrace_parallel(scene: &Scene, config: &RenderConfig) -> Vec<Vector3> {
+    raytrace_counted(scene, config, &RayCounter::default())
+}
+
+/// Left and right eye images for stereoscopic viewing, rendered with
+/// `raytrace_parallel` through copies of `camera` moved `eye_separation /
+/// 2` to either side along its `right` vector. Each pixel's random
+/// numbers depend only on its position, so both eyes use the same ones
+/// and path tracing noise matches between them instead of flickering.
+pub fn stereo_render(scene: &Scene, camera: &Camera, eye_separation: f64,
+                     config: &RenderConfig) -> (Vec<Vector3>, Vec<Vector3>) {
+    let offset = camera.right.normalize() * (eye_separation / 2.0);
+    let eye = |offset: Vector3| {
+        let camera = Camera { pos: camera.pos + offset, ..camera.clone() };
+        raytrace_parallel(scene, &RenderConfig { camera: Some(camera), ..config.clone() })
+    };
+    (eye(-offset), eye(offset))
+}
+
+/// `raytrace_parallel`, counting the rays it casts in `counter`.
+fn raytrace_counted(scene: &Scene, config: &RenderConfig, counter: &RayCounter) -> Vec<Vector3> {
+    let (width, height) = (config.width, config.height);
+    let mut pixels: Vec<Vector3> = vec![v3!(0.0, 0.0, 0.0); width*height];
+    let done = AtomicUsize::new(0);
+    let area = render_area(config);
+    if config.tile_size > 0 {
+        let tiles = tiles(area, config.tile_size);
+        let rendered: Vec<Vec<Vector3>> = tiles.par_iter().map(|&tile| {
+            let colors = counter.scope(|| render_tile(scene, config, tile));
+            if let Some(ref callback) = config.progress_callback {
+                callback(done.fetch_add(1, Ordering::Relaxed) + 1, tiles.len());
+            }
+            colors
+        }).collect();
+        for (&tile, colors) in tiles.iter().zip(&rendered) {
+            write_tile(&mut pixels, width, tile, colors);
+        }
+        return pixels;
+    }
+    let (x_min, row_min, x_max, row_max) = area;
+    let rows = &mut pixels[row_min*width..row_max*width];
+    rows.par_chunks_mut(width).enumerate().for_each(|(i, line)| {
+        let y = height-1-(row_min + i);
+        counter.scope(|| {
+            for (x, pixel) in line.iter_mut().enumerate().take(x_max).skip(x_min) {
+                *pixel = render_pixel(scene, config, x, y);
+            }
+        });
+        if let Some(ref callback) = config.progress_callback {
+            callback(done.fetch_add(1, Ordering::Relaxed) + 1, row_max - row_min);
+        }
+    });
+    pixels
+}
+
+/// Rendered image with the depth and surface normal seen through each
+/// pixel, for post-processing such as denoising or edge detection. Every
+/// buffer is in row-major order starting from the top row.
+pub struct RenderBuffer {
+    /// Tone mapped and gamma encoded colors, as returned by `raytrace`.
+    pub color: Vec<Vector3>,
+    /// Distance along the primary ray to the nearest hit, or infinity where
+    /// the ray hits nothing.
+    pub depth: Vec<f64>,
+    /// Normal at the nearest hit, or None where the ray hits nothing.
+    pub normal: Vec<Option<Vector3>>,
+    pub width: usize,
+    pub height: usize,
+    pub stats: RenderStats,
+}
+
+/// What went into a `raytrace_full` render.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    /// Camera rays, one per pixel and anti-aliasing sample.
+    pub primary_rays: u64,
+    pub shadow_rays: u64,
+    /// Rays followed after a bounce: mirror reflections, refractions and
+    /// path tracing bounces.
+    pub reflection_rays: u64,
+    /// Rays of every kind that hit something. The offset rays that
+    /// measure texture footprints aren't counted.
+    pub total_intersections: u64,
+    /// Time for the whole render, including the depth and normal pass.
+    pub elapsed_ms: u64,
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} primary, {} shadow and {} reflection rays, {} hits in {} ms",
+               self.primary_rays, self.shadow_rays, self.reflection_rays,
+               self.total_intersections, self.elapsed_ms)
+    }
+}
+
+/// Rays cast on one thread, added up into a `RayCounter`.
+#[derive(Copy, Clone, Default)]
+struct RayCounts {
+    primary: u64,
+    shadow: u64,
+    reflection: u64,
+    hits: u64,
+}
+
+thread_local! {
+    /// Rays cast on this thread since the innermost `RayCounter::scope`
+    /// began. Plain per-thread counters, so that counting rays costs no
+    /// synchronization in the render loop.
+    static RAY_COUNTS: Cell<RayCounts> = const {
+        Cell::new(RayCounts { primary: 0, shadow: 0, reflection: 0, hits: 0 })
+    };
+}
+
+fn count_rays(f: impl FnOnce(&mut RayCounts)) {
+    RAY_COUNTS.with(|counts| {
+        let mut c = counts.get();
+        f(&mut c);
+        counts.set(c);
+    });
+}
+
+/// Ray counts of one render, added up from every thread that works on it.
+/// Each render has its own, so renders running at the same time, even on
+/// the same rayon pool, don't count each other's rays.
+#[derive(Default)]
+struct RayCounter {
+    totals: [AtomicU64; 4],
+}
+
+impl RayCounter {
+    /// Runs `f` on this thread, counting the rays it casts here.
+    fn scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        // Whatever an enclosing scope counted so far is set aside, so that
+        // it stays out of this one and is restored for the rest of it.
+        let outer = RAY_COUNTS.with(|counts| counts.replace(RayCounts::default()));
+        let result = f();
+        let c = RAY_COUNTS.with(|counts| counts.replace(outer));
+        for (total, n) in self.totals.iter().zip(&[c.primary, c.shadow, c.reflection, c.hits]) {
+            total.fetch_add(*n, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Counts so far, leaving the counter at zero.
+    fn take(&self) -> RenderStats {
+        let [primary_rays, shadow_rays, reflection_rays, total_intersections] =
+            [0, 1, 2, 3].map(|i| self.totals[i].swap(0, Ordering::Relaxed));
+        RenderStats { primary_rays, shadow_rays, reflection_rays, total_intersections, elapsed_ms: 0 }
+    }
+}
+
+/// Like `raytrace_parallel`, but also records the depth and normal of the
+/// primary ray through each pixel's corner, and counts the rays cast.
+/// Pixels outside `config.region` are treated as misses.
+pub fn raytrace_full(scene: &Scene, config: &RenderConfig) -> RenderBuffer {
+    let (width, height) = (config.width, config.height);
+    let start = Instant::now();
+    let counter = RayCounter::default();
+    let color = raytrace_counted(scene, config, &counter);
+    let mut stats = counter.take();
+    let (depth, normal) = first_hits(scene, config);
+    stats.elapsed_ms = start.elapsed().as_millis() as u64;
+    if config.print_stats {
+        eprintln!("{}", stats);
+    }
+    RenderBuffer { color, depth, normal, width, height, stats }
+}
+
+/// Depth and normal of the primary ray through each pixel's corner, for
+/// `RenderBuffer`.
+fn first_hits(scene: &Scene, config: &RenderConfig) -> (Vec<f64>, Vec<Option<Vector3>>) {
+    let (width, height) = (config.width, config.height);
+    let (x_min, row_min, x_max, row_max) = render_area(config);
+    let hits: Vec<Option<Intersection>> = (0..width*height).into_par_iter().map(|i| {
+        let (x, row) = (i % width, i / width);
+        if x < x_min || x >= x_max || row < row_min || row >= row_max {
+            return None;
+        }
+        let y = height-1-row;
+        let mut rng = Rng::new((y*width + x) as u64);
+        let ray = primary_ray(render_camera(scene, config), x as f64, y as f64, width, height,
+                              config.max_ray_distance, &mut rng);
+        cast_ray(scene, &ray)
+    }).collect();
+    (hits.iter().map(|h| h.as_ref().map_or(f64::INFINITY, |i| i.dist)).collect(),
+     hits.iter().map(|h| h.as_ref().map(|i| i.normal)).collect())
+}
+
+/// Debug view of where glass focuses light, for setting up photon map
+/// caustics. The primary ray through each pixel's corner is refracted
+/// through the transparent surfaces it meets, ignoring reflection, for up
+/// to `config.max_depth` of them, and where it lands on an opaque surface
+/// that surface's pixel gains 1 in every channel. Rays that miss, meet no
+/// glass or are totally internally reflected land nowhere. Values well
+/// above 1 mark focused spots; glass is looked through when finding the
+/// landing pixel, so spots behind a lens show through it. Pixels outside
+/// `config.region` cast no rays but can still be landed on.
+pub fn raytrace_caustic_debug(scene: &Scene, config: &RenderConfig) -> Vec<Vector3> {
+    let (width, height) = (config.width, config.height);
+    let (x_min, row_min, x_max, row_max) = render_area(config);
+    let landings: Vec<Option<usize>> = (0..width*height).into_par_iter().map(|i| {
+        let (x, row) = (i % width, i / width);
+        if x < x_min || x >= x_max || row < row_min || row >= row_max {
+            return None;
+        }
+        let y = height-1-row;
+        let mut rng = Rng::new((y*width + x) as u64);
+        let mut ray = primary_ray(render_camera(scene, config), x as f64, y as f64, width, height,
+                                  config.max_ray_distance, &mut rng);
+        for depth in 0..=config.max_depth {
+            let isect = nearest_hit(scene, &ray)?;
+            if isect.material.transparency <= 0.0 {
+                return if depth > 0 { visible_pixel(scene, config, isect.pos) } else { None };
+            }
+            let ior = isect.material.refraction;
+            let (normal, eta) = if ray.dir.dot(isect.normal) > 0.0 {
+                (-isect.normal, ior)
+            } else {
+                (isect.normal, 1.0 / ior)
+            };
+            let dir = ray.dir.refract(normal, eta)?;
+            ray = Ray { origin: isect.pos+dir*0.001, dir, t_max: ray.t_max };
+        }
+        None
+    }).collect();
+    let mut image = vec![v3!(0.0, 0.0, 0.0); width*height];
+    for pixel in landings.into_iter().flatten() {
+        image[pixel] += v3!(1.0, 1.0, 1.0);
+    }
+    image
+}
+
+/// Row-major index of the pixel whose pinhole camera ray passes through
+/// `pos`, or None if `pos` is outside the image or hidden behind an opaque
+/// object.
+fn visible_pixel(scene: &Scene, config: &RenderConfig, pos: Vector3) -> Option<usize> {
+    let (width, height) = (config.width, config.height);
+    let camera = render_camera(scene, config);
+    let forward = camera.right.cross(&camera.up).normalize();
+    let to_pos = pos - camera.pos;
+    let depth = to_pos.dot(forward);
+    if depth <= 0.0 {
+        return None;
+    }
+    // Offset from the image center, in the plane of `right` and `up`.
+    let (origin, offset) = match camera.projection {
+        Projection::Perspective => (camera.pos, to_pos*(camera.dist / depth) - forward*camera.dist),
+        Projection::Orthographic => (pos - forward*depth, to_pos - forward*depth),
+    };
+    let u = offset.dot(camera.right) / camera.right.dot(camera.right);
+    let v = offset.dot(camera.up) / camera.up.dot(camera.up);
+    let (x, y) = ((u + 1.0) * 0.5 * width as f64, (v + 1.0) * 0.5 * height as f64);
+    if !(0.0..width as f64).contains(&x) || !(0.0..height as f64).contains(&y) {
+        return None;
+    }
+    let dist = (pos - origin).length();
+    let mut ray = Ray { origin, dir: (pos - origin) * (1.0 / dist), t_max: dist };
+    while let Some(isect) = nearest_hit(scene, &ray) {
+        if isect.dist > ray.t_max - 1e-3 {
+            break;
+        }
+        if isect.material.transparency <= 0.0 {
+            return None;
+        }
+        ray = Ray { origin: isect.pos+ray.dir*0.001, dir: ray.dir, t_max: ray.t_max - isect.dist - 0.001 };
+    }
+    Some((height-1 - y as usize)*width + x as usize)
+}
+
+/// Renders `passes` passes of the image, each with new random samples, and
+/// averages them, saving progress to `checkpoint_path` at least every
+/// `interval` and once more at the end. If the file already holds a
+/// checkpoint of an image of this size, rendering carries on from it
+/// instead of starting over, so an interrupted render loses at most
+/// `interval` of work; calling again with more `passes` refines a
+/// finished one. A checkpoint of a different scene or of different
+/// settings, see `render_fingerprint`, is an error. Meant for long
+/// `PathTrace` renders, where each pass adds paths to every pixel.
+///
+/// The checkpoint's `color` holds the sum of the linear colors of the
+/// passes so far. The returned buffer is like `raytrace_full`'s, with the
+/// average tone mapped and gamma encoded, and stats covering every pass.
+pub fn raytrace_resumable(scene: &Scene, config: &RenderConfig, passes: usize,
+                          checkpoint_path: &str, interval: Duration) -> io::Result<RenderBuffer> {
+    let (width, height) = (config.width, config.height);
+    let fingerprint = render_fingerprint(scene, config);
+    let (mut buffer, mut done) = match load_checkpoint(checkpoint_path) {
+        Ok((buffer, done, saved)) => {
+            if (buffer.width, buffer.height) != (width, height) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("checkpoint is {}x{}, not {}x{}", buffer.width,
+                                                  buffer.height, width, height)));
+            }
+            if saved != fingerprint {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "checkpoint is of a different scene or settings"));
+            }
+            (buffer, done)
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let (depth, normal) = first_hits(scene, config);
+            (RenderBuffer { color: vec![v3!(0.0, 0.0, 0.0); width*height], depth, normal,
+                            width, height, stats: RenderStats::default() },
+             0)
+        },
+        Err(e) => return Err(e),
+    };
+
+    let (x_min, row_min, x_max, row_max) = render_area(config);
+    let counter = RayCounter::default();
+    let mut start = Instant::now();
+    let mut last_save = Instant::now();
+    let add_stats = |buffer: &mut RenderBuffer, start: &mut Instant| {
+        let counts = counter.take();
+        let stats = &mut buffer.stats;
+        stats.primary_rays += counts.primary_rays;
+        stats.shadow_rays += counts.shadow_rays;
+        stats.reflection_rays += counts.reflection_rays;
+        stats.total_intersections += counts.total_intersections;
+        stats.elapsed_ms += start.elapsed().as_millis() as u64;
+        *start = Instant::now();
+    };
+    while done < passes {
+        let rows = &mut buffer.color[row_min*width..row_max*width];
+        rows.par_chunks_mut(width).enumerate().for_each(|(i, line)| {
+            let y = height-1-(row_min + i);
+            counter.scope(|| {
+                for (x, pixel) in line.iter_mut().enumerate().take(x_max).skip(x_min) {
+                    *pixel += sample_pixel(scene, config, x, y, done);
+                }
+            });
+        });
+        done += 1;
+        if let Some(ref callback) = config.progress_callback {
+            callback(done, passes);
+        }
+        if last_save.elapsed() >= interval || done == passes {
+            add_stats(&mut buffer, &mut start);
+            save_checkpoint(checkpoint_path, &buffer, done, fingerprint)?;
+            last_save = Instant::now();
+        }
+    }
+    add_stats(&mut buffer, &mut start);
+    if config.print_stats {
+        eprintln!("{}", buffer.stats);
+    }
+    let passes = done.max(1) as f64;
+    for color in buffer.color.iter_mut() {
+        *color = encode_pixel(scene, config, *color / passes);
+    }
+    Ok(buffer)
+}
+
+/// Hash of everything that goes into the linear colors `raytrace_resumable`
+/// adds up: the scene as `Scene::to_json` saves it, and the size,
+/// `max_depth`, `anti_alias`, `integrator`, `region`, `max_ray_distance`
+/// and `camera` of `config`. Tone mapping and gamma are applied
+/// afterwards, so they can change between runs. FNV-1a, which unlike
+/// `std`'s hasher is the same from one Rust release to the next.
+fn render_fingerprint(scene: &Scene, config: &RenderConfig) -> u64 {
+    let settings = format!("{} {} {} {:?} {:?} {:?} {:?} {:?}", config.width, config.height,
+                           config.max_depth, config.anti_alias, config.integrator, config.region,
+                           config.max_ray_distance.to_bits(), config.camera);
+    scene.to_json().bytes().chain(settings.bytes()).fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Renders `shutter_samples` frames at random times in the shutter
+/// interval, stratified so they cover it evenly, and averages them. Moving
+/// objects (see `Geometry::at_time`) are taken out of the scene for the
+/// duration, and a displaced copy of each is added for every frame.
+///
+/// This costs a full render per shutter sample, and the copies are tested
+/// one by one rather than through the BVH or grid, so keep the number of
+/// moving objects small. The scene is left as it was, apart from the BVH
+/// or grid being rebuilt if it had one.
+pub fn raytrace_motion_blur(scene: &mut Scene, config: &RenderConfig,
+                            shutter_samples: usize) -> Vec<Vector3> {
+    let (width, height) = (config.width, config.height);
+    let kind = scene.accel.as_ref().map(|accel| accel.kind());
+    let (moving, still): (Vec<_>, Vec<_>) =
+        scene.take_objects().into_iter().partition(|o| o.at_time(0.0).is_some());
+    scene.objects = still;
+    if let Some(kind) = kind {
+        scene.build_acceleration(kind);
+    }
+    let static_count = scene.objects.len();
+
+    let samples = shutter_samples.max(1);
+    let mut rng = Rng::new(0);
+    let mut sum = vec![v3!(0.0, 0.0, 0.0); width*height];
+    let lines_done = AtomicUsize::new(0);
+    for i in 0..samples {
+        let t = (i as f64 + rng.next_f64()) / samples as f64;
+        scene.objects.extend(moving.iter().filter_map(|o| o.at_time(t)));
+        let frame = &*scene;
+        sum.par_chunks_mut(width).enumerate().for_each(|(row, line)| {
+            let y = height-1-row;
+            for (x, pixel) in line.iter_mut().enumerate() {
+                *pixel += sample_pixel(frame, config, x, y, 0);
+            }
+            if let Some(ref callback) = config.progress_callback {
+                callback(lines_done.fetch_add(1, Ordering::Relaxed) + 1, height*samples);
+            }
+        });
+        scene.objects.truncate(static_count);
+    }
+
+    scene.objects.extend(moving);
+    if let Some(kind) = kind {
+        scene.build_acceleration(kind);
+    }
+    sum.into_iter().map(|c| encode_pixel(scene, config, c / samples as f64)).collect()
+}
+
+/// `raytrace` with the default options at the given size.
+#[deprecated(note = "use `raytrace` with a `RenderConfig`")]
+pub fn raytrace_simple(scene: &Scene, width: usize, height: usize) -> Vec<Vector3> {
+    raytrace(scene, &RenderConfig { width, height, ..RenderConfig::default() })
+}
+
+pub fn scene() -> Scene {
+    let cam = Camera {
+        pos: v3!(0.0, 0.0, -1.0),
+        up: v3!(0.0, 1.0, 0.0),
+        right: v3!(1.33, 0.0, 0.0),
+        dist: 2.0,
+        aperture: 0.0,
+        focus_distance: 1.0,
+        projection: Projection::Perspective,
+    };
+    Scene { camera: cam, lights: vec![], objects: vec![], accel: None,
+            generation: 0, accel_generation: 0,
+            tone_mapper: ToneMapper::default(), background: Background::default(),
+            ambient: v3!(0.1, 0.1, 0.1), fog: None, photon_map: None, materials: vec![],
+            light_portals: vec![], shadowing_mode: ShadowMode::default(), root: SceneNode::default() }
+}
+
+/// Builds a `Scene` with chained calls, starting from `scene()`, e.g.
+/// `SceneBuilder::new().sphere(pos, 1.0, material).light(pos, color).build()`.
+pub struct SceneBuilder {
+    scene: Scene,
+}
+
+impl Default for SceneBuilder {
+    fn default() -> SceneBuilder {
+        SceneBuilder::new()
+    }
+}
+
+impl SceneBuilder {
+    pub fn new() -> SceneBuilder {
+        SceneBuilder { scene: scene() }
+    }
+
+    pub fn camera(mut self, camera: Camera) -> SceneBuilder {
+        self.scene.camera = camera;
+        self
+    }
+
+    pub fn background(mut self, background: Background) -> SceneBuilder {
+        self.scene.background = background;
+        self
+    }
+
+    /// Adds any geometry, for types without a method of their own.
+    pub fn object<T: Geometry + 'static>(mut self, g: T) -> SceneBuilder {
+        self.scene.add(g);
+        self
+    }
+
+    pub fn sphere(self, pos: Vector3, radius: f64,
+                  material: impl Into<MaterialRef>) -> SceneBuilder {
+        self.object(Sphere { pos, radius, material: material.into(), motion: None })
+    }
+
+    pub fn bbox(self, v1: Vector3, v2: Vector3, material: impl Into<MaterialRef>) -> SceneBuilder {
+        self.object(BBox { v1, v2, material: material.into(), motion: None })
+    }
+
+    pub fn plane(self, pos: Vector3, normal: Vector3, material: impl Into<MaterialRef>) -> SceneBuilder {
+        self.object(Plane { pos, normal, material: material.into(), uv_scale: 1.0 })
+    }
+
+    /// Adds any light source.
+    pub fn add_light<T: LightSource + 'static>(mut self, l: T) -> SceneBuilder {
+        self.scene.add_light(l);
+        self
+    }
+
+    /// Adds a point light whose intensity doesn't fall off with distance.
+    pub fn light(self, pos: Vector3, color: Vector3) -> SceneBuilder {
+        self.add_light(Light { pos, color, intensity: 1.0, attenuation: (1.0, 0.0, 0.0) })
+    }
+
+    pub fn build(self) -> Scene {
+        self.scene
+    }
+}
+
+
+pub fn basic_material(color: Vector3) -> Material {
+    Material { shininess: 16.0,
+               spec_color: v3!(1.0, 1.0, 1.0),
+               color,
+               reflection: 0.0,
+               refraction: 1.0,
+               transparency: 0.0,
+               emission: v3!(0.0, 0.0, 0.0),
+               texture: None,
+               normal_map: None,
+               brdf: BrdfModel::BlinnPhong,
+               two_sided: false }
+}
+
+/// Material that glows with `emission` and reflects no light.
+pub fn emissive_material(emission: Vector3) -> Material {
+    Material { spec_color: v3!(0.0, 0.0, 0.0),
+               emission,
+               ..basic_material(v3!(0.0, 0.0, 0.0)) }
+}
+
+/// Point light with physically based inverse-square falloff.
+pub fn point_light(pos: Vector3, color: Vector3, intensity: f64) -> Light {
+    Light { pos,
+            color,
+            intensity,
+            attenuation: (0.0, 0.0, 1.0) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use output::{ImageWriter, PpmWriter};
+    use scenes::random_scene;
+    use texture::{FilterMode, ImageTexture, NoiseSpace, NormalMap, PerlinTexture, WrapMode};
+
+    /// Color of pixel (`x`, `y`), with y counted down from the top row, in
+    /// a `w` x `h` render of `scene`, linear and without tone mapping.
+    fn render_pixel(scene: &Scene, x: usize, y: usize, w: usize, h: usize) -> Vector3 {
+        let config = RenderConfig { width: w,
+                                    height: h,
+                                    tone_mapper: Some(ToneMapper::Linear),
+                                    gamma: 1.0,
+                                    ..RenderConfig::default() };
+        raytrace(scene, &config)[y*w + x]
+    }
+
+    /// `scene()` with a sphere straight ahead of the camera, lit from the
+    /// camera's side.
+    fn lit_sphere(color: Vector3) -> Scene {
+        SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, basic_material(color))
+            .light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0))
+            .build()
+    }
+
+    #[test]
+    fn sphere_in_center_is_not_black() {
+        let p = render_pixel(&lit_sphere(v3!(1.0, 1.0, 1.0)), 16, 16, 32, 32);
+        assert!(p.x > 0.0 && p.y > 0.0 && p.z > 0.0, "center pixel is {:?}", p);
+    }
+
+    #[test]
+    fn empty_scene_shows_background() {
+        let mut scene = scene();
+        scene.background = Background::Solid(v3!(0.2, 0.4, 0.6));
+        for &(x, y) in &[(0, 0), (16, 16), (31, 31)] {
+            let p = render_pixel(&scene, x, y, 32, 32);
+            assert!((p - v3!(0.2, 0.4, 0.6)).length() < 1e-12, "pixel ({}, {}) is {:?}", x, y, p);
+        }
+    }
+
+    #[test]
+    fn red_sphere_is_red() {
+        let p = render_pixel(&lit_sphere(v3!(1.0, 0.0, 0.0)), 16, 16, 32, 32);
+        assert!(p.x > p.y && p.x > p.z, "center pixel is {:?}", p);
+    }
+
+    #[cfg(feature = "debug_api")]
+    #[test]
+    fn bbox_debug_intersect_matches_intersects() {
+        let bbox = BBox { v1: v3!(-1.0, -2.0, 3.0), v2: v3!(1.0, 2.0, 5.0),
+                          material: basic_material(v3!(1.0, 1.0, 1.0)).into(), motion: None };
+        let ray = Ray { origin: v3!(-3.0, 0.5, 0.0), dir: v3!(0.5, 0.0, 1.0).normalize(),
+                        t_max: f64::INFINITY };
+        let hit = bbox.intersects(&ray).unwrap();
+        let values = debug_intersect(&bbox, &ray);
+        assert_eq!(values["z_tnear"], hit.dist);
+        assert_eq!((values["z_nx"], values["z_ny"], values["z_nz"]),
+                   (hit.normal.x, hit.normal.y, hit.normal.z));
+    }
+
+    #[test]
+    fn fresnel_schlick_limits() {
+        assert!((fresnel_schlick(1.0, 0.04) - 0.04).abs() < 1e-12);
+        assert!((fresnel_schlick(0.0, 0.04) - 1.0).abs() < 1e-12);
+        assert!(fresnel_schlick(0.5, 0.04) > 0.04 && fresnel_schlick(0.5, 0.04) < 1.0);
+    }
+
+    #[test]
+    fn reflection_blends_with_surface_color() {
+        // Head-on, half of the glow is kept and half replaced by the black
+        // background it reflects.
+        let material = basic_material(v3!(0.0, 0.0, 0.0))
+            .with_emission(v3!(0.5, 0.5, 0.5))
+            .with_reflection(0.5);
+        let scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, material)
+            .build();
+        let p = render_pixel(&scene, 16, 16, 32, 32);
+        assert!((p - v3!(0.25, 0.25, 0.25)).length() < 1e-3, "center pixel is {:?}", p);
+    }
+
+    #[test]
+    fn hits_beyond_t_max_are_ignored() {
+        // The sphere's front is 5 units from the camera.
+        let scene = lit_sphere(v3!(1.0, 1.0, 1.0));
+        let ray = |t_max| Ray { origin: v3!(0.0, 0.0, -1.0), dir: v3!(0.0, 0.0, 1.0), t_max };
+        assert!(cast_ray(&scene, &ray(4.9)).is_none());
+        assert!(cast_ray(&scene, &ray(5.0)).is_some());
+        assert!(cast_ray(&scene, &ray(f64::INFINITY)).is_some());
+
+        let config = RenderConfig { width: 32,
+                                    height: 32,
+                                    max_ray_distance: 4.9,
+                                    print_stats: false,
+                                    ..RenderConfig::default() };
+        assert_eq!(raytrace(&scene, &config)[16*32 + 16], v3!(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn streaming_matches_in_memory_render() {
+        let scene = lit_sphere(v3!(1.0, 0.5, 0.25));
+        let config = RenderConfig { width: 40, height: 30, print_stats: false, ..RenderConfig::default() };
+        let mut expected = Vec::new();
+        PpmWriter.write(&raytrace(&scene, &config), config.width, config.height, &mut expected).unwrap();
+        let mut streamed = io::Cursor::new(Vec::new());
+        render_streaming(&scene, &config, &mut streamed).unwrap();
+        assert!(streamed.into_inner() == expected, "streamed PPM differs from the in-memory render");
+    }
+
+    #[test]
+    fn resumed_render_matches_uninterrupted_one() {
+        let scene = lit_sphere(v3!(1.0, 0.5, 0.25));
+        let config = RenderConfig { width: 24,
+                                    height: 16,
+                                    integrator: IntegratorMode::PathTrace(1),
+                                    print_stats: false,
+                                    ..RenderConfig::default() };
+        let dir = std::env::temp_dir();
+        let path = |name: &str| {
+            dir.join(format!("rust_raytracer_{}_{}.bin", name, std::process::id()))
+                .to_str().unwrap().to_string()
+        };
+        let (resumed, whole) = (path("resumed"), path("whole"));
+        raytrace_resumable(&scene, &config, 2, &resumed, Duration::ZERO).unwrap();
+        let first = raytrace_resumable(&scene, &config, 4, &resumed, Duration::ZERO).unwrap();
+        let second = raytrace_resumable(&scene, &config, 4, &whole, Duration::from_secs(3600)).unwrap();
+        for path in [&resumed, &whole] {
+            std::fs::remove_file(path).unwrap();
+        }
+        let bits = |buffer: &RenderBuffer| -> Vec<[u64; 3]> {
+            buffer.color.iter().map(|c| c.as_array().map(f64::to_bits)).collect()
+        };
+        assert!(bits(&first) == bits(&second), "resuming changed the image");
+    }
+
+    #[test]
+    fn kelvin_colors() {
+        let color = |kelvin| Light::from_kelvin(v3!(0.0, 0.0, 0.0), kelvin, 1.0).color;
+        let daylight = color(6500.0);
+        assert!(daylight.min_component() > 0.95, "6500 K is {:?}", daylight);
+        let tungsten = color(2700.0);
+        assert!(tungsten.x == 1.0 && tungsten.y > 0.5 && tungsten.y < 0.8 && tungsten.z < 0.5,
+                "2700 K is {:?}", tungsten);
+        let sky = color(10000.0);
+        assert!(sky.z == 1.0 && sky.x < sky.y && sky.y < sky.z, "10000 K is {:?}", sky);
+    }
+
+    #[test]
+    fn disk_samples_cover_it_uniformly() {
+        use std::f64::consts::PI;
+        let disk = Disk { center: v3!(1.0, 2.0, 3.0), normal: v3!(0.0, 1.0, 1.0).normalize(),
+                          radius: 2.0, material: basic_material(v3!(1.0, 1.0, 1.0)).into() };
+        let from = disk.center + disk.normal*3.0;
+        let mut rng = Rng::new(5);
+        let n = 20000;
+        let (mut inner, mut solid_angle) = (0, 0.0);
+        for _ in 0..n {
+            let (p, normal, pdf) = disk.sample_surface(rng.next_f64(), rng.next_f64());
+            let d = p - disk.center;
+            assert!(d.dot(disk.normal).abs() < 1e-9 && d.length() <= disk.radius + 1e-9);
+            assert!((normal - disk.normal).length() < 1e-9);
+            assert!((pdf - 1.0 / (PI*4.0)).abs() < 1e-12);
+            if d.length() < disk.radius / 2.0 {
+                inner += 1;
+            }
+            solid_angle += 1.0 / disk.pdf_surface(from, p);
+        }
+        // A quarter of the area lies within half the radius.
+        let inner = inner as f64 / n as f64;
+        assert!((inner - 0.25).abs() < 0.01, "{} of the samples are inside", inner);
+        // Averaging 1 / pdf estimates the solid angle the disk subtends.
+        let expected = 2.0*PI*(1.0 - 3.0 / (3.0f64*3.0 + 2.0*2.0).sqrt());
+        let solid_angle = solid_angle / n as f64;
+        assert!((solid_angle - expected).abs() < 0.01*expected,
+                "solid angle {} instead of {}", solid_angle, expected);
+    }
+
+    #[test]
+    fn surface_pdf_is_dist_squared_over_cos_area() {
+        use std::f64::consts::PI;
+        let disk = Disk { center: v3!(0.0, 0.0, 0.0), normal: v3!(0.0, 0.0, 1.0), radius: 1.0,
+                          material: basic_material(v3!(1.0, 1.0, 1.0)).into() };
+        let from = v3!(0.0, 0.0, 2.0);
+        assert!((disk.pdf_surface(from, v3!(0.0, 0.0, 0.0)) - 4.0 / PI).abs() < 1e-12);
+        // Off center the distance grows to sqrt(4.25) and the cosine
+        // drops to 2 / sqrt(4.25).
+        let expected = 4.25f64.powf(1.5) / (2.0*PI);
+        assert!((disk.pdf_surface(from, v3!(0.5, 0.0, 0.0)) - expected).abs() < 1e-12);
+
+        let rect = BoundedPlane { pos: v3!(0.0, 0.0, 0.0), normal: v3!(0.0, 0.0, -1.0),
+                                  min: v3!(-1.0, -1.5, 0.0), max: v3!(1.0, 1.5, 0.0),
+                                  material: basic_material(v3!(1.0, 1.0, 1.0)).into() };
+        assert!((rect.pdf_surface(v3!(0.0, 0.0, -2.0), v3!(0.0, 0.0, 0.0)) - 4.0 / 6.0).abs() < 1e-12);
+        assert!((rect.pdf_surface(v3!(0.0, 0.0, -2.0), v3!(0.5, 0.0, 0.0)) - 4.25f64.powf(1.5) / 12.0).abs()
+                < 1e-12);
+    }
+
+    #[test]
+    fn sphere_and_rect_samples_lie_on_the_surface() {
+        use std::f64::consts::PI;
+        let sphere = Sphere { pos: v3!(1.0, 2.0, 3.0), radius: 2.0,
+                              material: basic_material(v3!(1.0, 1.0, 1.0)).into(), motion: None };
+        let rect = BoundedPlane { pos: v3!(0.0, 1.0, 0.0), normal: v3!(0.0, -1.0, 0.0),
+                                  min: v3!(-1.0, 1.0, 0.0), max: v3!(2.0, 1.0, 2.0),
+                                  material: basic_material(v3!(1.0, 1.0, 1.0)).into() };
+        let mut rng = Rng::new(9);
+        let n = 10000;
+        let (mut sphere_mean, mut rect_mean) = (v3!(0.0, 0.0, 0.0), v3!(0.0, 0.0, 0.0));
+        for _ in 0..n {
+            let (p, normal, pdf) = sphere.sample_surface(rng.next_f64(), rng.next_f64());
+            assert!(((p - sphere.pos).length() - 2.0).abs() < 1e-9);
+            assert!((normal - (p - sphere.pos)*0.5).length() < 1e-9);
+            assert!((pdf - 1.0 / (16.0*PI)).abs() < 1e-12);
+            sphere_mean += p*(1.0 / n as f64);
+
+            let (p, normal, pdf) = rect.sample_surface(rng.next_f64(), rng.next_f64());
+            assert!(p.y == 1.0 && p.x >= -1.0 && p.x <= 2.0 && p.z >= 0.0 && p.z <= 2.0, "{:?}", p);
+            assert_eq!(normal.as_array(), [0.0, -1.0, 0.0]);
+            assert!((pdf - 1.0 / 6.0).abs() < 1e-12);
+            rect_mean += p*(1.0 / n as f64);
+        }
+        // Uniform samples are centered on the shape.
+        assert!((sphere_mean - sphere.pos).length() < 0.05, "{:?}", sphere_mean);
+        assert!((rect_mean - v3!(0.5, 1.0, 1.0)).length() < 0.05, "{:?}", rect_mean);
+    }
+
+    fn torus() -> Torus {
+        Torus { center: v3!(1.0, 2.0, 3.0), major_radius: 2.0, minor_radius: 0.5,
+                material: basic_material(v3!(1.0, 1.0, 1.0)).into() }
+    }
+
+    /// Distance of the torus hit along the ray from `origin` towards
+    /// `dir`, both relative to the center, checking that the hit is on the
+    /// surface.
+    fn torus_hit(origin: Vector3, dir: Vector3) -> Option<f64> {
+        let torus = torus();
+        let ray = Ray { origin: torus.center + origin, dir: dir.normalize(), t_max: f64::INFINITY };
+        torus.intersects(&ray).map(|i| {
+            let p = i.pos - torus.center;
+            let ring = v3!(p.x, 0.0, p.z).normalize() * torus.major_radius;
+            assert!(((p - ring).length() - torus.minor_radius).abs() < 1e-6, "{:?} is off the surface", p);
+            assert!((i.normal - (p - ring).normalize()).length() < 1e-6);
+            assert!((i.pos - (ray.origin + ray.dir*i.dist)).length() < 1e-9);
+            i.dist
+        })
+    }
+
+    #[test]
+    fn torus_misses_along_its_axis() {
+        assert_eq!(torus_hit(v3!(0.0, 10.0, 0.0), v3!(0.0, -1.0, 0.0)), None);
+        assert_eq!(torus_hit(v3!(0.0, -10.0, 0.0), v3!(0.0, 1.0, 0.0)), None);
+    }
+
+    #[test]
+    fn torus_hit_through_the_tube() {
+        let t = torus_hit(v3!(-10.0, 0.0, 0.0), v3!(1.0, 0.0, 0.0)).unwrap();
+        assert!((t - 7.5).abs() < 1e-9, "hit at {}", t);
+        // From above, down through the middle of the tube.
+        let t = torus_hit(v3!(0.0, 10.0, 2.0), v3!(0.0, -1.0, 0.0)).unwrap();
+        assert!((t - 9.5).abs() < 1e-9, "hit at {}", t);
+        // From inside the tube, the far wall.
+        let t = torus_hit(v3!(2.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0)).unwrap();
+        assert!((t - 0.5).abs() < 1e-9, "hit at {}", t);
+    }
+
+    #[test]
+    fn torus_hole_is_empty() {
+        // Down through the hole, off the axis.
+        assert_eq!(torus_hit(v3!(1.4, 10.0, 0.0), v3!(0.0, -1.0, 0.0)), None);
+        // Out of the hole, the inner side of the tube.
+        let t = torus_hit(v3!(0.0, 0.0, 0.0), v3!(0.0, 0.0, -1.0)).unwrap();
+        assert!((t - 1.5).abs() < 1e-9, "hit at {}", t);
+        // Across the hole, at an angle, skipping the near side.
+        let t = torus_hit(v3!(-1.0, 0.0, 0.0), v3!(1.0, 0.0, 1.0)).unwrap();
+        let p = v3!(-1.0, 0.0, 0.0) + v3!(1.0, 0.0, 1.0).normalize()*t;
+        assert!(p.x > 0.0 && p.z > 0.0, "hit at {:?}", p);
+    }
+
+    #[test]
+    fn torus_grazing_rays() {
+        // Just under and over the top of the tube.
+        assert!(torus_hit(v3!(-10.0, 0.499, 0.0), v3!(1.0, 0.0, 0.0)).is_some());
+        assert_eq!(torus_hit(v3!(-10.0, 0.501, 0.0), v3!(1.0, 0.0, 0.0)), None);
+        // Just inside and outside the outer rim.
+        assert!(torus_hit(v3!(2.499, 0.0, -10.0), v3!(0.0, 0.0, 1.0)).is_some());
+        assert_eq!(torus_hit(v3!(2.501, 0.0, -10.0), v3!(0.0, 0.0, 1.0)), None);
+        // Along the top of the tube, touching both sides of the ring.
+        let t = torus_hit(v3!(-10.0, 0.5 - 1e-6, 0.0), v3!(1.0, 0.0, 0.0)).unwrap();
+        assert!((t - 8.0).abs() < 0.01, "hit at {}", t);
+        // Far away and nearly parallel to the tube top.
+        assert!(torus_hit(v3!(-1000.0, 0.49, 0.0), v3!(1.0, 0.0, 0.0)).is_some());
+    }
+
+    #[test]
+    fn stats_count_a_primary_ray_per_sample() {
+        let scene = lit_sphere(v3!(1.0, 1.0, 1.0));
+        let samples = [(AntiAlias::None, 1), (AntiAlias::Grid(3), 9), (AntiAlias::Stochastic(5), 5),
+                       (AntiAlias::Stratified(2), 4)];
+        for (anti_alias, spp) in samples {
+            for tile_size in [0, 7] {
+                let config = RenderConfig { width: 20, height: 15, anti_alias, tile_size,
+                                            print_stats: false, ..RenderConfig::default() };
+                let stats = raytrace_full(&scene, &config).stats;
+                assert_eq!(stats.primary_rays, 20*15*spp, "{:?} in tiles of {}", anti_alias, tile_size);
+                assert!(stats.shadow_rays > 0 && stats.total_intersections > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_renders_count_their_own_rays() {
+        let scene = lit_sphere(v3!(1.0, 1.0, 1.0));
+        let config = |width, height| RenderConfig { width, height, anti_alias: AntiAlias::Grid(2),
+                                                    print_stats: false, ..RenderConfig::default() };
+        let alone = |width, height| RenderStats { elapsed_ms: 0,
+                                                  ..raytrace_full(&scene, &config(width, height)).stats };
+        let (big, small) = (alone(40, 30), alone(10, 10));
+        for _ in 0..10 {
+            let (a, b) = rayon::join(|| raytrace_full(&scene, &config(40, 30)).stats,
+                                     || raytrace_full(&scene, &config(10, 10)).stats);
+            assert_eq!(RenderStats { elapsed_ms: 0, ..a }, big);
+            assert_eq!(RenderStats { elapsed_ms: 0, ..b }, small);
+        }
+    }
+
+    /// `scene()` with a white point light behind the camera and `objects`.
+    fn lit_scene(objects: Vec<Box<dyn Geometry>>) -> Scene {
+        let mut scene = SceneBuilder::new().light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0)).build();
+        scene.objects = objects;
+        scene
+    }
+
+    fn sphere(pos: Vector3, radius: f64, material: impl Into<MaterialRef>) -> Box<dyn Geometry> {
+        Box::new(Sphere { pos, radius, material: material.into(), motion: None })
+    }
+
+    #[test]
+    fn validate_accepts_a_sound_scene() {
+        let scene = lit_scene(vec![sphere(v3!(0.0, 0.0, 5.0), 1.0, basic_material(v3!(1.0, 1.0, 1.0)))]);
+        assert_eq!(scene.validate(), vec![]);
+    }
+
+    #[test]
+    fn validate_warns_about_no_objects() {
+        assert_eq!(lit_scene(vec![]).validate(), vec![SceneWarning::NoObjects]);
+    }
+
+    #[test]
+    fn validate_warns_about_no_lights() {
+        let mut scene = scene();
+        scene.add(Sphere { pos: v3!(0.0, 0.0, 5.0), radius: 1.0,
+                           material: basic_material(v3!(1.0, 1.0, 1.0)).into(), motion: None });
+        assert_eq!(scene.validate(), vec![SceneWarning::NoLights]);
+        // An emissive object lights the scene, also through the registry.
+        let glow = scene.register_material("glow", emissive_material(v3!(1.0, 1.0, 1.0)));
+        scene.add(Sphere { pos: v3!(3.0, 0.0, 5.0), radius: 1.0, material: glow.into(), motion: None });
+        assert_eq!(scene.validate(), vec![]);
+    }
+
+    #[test]
+    fn validate_warns_about_camera_inside_geometry() {
+        let white = basic_material(v3!(1.0, 1.0, 1.0));
+        let scene = lit_scene(vec![sphere(v3!(0.0, 0.0, 5.0), 1.0, white.clone()),
+                                   sphere(v3!(0.0, 0.0, 0.0), 2.0, white)]);
+        assert_eq!(scene.validate(), vec![SceneWarning::CameraInsideGeometry(1)]);
+        // Standing in the hole of a torus is outside it.
+        let torus = Torus { center: v3!(0.0, 0.0, -1.0), major_radius: 2.0, minor_radius: 0.5,
+                            material: basic_material(v3!(1.0, 1.0, 1.0)).into() };
+        assert_eq!(lit_scene(vec![Box::new(torus)]).validate(), vec![]);
+    }
+
+    #[test]
+    fn validate_ignores_camera_inside_registered_glass() {
+        let mut scene = lit_scene(vec![]);
+        let glass = scene.register_material(
+            "glass", basic_material(v3!(1.0, 1.0, 1.0)).with_transparency(0.9, 1.5));
+        scene.add(Sphere { pos: v3!(0.0, 0.0, 0.0), radius: 2.0, material: glass.into(), motion: None });
+        assert_eq!(scene.validate(), vec![]);
+    }
+
+    #[test]
+    fn planes_use_registered_materials() {
+        let mut scene = lit_scene(vec![]);
+        let floor = scene.register_material("floor", basic_material(v3!(1.0, 0.0, 0.0)));
+        let plane = Plane { pos: v3!(0.0, -1.0, 0.0), normal: v3!(0.0, 1.0, 0.0), material: floor.into(),
+                            uv_scale: 1.0 };
+        let ray = Ray { origin: v3!(0.0, 0.0, 0.0), dir: v3!(0.0, -1.0, 0.0), t_max: f64::INFINITY };
+        assert_eq!(plane.intersects(&ray).unwrap().material_id, Some(floor));
+        scene.register_material("floor", basic_material(v3!(0.0, 0.0, 1.0)));
+        assert_eq!(scene.object_material(&plane).color, v3!(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn validate_warns_about_dark_lights() {
+        let mut scene = lit_scene(vec![sphere(v3!(0.0, 0.0, 5.0), 1.0, basic_material(v3!(1.0, 1.0, 1.0)))]);
+        scene.add_light(Light { pos: v3!(0.0, 5.0, 0.0), color: v3!(0.0, 0.0, 0.0), intensity: 1.0,
+                                attenuation: (1.0, 0.0, 0.0) });
+        scene.add_light(Light { pos: v3!(0.0, 5.0, 0.0), color: v3!(1.0, 1.0, 1.0), intensity: 0.0,
+                                attenuation: (1.0, 0.0, 0.0) });
+        assert_eq!(scene.validate(),
+                   vec![SceneWarning::LightWithZeroColor(1), SceneWarning::LightWithZeroColor(2)]);
+    }
+
+    #[test]
+    fn validate_warns_about_negative_radius() {
+        let white = basic_material(v3!(1.0, 1.0, 1.0));
+        let scene = lit_scene(vec![sphere(v3!(0.0, 0.0, 5.0), 1.0, white.clone()),
+                                   sphere(v3!(3.0, 0.0, 5.0), -1.0, white)]);
+        assert_eq!(scene.validate(), vec![SceneWarning::NegativeRadius(1)]);
+    }
+
+    #[test]
+    fn validate_warns_about_only_mirrors() {
+        let mirror = basic_material(v3!(1.0, 1.0, 1.0)).with_reflection(1.0);
+        let scene = lit_scene(vec![sphere(v3!(0.0, 0.0, 5.0), 1.0, mirror.clone()),
+                                   sphere(v3!(3.0, 0.0, 5.0), 1.0, mirror.clone())]);
+        assert_eq!(scene.validate(), vec![SceneWarning::OnlyMirrors]);
+
+        // Registered mirrors count as mirrors too.
+        let mut scene = lit_scene(vec![]);
+        let id = scene.register_material("mirror", mirror);
+        scene.add(Sphere { pos: v3!(0.0, 0.0, 5.0), radius: 1.0, material: id.into(), motion: None });
+        assert_eq!(scene.validate(), vec![SceneWarning::OnlyMirrors]);
+    }
+
+    #[test]
+    fn objects_within_radius_finds_overlapping_spheres() {
+        let mut builder = SceneBuilder::new();
+        for i in 0..5 {
+            builder = builder.sphere(v3!(i as f64 * 2.0, 0.0, 0.0), 0.5, basic_material(v3!(1.0, 1.0, 1.0)));
+        }
+        let mut scene = builder.build();
+        let queries = [(v3!(0.0, 0.0, 0.0), 0.1, vec![0.0]),
+                       (v3!(0.0, 0.0, 0.0), 1.6, vec![0.0, 2.0]),
+                       (v3!(0.0, 0.0, 0.0), 3.6, vec![0.0, 2.0, 4.0]),
+                       (v3!(3.0, 0.0, 0.0), 0.6, vec![2.0, 4.0]),
+                       (v3!(3.0, 2.0, 0.0), 1.0, vec![]),
+                       (v3!(4.0, 0.0, 0.0), 100.0, vec![0.0, 2.0, 4.0, 6.0, 8.0])];
+        let kinds = [None,
+                     Some(AccelerationStructure::Bvh),
+                     Some(AccelerationStructure::Grid),
+                     Some(AccelerationStructure::KdTree { max_depth: 8, min_objects_per_leaf: 1 })];
+        for kind in kinds {
+            if let Some(kind) = kind {
+                scene.build_acceleration(kind);
+            }
+            // Building may reorder the objects, so compare their positions.
+            let centers: Vec<f64> = scene.all_objects()
+                .map(|o| o.bounding_box().unwrap().centroid().x)
+                .collect();
+            for &(center, radius, ref expected) in &queries {
+                let mut found: Vec<f64> = scene.objects_within_radius(center, radius)
+                    .into_iter().map(|i| centers[i]).collect();
+                found.sort_by(f64::total_cmp);
+                assert_eq!(&found, expected, "{:?} within {} using {:?}", center, radius, kind);
+            }
+        }
+    }
+
+    fn ray(origin: Vector3, dir: Vector3) -> Ray {
+        Ray { origin, dir: dir.normalize(), t_max: f64::INFINITY }
+    }
+
+    fn white() -> Material {
+        basic_material(v3!(1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn triangle_hits_inside_its_edges() {
+        let triangle = Triangle::new(v3!(0.0, 0.0, 5.0), v3!(2.0, 0.0, 5.0), v3!(0.0, 2.0, 5.0), white());
+        let hit = triangle.intersects(&ray(v3!(0.5, 0.5, 0.0), v3!(0.0, 0.0, 1.0))).unwrap();
+        assert_eq!(hit.dist, 5.0);
+        assert_eq!(hit.pos, v3!(0.5, 0.5, 5.0));
+        assert_eq!(hit.normal, v3!(0.0, 0.0, 1.0));
+        let (u, v) = hit.barycentric.unwrap();
+        assert!((u - 0.25).abs() < 1e-12 && (v - 0.25).abs() < 1e-12);
+        // From behind as well; the winding only sets the normal.
+        assert!(triangle.intersects(&ray(v3!(0.5, 0.5, 10.0), v3!(0.0, 0.0, -1.0))).is_some());
+        // Past each edge, behind the ray and parallel to the face.
+        for origin in [v3!(-0.1, 0.5, 0.0), v3!(0.5, -0.1, 0.0), v3!(1.1, 1.1, 0.0)] {
+            assert!(triangle.intersects(&ray(origin, v3!(0.0, 0.0, 1.0))).is_none(), "hit from {:?}", origin);
+        }
+        assert!(triangle.intersects(&ray(v3!(0.5, 0.5, 6.0), v3!(0.0, 0.0, 1.0))).is_none());
+        assert!(triangle.intersects(&ray(v3!(-1.0, 0.5, 5.0), v3!(1.0, 0.0, 0.0))).is_none());
+        // Degenerate triangles are never hit.
+        let line = Triangle::new(v3!(0.0, 0.0, 5.0), v3!(1.0, 1.0, 5.0), v3!(2.0, 2.0, 5.0), white());
+        assert!(line.intersects(&ray(v3!(1.0, 1.0, 0.0), v3!(0.0, 0.0, 1.0))).is_none());
+    }
+
+    #[test]
+    fn triangle_interpolates_vertex_normals() {
+        let triangle = TriangleBuilder::new(v3!(0.0, 0.0, 5.0), v3!(2.0, 0.0, 5.0), v3!(0.0, 2.0, 5.0),
+                                            white())
+            .vertex_normals(v3!(0.0, 0.0, 1.0), v3!(1.0, 0.0, 1.0), v3!(0.0, 1.0, 1.0))
+            .build();
+        let normal_at = |x, y| triangle.intersects(&ray(v3!(x, y, 0.0), v3!(0.0, 0.0, 1.0))).unwrap().normal;
+        assert_eq!(normal_at(0.0, 0.0), v3!(0.0, 0.0, 1.0));
+        assert_eq!(normal_at(2.0 - 1e-9, 0.0), v3!(1.0, 0.0, 1.0).normalize());
+        let n = normal_at(1.0, 0.0);
+        assert!((n.length() - 1.0).abs() < 1e-12 && n.x > 0.0 && n.y == 0.0, "normal {:?}", n);
+        assert_eq!(triangle.normal(), v3!(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn parallel_render_matches_serial_one() {
+        let scene = random_scene(40, 3);
+        for tile_size in [0, 5] {
+            let config = RenderConfig { width: 24, height: 18, tile_size, anti_alias: AntiAlias::Stochastic(2),
+                                        ..RenderConfig::default() };
+            let (serial, parallel) = (raytrace(&scene, &config), raytrace_parallel(&scene, &config));
+            assert!(serial.iter().zip(&parallel).all(|(a, b)| a.as_array() == b.as_array()),
+                    "images differ in tiles of {}", tile_size);
+        }
+    }
+
+    #[test]
+    fn cylinder_body_and_caps() {
+        let cylinder = |capped| Cylinder { axis_start: v3!(0.0, -1.0, 5.0), axis_end: v3!(0.0, 1.0, 5.0),
+                                           radius: 0.5, capped, material: white().into() };
+        for capped in [false, true] {
+            // Side on: the near side of the body.
+            let hit = cylinder(capped).intersects(&ray(v3!(0.0, 0.5, 0.0), v3!(0.0, 0.0, 1.0))).unwrap();
+            assert!((hit.dist - 4.5).abs() < 1e-9);
+            assert_eq!(hit.normal, v3!(0.0, 0.0, -1.0));
+            // Past the ends of the segment, and beside it.
+            assert!(cylinder(capped).intersects(&ray(v3!(0.0, 1.1, 0.0), v3!(0.0, 0.0, 1.0))).is_none());
+            assert!(cylinder(capped).intersects(&ray(v3!(0.6, 0.0, 0.0), v3!(0.0, 0.0, 1.0))).is_none());
+        }
+        // Down the axis: open, it is seen through; capped, the top cap.
+        let down = ray(v3!(0.1, 5.0, 5.0), v3!(0.0, -1.0, 0.0));
+        assert!(cylinder(false).intersects(&down).is_none());
+        let hit = cylinder(true).intersects(&down).unwrap();
+        assert!((hit.dist - 4.0).abs() < 1e-9);
+        assert_eq!(hit.normal, v3!(0.0, 1.0, 0.0));
+        // Slanting in through the open top, the inside of the far wall.
+        let hit = cylinder(false).intersects(&ray(v3!(-0.4, 2.0, 5.0), v3!(1.0, -2.0, 0.0))).unwrap();
+        assert!((hit.pos.x - 0.5).abs() < 1e-9 && hit.pos.y < 1.0, "hit at {:?}", hit.pos);
+        // A tilted cylinder hit from the side.
+        let tilted = Cylinder { axis_start: v3!(-1.0, -1.0, 5.0), axis_end: v3!(1.0, 1.0, 5.0), radius: 0.5,
+                                capped: true, material: white().into() };
+        let hit = tilted.intersects(&ray(v3!(0.0, 0.0, 0.0), v3!(0.0, 0.0, 1.0))).unwrap();
+        assert!((hit.dist - 4.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn point_lights_fall_off_with_distance() {
+        let mut rng = Rng::new(1);
+        let intensity_at = |light: &Light, d: f64, rng: &mut Rng| {
+            light.sample(light.pos + v3!(0.0, 0.0, d), rng)[0].intensity
+        };
+        // Inverse square.
+        let light = point_light(v3!(1.0, 2.0, 3.0), v3!(1.0, 1.0, 1.0), 16.0);
+        assert!((intensity_at(&light, 8.0, &mut rng) - 0.25).abs() < 1e-12);
+        assert!((intensity_at(&light, 16.0, &mut rng) - 0.0625).abs() < 1e-12);
+        // Close by, clamped at full intensity.
+        assert_eq!(intensity_at(&light, 1.0, &mut rng), 1.0);
+        // Constant, linear and quadratic terms together.
+        let light = Light { pos: v3!(0.0, 0.0, 0.0), color: v3!(1.0, 1.0, 1.0), intensity: 2.0,
+                            attenuation: (1.0, 0.5, 0.25) };
+        assert!((intensity_at(&light, 2.0, &mut rng) - 2.0 / 3.0).abs() < 1e-12);
+        // No falloff at all.
+        let light = Light { attenuation: (1.0, 0.0, 0.0), intensity: 0.5, ..light };
+        assert_eq!(intensity_at(&light, 1000.0, &mut rng), 0.5);
+        let sample = &light.sample(v3!(0.0, 3.0, 4.0), &mut rng)[0];
+        assert_eq!((sample.dir, sample.dist), (v3!(0.0, -0.6, -0.8), 5.0));
+    }
+
+    #[test]
+    fn gamma_encodes_the_output() {
+        let mut scene = scene();
+        scene.background = Background::Solid(v3!(0.5, 0.0, 2.0));
+        let render = |gamma| {
+            let config = RenderConfig { width: 2, height: 2, gamma, ..RenderConfig::default() };
+            raytrace(&scene, &config)[0]
+        };
+        assert_eq!(render(1.0), v3!(0.5, 0.0, 1.0));
+        let encoded = render(2.2);
+        assert!((encoded.x - 0.5f64.powf(1.0 / 2.2)).abs() < 1e-12, "{:?}", encoded);
+        assert_eq!((encoded.y, encoded.z), (0.0, 1.0));
+        // Quantizing rounds the encoded value.
+        assert_eq!(v3!(0.5, 0.0, 1.0).to_rgb_gamma(2.2), (186, 0, 255));
+        assert_eq!(v3!(0.5, -1.0, 7.0).to_rgb_gamma(1.0), (128, 0, 255));
+    }
+
+    #[test]
+    fn look_at_aims_the_camera() {
+        let (from, at) = (v3!(1.0, 2.0, 3.0), v3!(4.0, 2.0, 7.0));
+        let camera = Camera::look_at(from, at, v3!(0.0, 1.0, 0.0), 60.0, 2.0);
+        let mut rng = Rng::new(1);
+        let (width, height) = (200, 100);
+        let through = |x, y, rng: &mut Rng| primary_ray(&camera, x, y, width, height, f64::INFINITY, rng);
+        // The middle of the image looks at the target.
+        let center = through(100.0, 50.0, &mut rng);
+        assert_eq!(center.origin, from);
+        assert_eq!(center.dir, (at - from).normalize());
+        // The top and bottom edges are half the field of view away, and the
+        // sides half the horizontal one, with up staying up.
+        let top = through(100.0, 100.0, &mut rng);
+        assert!((top.dir.dot(center.dir).acos().to_degrees() - 30.0).abs() < 1e-9);
+        assert!(top.dir.y > 0.0 && through(100.0, 0.0, &mut rng).dir.y < 0.0);
+        let hfov = 2.0 * (2.0 * 30f64.to_radians().tan()).atan();
+        let side = through(200.0, 50.0, &mut rng);
+        assert!((side.dir.dot(center.dir).acos() - hfov / 2.0).abs() < 1e-9);
+        // As in `scene()`, +x is to the right when looking along +z.
+        let ahead = Camera::look_at(from, from + v3!(0.0, 0.0, 1.0), v3!(0.0, 1.0, 0.0), 60.0, 2.0);
+        assert!(primary_ray(&ahead, 200.0, 50.0, width, height, f64::INFINITY, &mut rng).dir.x > 0.0);
+        assert_eq!(camera.focus_distance, 5.0);
+    }
+
+    #[test]
+    fn max_depth_limits_reflections() {
+        // A black mirror ahead reflects a big glowing sphere behind the
+        // camera.
+        let mirror = basic_material(v3!(0.0, 0.0, 0.0)).with_reflection(1.0);
+        let scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, mirror)
+            .sphere(v3!(0.0, 0.0, -40.0), 30.0, emissive_material(v3!(0.5, 0.5, 0.5)))
+            .build();
+        let center = |max_depth| {
+            let config = RenderConfig { width: 32, height: 32, max_depth,
+                                        tone_mapper: Some(ToneMapper::Linear), gamma: 1.0,
+                                        ..RenderConfig::default() };
+            raytrace(&scene, &config)[16*32 + 16]
+        };
+        assert_eq!(center(0), v3!(0.0, 0.0, 0.0));
+        let reflected = center(1);
+        assert!((reflected - v3!(0.5, 0.5, 0.5)).length() < 1e-3, "reflection is {:?}", reflected);
+        assert_eq!(RenderConfig::default().max_depth, 3);
+    }
+
+    #[test]
+    fn bbox_normals_face_out_of_every_side() {
+        let bbox = BBox { v1: v3!(-1.0, -2.0, 3.0), v2: v3!(1.0, 2.0, 5.0),
+                          material: white().into(), motion: None };
+        let center = v3!(0.0, 0.0, 4.0);
+        for normal in [v3!(1.0, 0.0, 0.0), v3!(-1.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0),
+                       v3!(0.0, -1.0, 0.0), v3!(0.0, 0.0, 1.0), v3!(0.0, 0.0, -1.0)] {
+            // Slightly off the face's center, so no other slab ties with it.
+            let origin = center + normal*10.0 + v3!(0.1, 0.2, 0.3);
+            let hit = bbox.intersects(&ray(origin, -normal)).unwrap();
+            assert_eq!(hit.normal, normal);
+            let half_size = v3!(1.0, 2.0, 1.0);
+            assert!(((hit.pos - center).dot(normal) - half_size.dot(normal).abs()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn bbox_hit_from_inside_is_on_the_exit_face() {
+        let bbox = BBox { v1: v3!(-1.0, -2.0, 3.0), v2: v3!(1.0, 2.0, 5.0),
+                          material: white().into(), motion: None };
+        let origin = v3!(0.1, 0.2, 4.3);
+        for dir in [v3!(1.0, 0.0, 0.0), v3!(-1.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0),
+                    v3!(0.0, -1.0, 0.0), v3!(0.0, 0.0, 1.0), v3!(0.0, 0.0, -1.0)] {
+            let hit = bbox.intersects(&ray(origin, dir)).unwrap();
+            // On the face the ray leaves through, with the box centered at
+            // (0, 0, 4) and half as large as (2, 4, 2), and with the normal
+            // facing back into the box, towards the origin.
+            let face = dir.dot(v3!(1.0, 2.0, 1.0)).abs() + dir.dot(v3!(0.0, 0.0, 4.0));
+            assert!((hit.pos.dot(dir) - face).abs() < 1e-12, "{:?} leaves at {:?}", dir, hit.pos);
+            assert!((hit.dist - (hit.pos - origin).length()).abs() < 1e-12);
+            assert_eq!(hit.normal, -dir);
+        }
+    }
+
+    #[test]
+    fn colored_light_tints_the_surface() {
+        let scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, white())
+            .light(v3!(0.0, 0.0, -5.0), v3!(1.0, 0.0, 0.0))
+            .build();
+        let p = render_pixel(&scene, 16, 16, 32, 32);
+        // Green and blue only get the white ambient light.
+        assert!(p.x > 0.5, "center pixel is {:?}", p);
+        assert!((p.y - scene.ambient.y).abs() < 1e-12 && (p.z - scene.ambient.z).abs() < 1e-12,
+                "center pixel is {:?}", p);
+    }
+
+    #[test]
+    fn emissive_sphere_glows_without_lights() {
+        let scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, emissive_material(v3!(0.8, 0.4, 0.2)))
+            .build();
+        assert!(scene.lights.is_empty());
+        assert_eq!(render_pixel(&scene, 16, 16, 32, 32), v3!(0.8, 0.4, 0.2));
+        assert_eq!(render_pixel(&scene, 0, 0, 32, 32), v3!(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn path_tracer_lights_diffuse_sphere_with_the_sky() {
+        // Every bounce off a convex sphere escapes to the uniform sky, so
+        // each path returns the sphere's color times the sky's.
+        let scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(1.0, 1.0, 1.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, basic_material(v3!(0.5, 0.25, 0.75)))
+            .build();
+        let config = RenderConfig { width: 32, height: 32, integrator: IntegratorMode::PathTrace(8),
+                                    tone_mapper: Some(ToneMapper::Linear), gamma: 1.0,
+                                    ..RenderConfig::default() };
+        let image = raytrace(&scene, &config);
+        assert!((image[16*32 + 16] - v3!(0.5, 0.25, 0.75)).length() < 1e-12);
+        assert_eq!(image[0], v3!(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn ambient_occlusion_darkens_the_contact() {
+        // A sphere resting on the floor, with a light below the floor so
+        // that the floor only gets ambient light.
+        let scene = SceneBuilder::new()
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, white())
+            .bbox(v3!(-100.0, -2.0, -100.0), v3!(100.0, -1.0, 100.0), white())
+            .light(v3!(0.0, -10.0, 5.0), v3!(1.0, 1.0, 1.0))
+            .build();
+        let render = |integrator| {
+            let config = RenderConfig { width: 32, height: 32, integrator,
+                                        tone_mapper: Some(ToneMapper::Linear), gamma: 1.0,
+                                        ..RenderConfig::default() };
+            raytrace(&scene, &config)
+        };
+        // Floor just in front of the sphere, and far off to its side.
+        let (near, far) = (21*32 + 16, 21*32 + 31);
+        let flat = render(IntegratorMode::Whitted);
+        assert_eq!(flat[near], scene.ambient);
+        assert_eq!(flat[far], scene.ambient);
+        let occluded = render(IntegratorMode::AmbientOcclusion { samples: 256, radius: 1.0 });
+        assert_eq!(occluded[far], scene.ambient);
+        assert!(occluded[near].x < 0.8 * scene.ambient.x, "near the contact: {:?}", occluded[near]);
+    }
+
+    #[test]
+    fn progress_callback_is_called_per_line() {
+        let scene = lit_sphere(v3!(1.0, 1.0, 1.0));
+        let (width, height) = (12, 9);
+        for render in [raytrace as fn(&Scene, &RenderConfig) -> Vec<Vector3>, raytrace_parallel] {
+            let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let log = calls.clone();
+            let config = RenderConfig {
+                width, height,
+                progress_callback: Some(Arc::new(move |done, total| log.lock().unwrap().push((done, total)))),
+                print_stats: false,
+                ..RenderConfig::default()
+            };
+            render(&scene, &config);
+            let mut calls = calls.lock().unwrap().clone();
+            calls.sort();
+            assert_eq!(calls, (1..=height).map(|done| (done, height)).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn cone_apex_body_and_cap() {
+        // Pointing down from its apex, widening to a radius of 2 at y = -1.
+        let cone = |capped| Cone { apex: v3!(0.0, 1.0, 5.0), axis: v3!(0.0, -1.0, 0.0),
+                                   half_angle: 45f64.to_radians(), height: 2.0, capped,
+                                   material: white().into() };
+        for capped in [false, true] {
+            // Straight at the apex, where the two roots meet.
+            let hit = cone(capped).intersects(&ray(v3!(0.0, 1.0, 0.0), v3!(0.0, 0.0, 1.0))).unwrap();
+            assert!((hit.dist - 5.0).abs() < 1e-9);
+            // Through the body, where it is 1 wide.
+            let hit = cone(capped).intersects(&ray(v3!(0.0, 0.0, 0.0), v3!(0.0, 0.0, 1.0))).unwrap();
+            assert!((hit.dist - 4.0).abs() < 1e-9);
+            assert_eq!(hit.normal, v3!(0.0, 1.0, -1.0).normalize());
+            // Beside it, and above the apex on the other nappe.
+            assert!(cone(capped).intersects(&ray(v3!(1.5, 0.0, 0.0), v3!(0.0, 0.0, 1.0))).is_none());
+            assert!(cone(capped).intersects(&ray(v3!(0.0, 1.5, 0.0), v3!(0.0, 0.0, 1.0))).is_none());
+        }
+        // Up into the base: capped, the cap; open, the inside of the body.
+        let up = ray(v3!(0.5, -5.0, 5.0), v3!(0.0, 1.0, 0.0));
+        let hit = cone(true).intersects(&up).unwrap();
+        assert!((hit.dist - 4.0).abs() < 1e-9);
+        assert_eq!(hit.normal, v3!(0.0, -1.0, 0.0));
+        let hit = cone(false).intersects(&up).unwrap();
+        assert!((hit.dist - 5.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn moving_sphere_leaves_a_streak() {
+        // A glowing sphere moving from x = -1 to x = 1 while the shutter is
+        // open.
+        let mut scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .object(Sphere { pos: v3!(-1.0, 0.0, 5.0), radius: 0.5,
+                             material: emissive_material(v3!(1.0, 1.0, 1.0)).into(),
+                             motion: Some(v3!(2.0, 0.0, 0.0)) })
+            .build();
+        let config = RenderConfig { width: 32, height: 32,
+                                    tone_mapper: Some(ToneMapper::Linear), gamma: 1.0,
+                                    ..RenderConfig::default() };
+        let still = raytrace(&scene, &config);
+        let blurred = raytrace_motion_blur(&mut scene, &config, 64);
+        let row = |image: &[Vector3], x: usize| image[16*32 + x].x;
+        // Still, only the start is covered; blurred, it fades in and out of
+        // the whole path, and nothing past its ends.
+        assert_eq!((row(&still, 12), row(&still, 16), row(&still, 20)), (1.0, 0.0, 0.0));
+        for x in [12, 16, 20] {
+            assert!(row(&blurred, x) > 0.1 && row(&blurred, x) < 0.9,
+                    "pixel {} of the streak is {}", x, row(&blurred, x));
+        }
+        assert_eq!((row(&blurred, 4), row(&blurred, 28)), (0.0, 0.0));
+        // The sphere is back where it started.
+        assert_eq!(raytrace(&scene, &config), still);
+    }
+
+    #[test]
+    fn white_env_map_gives_white_background() {
+        // A 1x1 Radiance image of (1, 1, 1): mantissas of 128 with an
+        // exponent of 129.
+        let path = std::env::temp_dir().join(format!("rust_raytracer_white_{}.hdr", std::process::id()));
+        let mut file = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 1\n".to_vec();
+        file.extend([128, 128, 128, 129]);
+        std::fs::write(&path, file).unwrap();
+        let background = load_hdr(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let scene = SceneBuilder::new().background(background.unwrap()).build();
+        let config = RenderConfig { width: 8, height: 6, tone_mapper: Some(ToneMapper::Linear),
+                                    gamma: 1.0, ..RenderConfig::default() };
+        assert!(raytrace(&scene, &config).iter().all(|&p| p == v3!(1.0, 1.0, 1.0)));
+        for dir in [v3!(0.0, 1.0, 0.0), v3!(0.0, -1.0, 0.0), v3!(-1.0, 0.0, 0.0)] {
+            assert_eq!(scene.background.color(dir), v3!(1.0, 1.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn empty_env_map_is_black() {
+        for (width, height) in [(0, 0), (3, 0), (0, 3)] {
+            let background = Background::EnvMap(EnvMap::new(vec![], width, height));
+            for dir in [v3!(0.0, 1.0, 0.0), v3!(0.0, -1.0, 0.0), v3!(0.6, 0.0, 0.8)] {
+                assert_eq!(background.color(dir), v3!(0.0, 0.0, 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn env_map_power_scales_the_lighting() {
+        // Bright blue above and dim orange below, lighting a white sphere
+        // through the path tracer only.
+        let pixels: Vec<Vector3> = (0..8*4).map(|i| if i < 16 { v3!(0.1, 0.2, 0.4) } else { v3!(0.2, 0.1, 0.05) }).collect();
+        let render = |power: f64| {
+            let mut env = EnvMap::new(pixels.clone(), 8, 4);
+            env.power = power;
+            let mut scene = SceneBuilder::new()
+                .background(Background::EnvMap(env))
+                .sphere(v3!(0.0, 0.0, 5.0), 1.0, white())
+                .build();
+            scene.ambient = v3!(0.0, 0.0, 0.0);
+            let config = RenderConfig { width: 16, height: 16, integrator: IntegratorMode::PathTrace(4),
+                                        tone_mapper: Some(ToneMapper::Linear), gamma: 1.0,
+                                        print_stats: false, ..RenderConfig::default() };
+            let image = raytrace(&scene, &config);
+            image.iter().map(|p| p.dot(v3!(0.2126, 0.7152, 0.0722))).sum::<f64>() / image.len() as f64
+        };
+        let (single, double) = (render(1.0), render(2.0));
+        assert!(single > 0.0 && (double / single - 2.0).abs() < 1e-9, "{} and {}", single, double);
+    }
+
+    #[test]
+    fn auto_normalized_env_map_averages_to_its_power() {
+        let pixels: Vec<Vector3> = (0..16*8).map(|i| v3!(1.0, 2.0, 3.0) * (1.0 + (i % 5) as f64)).collect();
+        let mut env = EnvMap::new(pixels, 16, 8);
+        env.power = 0.5;
+        env.auto_normalize = true;
+        // Average over directions spread evenly over the sphere.
+        let mut rng = Rng::new(2);
+        let n = 20000;
+        let mean = (0..n).map(|_| {
+            let dir = uniform_sphere_sample(rng.next_f64(), rng.next_f64());
+            env.sample(dir).dot(v3!(0.2126, 0.7152, 0.0722))
+        }).sum::<f64>() / n as f64;
+        assert!((mean - 0.5).abs() < 0.01, "mean luminance {}", mean);
+        assert_eq!(EnvMap::new(vec![], 0, 0).mean_luminance(), 0.0);
+    }
+
+    #[test]
+    fn atmosphere_background_is_saved_and_renders_a_blue_sky() {
+        let mut scene = SceneBuilder::new().background(Background::Atmosphere(AtmosphereModel::default())).build();
+        // Looking up and away from the sun.
+        scene.camera = Camera::look_at(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, -1.0), v3!(0.0, 1.0, 0.0), 60.0, 1.0);
+        let scene = Scene::from_json(&scene.to_json()).unwrap();
+        match scene.background {
+            Background::Atmosphere(ref model) => assert_eq!(model.sun_dir, AtmosphereModel::default().sun_dir),
+            ref other => panic!("loaded as {:?}", other),
+        }
+        let config = RenderConfig { width: 8, height: 8, tone_mapper: Some(ToneMapper::Linear), gamma: 1.0,
+                                    print_stats: false, ..RenderConfig::default() };
+        let image = raytrace(&scene, &config);
+        assert!(image.iter().all(|p| p.z > p.x && p.x > 0.0), "{:?}", image);
+    }
+
+    /// Intersection at the origin with a surface facing up.
+    fn flat_hit(material: Material) -> Intersection {
+        Intersection { pos: v3!(0.0, 0.0, 0.0), normal: v3!(0.0, 1.0, 0.0), dist: 1.0, material,
+                       material_id: None, barycentric: None, uv: (0.0, 0.0), tangent: None,
+                       object_pos: None }
+    }
+
+    #[test]
+    fn cook_torrance_conserves_energy() {
+        // `shade_point` is the BRDF times the cosine, scaled by pi, so the
+        // fraction of light reflected towards the viewer is its integral
+        // over the hemisphere of light directions divided by pi. The light
+        // directions are spread evenly over the hemisphere's solid angle.
+        let n = 300;
+        for roughness in [0.3, 0.6, 1.0] {
+            for metalness in [0.0, 1.0] {
+                let material = Material { brdf: BrdfModel::CookTorrance { roughness, metalness },
+                                          ..basic_material(v3!(1.0, 1.0, 1.0)) };
+                let isect = flat_hit(material);
+                for view_angle in [0.0f64, 45.0, 80.0] {
+                    let view_dir = v3!(view_angle.to_radians().sin(), view_angle.to_radians().cos(), 0.0);
+                    let mut sum = v3!(0.0, 0.0, 0.0);
+                    for i in 0..n*n {
+                        let cos = ((i / n) as f64 + 0.5) / n as f64;
+                        let phi = 2.0 * std::f64::consts::PI * ((i % n) as f64 + 0.5) / n as f64;
+                        let sin = (1.0 - cos*cos).sqrt();
+                        let light_dir = v3!(sin * phi.cos(), cos, sin * phi.sin());
+                        sum += shade_point(isect.material.brdf, light_dir, view_dir,
+                                           v3!(1.0, 1.0, 1.0), &isect);
+                    }
+                    let reflected = sum * (2.0 / (n*n) as f64);
+                    assert!(reflected.max_component() <= 1.0,
+                            "roughness {} metalness {} at {} degrees reflects {:?}",
+                            roughness, metalness, view_angle, reflected);
+                    assert!(reflected.x > 0.3, "{:?}", reflected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn phong_and_blinn_phong_highlights_differ() {
+        let isect = flat_hit(basic_material(v3!(0.2, 0.2, 0.2)).with_shininess(64.0));
+        let dir = |degrees: f64| v3!(degrees.to_radians().sin(), degrees.to_radians().cos(), 0.0);
+        let white = v3!(1.0, 1.0, 1.0);
+        // Viewed 10 degrees off the mirror direction of the light, the
+        // half vector is only 5 degrees off the normal.
+        let (light_dir, view_dir) = (dir(-30.0), dir(40.0));
+        let blinn = shade_point(BrdfModel::BlinnPhong, light_dir, view_dir, white, &isect);
+        let phong = shade_point(BrdfModel::Phong, light_dir, view_dir, white, &isect);
+        let diffuse = 0.2 * 30f64.to_radians().cos();
+        assert!((blinn.x - diffuse - 5f64.to_radians().cos().powi(64)).abs() < 1e-9, "{:?}", blinn);
+        assert!((phong.x - diffuse - 10f64.to_radians().cos().powi(64)).abs() < 1e-9, "{:?}", phong);
+        assert!(blinn.x - phong.x > 0.3);
+        // Both clamp to [0, 1], even where diffuse and highlight add up to
+        // more.
+        for light in -8..9 {
+            for view in -8..9 {
+                let (light_dir, view_dir) = (dir(light as f64 * 10.0), dir(view as f64 * 10.0));
+                for model in [BrdfModel::BlinnPhong, BrdfModel::Phong] {
+                    let color = shade_point(model, light_dir, view_dir, white, &isect);
+                    assert!(color.as_array().iter().all(|c| (0.0..=1.0).contains(c)), "{:?}", color);
+                }
+            }
+        }
+        assert_eq!(shade_point(BrdfModel::Phong, dir(0.0), dir(0.0), white, &isect), white);
+    }
+
+    #[test]
+    fn normal_map_shades_a_flat_plane() {
+        // Stripes across x alternating between flat and tilted normals, on
+        // a plain white floor lit from straight above.
+        let stripes = NormalMap { pixels: vec![v3!(0.5, 0.5, 1.0), v3!(0.9, 0.5, 0.6)],
+                                  width: 2, height: 1, scale: 1.0 };
+        let white_texture = ImageTexture::new(vec![v3!(1.0, 1.0, 1.0)], 1, 1, WrapMode::Repeat,
+                                              FilterMode::Bilinear);
+        let floor = |normal_map: Option<Arc<dyn Texture>>| Material {
+            texture: Some(Arc::new(white_texture.clone())),
+            normal_map,
+            spec_color: v3!(0.0, 0.0, 0.0),
+            ..white()
+        };
+        let bottom_row = |material| {
+            let scene = SceneBuilder::new()
+                .plane(v3!(0.0, -1.0, 0.0), v3!(0.0, 1.0, 0.0), material)
+                .add_light(Light { pos: v3!(0.0, 1000.0, 0.0), color: v3!(1.0, 1.0, 1.0),
+                                   intensity: 1.0, attenuation: (1.0, 0.0, 0.0) })
+                .build();
+            (0..32).map(|x| render_pixel(&scene, x, 31, 32, 32).x).collect::<Vec<f64>>()
+        };
+        let spread = |row: Vec<f64>| {
+            row.iter().cloned().fold(f64::MIN, f64::max) - row.iter().cloned().fold(f64::MAX, f64::min)
+        };
+        assert!(spread(bottom_row(floor(None))) < 1e-3);
+        assert!(spread(bottom_row(floor(Some(Arc::new(stripes))))) > 0.2);
+    }
+
+    #[test]
+    fn sphere_uv_depends_only_on_the_point() {
+        use std::f64::consts::PI;
+        let sphere = Sphere { pos: v3!(1.0, 2.0, 3.0), radius: 2.0, material: white().into(),
+                              motion: None };
+        let mut rng = Rng::new(5);
+        for _ in 0..50 {
+            let n = uniform_sphere_sample(rng.next_f64(), rng.next_f64());
+            let point = sphere.pos + n*sphere.radius;
+            let expected = ((n.z.atan2(n.x) + PI) / (2.0*PI), n.y.acos() / PI);
+            // Rays from different places outside the sphere onto the point.
+            for _ in 0..5 {
+                let mut away = uniform_sphere_sample(rng.next_f64(), rng.next_f64());
+                if away.dot(n) < 0.2 {
+                    away = (away + n*2.0).normalize();
+                }
+                let origin = point + away*(1.0 + 9.0*rng.next_f64());
+                let hit = sphere.intersects(&ray(origin, point - origin)).unwrap();
+                assert!((hit.pos - point).length() < 1e-9);
+                assert!((hit.uv.0 - expected.0).abs() < 1e-9 && (hit.uv.1 - expected.1).abs() < 1e-9,
+                        "{:?} from {:?} has uv {:?}, not {:?}", point, origin, hit.uv, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn quadrants_stitch_into_the_full_image() {
+        let scene = lit_sphere(v3!(1.0, 0.5, 0.25));
+        let (width, height) = (13, 9);
+        let config = |region| RenderConfig { width, height, region, print_stats: false,
+                                             ..RenderConfig::default() };
+        let full = raytrace(&scene, &config(None));
+        let quadrants = [(0, 0, 6, 4), (6, 0, 13, 4), (0, 4, 6, 9), (6, 4, 13, 9)];
+        for render in [raytrace as fn(&Scene, &RenderConfig) -> Vec<Vector3>, raytrace_parallel] {
+            let mut stitched = vec![v3!(0.0, 0.0, 0.0); width*height];
+            for &(x_min, y_min, x_max, y_max) in &quadrants {
+                let part = render(&scene, &config(Some((x_min, y_min, x_max, y_max))));
+                for (i, (&pixel, sum)) in part.iter().zip(stitched.iter_mut()).enumerate() {
+                    let (x, y) = (i % width, i / width);
+                    if x < x_min || x >= x_max || y < y_min || y >= y_max {
+                        assert_eq!(pixel, v3!(0.0, 0.0, 0.0));
+                    }
+                    *sum += pixel;
+                }
+            }
+            assert!(stitched.iter().zip(&full).all(|(a, b)| a.as_array() == b.as_array()));
+        }
+    }
+
+    #[test]
+    fn toml_scene_renders_like_its_json() {
+        let toml = r#"
+            # A red sphere in front of the default camera.
+            ambient = { x = 0.1, y = 0.1, z = 0.1 }
+            background = { Solid = { x = 0.0, y = 0.0, z = 0.0 } }
+
+            [camera]
+            pos = { x = 0.0, y = 0.0, z = -1.0 }
+            up = { x = 0.0, y = 1.0, z = 0.0 }
+            right = { x = 1.33, y = 0.0, z = 0.0 }
+            dist = 2.0
+            aperture = 0.0
+            focus_distance = 1.0
+            projection = "Perspective"
+
+            [[lights]]
+            type = "Light"
+            pos = { x = 0.0, y = 0.0, z = -5.0 }
+            color = { x = 1.0, y = 1.0, z = 1.0 }
+            intensity = 1.0
+            attenuation = [1.0, 0.0, 0.0]
+
+            [[objects]]
+            type = "Sphere"
+            pos = { x = 0.0, y = 0.0, z = 5.0 }
+            radius = 1.0
+
+            [objects.material]
+            shininess = 16.0
+            spec_color = { x = 0.0, y = 0.0, z = 0.0 }
+            color = { x = 1.0, y = 0.0, z = 0.0 }
+            reflection = 0.0
+            refraction = 1.0
+            transparency = 0.0
+            emission = { x = 0.0, y = 0.0, z = 0.0 }
+        "#;
+        let scene = Scene::from_toml(toml).unwrap();
+        let json = scene.to_json();
+        let from_json = Scene::from_json(&json).unwrap();
+        assert_eq!(from_json.to_json(), json);
+
+        let config = RenderConfig { width: 32, height: 32, print_stats: false,
+                                    ..RenderConfig::default() };
+        let image = raytrace(&scene, &config);
+        let center = image[16*32 + 16];
+        assert!(center.x > 0.5 && center.y == 0.0, "center pixel is {:?}", center);
+        assert!(image.iter().zip(raytrace(&from_json, &config))
+                    .all(|(a, b)| a.as_array() == b.as_array()));
+    }
+
+    #[test]
+    fn two_sided_sphere_is_lit_from_inside() {
+        // The camera and the light are both inside the sphere.
+        let inside = |two_sided| {
+            let material = Material { two_sided, spec_color: v3!(0.0, 0.0, 0.0), ..white() };
+            let scene = SceneBuilder::new()
+                .sphere(v3!(0.0, 0.0, 0.0), 5.0, material)
+                .light(v3!(0.0, 1.0, 0.0), v3!(1.0, 1.0, 1.0))
+                .build();
+            render_pixel(&scene, 16, 16, 32, 32)
+        };
+        assert_eq!(inside(false), v3!(0.1, 0.1, 0.1));
+        assert!(inside(true).x > 0.5, "inside is {:?}", inside(true));
+    }
+
+    #[test]
+    fn capsule_ends_body_and_misses() {
+        let capsule = Capsule { start: v3!(0.0, -1.0, 5.0), end: v3!(0.0, 1.0, 5.0), radius: 0.5,
+                                material: white().into() };
+        // Down the axis onto the top end, and beside the axis onto its side.
+        let hit = capsule.intersects(&ray(v3!(0.0, 5.0, 5.0), v3!(0.0, -1.0, 0.0))).unwrap();
+        assert!((hit.dist - 3.5).abs() < 1e-9);
+        assert_eq!(hit.normal, v3!(0.0, 1.0, 0.0));
+        let hit = capsule.intersects(&ray(v3!(0.3, 5.0, 5.0), v3!(0.0, -1.0, 0.0))).unwrap();
+        assert!((hit.dist - 3.6).abs() < 1e-9);
+        assert_eq!(hit.normal, v3!(0.6, 0.8, 0.0));
+        // Up the axis onto the bottom end.
+        let hit = capsule.intersects(&ray(v3!(0.0, -5.0, 5.0), v3!(0.0, 1.0, 0.0))).unwrap();
+        assert!((hit.dist - 3.5).abs() < 1e-9);
+        assert_eq!(hit.normal, v3!(0.0, -1.0, 0.0));
+        // Side on through the body.
+        let hit = capsule.intersects(&ray(v3!(0.0, 0.5, 0.0), v3!(0.0, 0.0, 1.0))).unwrap();
+        assert!((hit.dist - 4.5).abs() < 1e-9);
+        assert_eq!(hit.normal, v3!(0.0, 0.0, -1.0));
+        // Beside it, past its end, and past the corner where the body
+        // would be if it didn't stop at the end.
+        for origin in [v3!(0.6, 0.0, 0.0), v3!(0.0, 1.6, 0.0), v3!(0.45, 1.45, 0.0)] {
+            assert!(capsule.intersects(&ray(origin, v3!(0.0, 0.0, 1.0))).is_none(), "hit from {:?}", origin);
+        }
+    }
+
+    #[test]
+    fn directional_light_is_like_a_point_light_far_away() {
+        let render = |light: Box<dyn LightSource>| {
+            let mut scene = SceneBuilder::new()
+                .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+                .sphere(v3!(0.0, 0.0, 5.0), 1.0, Material { spec_color: v3!(0.0, 0.0, 0.0), ..white() })
+                .build();
+            scene.lights.push(light);
+            let config = RenderConfig { width: 32, height: 32, tone_mapper: Some(ToneMapper::Linear),
+                                        gamma: 1.0, ..RenderConfig::default() };
+            raytrace(&scene, &config)
+        };
+        // Both shine along +z. The point light is only 10 from the sphere,
+        // so towards the sphere's rim it comes from a different angle.
+        let sun = render(Box::new(DirectionalLight { dir: v3!(0.0, 0.0, 1.0), color: v3!(1.0, 1.0, 1.0) }));
+        let point = render(Box::new(Light { pos: v3!(0.0, 0.0, -5.0), color: v3!(1.0, 1.0, 1.0),
+                                            intensity: 1.0, attenuation: (1.0, 0.0, 0.0) }));
+        // Head-on they agree.
+        assert!((sun[16*32 + 16] - point[16*32 + 16]).length() < 0.01);
+        let largest = sun.iter().zip(&point).map(|(a, b)| (*a - *b).length()).fold(0.0, f64::max);
+        assert!(largest > 0.05 && largest < 0.25, "the lights differ by up to {}", largest);
+    }
+
+    #[test]
+    fn shadow_maps_render_like_shadow_rays() {
+        let mut scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, white())
+            .bbox(v3!(-4.0, -2.0, 2.0), v3!(4.0, -1.5, 10.0), white())
+            .build();
+        scene.lights.push(Box::new(DirectionalLight { dir: v3!(-0.5, -1.0, 0.3), color: v3!(1.0, 1.0, 1.0) }));
+        let config = RenderConfig { width: 64, height: 48, print_stats: false, ..RenderConfig::default() };
+        let traced = raytrace(&scene, &config);
+        scene.build_shadow_maps(512);
+        let mapped = raytrace(&scene, &config);
+        let differing = traced.iter().zip(&mapped).filter(|(a, b)| (**a - **b).length() > 0.01).count();
+        // The shadow on the slab and the sphere's dark side are lit only
+        // by the ambient light, and only the shadow's edge may differ.
+        let ambient_only = traced.iter().filter(|p| (p.length() - 0.608).abs() < 0.01).count();
+        assert!(ambient_only > 100, "only {} pixels are in shadow", ambient_only);
+        assert!(differing < 20, "{} pixels differ", differing);
+    }
+
+    #[test]
+    fn light_portal_samples_integrate_the_window() {
+        // A square window of side 1, 2 above the point, under a white sky.
+        let mut scene = SceneBuilder::new().background(Background::Solid(v3!(1.0, 1.0, 1.0))).build();
+        let window = Aabb::from_points(v3!(-0.5, 2.0, -0.5), v3!(0.5, 2.0, 0.5));
+        scene.light_portals.push(LightPortal { bounds: window });
+        let mut rng = Rng::new(3);
+        let (n, normal) = (20000, v3!(0.0, 1.0, 0.0));
+        let samples: Vec<f64> = (0..n)
+            .map(|_| sky_through_portals(&scene, v3!(0.0, 0.0, 0.0), normal, &mut rng).x)
+            .collect();
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        let variance = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / n as f64;
+
+        // Form factor from a point to the parallel square above it, made
+        // of four rectangles with a corner over the point.
+        let x: f64 = 0.25;
+        let quarter = x / (1.0 + x*x).sqrt() * (x / (1.0 + x*x).sqrt()).atan() / std::f64::consts::PI;
+        let expected = 4.0 * quarter;
+        assert!((mean - expected).abs() < 0.01 * expected, "{} instead of {}", mean, expected);
+        // A cosine sampled bounce finds the window with probability
+        // `expected`, which is much noisier.
+        assert!(variance < 0.01 * expected * (1.0 - expected), "variance {}", variance);
+        let (dir, pdf) = scene.light_portals[0].sample(v3!(0.0, 0.0, 0.0), &mut rng);
+        assert!(dir.y > 0.9 && (pdf - scene.light_portals[0].pdf(v3!(0.0, 0.0, 0.0), dir)).abs() < 1e-12);
+        assert_eq!(scene.light_portals[0].pdf(v3!(0.0, 0.0, 0.0), v3!(1.0, 0.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn light_portals_keep_a_room_as_bright() {
+        let grey = Material { spec_color: v3!(0.0, 0.0, 0.0), ..basic_material(v3!(0.5, 0.5, 0.5)) };
+        // A closed room with a window in the ceiling.
+        let mut scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(1.0, 1.0, 1.0)))
+            .camera(Camera::look_at(v3!(0.0, 1.5, -1.5), v3!(0.0, 0.0, 0.5), v3!(0.0, 1.0, 0.0), 60.0, 1.0))
+            .bbox(v3!(-2.1, -0.1, -2.1), v3!(2.1, 0.0, 2.1), grey.clone())
+            .bbox(v3!(-2.1, -0.1, -2.1), v3!(-2.0, 2.1, 2.1), grey.clone())
+            .bbox(v3!(2.0, -0.1, -2.1), v3!(2.1, 2.1, 2.1), grey.clone())
+            .bbox(v3!(-2.1, -0.1, -2.1), v3!(2.1, 2.1, -2.0), grey.clone())
+            .bbox(v3!(-2.1, -0.1, 2.0), v3!(2.1, 2.1, 2.1), grey.clone())
+            .bbox(v3!(-2.1, 2.0, -2.1), v3!(-0.5, 2.1, 2.1), grey.clone())
+            .bbox(v3!(0.5, 2.0, -2.1), v3!(2.1, 2.1, 2.1), grey.clone())
+            .bbox(v3!(-0.5, 2.0, -2.1), v3!(0.5, 2.1, -0.5), grey.clone())
+            .bbox(v3!(-0.5, 2.0, 0.5), v3!(0.5, 2.1, 2.1), grey)
+            .build();
+        scene.ambient = v3!(0.0, 0.0, 0.0);
+        let config = RenderConfig { width: 12, height: 12, print_stats: false,
+                                    tone_mapper: Some(ToneMapper::Linear), gamma: 1.0,
+                                    integrator: IntegratorMode::PathTrace(64),
+                                    ..RenderConfig::default() };
+        let mean = |pixels: &[Vector3]| pixels.iter().map(|p| p.x).sum::<f64>() / pixels.len() as f64;
+        let without = raytrace(&scene, &config);
+        scene.light_portals.push(LightPortal { bounds: Aabb::from_points(v3!(-0.5, 2.0, -0.5),
+                                                                         v3!(0.5, 2.1, 0.5)) });
+        let with = raytrace(&scene, &config);
+        let (a, b) = (mean(&without), mean(&with));
+        assert!(a > 0.01 && (a - b).abs() < 0.1 * a, "{} without portals, {} with", a, b);
+    }
+
+    #[test]
+    fn stereo_eyes_see_the_expected_parallax() {
+        let scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, white())
+            .light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0))
+            .build();
+        let camera = Camera::look_at(v3!(0.0, 0.0, 0.0), v3!(0.0, 0.0, 1.0), v3!(0.0, 1.0, 0.0), 60.0, 1.0);
+        let (width, height) = (64, 64);
+        let config = RenderConfig { width, height, print_stats: false, ..RenderConfig::default() };
+        let (left, right) = stereo_render(&scene, &camera, 0.5, &config);
+        // Mean column and row of the pixels that see the sphere.
+        let centroid = |pixels: &[Vector3]| {
+            let hits: Vec<usize> = (0..pixels.len()).filter(|&i| pixels[i].x > 0.0).collect();
+            let n = hits.len() as f64;
+            (hits.iter().map(|i| (i % width) as f64).sum::<f64>() / n,
+             hits.iter().map(|i| (i / width) as f64).sum::<f64>() / n)
+        };
+        let ((left_x, left_y), (right_x, right_y)) = (centroid(&left), centroid(&right));
+        // 0.5 across at a depth of 5 is 0.1 of the image plane, which is
+        // `right.length()` to either side of the center.
+        let expected = 0.1 / camera.right.length() * width as f64 / 2.0;
+        assert!((left_x - right_x - expected).abs() < 0.3, "{} apart, not {}", left_x - right_x, expected);
+        assert!((left_y - right_y).abs() < 1e-9);
+
+        // Path tracing noise is the same in both eyes.
+        let traced = RenderConfig { integrator: IntegratorMode::PathTrace(1), ..config };
+        let (left, right) = stereo_render(&scene, &camera, 0.0, &traced);
+        assert!(left.iter().zip(&right).all(|(a, b)| a.as_array() == b.as_array()));
+    }
+
+    #[test]
+    fn adaptive_sampling_spends_its_rays_on_edges() {
+        let scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, emissive_material(v3!(1.0, 1.0, 1.0)))
+            .build();
+        let adaptive = AdaptiveConfig { warmup_spp: 8, min_spp: 4, max_spp: 128, target_variance: 1e-4 };
+        let config = RenderConfig { width: 32, height: 32, anti_alias: AntiAlias::Adaptive(adaptive),
+                                    ..RenderConfig::default() };
+        let samples = |x: usize, y: usize, adaptive: &AdaptiveConfig| {
+            let mut rng = Rng::new((y*32 + x) as u64);
+            sample_adaptively(&scene, &config, x, y, adaptive, &mut rng).1
+        };
+        // The background and the middle of the sphere are flat, and stop
+        // as soon as the variance can be trusted.
+        assert_eq!(samples(0, 0, &adaptive), 8);
+        assert_eq!(samples(16, 16, &adaptive), 8);
+        let counts: Vec<u32> = (0..32*32).map(|i| samples(i % 32, i / 32, &adaptive)).collect();
+        let edges: Vec<u32> = counts.iter().cloned().filter(|&n| n > 8).collect();
+        assert!(edges.len() > 10 && edges.iter().all(|&n| n > 32), "{:?}", edges);
+        assert!(counts.iter().filter(|&&n| n == 8).count() > 900);
+        // min_spp holds even where the warmup alone would have stopped.
+        assert_eq!(samples(0, 0, &AdaptiveConfig { warmup_spp: 2, min_spp: 20, ..adaptive }), 20);
+
+        // And through the renderer, the edge pixels are blended.
+        let image = raytrace(&scene, &config);
+        assert!(image.iter().any(|p| p.x > 0.2 && p.x < 0.8));
+    }
+
+    #[test]
+    fn stratified_samples_vary_less_than_random_ones() {
+        let scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, emissive_material(v3!(1.0, 1.0, 1.0)))
+            .build();
+        let config = |anti_alias| RenderConfig { width: 32, height: 32, anti_alias,
+                                                 ..RenderConfig::default() };
+        // Pixels on the sphere's edge, judging by a fine grid of samples.
+        let grid = config(AntiAlias::Grid(8));
+        let edge: Vec<(usize, usize)> = (0..32*32)
+            .map(|i| (i % 32, i / 32))
+            .filter(|&(x, y)| (0.2..0.8).contains(&sample_pixel(&scene, &grid, x, y, 0).x))
+            .collect();
+        assert!(edge.len() > 10);
+        // Variance over many passes of a pixel's mean of 16 samples.
+        let variance = |anti_alias| {
+            let config = config(anti_alias);
+            let passes = 100;
+            edge.iter().map(|&(x, y)| {
+                let values: Vec<f64> = (0..passes)
+                    .map(|pass| sample_pixel(&scene, &config, x, y, pass).x)
+                    .collect();
+                let mean = values.iter().sum::<f64>() / passes as f64;
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / passes as f64
+            }).sum::<f64>() / edge.len() as f64
+        };
+        let (random, stratified) = (variance(AntiAlias::Stochastic(16)), variance(AntiAlias::Stratified(4)));
+        assert!(stratified < random / 2.0, "stratified {} vs random {}", stratified, random);
+    }
+
+    /// Two spheres side by side, in a BVH.
+    fn two_spheres_in_bvh() -> Scene {
+        let mut scene = lit_scene(vec![sphere(v3!(-2.0, 0.0, 5.0), 1.0, white()),
+                                       sphere(v3!(2.0, 0.0, 5.0), 1.0, white())]);
+        scene.build_bvh();
+        scene
+    }
+
+    #[test]
+    fn moved_object_is_hit_after_rebuilding() {
+        let mut scene = two_spheres_in_bvh();
+        // Straight at the left sphere, and where it is moved to.
+        let old = ray(v3!(-2.0, 0.0, 0.0), v3!(0.0, 0.0, 1.0));
+        let new = ray(v3!(-2.0, 3.0, 0.0), v3!(0.0, 0.0, 1.0));
+        assert!(scene.intersect(&old).is_some() && scene.intersect(&new).is_none());
+        let id = (0..2).find(|&i| scene.all_objects().nth(i).unwrap().intersects(&old).is_some()).unwrap();
+        scene.move_object(id, v3!(-2.0, 3.0, 5.0));
+        scene.rebuild_acceleration();
+        assert!(scene.intersect(&old).is_none());
+        assert!((scene.intersect(&new).unwrap().dist - 4.0).abs() < 1e-9);
+        // The other sphere stayed where it was.
+        assert!(scene.intersect(&ray(v3!(2.0, 0.0, 0.0), v3!(0.0, 0.0, 1.0))).is_some());
+    }
+
+    #[test]
+    fn stale_bvh_is_not_used() {
+        let mut scene = two_spheres_in_bvh();
+        let id = (0..2).find(|&i| scene.all_objects().nth(i).unwrap().bounding_box().unwrap().min.x < 0.0);
+        scene.move_object(id.unwrap(), v3!(0.0, 3.0, 5.0));
+        // Without rebuilding, the moved sphere is found where it is now.
+        assert!(scene.intersect(&ray(v3!(-2.0, 0.0, 0.0), v3!(0.0, 0.0, 1.0))).is_none());
+        assert!((scene.intersect(&ray(v3!(0.0, 3.0, 0.0), v3!(0.0, 0.0, 1.0))).unwrap().dist - 4.0).abs() < 1e-9);
+        let config = RenderConfig { width: 8, height: 8, print_stats: false, ..RenderConfig::default() };
+        raytrace(&scene, &config);
+    }
+
+    #[test]
+    fn moving_twice_ends_at_the_last_position() {
+        let mut scene = two_spheres_in_bvh();
+        let id = (0..2).find(|&i| scene.all_objects().nth(i).unwrap().bounding_box().unwrap().min.x < 0.0);
+        scene.move_object(id.unwrap(), v3!(0.0, 3.0, 5.0));
+        scene.move_object(id.unwrap(), v3!(0.0, -3.0, 5.0));
+        scene.rebuild_acceleration();
+        let moved = scene.all_objects().find(|o| o.bounding_box().unwrap().min.y < -1.0).unwrap();
+        assert_eq!(moved.bounding_box().unwrap().centroid(), v3!(0.0, -3.0, 5.0));
+        assert!((scene.intersect(&ray(v3!(0.0, -3.0, 0.0), v3!(0.0, 0.0, 1.0))).unwrap().dist - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn depth_buffer_holds_hit_distances() {
+        let scene = lit_sphere(v3!(1.0, 1.0, 1.0));
+        let config = RenderConfig { width: 32, height: 32, print_stats: false, ..RenderConfig::default() };
+        let buffer = raytrace_full(&scene, &config);
+        let hits = buffer.depth.iter().filter(|d| d.is_finite()).count();
+        assert!(hits > 50 && hits < 32*32, "{} hits", hits);
+        for i in 0..32*32 {
+            let depth = buffer.depth[i];
+            assert!(depth > 0.0, "pixel {} has depth {}", i, depth);
+            assert_eq!(depth.is_finite(), buffer.normal[i].is_some());
+            if depth.is_infinite() {
+                assert_eq!(buffer.color[i], v3!(0.0, 0.0, 0.0));
+            }
+        }
+        // The camera is 5 from the front of the sphere.
+        assert!((buffer.depth[16*32 + 16] - 5.0).abs() < 0.05, "{}", buffer.depth[16*32 + 16]);
+    }
+
+    #[test]
+    fn fog_hides_far_spheres_only() {
+        let fog = Fog { color: v3!(0.5, 0.5, 0.5), density: 0.05 };
+        // A glowing sphere whose front is `dist` from the camera, big enough
+        // to fill the middle pixel.
+        let seen_at = |dist: f64| {
+            let radius = dist * 0.2;
+            let mut scene = SceneBuilder::new()
+                .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+                .sphere(v3!(0.0, 0.0, dist - 1.0 + radius), radius, emissive_material(v3!(1.0, 1.0, 1.0)))
+                .build();
+            scene.fog = Some(fog);
+            render_pixel(&scene, 16, 16, 32, 32)
+        };
+        let near = seen_at(1.0);
+        assert!((near - v3!(1.0, 1.0, 1.0)).length() < 0.05, "1 away: {:?}", near);
+        let far = seen_at(100.0);
+        assert!((far - fog.color).length() < 0.01, "100 away: {:?}", far);
+        // Both are blended, the near one a little and the far one almost
+        // entirely.
+        assert!(near.x < 1.0 && far.x > 0.5);
+    }
+
+    #[test]
+    fn builder_matches_manual_construction() {
+        let camera = || Camera::look_at(v3!(0.0, 2.0, -4.0), v3!(0.0, 0.0, 5.0), v3!(0.0, 1.0, 0.0),
+                                        50.0, 4.0 / 3.0);
+        let (red, mirror) = (basic_material(v3!(1.0, 0.0, 0.0)), white().with_reflection(0.8));
+
+        let mut manual = scene();
+        manual.camera = camera();
+        manual.background = Background::Solid(v3!(0.2, 0.3, 0.4));
+        manual.add(Sphere { pos: v3!(0.0, 0.0, 5.0), radius: 1.0, material: red.clone().into(),
+                            motion: None });
+        manual.add(BBox { v1: v3!(2.0, -1.0, 4.0), v2: v3!(3.0, 1.0, 6.0),
+                          material: mirror.clone().into(), motion: None });
+        manual.add(Plane { pos: v3!(0.0, -1.0, 0.0), normal: v3!(0.0, 1.0, 0.0), material: red.clone().into(),
+                           uv_scale: 1.0 });
+        manual.add_light(Light { pos: v3!(0.0, 5.0, 0.0), color: v3!(1.0, 1.0, 1.0), intensity: 1.0,
+                                 attenuation: (1.0, 0.0, 0.0) });
+        manual.add_light(point_light(v3!(3.0, 5.0, 0.0), v3!(1.0, 0.5, 0.5), 20.0));
+
+        let built = SceneBuilder::new()
+            .camera(camera())
+            .background(Background::Solid(v3!(0.2, 0.3, 0.4)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, red.clone())
+            .bbox(v3!(2.0, -1.0, 4.0), v3!(3.0, 1.0, 6.0), mirror)
+            .plane(v3!(0.0, -1.0, 0.0), v3!(0.0, 1.0, 0.0), red)
+            .light(v3!(0.0, 5.0, 0.0), v3!(1.0, 1.0, 1.0))
+            .add_light(point_light(v3!(3.0, 5.0, 0.0), v3!(1.0, 0.5, 0.5), 20.0))
+            .build();
+        assert_eq!(built.to_json(), manual.to_json());
+        assert_eq!(format!("{:?}", built), format!("{:?}", manual));
+    }
+
+    #[test]
+    fn bounded_plane_only_hits_inside_its_bounds() {
+        // A floor from x = -1 to 3 and z = 4 to 6, in the plane y = -1.
+        let floor = BoundedPlane { pos: v3!(0.0, -1.0, 0.0), normal: v3!(0.0, 1.0, 0.0),
+                                   min: v3!(-1.0, -1.0, 4.0), max: v3!(3.0, -1.0, 6.0),
+                                   material: white().into() };
+        let down = |x, z| ray(v3!(x, 2.0, z), v3!(0.0, -1.0, 0.0));
+        let hit = floor.intersects(&down(0.0, 5.0)).unwrap();
+        assert!((hit.dist - 3.0).abs() < 1e-12);
+        assert!((hit.uv.0 - 0.25).abs() < 1e-12 && (hit.uv.1 - 0.5).abs() < 1e-12, "uv {:?}", hit.uv);
+        // Right on the edges still counts.
+        assert!(floor.intersects(&down(3.0, 6.0)).is_some());
+        for (x, z) in [(-1.1, 5.0), (3.1, 5.0), (0.0, 3.9), (0.0, 6.1), (10.0, 10.0)] {
+            assert!(floor.intersects(&down(x, z)).is_none(), "hit at ({}, {})", x, z);
+        }
+    }
+
+    #[test]
+    fn light_below_a_plane_does_not_light_its_top() {
+        let render = |light: Option<Vector3>| {
+            let mut builder = SceneBuilder::new()
+                .plane(v3!(0.0, -1.0, 0.0), v3!(0.0, 1.0, 0.0), white());
+            if let Some(pos) = light {
+                builder = builder.light(pos, v3!(1.0, 1.0, 1.0));
+            }
+            let config = RenderConfig { width: 16, height: 16, print_stats: false,
+                                        ..RenderConfig::default() };
+            raytrace_full(&builder.build(), &config)
+        };
+        let unlit = render(None);
+        let below = render(Some(v3!(0.0, -2.0, 5.0)));
+        assert!(below.color.iter().zip(&unlit.color).all(|(a, b)| a == b));
+        // Not even a shadow ray is spent on it.
+        assert_eq!(below.stats.shadow_rays, 0);
+        let above = render(Some(v3!(0.0, 0.0, 5.0)));
+        assert!(above.stats.shadow_rays > 0);
+        assert!(above.color.iter().zip(&unlit.color).any(|(a, b)| a.x > b.x + 0.1));
+    }
+
+    #[test]
+    fn material_builders_match_struct_literals() {
+        let color = v3!(0.8, 0.1, 0.2);
+        let built = basic_material(color)
+            .with_reflection(0.7)
+            .with_shininess(64.0)
+            .with_spec_color(v3!(0.5, 0.5, 0.25))
+            .with_emission(v3!(0.1, 0.0, 0.3));
+        let manual = Material { shininess: 64.0,
+                                spec_color: v3!(0.5, 0.5, 0.25),
+                                color,
+                                reflection: 0.7,
+                                refraction: 1.0,
+                                transparency: 0.0,
+                                emission: v3!(0.1, 0.0, 0.3),
+                                texture: None,
+                                normal_map: None,
+                                brdf: BrdfModel::BlinnPhong,
+                                two_sided: false };
+        // Material has no PartialEq, because of its textures; none are set
+        // here, and everything else is serialized.
+        assert_eq!(serde_json::to_value(&built).unwrap(), serde_json::to_value(&manual).unwrap());
+        assert!(built.texture.is_none() && built.normal_map.is_none());
+    }
+
+    #[test]
+    fn far_checkerboard_fades_to_its_average() {
+        // A floor from the horizon at row 24 to the camera's feet, checkered
+        // with black and white squares half a unit across, beside one of
+        // flat gray.
+        let (width, height) = (64, 48);
+        let render = |texture: Arc<dyn Texture>| {
+            let floor = Material { texture: Some(texture), ..white().with_spec_color(v3!(0.0, 0.0, 0.0)) };
+            let scene = SceneBuilder::new()
+                .plane(v3!(0.0, -1.0, 0.0), v3!(0.0, 1.0, 0.0), floor)
+                .light(v3!(0.0, 100.0, 0.0), v3!(1.0, 1.0, 1.0))
+                .build();
+            let config = RenderConfig { width, height, tone_mapper: Some(ToneMapper::Linear),
+                                        gamma: 1.0, print_stats: false, ..RenderConfig::default() };
+            raytrace(&scene, &config)
+        };
+        let (black, white) = (v3!(0.0, 0.0, 0.0), v3!(1.0, 1.0, 1.0));
+        let texels = [black, white, black, white, white, black, white, black];
+        let average = render(Arc::new(ImageTexture::new(vec![v3!(0.5, 0.5, 0.5)], 1, 1, WrapMode::Repeat,
+                                                        FilterMode::Bilinear)));
+        let textures: Vec<Arc<dyn Texture>> = vec![
+            Arc::new(CheckerboardTexture { scale: 2.0, color_a: black, color_b: white }),
+            Arc::new(ImageTexture::new(texels.repeat(2), 4, 4, WrapMode::Repeat, FilterMode::Bicubic)),
+        ];
+        for texture in textures {
+            let image = render(texture.clone());
+            let row_difference = |row: usize| {
+                (0..width).map(|x| (image[row*width + x] - average[row*width + x]).length())
+                    .fold(0.0, f64::max)
+            };
+            for row in 24..28 {
+                assert!(row_difference(row) < 0.01, "{:?} row {} is {} off", texture, row,
+                        row_difference(row));
+            }
+            // Up close the squares are still there.
+            assert!((40..47).any(|row| row_difference(row) > 0.5), "{:?} is blurred", texture);
+        }
+    }
+
+    #[test]
+    fn checkpoint_of_other_settings_is_rejected() {
+        let scene = lit_sphere(v3!(1.0, 0.5, 0.25));
+        let config = RenderConfig { width: 12,
+                                    height: 8,
+                                    integrator: IntegratorMode::PathTrace(1),
+                                    print_stats: false,
+                                    ..RenderConfig::default() };
+        let path = std::env::temp_dir()
+            .join(format!("rust_raytracer_fingerprint_{}.bin", std::process::id()))
+            .to_str().unwrap().to_string();
+        raytrace_resumable(&scene, &config, 1, &path, Duration::ZERO).unwrap();
+        let resume = |scene: &Scene, config: &RenderConfig| {
+            raytrace_resumable(scene, config, 2, &path, Duration::ZERO).map_err(|e| e.kind())
+        };
+        let changes = [RenderConfig { max_depth: 5, ..config.clone() },
+                       RenderConfig { anti_alias: AntiAlias::Grid(2), ..config.clone() },
+                       RenderConfig { integrator: IntegratorMode::PathTrace(2), ..config.clone() },
+                       RenderConfig { region: Some((0, 0, 6, 8)), ..config.clone() },
+                       RenderConfig { max_ray_distance: 10.0, ..config.clone() }];
+        for changed in &changes {
+            assert_eq!(resume(&scene, changed).err(), Some(io::ErrorKind::InvalidInput), "{:?}", changed);
+        }
+        assert_eq!(resume(&lit_sphere(v3!(0.0, 1.0, 0.0)), &config).err(), Some(io::ErrorKind::InvalidInput));
+        // Tone mapping comes after the checkpoint, so it may change.
+        assert!(resume(&scene, &RenderConfig { gamma: 1.0, ..config.clone() }).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn distant_textured_sphere_does_not_alias() {
+        // A sphere about ten pixels across, wrapped in a checkerboard of 256
+        // x 128 texels, next to one in the checkerboard's average gray.
+        let (width, height) = (64, 48);
+        let render = |pixels: Vec<Vector3>, w: usize, h: usize| {
+            let texture = ImageTexture::new(pixels, w, h, WrapMode::Repeat, FilterMode::Bilinear);
+            let material = Material { texture: Some(Arc::new(texture)),
+                                      ..white().with_spec_color(v3!(0.0, 0.0, 0.0)) };
+            let scene = SceneBuilder::new()
+                .sphere(v3!(0.0, 0.0, 9.0), 1.0, material)
+                .light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0))
+                .build();
+            let config = RenderConfig { width, height, tone_mapper: Some(ToneMapper::Linear),
+                                        gamma: 1.0, print_stats: false, ..RenderConfig::default() };
+            raytrace(&scene, &config)
+        };
+        let (w, h) = (256, 128);
+        let checkerboard = (0..w*h)
+            .map(|i| if (i % w + i / w) % 2 == 0 { v3!(0.0, 0.0, 0.0) } else { v3!(1.0, 1.0, 1.0) })
+            .collect();
+        let (image, gray) = (render(checkerboard, w, h), render(vec![v3!(0.5, 0.5, 0.5)], 1, 1));
+        // Pixels well inside the silhouette, where the neighboring pixels'
+        // rays land on the sphere too and measure the footprint.
+        let inside: Vec<usize> = (0..width*height)
+            .filter(|&i| (-2..=2).all(|d: isize| {
+                [i as isize + d, i as isize + d*width as isize].iter()
+                    .all(|&j| gray.get(j as usize).is_some_and(|p| p.x > 0.05))
+            }))
+            .collect();
+        assert!(inside.len() > 20, "only {} pixels inside the sphere", inside.len());
+        for &i in &inside {
+            assert!((image[i] - gray[i]).length() < 0.05, "pixel {} is {:?}, not {:?}",
+                    i, image[i], gray[i]);
+        }
+    }
+
+
+    #[test]
+    fn ray_differentials_step_to_the_next_pixel() {
+        let (width, height) = (64, 48);
+        let pinhole = Camera { pos: v3!(0.0, 0.0, -1.0), up: v3!(0.0, 1.0, 0.0), right: v3!(1.33, 0.0, 0.0),
+                               dist: 2.0, aperture: 0.0, focus_distance: 1.0,
+                               projection: Projection::Perspective };
+        let center = |camera: &Camera| {
+            let ray = primary_ray(camera, 32.0, 24.0, width, height, f64::INFINITY, &mut Rng::new(0));
+            let differential = RayDifferential::for_pixel(camera, 32.0, 24.0, width, height, f64::INFINITY,
+                                                          &Rng::new(0));
+            (ray, differential)
+        };
+        // Through the middle of the image plane, a pixel to the right turns
+        // the ray by about a pixel's width over the distance to the plane.
+        let (ray, differential) = center(&pinhole);
+        assert_eq!(differential.dpdx(&ray), v3!(0.0, 0.0, 0.0));
+        let expected = pinhole.right * (2.0 / width as f64 / pinhole.dist);
+        assert!((differential.dddx(&ray) - expected).length() < 0.02*expected.length(),
+                "{:?} instead of {:?}", differential.dddx(&ray), expected);
+        let expected = pinhole.up * (2.0 / height as f64 / pinhole.dist);
+        assert!((differential.dddy(&ray) - expected).length() < 0.02*expected.length());
+
+        // An orthographic camera moves the ray instead of turning it.
+        let orthographic = Camera { projection: Projection::Orthographic, ..pinhole };
+        let (ray, differential) = center(&orthographic);
+        assert_eq!(differential.dddx(&ray).as_array(), [0.0; 3]);
+        assert_eq!(differential.dpdx(&ray), orthographic.right * (2.0 / width as f64));
+        assert_eq!(differential.dpdy(&ray), orthographic.up * (2.0 / height as f64));
+    }
+
+    #[test]
+    fn object_space_noise_moves_with_the_instance() {
+        let color_at = |space: NoiseSpace, offset: Vector3| {
+            let noise = PerlinTexture { scale: 3.0, octaves: 4, color_a: v3!(0.0, 0.0, 0.0),
+                                        color_b: v3!(1.0, 1.0, 1.0), space };
+            let material = Material { texture: Some(Arc::new(noise)), ..white() };
+            let handle = InstanceHandle::new(Sphere { pos: v3!(0.0, 0.0, 0.0), radius: 1.0,
+                                                      material: material.into(), motion: None });
+            let instance = Instance::new(&handle, Matrix4::translate(v3!(0.0, 0.0, 5.0) + offset));
+            // The same point of the sphere, wherever it is.
+            (0..20).map(|i| {
+                let local = v3!(0.08*i as f64 - 0.8, 0.3, -1.0);
+                let mut isect = instance.intersects(&ray(local + offset, v3!(0.0, 0.0, 1.0))).unwrap();
+                apply_textures(&mut isect, None);
+                isect.material.color
+            }).collect::<Vec<_>>()
+        };
+        let offset = v3!(0.37, 1.21, -0.6);
+        assert_eq!(color_at(NoiseSpace::ObjectSpace, v3!(0.0, 0.0, 0.0)),
+                   color_at(NoiseSpace::ObjectSpace, offset));
+        // In world space the pattern swims across the moved sphere.
+        assert_ne!(color_at(NoiseSpace::WorldSpace, v3!(0.0, 0.0, 0.0)),
+                   color_at(NoiseSpace::WorldSpace, offset));
+        // Untransformed shapes have no object space and use world space.
+        let noise = PerlinTexture { scale: 3.0, octaves: 4, color_a: v3!(0.0, 0.0, 0.0),
+                                    color_b: v3!(1.0, 1.0, 1.0), space: NoiseSpace::ObjectSpace };
+        let sphere = Sphere { pos: v3!(0.0, 0.0, 5.0), radius: 1.0,
+                              material: Material { texture: Some(Arc::new(noise.clone())), ..white() }.into(),
+                              motion: None };
+        let mut isect = sphere.intersects(&ray(v3!(0.2, 0.3, 0.0), v3!(0.0, 0.0, 1.0))).unwrap();
+        assert!(isect.object_pos.is_none());
+        apply_textures(&mut isect, None);
+        assert_eq!(isect.material.color, noise.sample(isect.pos, isect.normal, isect.uv));
+    }
+
+    #[test]
+    fn sky_dome_shows_its_texture_without_shadowing_the_scene() {
+        let sky = v3!(1.0, 0.5, 0.25);
+        let texture = ImageTexture::new(vec![sky], 1, 1, WrapMode::Repeat, FilterMode::NearestNeighbor);
+        let sphere = |scene: SceneBuilder| {
+            scene.sphere(v3!(0.0, 0.0, 5.0), 1.0, white().with_spec_color(v3!(0.0, 0.0, 0.0)))
+                 .light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0))
+        };
+        let dome = SkyDome { center: v3!(0.0, 0.0, 0.0), radius: 100.0, texture: Arc::new(texture) };
+        let config = RenderConfig { width: 32, height: 24, tone_mapper: Some(ToneMapper::Linear),
+                                    gamma: 1.0, print_stats: false, ..RenderConfig::default() };
+        let image = raytrace(&sphere(SceneBuilder::new().object(dome)).build(), &config);
+        let plain = raytrace(&sphere(SceneBuilder::new()).build(), &config);
+        // The corner sees the sky instead of the background; the sphere's
+        // center is lit just as without the dome.
+        assert!((image[0] - sky).length() < 1e-6, "corner is {:?}", image[0]);
+        let center = 12*32 + 16;
+        assert!(plain[center].x > 0.1);
+        assert!((image[center] - plain[center]).length() < 1e-6,
+                "center is {:?}, not {:?}", image[center], plain[center]);
+    }
+
+
+    #[test]
+    fn caustic_debug_finds_the_focus_of_a_glass_ball() {
+        let render = |ball: Material| {
+            let scene = SceneBuilder::new()
+                .sphere(v3!(0.0, 0.0, 5.0), 1.5, ball)
+                .plane(v3!(0.0, 0.0, 8.0), v3!(0.0, 0.0, -1.0), white())
+                .light(v3!(0.0, 5.0, 0.0), v3!(1.0, 1.0, 1.0))
+                .build();
+            let config = RenderConfig { width: 64, height: 48, print_stats: false, ..RenderConfig::default() };
+            raytrace_caustic_debug(&scene, &config)
+        };
+        // The ball is a lens: the rays through it crowd onto a patch of the
+        // wall, seen through the ball, a fraction of the ball's size.
+        let glass = render(white().with_transparency(1.0, 1.5));
+        let brightest = glass.iter().map(|p| p.x).fold(0.0, f64::max);
+        assert!(brightest >= 8.0, "brightest pixel gets only {} rays", brightest);
+        let spot = glass.iter().filter(|p| p.x > 0.0).count();
+        let landed: f64 = glass.iter().map(|p| p.x).sum();
+        assert!((spot as f64) < landed / 2.0, "{} rays spread over {} pixels", landed, spot);
+        assert!(glass.iter().all(|p| p.x == p.y && p.y == p.z));
+
+        let opaque = render(white());
+        assert!(opaque.iter().all(|p| p.max_component() == 0.0));
+    }
+
+
+    #[test]
+    fn spotlight_penumbra_fades_smoothly() {
+        // The spotlight sits at the camera and is turned away from the
+        // sphere's center by `angle` degrees.
+        let render = |angle: f64| {
+            let dir = v3!(angle.to_radians().sin(), 0.0, angle.to_radians().cos());
+            let scene = SceneBuilder::new()
+                .sphere(v3!(0.0, 0.0, 5.0), 1.0, white().with_spec_color(v3!(0.0, 0.0, 0.0)))
+                .add_light(Spotlight { pos: v3!(0.0, 0.0, -1.0), dir, color: v3!(1.0, 1.0, 1.0),
+                                       inner_angle: 10f64.to_radians(), outer_angle: 20f64.to_radians() })
+                .build();
+            let config = RenderConfig { width: 32, height: 24, tone_mapper: Some(ToneMapper::Linear),
+                                        gamma: 1.0, print_stats: false, ..RenderConfig::default() };
+            raytrace(&scene, &config)[12*32 + 16].x
+        };
+        let (full, dark) = (render(0.0), render(30.0));
+        assert!(full > dark + 0.1, "the lit sphere is {}, the unlit one {}", full, dark);
+        assert_eq!(render(5.0), full);
+        // Across the penumbra the light fades steadily from full to none.
+        let penumbra: Vec<f64> = (11..20).map(|angle| render(angle as f64)).collect();
+        assert!(penumbra.iter().all(|&p| dark < p && p < full), "{:?}", penumbra);
+        assert!(penumbra.windows(2).all(|w| w[0] > w[1]), "{:?}", penumbra);
+        // The pixel sees a point just off the center, a little farther out.
+        let middle = (render(15.0) - dark) / (full - dark);
+        assert!((middle - 0.5).abs() < 0.05, "halfway through the penumbra gets {}", middle);
+    }
+
+
+    #[test]
+    fn focal_length_camera_blurs_only_out_of_focus_objects() {
+        let camera = Camera::from_focal_length(50.0, 1.8, 1.8, 36.0, v3!(0.0, 0.0, 0.0),
+                                               v3!(0.0, 0.0, 1.0), v3!(0.0, 1.0, 0.0), 4.0 / 3.0);
+        assert!((camera.aperture - 0.05 / 1.8 / 2.0).abs() < 1e-12);
+        assert!((camera.right.length() / camera.dist - 0.36).abs() < 1e-12);
+        assert!((camera.right.length() / camera.up.length() - 4.0 / 3.0).abs() < 1e-12);
+        assert_eq!(camera.focus_distance, 1.8);
+
+        // A sphere on the left at the focus distance, and one on the right
+        // twenty meters away, rendered with the lens and as a pinhole.
+        let (width, height) = (160, 120);
+        let render = |camera: Camera| {
+            let matte = |color| basic_material(color).with_spec_color(v3!(0.0, 0.0, 0.0));
+            let scene = SceneBuilder::new()
+                .camera(camera)
+                .sphere(v3!(-0.25, 0.0, 1.8), 0.1, matte(v3!(1.0, 0.0, 0.0)))
+                .sphere(v3!(3.0, 0.0, 20.0), 1.5, matte(v3!(0.0, 1.0, 0.0)))
+                .light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0))
+                .build();
+            let config = RenderConfig { width, height, anti_alias: AntiAlias::Grid(4),
+                                        print_stats: false, ..RenderConfig::default() };
+            raytrace(&scene, &config)
+        };
+        let pinhole = render(Camera { aperture: 0.0, ..camera.clone() });
+        let lens = render(camera);
+        // A sub-pixel ray grazing the near sphere can land on either side of
+        // its edge, so only changes of a quarter of the pixel's samples or
+        // more count as blur.
+        let changed = |columns: std::ops::Range<usize>| {
+            (0..width*height).filter(|i| columns.contains(&(i % width)))
+                .filter(|&i| (lens[i] - pinhole[i]).length() > 0.25).count()
+        };
+        assert!(pinhole.iter().any(|p| p.x > 0.5) && pinhole.iter().any(|p| p.y > 0.9));
+        assert_eq!(changed(0..width / 2), 0, "the sphere in focus is blurred");
+        assert!(changed(width / 2..width) > 40, "the far sphere is sharp");
+    }
+
+
+    #[test]
+    fn only_occluders_between_surface_and_light_cast_shadows() {
+        // The sphere ahead is lit from behind the camera at z = -5; a small
+        // sphere either past the light or between it and the surface.
+        let center = |occluder: Vector3| {
+            let scene = SceneBuilder::new()
+                .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+                .sphere(v3!(0.0, 0.0, 5.0), 1.0, white())
+                .sphere(occluder, 0.5, white())
+                .light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0))
+                .build();
+            render_pixel(&scene, 16, 16, 32, 32)
+        };
+        let ambient = scene().ambient;
+        let behind = center(v3!(0.0, 0.0, -8.0));
+        assert!(behind.x > ambient.x + 0.5, "lit surface is {:?}", behind);
+        let between = center(v3!(0.0, 0.0, -3.0));
+        assert!((between - ambient).length() < 1e-12, "shadowed surface is {:?}", between);
+    }
+
+
+    #[test]
+    fn glass_sphere_shows_the_background_inverted() {
+        // Red above the horizon ahead, green below, white behind the camera.
+        let background = || Background::Custom(Box::new(|dir: Vector3| {
+            if dir.z < 0.0 {
+                v3!(1.0, 1.0, 1.0)
+            } else if dir.y > 0.0 {
+                v3!(1.0, 0.0, 0.0)
+            } else {
+                v3!(0.0, 1.0, 0.0)
+            }
+        }));
+        let glass = basic_material(v3!(0.0, 0.0, 0.0)).with_transparency(1.0, 1.5);
+        let scene = SceneBuilder::new().background(background())
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, glass)
+            .build();
+        let empty = SceneBuilder::new().background(background()).build();
+        // The column through the middle, from the top of the image.
+        let column = |scene: &Scene| -> Vec<Vector3> {
+            (0..64).map(|row| render_pixel(scene, 32, row, 64, 64)).collect()
+        };
+        let (glass, empty) = (column(&scene), column(&empty));
+        let ball: Vec<usize> = (0..64).filter(|&row| (glass[row] - empty[row]).length() > 1e-9).collect();
+        let (top, bottom) = (ball[0], ball[ball.len() - 1]);
+        assert!(bottom - top > 15, "the ball covers rows {} to {}", top, bottom);
+        // The ball is a lens: above its middle it shows the green below the
+        // horizon, and below its middle the red above.
+        for row in top..=bottom {
+            // The rays on either side of the horizon pass almost through
+            // the ball's center and are barely bent.
+            if row == 31 || row == 32 {
+                continue;
+            }
+            let (p, behind) = (glass[row], empty[row]);
+            if behind.x > 0.5 {
+                assert!(p.y > 0.8 && p.x < 0.2, "row {} is {:?} in front of red", row, p);
+            } else {
+                assert!(p.x > 0.8 && p.y < 0.2, "row {} is {:?} in front of green", row, p);
+            }
+        }
+        // Head-on, a few percent of the white behind the camera is
+        // reflected; towards the rim Fresnel reflection takes over from
+        // the transmitted color.
+        let middle = glass[31];
+        assert!(middle.z > 0.03 && middle.z < 0.1, "the middle is {:?}", middle);
+        let rim = glass[top];
+        assert!(rim.y < middle.y - 0.1, "the rim transmits {:?}, the middle {:?}", rim, middle);
+        assert!(rim.x > middle.x + 0.05, "the rim reflects {:?}, the middle {:?}", rim, middle);
+    }
+
+
+    #[test]
+    fn grid_anti_aliasing_blends_sphere_edges() {
+        // A glowing white sphere on black, so that each ray sees 0 or 1.
+        let scene = SceneBuilder::new()
+            .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+            .sphere(v3!(0.0, 0.0, 5.0), 1.0, emissive_material(v3!(1.0, 1.0, 1.0)))
+            .build();
+        let render = |anti_alias| {
+            let config = RenderConfig { width: 64, height: 64, anti_alias,
+                                        tone_mapper: Some(ToneMapper::Linear),
+                                        gamma: 1.0, print_stats: false, ..RenderConfig::default() };
+            raytrace(&scene, &config)
+        };
+        let single = render(AntiAlias::None);
+        assert!(single.iter().all(|p| p.x == 0.0 || p.x == 1.0));
+        assert!(single.iter().any(|p| p.x == 1.0));
+        // Edge pixels cover the sphere with 1, 2 or 3 of their 4 rays.
+        let grid = render(AntiAlias::Grid(2));
+        assert!(grid.iter().all(|p| [0.0, 0.25, 0.5, 0.75, 1.0].contains(&p.x)));
+        for level in [0.25, 0.5, 0.75] {
+            assert!(grid.iter().any(|p| p.x == level), "no edge pixel is {}", level);
+        }
+        assert!(grid.iter().all(|p| p.x == p.y && p.y == p.z));
+    }
+
+
+    #[test]
+    fn thin_lens_keeps_the_focus_plane_sharp() {
+        // A small sphere on the left in the focus plane, 4 units ahead of
+        // the camera, and a big one on the right far behind it.
+        let (width, height) = (96, 96);
+        let render = |aperture: f64, focus_distance: f64| {
+            let matte = |color| basic_material(color).with_spec_color(v3!(0.0, 0.0, 0.0));
+            let camera = Camera { aperture, focus_distance, ..scene().camera };
+            let scene = SceneBuilder::new()
+                .camera(camera)
+                .sphere(v3!(-0.8, 0.0, 3.0), 0.4, matte(v3!(1.0, 0.0, 0.0)))
+                .sphere(v3!(8.0, 0.0, 40.0), 4.0, matte(v3!(0.0, 1.0, 0.0)))
+                .light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0))
+                .build();
+            let config = RenderConfig { width, height, anti_alias: AntiAlias::Grid(4),
+                                        print_stats: false, ..RenderConfig::default() };
+            raytrace(&scene, &config)
+        };
+        // Without an aperture the focus distance makes no difference.
+        let pinhole = render(0.0, 1.0);
+        assert!(render(0.0, 7.5).iter().zip(&pinhole).all(|(a, b)| a.as_array() == b.as_array()));
+
+        let lens = render(0.08, 4.0);
+        // A sub-pixel ray grazing the near sphere can land on either side of
+        // its edge, so only changes of a quarter of the pixel's samples or
+        // more count as blur.
+        let changed = |columns: std::ops::Range<usize>| {
+            (0..width*height).filter(|i| columns.contains(&(i % width)))
+                .filter(|&i| (lens[i] - pinhole[i]).length() > 0.25).count()
+        };
+        assert!(pinhole.iter().any(|p| p.x > 0.5) && pinhole.iter().any(|p| p.y > 0.9));
+        for i in 0..width*height { if i % width < width/2 && (lens[i]-pinhole[i]).length() > 0.1 { eprintln!("{} {} {:?} {:?}", i%width, i/width, lens[i], pinhole[i]); } }
+        assert_eq!(changed(0..width / 2), 0, "the sphere in focus is blurred");
+        assert!(changed(width / 2..width) > 30, "the far sphere is sharp");
+    }
+
+
+    #[test]
+    fn orthographic_spheres_keep_their_size_with_distance() {
+        // Width in pixels of a glowing unit sphere straight ahead, across
+        // the middle row, its height down the middle column and its area.
+        let size = |projection: Projection, z: f64| {
+            let camera = Camera { right: v3!(2.0, 0.0, 0.0), up: v3!(0.0, 2.0, 0.0), projection,
+                                  ..scene().camera };
+            let scene = SceneBuilder::new()
+                .camera(camera)
+                .background(Background::Solid(v3!(0.0, 0.0, 0.0)))
+                .sphere(v3!(0.0, 0.0, z), 1.0, emissive_material(v3!(1.0, 1.0, 1.0)))
+                .build();
+            let config = RenderConfig { width: 64, height: 64, print_stats: false, ..RenderConfig::default() };
+            let image = raytrace(&scene, &config);
+            let row = (0..64).filter(|&x| image[32*64 + x].x > 0.5).count();
+            let column = (0..64).filter(|&y| image[y*64 + 32].x > 0.5).count();
+            (row, column, image.iter().filter(|p| p.x > 0.5).count())
+        };
+        // The image is 4 units across, so the sphere is a circle 16 pixels
+        // in radius, however far away. The rays go through the pixels'
+        // corners, so the middle row and column are off center by half a
+        // pixel.
+        let near = size(Projection::Orthographic, 5.0);
+        assert!((near.0 as i64 - 32).abs() <= 1 && (near.1 as i64 - 32).abs() <= 1, "{:?}", near);
+        let area = std::f64::consts::PI * 16.0 * 16.0;
+        assert!((near.2 as f64 - area).abs() < 0.02 * area, "{:?}", near);
+        assert_eq!(size(Projection::Orthographic, 50.0), near);
+        let (close, far) = (size(Projection::Perspective, 5.0), size(Projection::Perspective, 50.0));
+        assert!(far.0 * 4 < close.0, "perspective sizes {:?} and {:?}", close, far);
+    }
 }
@@ -0,0 +1,95 @@
+//! Random directions for Monte Carlo integration. Each sampler maps two
+//! uniform random numbers in [0, 1) to a unit direction in a local frame
+//! with z up; `local_to_world` turns it around a surface normal. Passing
+//! stratified pairs of numbers gives stratified directions.
+
+use std::f64::consts::PI;
+
+use vector::Vector3;
+
+/// Direction in the hemisphere around z with probability density
+/// `cos(theta) / pi`, where theta is its angle to z.
+pub fn cosine_hemisphere_sample(u1: f64, u2: f64) -> Vector3 {
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    v3!(r*phi.cos(), r*phi.sin(), (1.0 - r*r).max(0.0).sqrt())
+}
+
+/// Probability density of `cosine_hemisphere_sample` for `dir` once it is
+/// turned around `normal`. Both must be unit vectors.
+pub fn pdf_cosine_hemisphere(dir: Vector3, normal: Vector3) -> f64 {
+    dir.dot(normal).max(0.0) / PI
+}
+
+/// Direction anywhere on the unit sphere, each equally likely: a
+/// probability density of `1 / (4 pi)`.
+pub fn uniform_sphere_sample(u1: f64, u2: f64) -> Vector3 {
+    let z = 1.0 - 2.0*u1;
+    let r = (1.0 - z*z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    v3!(r*phi.cos(), r*phi.sin(), z)
+}
+
+/// Direction within the cone of directions whose angle to z has cosine at
+/// least `cos_max`, each equally likely: a probability density of
+/// `1 / (2 pi (1 - cos_max))`.
+pub fn uniform_cone_sample(u1: f64, u2: f64, cos_max: f64) -> Vector3 {
+    let z = 1.0 - u1*(1.0 - cos_max);
+    let r = (1.0 - z*z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    v3!(r*phi.cos(), r*phi.sin(), z)
+}
+
+/// Direction around z with probability density
+/// `(exponent + 1) / (2 pi) * cos(theta)^exponent`, concentrated more
+/// tightly around z the higher `exponent` is. For sampling specular lobes,
+/// with z along the mirror direction.
+pub fn power_cosine_sample(u1: f64, u2: f64, exponent: f64) -> Vector3 {
+    let cos_theta = u1.powf(1.0 / (exponent + 1.0));
+    let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    v3!(sin_theta*phi.cos(), sin_theta*phi.sin(), cos_theta)
+}
+
+/// `local` in a frame whose z axis is the unit vector `normal`.
+pub fn local_to_world(local: Vector3, normal: Vector3) -> Vector3 {
+    let a = if normal.x.abs() > 0.9 { v3!(0.0, 1.0, 0.0) } else { v3!(1.0, 0.0, 0.0) };
+    let tangent = a.cross(&normal).normalize();
+    let bitangent = normal.cross(&tangent);
+    (tangent*local.x + bitangent*local.y + normal*local.z).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rng::Rng;
+
+    #[test]
+    fn cosine_samples_center_on_the_normal() {
+        let normal = v3!(1.0, 2.0, -0.5).normalize();
+        let mut rng = Rng::new(5);
+        let mut sum = v3!(0.0, 0.0, 0.0);
+        for _ in 0..10000 {
+            let dir = local_to_world(cosine_hemisphere_sample(rng.next_f64(), rng.next_f64()), normal);
+            assert!(dir.dot(normal) >= 0.0);
+            sum += dir;
+        }
+        let mean = sum / 10000.0;
+        // The mean cosine of the distribution is 2/3.
+        assert!((mean.length() - 2.0/3.0).abs() < 0.01, "mean length {}", mean.length());
+        assert!(mean.normalize().dot(normal) > 0.999, "mean direction {:?}", mean);
+    }
+
+    #[test]
+    fn cosine_pdf_integrates_to_one() {
+        let normal = v3!(0.0, 1.0, 0.0);
+        let mut rng = Rng::new(9);
+        let n = 100000;
+        let sum: f64 = (0..n)
+            .map(|_| pdf_cosine_hemisphere(uniform_sphere_sample(rng.next_f64(), rng.next_f64()), normal))
+            .sum();
+        // Uniform sphere samples have density 1 / (4 pi).
+        let integral = sum / n as f64 * 4.0 * PI;
+        assert!((integral - 1.0).abs() < 0.02, "pdf integrates to {}", integral);
+    }
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+
+use raytracer::{scene, AreaLight, BBox, Background, BoundedPlane, Camera, Capsule, Cone, Cylinder,
+                DirectionalLight, Disk, Fog, Geometry, Light, LightSource, Material, MaterialRef, Plane,
+                Scene, Sphere, Spotlight, Torus, Triangle};
+use tonemap::ToneMapper;
+use vector::Vector3;
+
+/// Serializable description of a `Scene`, the format of JSON scene files.
+#[derive(Serialize, Deserialize)]
+pub struct SceneFile {
+    pub camera: Camera,
+    #[serde(default)]
+    pub background: Background,
+    #[serde(default)]
+    pub tone_mapper: ToneMapper,
+    #[serde(default = "default_ambient")]
+    pub ambient: Vector3,
+    #[serde(default)]
+    pub fog: Option<Fog>,
+    /// Materials shared by name, in `MaterialId` order.
+    #[serde(default)]
+    pub materials: Vec<(String, Material)>,
+    #[serde(default)]
+    pub lights: Vec<LightDesc>,
+    #[serde(default)]
+    pub objects: Vec<ObjectDesc>,
+}
+
+/// A geometry object in a scene file, selected by its `type` field.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ObjectDesc {
+    Sphere(Sphere),
+    Plane(Plane),
+    BoundedPlane(BoundedPlane),
+    Disk(Disk),
+    BBox(BBox),
+    Triangle {
+        v0: Vector3,
+        v1: Vector3,
+        v2: Vector3,
+        #[serde(default)]
+        vertex_normals: [Option<Vector3>; 3],
+        #[serde(default)]
+        vertex_uvs: [Option<(f64, f64)>; 3],
+        material: MaterialRef,
+    },
+    Cylinder(Cylinder),
+    Capsule(Capsule),
+    Cone(Cone),
+    Torus(Torus),
+}
+
+/// A light in a scene file, selected by its `type` field.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum LightDesc {
+    Light(Light),
+    DirectionalLight(DirectionalLight),
+    Spotlight(Spotlight),
+    AreaLight(AreaLight),
+}
+
+impl ObjectDesc {
+    pub fn into_geometry(self) -> Box<dyn Geometry> {
+        match self {
+            ObjectDesc::Sphere(o) => Box::new(o),
+            ObjectDesc::Plane(o) => Box::new(o),
+            ObjectDesc::BoundedPlane(o) => Box::new(o),
+            ObjectDesc::Disk(o) => Box::new(o),
+            ObjectDesc::BBox(o) => Box::new(o),
+            ObjectDesc::Triangle { v0, v1, v2, vertex_normals, vertex_uvs, material } => {
+                let mut triangle = Triangle::new(v0, v1, v2, material);
+                triangle.vertex_normals = vertex_normals;
+                triangle.vertex_uvs = vertex_uvs;
+                Box::new(triangle)
+            },
+            ObjectDesc::Cylinder(o) => Box::new(o),
+            ObjectDesc::Capsule(o) => Box::new(o),
+            ObjectDesc::Cone(o) => Box::new(o),
+            ObjectDesc::Torus(o) => Box::new(o),
+        }
+    }
+}
+
+impl LightDesc {
+    pub fn into_light(self) -> Box<dyn LightSource> {
+        match self {
+            LightDesc::Light(l) => Box::new(l),
+            LightDesc::DirectionalLight(l) => Box::new(l),
+            LightDesc::Spotlight(l) => Box::new(l),
+            LightDesc::AreaLight(l) => Box::new(l),
+        }
+    }
+}
+
+impl SceneFile {
+    /// Describes `scene`, leaving out what can't be saved: objects and
+    /// lights without a description, textures, and custom or image
+    /// backgrounds.
+    pub fn from_scene(scene: &Scene) -> SceneFile {
+        let background = match scene.background {
+            Background::Solid(color) => Background::Solid(color),
+            Background::Gradient { top, bottom } => Background::Gradient { top, bottom },
+            Background::Atmosphere(ref model) => Background::Atmosphere(model.clone()),
+            Background::Custom(_) | Background::EnvMap(_) => Background::default(),
+        };
+        SceneFile { camera: scene.camera.clone(),
+                    background,
+                    tone_mapper: scene.tone_mapper(),
+                    ambient: scene.ambient,
+                    fog: scene.fog,
+                    materials: scene.materials().to_vec(),
+                    lights: scene.lights.iter().filter_map(|l| l.to_desc()).collect(),
+                    objects: scene.all_objects().filter_map(|o| o.to_desc()).collect() }
+    }
+
+    pub fn into_scene(self) -> Scene {
+        let mut scene = scene();
+        scene.camera = self.camera;
+        scene.background = self.background;
+        scene.set_tone_mapper(self.tone_mapper);
+        scene.ambient = self.ambient;
+        scene.fog = self.fog;
+        for (name, material) in self.materials {
+            scene.register_material(&name, material);
+        }
+        scene.lights = self.lights.into_iter().map(LightDesc::into_light).collect();
+        scene.objects = self.objects.into_iter().map(ObjectDesc::into_geometry).collect();
+        scene
+    }
+}
+
+fn default_ambient() -> Vector3 {
+    scene().ambient
+}
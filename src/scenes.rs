@@ -0,0 +1,145 @@
+use raytracer::{basic_material, emissive_material, scene, AreaLight, Background, BBox,
+                BoundedPlane, Camera, DirectionalLight, Material, Plane, Scene, Sphere};
+use rng::Rng;
+use transform::Transform;
+use vector::{Matrix4, Vector3};
+
+/// `image_checksum` of `raytrace(&cornell_box(), &RenderConfig::default())`.
+/// A different checksum means the renderer's output has changed.
+pub const CORNELL_BOX_CHECKSUM: u64 = 17046932836036520248;
+
+/// Spheres scattered over a ground plane, after the final scene of Peter
+/// Shirley's "Ray Tracing in One Weekend": `num_spheres` spheres resting on
+/// the ground with random positions, radii and colors. About 80% are
+/// diffuse, 15% mirrors and 5% glass. The same `seed` always gives the
+/// same scene. The BVH isn't built.
+pub fn random_scene(num_spheres: usize, seed: u64) -> Scene {
+    let mut scene = scene();
+    scene.camera = Camera::look_at(v3!(13.0, 2.0, 3.0), v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0),
+                                   20.0, 4.0 / 3.0);
+    scene.add(Plane { pos: v3!(0.0, 0.0, 0.0),
+                      normal: v3!(0.0, 1.0, 0.0),
+                      material: basic_material(v3!(0.5, 0.5, 0.5)).into(),
+                      uv_scale: 1.0 });
+    scene.add_light(DirectionalLight { dir: v3!(-1.0, -2.0, -0.5), color: v3!(1.0, 1.0, 1.0) });
+
+    let mut rng = Rng::new(seed);
+    for _ in 0..num_spheres {
+        let radius = 0.1 + rng.next_f64()*0.3;
+        let pos = v3!(rng.next_f64()*22.0 - 11.0, radius, rng.next_f64()*22.0 - 11.0);
+        let color = v3!(rng.next_f64(), rng.next_f64(), rng.next_f64());
+        let choice = rng.next_f64();
+        let material = if choice < 0.8 {
+            basic_material(color)
+        } else if choice < 0.95 {
+            basic_material(color*0.5 + v3!(0.5, 0.5, 0.5))
+                .with_reflection(0.5 + rng.next_f64()*0.5)
+                .with_shininess(64.0)
+        } else {
+            basic_material(v3!(1.0, 1.0, 1.0)).with_transparency(0.9, 1.5).with_shininess(128.0)
+        };
+        scene.add(Sphere { pos, radius, material: material.into(), motion: None });
+    }
+    scene
+}
+
+/// The Cornell Box, scaled down from its measured 555 units to 5.55: a
+/// room open towards the camera with a red left wall, a green right wall,
+/// white floor, ceiling and back wall, a tall white box at the back right
+/// turned 15 degrees and a short one at the front left turned -18 degrees.
+/// A rectangular area light just under the middle of the ceiling lights it
+/// from above, so both boxes cast soft shadows on the floor. Every surface
+/// is matte, without highlights. The room is framed on a black background
+/// that fills the sides of a 4:3 image. The BVH is built.
+pub fn cornell_box() -> Scene {
+    const SIZE: f64 = 5.55;
+    let white = v3!(0.73, 0.73, 0.73);
+    let mut scene = scene();
+    scene.camera = Camera::look_at(v3!(SIZE / 2.0, SIZE / 2.0, -8.0),
+                                   v3!(SIZE / 2.0, SIZE / 2.0, 0.0),
+                                   v3!(0.0, 1.0, 0.0), 40.0, 4.0 / 3.0);
+    scene.background = Background::Solid(v3!(0.0, 0.0, 0.0));
+
+    let mut wall = |pos: Vector3, normal: Vector3, color: Vector3| {
+        // Walls span the whole room across their two minor axes.
+        let min = pos * normal.abs();
+        let max = v3!(SIZE, SIZE, SIZE) - (v3!(SIZE, SIZE, SIZE) - pos) * normal.abs();
+        scene.add(BoundedPlane { pos, normal, min, max, material: matte(color).into() });
+    };
+    wall(v3!(0.0, 0.0, 0.0), v3!(1.0, 0.0, 0.0), v3!(0.65, 0.05, 0.05));
+    wall(v3!(SIZE, 0.0, 0.0), v3!(-1.0, 0.0, 0.0), v3!(0.12, 0.45, 0.15));
+    wall(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), white);
+    wall(v3!(0.0, SIZE, 0.0), v3!(0.0, -1.0, 0.0), white);
+    wall(v3!(0.0, 0.0, SIZE), v3!(0.0, 0.0, -1.0), white);
+
+    let mut block = |size: Vector3, pos: Vector3, degrees: f64| {
+        let matrix = Matrix4::translate(pos + v3!(0.0, size.y / 2.0, 0.0))
+            * Matrix4::rotate_y(degrees.to_radians());
+        let bbox = BBox { v1: size * -0.5, v2: size * 0.5,
+                          material: matte(white).into(), motion: None };
+        scene.add(Transform::new(matrix, Box::new(bbox)));
+    };
+    block(v3!(1.65, 3.3, 1.65), v3!(3.68, 0.0, 3.51), 15.0);
+    block(v3!(1.65, 1.65, 1.65), v3!(1.85, 0.0, 1.69), -18.0);
+
+    // The visible light panel sits just above the light itself, so that
+    // it doesn't shadow it.
+    let (light_min, light_max) = (v3!(2.13, SIZE - 0.01, 2.27), v3!(3.43, SIZE - 0.01, 3.32));
+    scene.add(BoundedPlane { pos: light_min + v3!(0.0, 0.005, 0.0),
+                             normal: v3!(0.0, -1.0, 0.0),
+                             min: light_min + v3!(0.0, 0.005, 0.0),
+                             max: light_max + v3!(0.0, 0.005, 0.0),
+                             material: emissive_material(v3!(1.0, 1.0, 1.0)).into() });
+    scene.add_light(AreaLight { pos: light_min,
+                                u: v3!(light_max.x - light_min.x, 0.0, 0.0),
+                                v: v3!(0.0, 0.0, light_max.z - light_min.z),
+                                color: v3!(1.0, 1.0, 1.0),
+                                samples: 16 });
+    scene.build_bvh();
+    scene
+}
+
+fn matte(color: Vector3) -> Material {
+    basic_material(color).with_spec_color(v3!(0.0, 0.0, 0.0))
+}
+
+/// 64-bit FNV-1a hash of the pixels quantized to 8 bits per channel, as
+/// they would be saved. Small floating point differences that don't change
+/// the saved image don't change the checksum.
+pub fn image_checksum(pixels: &[Vector3]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for p in pixels {
+        let (r, g, b) = p.to_rgb_gamma(1.0);
+        for byte in [r, g, b] {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raytracer::{raytrace, RenderConfig};
+
+    #[test]
+    fn random_scene_depends_only_on_the_seed() {
+        let (a, b) = (random_scene(200, 42), random_scene(200, 42));
+        assert_eq!(a.to_json(), b.to_json());
+        assert!(random_scene(200, 43).to_json() != a.to_json());
+        // The ground and the spheres, some of them mirrors and some glass.
+        assert_eq!(a.objects.len(), 201);
+        let materials: Vec<Material> = a.objects.iter().skip(1).map(|o| o.material()).collect();
+        assert!(materials.iter().any(|m| m.reflection > 0.0));
+        assert!(materials.iter().any(|m| m.transparency > 0.0));
+        assert!(materials.iter().filter(|m| m.reflection == 0.0 && m.transparency == 0.0).count() > 100);
+    }
+
+    #[test]
+    #[ignore = "renders 800 x 600, about 10 s without optimizations; run with --ignored"]
+    fn cornell_box_matches_its_checksum() {
+        let pixels = raytrace(&cornell_box(), &RenderConfig::default());
+        assert_eq!(image_checksum(&pixels), CORNELL_BOX_CHECKSUM);
+    }
+}
@@ -0,0 +1,137 @@
+//! Shadow maps, after Lance Williams' "Casting Curved Shadows on Curved
+//! Surfaces". The scene is seen once from a directional light, storing how
+//! far each texel's ray travels before it hits something. A point is then
+//! in shadow if it lies farther from the light than what its texel saw,
+//! which costs a lookup instead of a shadow ray. Shadows are only as sharp
+//! as the texels, and a small depth bias is needed so that surfaces don't
+//! shadow themselves.
+
+use raytracer::{DirectionalLight, Ray, Scene};
+use vector::{Matrix4, Vector3};
+
+/// How far in front of the scene's bounds the depth rays start.
+const NEAR_MARGIN: f64 = 1.0;
+
+/// Depths seen from a directional light, for `ShadowMode::ShadowMap`.
+/// Only bounded objects are fitted into the map; points outside it, and
+/// shadows cast by unbounded objects such as planes, are never in shadow.
+#[derive(Clone)]
+pub struct ShadowMap {
+    /// Distance along the light from the near plane to the first hit, per
+    /// texel in row-major order, or infinity where nothing was hit.
+    pub texture: Vec<f64>,
+    pub width: usize,
+    pub height: usize,
+    /// Rotates world space so that the light shines along +z.
+    pub light_view: Matrix4,
+    /// Maps the light space bounds of the scene to [0, 1] in x and y, and
+    /// depth from the near plane in z.
+    pub light_proj: Matrix4,
+    /// Depth difference below which a point counts as the surface its
+    /// texel saw.
+    bias: f64,
+}
+
+impl ShadowMap {
+    /// Renders the depth map of `scene` as seen from `light`, with
+    /// `resolution` by `resolution` texels covering the bounds of the
+    /// scene's bounded objects.
+    pub fn build(scene: &Scene, light: &DirectionalLight, resolution: usize) -> ShadowMap {
+        let dir = light.dir.normalize();
+        let helper = if dir.y.abs() < 0.9 { v3!(0.0, 1.0, 0.0) } else { v3!(1.0, 0.0, 0.0) };
+        let right = helper.cross(&dir).normalize();
+        let up = dir.cross(&right);
+        let light_view = Matrix4 { m: [[right.x, right.y, right.z, 0.0],
+                                        [up.x, up.y, up.z, 0.0],
+                                        [dir.x, dir.y, dir.z, 0.0],
+                                        [0.0, 0.0, 0.0, 1.0]] };
+
+        let bounds = scene.all_objects().filter_map(|o| o.bounding_box()).reduce(|a, b| a.merge(&b));
+        let bounds = match bounds {
+            Some(bounds) => bounds,
+            None => return ShadowMap { texture: vec![], width: 0, height: 0, light_view,
+                                       light_proj: Matrix4::identity(), bias: 0.0 },
+        };
+        let (mut min, mut max) = (v3!(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+                                  v3!(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY));
+        for i in 0..8 {
+            let corner = v3!(if i & 1 == 0 { bounds.min.x } else { bounds.max.x },
+                             if i & 2 == 0 { bounds.min.y } else { bounds.max.y },
+                             if i & 4 == 0 { bounds.min.z } else { bounds.max.z });
+            let p = light_view.transform_point(corner);
+            min = Vector3::zip_with(min, p, f64::min);
+            max = Vector3::zip_with(max, p, f64::max);
+        }
+        let size = (max - min).map(|s| s.max(1e-9));
+        let near = min.z - NEAR_MARGIN;
+        let light_proj = Matrix4::scale(v3!(1.0 / size.x, 1.0 / size.y, 1.0))
+            * Matrix4::translate(v3!(-min.x, -min.y, -near));
+
+        let to_world = light_view.invert().expect("rotation is invertible");
+        let mut texture = Vec::with_capacity(resolution * resolution);
+        for j in 0..resolution {
+            for i in 0..resolution {
+                let (u, v) = ((i as f64 + 0.5) / resolution as f64, (j as f64 + 0.5) / resolution as f64);
+                let origin = to_world.transform_point(v3!(min.x + u*size.x, min.y + v*size.y, near));
+                let ray = Ray { origin, dir, t_max: f64::INFINITY };
+                texture.push(scene.intersect(&ray).map_or(f64::INFINITY, |isect| isect.dist));
+            }
+        }
+        // A texel covers a range of depths on a sloping surface; two
+        // texels' worth allows for slopes up to about 60 degrees.
+        let bias = 2.0 * size.x.max(size.y) / resolution as f64 + 1e-3;
+        ShadowMap { texture, width: resolution, height: resolution, light_view, light_proj, bias }
+    }
+
+    /// Whether something lies between `world_pos` and the light.
+    pub fn test(&self, world_pos: Vector3) -> bool {
+        let p = (self.light_proj * self.light_view).transform_point(world_pos);
+        if !(0.0..1.0).contains(&p.x) || !(0.0..1.0).contains(&p.y) {
+            return false;
+        }
+        let (i, j) = ((p.x * self.width as f64) as usize, (p.y * self.height as f64) as usize);
+        match self.texture.get(j*self.width + i) {
+            Some(&depth) => p.z > depth + self.bias,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raytracer::{basic_material, SceneBuilder};
+
+    #[test]
+    fn shadow_map_matches_shadow_rays() {
+        let white = basic_material(v3!(1.0, 1.0, 1.0));
+        let scene = SceneBuilder::new()
+            .sphere(v3!(0.0, 0.0, 0.0), 1.0, white.clone())
+            .bbox(v3!(-3.0, -2.0, -3.0), v3!(3.0, -1.5, 3.0), white)
+            .build();
+        let light = DirectionalLight { dir: v3!(0.3, -1.0, 0.2), color: v3!(1.0, 1.0, 1.0) };
+        let map = ShadowMap::build(&scene, &light, 256);
+
+        // Points on top of the slab, where the sphere's shadow falls.
+        let (mut disagree, mut shadowed) = (0, 0);
+        for i in 0..60 {
+            for j in 0..60 {
+                let p = v3!(-2.95 + 0.1*i as f64, -1.5, -2.95 + 0.1*j as f64);
+                let towards_light = -light.dir.normalize();
+                let ray = Ray { origin: p + towards_light*1e-3, dir: towards_light, t_max: f64::INFINITY };
+                let traced = scene.intersect(&ray).is_some();
+                shadowed += traced as usize;
+                disagree += (traced != map.test(p)) as usize;
+            }
+        }
+        assert!(shadowed > 300, "only {} points in shadow", shadowed);
+        // Only points within a texel or two of the shadow's edge may differ.
+        assert!(disagree < shadowed / 20, "{} of {} shadowed points disagree", disagree, shadowed);
+        // Nothing shadows the sphere's top, and its bottom is in its own
+        // shadow.
+        assert!(!map.test(v3!(0.0, 1.0, 0.0)));
+        assert!(map.test(v3!(0.0, -1.0, 0.0)));
+        // Outside the map.
+        assert!(!map.test(v3!(50.0, -1.5, 0.0)));
+    }
+}
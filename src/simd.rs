@@ -0,0 +1,373 @@
+//! Four-wide `f32` vector math for testing one ray against four spheres at
+//! once. Uses SSE on x86_64 and plain arrays elsewhere. `Bvh` uses
+//! `SpherePacket` to skip the spheres in a leaf that a ray misses.
+//!
+//! The four-wide distance test is about 1.6x as fast as the scalar one
+//! (`examples/simd_spheres.rs`: 4.0 ms against 6.5 ms for a million
+//! queries on x86_64), well short of 4x. It doesn't make `Bvh` traversal
+//! faster (`examples/bvh_traversal.rs`: 542 ms against 539 ms without
+//! the feature), because the leaf boxes already reject most misses.
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+use std::ops::{Add, Mul, Sub};
+
+use raytracer::{Geometry, Ray};
+use scene_file::ObjectDesc;
+use vector::Vector3;
+
+// SSE is part of the x86_64 baseline, so its intrinsics are always safe to
+// call there.
+#[cfg(target_arch = "x86_64")]
+type Lanes = __m128;
+#[cfg(not(target_arch = "x86_64"))]
+type Lanes = [f32; 4];
+
+/// Four `f32` lanes. Comparisons return masks, with every bit of a lane
+/// set where the comparison holds, for `select`.
+#[derive(Copy, Clone, Debug)]
+pub struct F32x4(Lanes);
+
+#[cfg(target_arch = "x86_64")]
+impl F32x4 {
+    pub fn splat(v: f32) -> F32x4 {
+        unsafe { F32x4(_mm_set1_ps(v)) }
+    }
+
+    pub fn from_array(a: [f32; 4]) -> F32x4 {
+        unsafe { F32x4(_mm_setr_ps(a[0], a[1], a[2], a[3])) }
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        let mut a = [0.0; 4];
+        unsafe { _mm_storeu_ps(a.as_mut_ptr(), self.0) };
+        a
+    }
+
+    pub fn sqrt(self) -> F32x4 {
+        unsafe { F32x4(_mm_sqrt_ps(self.0)) }
+    }
+
+    pub fn max(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_max_ps(self.0, other.0)) }
+    }
+
+    pub fn lt(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_cmplt_ps(self.0, other.0)) }
+    }
+
+    pub fn le(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_cmple_ps(self.0, other.0)) }
+    }
+
+    pub fn and(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_and_ps(self.0, other.0)) }
+    }
+
+    /// One bit per lane, set where the lane's sign bit is, as for masks.
+    pub fn bitmask(self) -> u32 {
+        unsafe { _mm_movemask_ps(self.0) as u32 }
+    }
+
+    /// `a` in the lanes where `self` is set, otherwise `b`.
+    pub fn select(self, a: F32x4, b: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_or_ps(_mm_and_ps(self.0, a.0), _mm_andnot_ps(self.0, b.0))) }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl F32x4 {
+    pub fn splat(v: f32) -> F32x4 {
+        F32x4([v; 4])
+    }
+
+    pub fn from_array(a: [f32; 4]) -> F32x4 {
+        F32x4(a)
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        self.0
+    }
+
+    fn map(self, f: impl Fn(f32) -> f32) -> F32x4 {
+        F32x4([f(self.0[0]), f(self.0[1]), f(self.0[2]), f(self.0[3])])
+    }
+
+    fn zip(self, other: F32x4, f: impl Fn(f32, f32) -> f32) -> F32x4 {
+        F32x4([f(self.0[0], other.0[0]), f(self.0[1], other.0[1]),
+               f(self.0[2], other.0[2]), f(self.0[3], other.0[3])])
+    }
+
+    fn mask(set: bool) -> f32 {
+        f32::from_bits(if set { !0 } else { 0 })
+    }
+
+    pub fn sqrt(self) -> F32x4 {
+        self.map(f32::sqrt)
+    }
+
+    pub fn max(self, other: F32x4) -> F32x4 {
+        self.zip(other, f32::max)
+    }
+
+    pub fn lt(self, other: F32x4) -> F32x4 {
+        self.zip(other, |a, b| F32x4::mask(a < b))
+    }
+
+    pub fn le(self, other: F32x4) -> F32x4 {
+        self.zip(other, |a, b| F32x4::mask(a <= b))
+    }
+
+    pub fn and(self, other: F32x4) -> F32x4 {
+        self.zip(other, |a, b| f32::from_bits(a.to_bits() & b.to_bits()))
+    }
+
+    /// One bit per lane, set where the lane's sign bit is, as for masks.
+    pub fn bitmask(self) -> u32 {
+        (0..4).map(|i| (self.0[i].to_bits() >> 31) << i).sum()
+    }
+
+    /// `a` in the lanes where `self` is set, otherwise `b`.
+    pub fn select(self, a: F32x4, b: F32x4) -> F32x4 {
+        let mut lanes = b.0;
+        for (i, lane) in lanes.iter_mut().enumerate() {
+            if self.0[i].to_bits() != 0 {
+                *lane = a.0[i];
+            }
+        }
+        F32x4(lanes)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Add for F32x4 {
+    type Output = F32x4;
+
+    fn add(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_add_ps(self.0, other.0)) }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Sub for F32x4 {
+    type Output = F32x4;
+
+    fn sub(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_sub_ps(self.0, other.0)) }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Mul for F32x4 {
+    type Output = F32x4;
+
+    fn mul(self, other: F32x4) -> F32x4 {
+        unsafe { F32x4(_mm_mul_ps(self.0, other.0)) }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Add for F32x4 {
+    type Output = F32x4;
+
+    fn add(self, other: F32x4) -> F32x4 {
+        self.zip(other, |a, b| a + b)
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Sub for F32x4 {
+    type Output = F32x4;
+
+    fn sub(self, other: F32x4) -> F32x4 {
+        self.zip(other, |a, b| a - b)
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Mul for F32x4 {
+    type Output = F32x4;
+
+    fn mul(self, other: F32x4) -> F32x4 {
+        self.zip(other, |a, b| a * b)
+    }
+}
+
+/// Four 3D vectors stored by component, so that each operation works on
+/// all four at once.
+#[derive(Copy, Clone, Debug)]
+pub struct Vector3f32x4 {
+    pub x: F32x4,
+    pub y: F32x4,
+    pub z: F32x4,
+}
+
+impl Vector3f32x4 {
+    pub fn new(v: [Vector3; 4]) -> Vector3f32x4 {
+        let lanes = |f: fn(&Vector3) -> f64| {
+            F32x4::from_array([f(&v[0]) as f32, f(&v[1]) as f32, f(&v[2]) as f32, f(&v[3]) as f32])
+        };
+        Vector3f32x4 { x: lanes(|v| v.x), y: lanes(|v| v.y), z: lanes(|v| v.z) }
+    }
+
+    /// The same vector in every lane.
+    pub fn splat(v: Vector3) -> Vector3f32x4 {
+        Vector3f32x4 { x: F32x4::splat(v.x as f32),
+                       y: F32x4::splat(v.y as f32),
+                       z: F32x4::splat(v.z as f32) }
+    }
+
+    pub fn dot(&self, other: &Vector3f32x4) -> F32x4 {
+        self.x*other.x + self.y*other.y + self.z*other.z
+    }
+}
+
+impl Sub for Vector3f32x4 {
+    type Output = Vector3f32x4;
+
+    fn sub(self, other: Vector3f32x4) -> Vector3f32x4 {
+        Vector3f32x4 { x: self.x - other.x, y: self.y - other.y, z: self.z - other.z }
+    }
+}
+
+/// Distance along the ray from `origin` in the unit direction `dir` to
+/// each of four spheres, or infinity where the ray misses. Like
+/// `Sphere::intersects`, a ray starting inside a sphere hits its far side.
+pub fn intersect_spheres_x4(origin: Vector3, dir: Vector3, centers: &Vector3f32x4,
+                            radii: F32x4) -> [f32; 4] {
+    let l = *centers - Vector3f32x4::splat(origin);
+    let tca = l.dot(&Vector3f32x4::splat(dir));
+    let radius2 = radii*radii;
+    let d2 = l.dot(&l) - tca*tca;
+    let thc = (radius2 - d2).max(F32x4::splat(0.0)).sqrt();
+    let t0 = tca - thc;
+    let t1 = tca + thc;
+
+    let zero = F32x4::splat(0.0);
+    let hit = d2.le(radius2).and(zero.le(t1));
+    let t = t0.lt(zero).select(t1, t0);
+    hit.select(t, F32x4::splat(f32::INFINITY)).to_array()
+}
+
+/// Up to four spheres, in the order of the objects they came from, with
+/// the lanes of objects that aren't spheres marked.
+pub struct SpherePacket {
+    centers: Vector3f32x4,
+    radii: F32x4,
+    /// Bit `i` is set if object `i` is a sphere.
+    spheres: u32,
+}
+
+impl SpherePacket {
+    /// Packs the spheres among the first four `objects`, recognized by
+    /// their `to_desc`, which is only called here while building the BVH.
+    pub fn new(objects: &[Box<dyn Geometry>]) -> SpherePacket {
+        let mut centers = [v3!(0.0, 0.0, 0.0); 4];
+        let mut radii = [0.0; 4];
+        let mut spheres = 0;
+        for (i, object) in objects.iter().take(4).enumerate() {
+            if let Some(ObjectDesc::Sphere(sphere)) = object.to_desc() {
+                centers[i] = sphere.pos;
+                radii[i] = sphere.radius as f32;
+                spheres |= 1 << i;
+            }
+        }
+        SpherePacket { centers: Vector3f32x4::new(centers), radii: F32x4::from_array(radii), spheres }
+    }
+
+    /// For each of the four objects, whether `ray` might hit it nearer
+    /// than `t_max`. Objects that aren't spheres always might. The test
+    /// is done in `f32` with the spheres grown a little, so it can let
+    /// through a sphere that is just missed but never drops a hit.
+    pub fn may_hit(&self, ray: &Ray, t_max: f64) -> [bool; 4] {
+        if self.spheres == 0 {
+            return [true; 4];
+        }
+        let l = self.centers - Vector3f32x4::splat(ray.origin);
+        let tca = l.dot(&Vector3f32x4::splat(ray.dir));
+        let l2 = l.dot(&l);
+        // Cancellation in l2 - tca^2 loses bits relative to l2, so the
+        // slack has to grow with the distance to the sphere.
+        let radius2 = self.radii*self.radii + l2*F32x4::splat(1e-5);
+        let d2 = l2 - tca*tca;
+        let thc = (radius2 - d2).max(F32x4::splat(0.0)).sqrt();
+        let hit = d2.le(radius2)
+            .and(F32x4::splat(0.0).le(tca + thc))
+            .and((tca - thc).le(F32x4::splat(t_max as f32)));
+        let may_hit = hit.bitmask() | !self.spheres;
+        [may_hit & 1 != 0, may_hit & 2 != 0, may_hit & 4 != 0, may_hit & 8 != 0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raytracer::{basic_material, Sphere};
+    use rng::Rng;
+
+    fn random_vector(rng: &mut Rng) -> Vector3 {
+        v3!(rng.next_f64()*2.0 - 1.0, rng.next_f64()*2.0 - 1.0, rng.next_f64()*2.0 - 1.0)
+    }
+
+    fn random_spheres(rng: &mut Rng) -> Vec<Sphere> {
+        (0..4).map(|_| Sphere { pos: random_vector(rng)*5.0, radius: 0.2 + rng.next_f64(),
+                                material: basic_material(v3!(1.0, 1.0, 1.0)).into(),
+                                motion: None })
+            .collect()
+    }
+
+    #[test]
+    fn packet_distances_match_scalar() {
+        let mut rng = Rng::new(9);
+        let mut hits = 0;
+        for _ in 0..10000 {
+            let spheres = random_spheres(&mut rng);
+            // Aimed near one of the spheres, so that about half the rays hit it.
+            let origin = random_vector(&mut rng)*2.0;
+            let target = spheres[(rng.next_f64()*4.0) as usize].pos + random_vector(&mut rng);
+            let ray = Ray { origin, dir: (target - origin).normalize(), t_max: f64::INFINITY };
+            let centers = Vector3f32x4::new([spheres[0].pos, spheres[1].pos, spheres[2].pos, spheres[3].pos]);
+            let radii = F32x4::from_array([0, 1, 2, 3].map(|i| spheres[i].radius as f32));
+            let t = intersect_spheres_x4(ray.origin, ray.dir, &centers, radii);
+            for (sphere, t) in spheres.iter().zip(t) {
+                match sphere.intersects(&ray) {
+                    Some(i) => {
+                        hits += 1;
+                        assert!((i.dist - t as f64).abs() < 1e-3, "{} instead of {}", t, i.dist);
+                    },
+                    // Grazing rays may still hit in f32.
+                    None => assert!(t.is_infinite() || {
+                        let l = sphere.pos - ray.origin;
+                        let d2 = l.length_squared() - l.dot(ray.dir).powi(2);
+                        (d2 - sphere.radius*sphere.radius).abs() < 1e-4
+                    }, "f32 hit at {} where there is none", t),
+                }
+            }
+        }
+        assert!(hits > 5000, "only {} hits", hits);
+    }
+
+    #[test]
+    fn packet_never_drops_a_hit() {
+        let mut rng = Rng::new(4);
+        let mut culled = 0;
+        for _ in 0..10000 {
+            let mut objects: Vec<Box<dyn Geometry>> = random_spheres(&mut rng).into_iter()
+                .map(|s| Box::new(s) as Box<dyn Geometry>)
+                .collect();
+            objects.truncate(1 + (rng.next_f64()*4.0) as usize);
+            let packet = SpherePacket::new(&objects);
+            // Far away, to stress the precision.
+            let origin = random_vector(&mut rng).normalize()*1000.0;
+            let ray = Ray { origin, dir: (random_vector(&mut rng) - origin).normalize(), t_max: f64::INFINITY };
+            let t_max = 995.0 + rng.next_f64()*10.0;
+            let may_hit = packet.may_hit(&ray, t_max);
+            for (object, may_hit) in objects.iter().zip(may_hit) {
+                let hit = object.intersects(&ray).is_some_and(|i| i.dist < t_max);
+                assert!(may_hit || !hit, "culled a hit");
+                culled += !may_hit as usize;
+            }
+        }
+        assert!(culled > 10000, "only culled {}", culled);
+    }
+}
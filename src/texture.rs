@@ -0,0 +1,827 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+
+use image;
+use image::ImageError;
+
+use vector::Vector3;
+
+/// Procedural or image based surface color, looked up per hit point.
+pub trait Texture: Send + Sync + Debug {
+    /// Color at world position `pos` with surface normal `normal` and
+    /// texture coordinates `uv`.
+    fn sample(&self, pos: Vector3, normal: Vector3, uv: (f64, f64)) -> Vector3;
+
+    /// `sample` averaged over the part of the surface a pixel covers, so
+    /// that a pixel spanning many texels or squares doesn't pick one of
+    /// them at random. The default doesn't filter.
+    fn sample_footprint(&self, pos: Vector3, normal: Vector3, uv: (f64, f64),
+                        _footprint: &TextureFootprint) -> Vector3 {
+        self.sample(pos, normal, uv)
+    }
+
+    /// `sample_footprint` at texture coordinates (u, v), for a pixel
+    /// across which they change by (`dudx`, `dvdx`) to the right and by
+    /// (`dudy`, `dvdy`) upwards. Only meaningful for textures looked up by
+    /// their texture coordinates alone, such as images: the position and
+    /// normal passed on are zero.
+    fn sample_with_differential(&self, u: f64, v: f64, dudx: f64, dvdx: f64, dudy: f64,
+                                dvdy: f64) -> Vector3 {
+        let zero = v3!(0.0, 0.0, 0.0);
+        let footprint = TextureFootprint { dpdx: zero, dpdy: zero, duvdx: (dudx, dvdx), duvdy: (dudy, dvdy) };
+        self.sample_footprint(zero, zero, (u, v), &footprint)
+    }
+
+    /// Whether `sample` takes positions in the object space of a
+    /// transformed shape, so that the texture moves with it, rather than
+    /// in world space.
+    fn object_space(&self) -> bool {
+        false
+    }
+}
+
+/// How far the hit point (`dpdx`, `dpdy`) and its texture coordinates
+/// (`duvdx`, `duvdy`) move on the surface from one pixel to the next one
+/// to the right and up.
+#[derive(Copy, Clone, Debug)]
+pub struct TextureFootprint {
+    pub dpdx: Vector3,
+    pub dpdy: Vector3,
+    pub duvdx: (f64, f64),
+    pub duvdy: (f64, f64),
+}
+
+/// Alternating squares of `color_a` and `color_b` in the xz-plane, with
+/// `scale` squares per unit.
+#[derive(Debug, Clone)]
+pub struct CheckerboardTexture {
+    pub scale: f64,
+    pub color_a: Vector3,
+    pub color_b: Vector3,
+}
+
+impl Texture for CheckerboardTexture {
+    fn sample(&self, pos: Vector3, _normal: Vector3, _uv: (f64, f64)) -> Vector3 {
+        let cell = (self.scale*pos.x).floor() + (self.scale*pos.z).floor();
+        if (cell as i64).rem_euclid(2) == 0 { self.color_a } else { self.color_b }
+    }
+
+    /// Box filtered over the footprint's extent along x and z, which fades
+    /// the squares to the average color where they get smaller than a
+    /// pixel.
+    fn sample_footprint(&self, pos: Vector3, _normal: Vector3, _uv: (f64, f64),
+                        footprint: &TextureFootprint) -> Vector3 {
+        let width = |axis: usize| {
+            footprint.dpdx[axis].abs().max(footprint.dpdy[axis].abs()) * self.scale
+        };
+        // Fraction of [x - w/2, x + w/2] covered by odd squares, from the
+        // integral of the square wave that is 1 in odd squares.
+        let odd = |x: f64, w: f64| {
+            if w < 1e-9 {
+                return x.floor().rem_euclid(2.0);
+            }
+            let integral = |t: f64| {
+                let half = t / 2.0;
+                half.floor() + (2.0 * (half - half.floor()) - 1.0).max(0.0)
+            };
+            (integral(x + w / 2.0) - integral(x - w / 2.0)) / w
+        };
+        let a = odd(self.scale*pos.x, width(0));
+        let b = odd(self.scale*pos.z, width(2));
+        // A square is odd when exactly one of its coordinates is.
+        let mix = a + b - 2.0*a*b;
+        self.color_a.lerp(self.color_b, mix)
+    }
+}
+
+/// Smooth pseudo-random variation between `color_a` (noise -1) and
+/// `color_b` (noise 1), from `octaves` octaves of Perlin noise with
+/// `scale` features per unit at the coarsest. For marble, wood, rock and
+/// other organic surfaces.
+#[derive(Debug, Clone)]
+pub struct PerlinTexture {
+    pub scale: f64,
+    pub octaves: usize,
+    pub color_a: Vector3,
+    pub color_b: Vector3,
+    pub space: NoiseSpace,
+}
+
+/// Where `PerlinTexture` evaluates its noise.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum NoiseSpace {
+    /// At the world position of the hit, so the pattern stays put while
+    /// objects move through it.
+    #[default]
+    WorldSpace,
+    /// At the hit in the object space of the `Transform` or `Instance`
+    /// placing the shape, so the pattern moves with it. Shapes that
+    /// aren't transformed use world space.
+    ObjectSpace,
+}
+
+impl Texture for PerlinTexture {
+    fn sample(&self, pos: Vector3, _normal: Vector3, _uv: (f64, f64)) -> Vector3 {
+        let noise = fractal_noise(pos * self.scale, self.octaves);
+        self.color_a.lerp(self.color_b, (noise + 1.0) * 0.5)
+    }
+
+    fn object_space(&self) -> bool {
+        self.space == NoiseSpace::ObjectSpace
+    }
+}
+
+/// Ken Perlin's permutation of 0 to 255, which hashes lattice points to
+/// gradients.
+const PERMUTATION: [u8; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225,
+    140, 36, 103, 30, 69, 142, 8, 99, 37, 240, 21, 10, 23, 190, 6, 148,
+    247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219, 203, 117, 35, 11, 32,
+    57, 177, 33, 88, 237, 149, 56, 87, 174, 20, 125, 136, 171, 168, 68, 175,
+    74, 165, 71, 134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122,
+    60, 211, 133, 230, 220, 105, 92, 41, 55, 46, 245, 40, 244, 102, 143, 54,
+    65, 25, 63, 161, 1, 216, 80, 73, 209, 76, 132, 187, 208, 89, 18, 169,
+    200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173, 186, 3, 64,
+    52, 217, 226, 250, 124, 123, 5, 202, 38, 147, 118, 126, 255, 82, 85, 212,
+    207, 206, 59, 227, 47, 16, 58, 17, 182, 189, 28, 42, 223, 183, 170, 213,
+    119, 248, 152, 2, 44, 154, 163, 70, 221, 153, 101, 155, 167, 43, 172, 9,
+    129, 22, 39, 253, 19, 98, 108, 110, 79, 113, 224, 232, 178, 185, 112, 104,
+    218, 246, 97, 228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162, 241,
+    81, 51, 145, 235, 249, 14, 239, 107, 49, 192, 214, 31, 181, 199, 106, 157,
+    184, 84, 204, 176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93,
+    222, 114, 67, 29, 24, 72, 243, 141, 128, 195, 78, 66, 215, 61, 156, 180,
+];
+
+fn hash(i: i64) -> i64 {
+    PERMUTATION[(i & 255) as usize] as i64
+}
+
+/// Dot product of `(x, y, z)` with one of 12 edge directions of a cube,
+/// picked by `hash`.
+fn gradient(hash: i64, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+/// Ken Perlin's improved gradient noise at `p`: a smooth function that is
+/// 0 at integer coordinates and varies on a scale of about one unit. The
+/// same point always gives the same value, in [-1, 1].
+pub fn perlin_noise(p: Vector3) -> f64 {
+    let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (xi, yi, zi) = (fx as i64, fy as i64, fz as i64);
+    let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    let lerp = |t: f64, a: f64, b: f64| a + t * (b - a);
+
+    // Hashes of the cell's corners, from (xi, yi, zi) to (xi+1, yi+1, zi+1).
+    let (a, b) = (hash(xi) + yi, hash(xi + 1) + yi);
+    let (aa, ab, ba, bb) = (hash(a) + zi, hash(a + 1) + zi, hash(b) + zi, hash(b + 1) + zi);
+    let noise = lerp(w,
+        lerp(v, lerp(u, gradient(hash(aa), x, y, z),
+                        gradient(hash(ba), x - 1.0, y, z)),
+                lerp(u, gradient(hash(ab), x, y - 1.0, z),
+                        gradient(hash(bb), x - 1.0, y - 1.0, z))),
+        lerp(v, lerp(u, gradient(hash(aa + 1), x, y, z - 1.0),
+                        gradient(hash(ba + 1), x - 1.0, y, z - 1.0)),
+                lerp(u, gradient(hash(ab + 1), x, y - 1.0, z - 1.0),
+                        gradient(hash(bb + 1), x - 1.0, y - 1.0, z - 1.0))));
+    // The extremes slightly exceed 1 at a few points.
+    noise.clamp(-1.0, 1.0)
+}
+
+/// Fractal Brownian motion: `octaves` layers of `perlin_noise`, each at
+/// twice the frequency and half the amplitude of the one before, scaled
+/// back to [-1, 1].
+pub fn fractal_noise(p: Vector3, octaves: usize) -> f64 {
+    let (mut sum, mut amplitude, mut total, mut frequency) = (0.0, 1.0, 0.0, 1.0);
+    for _ in 0..octaves.max(1) {
+        sum += perlin_noise(p * frequency) * amplitude;
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+/// Tangent-space normals encoded as colors, each channel mapping [0, 1] to
+/// [-1, 1]. Red points along the surface tangent (growing u), green along
+/// the normal crossed with the tangent and blue along the normal. The image
+/// covers uv in [0, 1] and repeats outside it, with v = 0 at the top row.
+/// `scale` exaggerates (> 1) or flattens (< 1) the bumps. An image with no
+/// pixels leaves the surface flat.
+#[derive(Debug, Clone)]
+pub struct NormalMap {
+    pub pixels: Vec<Vector3>,
+    pub width: usize,
+    pub height: usize,
+    pub scale: f64,
+}
+
+impl Texture for NormalMap {
+    fn sample(&self, _pos: Vector3, _normal: Vector3, uv: (f64, f64)) -> Vector3 {
+        if self.width == 0 || self.height == 0 {
+            return v3!(0.5, 0.5, 1.0);
+        }
+        let encoded = bilinear(&self.pixels, self.width, self.height, uv, WrapMode::Repeat);
+        let n = encoded * 2.0 - v3!(1.0, 1.0, 1.0);
+        let n = v3!(n.x * self.scale, n.y * self.scale, n.z).normalize();
+        (n + v3!(1.0, 1.0, 1.0)) * 0.5
+    }
+}
+
+/// How texture coordinates outside [0, 1] are handled.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum WrapMode {
+    /// Tile the image.
+    #[default]
+    Repeat,
+    /// Extend the edge pixels.
+    Clamp,
+}
+
+/// How `ImageTexture` blends the texels around a lookup. Times are per
+/// lookup into a 4096 x 4096 texture at random coordinates, from the
+/// `texture_filter` example on one core; they are mostly cache misses, so
+/// lookups near the previous one are much cheaper.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum FilterMode {
+    /// The texel the coordinates fall in, blocky up close. About 65 ns.
+    NearestNeighbor,
+    /// Linear blend of the four nearest texels. About 110 ns.
+    #[default]
+    Bilinear,
+    /// Catmull-Rom spline through the 16 nearest texels, smooth where
+    /// bilinear shows the texel grid when magnified. About 270 ns.
+    Bicubic,
+}
+
+/// Image mapped onto the surface by its texture coordinates, with v = 0 at
+/// the top row. An image with no pixels samples as black. `new` also
+/// builds a MIP pyramid, copies of the image halved in size again and
+/// again down to one texel, which take a third more memory and let
+/// `sample_footprint` filter a pixel that spans many texels at the cost
+/// of one that spans a few.
+#[derive(Debug, Clone)]
+pub struct ImageTexture {
+    pixels: Vec<Vector3>,
+    width: usize,
+    height: usize,
+    /// The levels of the pyramid after the image itself, each half the
+    /// size of the one before, rounded up.
+    mips: Vec<MipLevel>,
+    pub wrap: WrapMode,
+    pub filter: FilterMode,
+}
+
+#[derive(Debug, Clone)]
+struct MipLevel {
+    pixels: Vec<Vector3>,
+    width: usize,
+    height: usize,
+}
+
+/// Most lookups `ImageTexture::sample_footprint` spreads along a
+/// footprint that is longer than it is wide.
+const MAX_ANISOTROPY: usize = 4;
+
+impl ImageTexture {
+    /// Texture of a `width` x `height` image, `pixels` being linear colors
+    /// in row-major order.
+    pub fn new(pixels: Vec<Vector3>, width: usize, height: usize, wrap: WrapMode,
+               filter: FilterMode) -> ImageTexture {
+        assert_eq!(pixels.len(), width*height, "pixels don't match the image size");
+        let mut mips: Vec<MipLevel> = Vec::new();
+        if width > 0 && height > 0 {
+            let (mut w, mut h) = (width, height);
+            while w > 1 || h > 1 {
+                let level = downsample(mips.last().map_or(&pixels, |l| &l.pixels), w, h);
+                w = level.width;
+                h = level.height;
+                mips.push(level);
+            }
+        }
+        ImageTexture { pixels, width, height, mips, wrap, filter }
+    }
+
+    pub fn pixels(&self) -> &[Vector3] {
+        &self.pixels
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Bilinear lookup at `uv` in the pyramid, blended between the two
+    /// levels around `lod`, where level 0 is the image itself.
+    fn trilinear(&self, uv: (f64, f64), lod: f64) -> Vector3 {
+        let level = |i: usize| {
+            if i == 0 {
+                bilinear(&self.pixels, self.width, self.height, uv, self.wrap)
+            } else {
+                let mip = &self.mips[i - 1];
+                bilinear(&mip.pixels, mip.width, mip.height, uv, self.wrap)
+            }
+        };
+        let coarsest = self.mips.len();
+        let lod = lod.clamp(0.0, coarsest as f64);
+        let lower = lod.floor() as usize;
+        if lower == coarsest {
+            return level(coarsest);
+        }
+        level(lower).lerp(level(lower + 1), lod - lower as f64)
+    }
+}
+
+/// Next level of a MIP pyramid: each texel the average of a 2 x 2 block
+/// of `pixels`, repeating the last row or column of an odd size.
+fn downsample(pixels: &[Vector3], width: usize, height: usize) -> MipLevel {
+    let (w, h) = (width.div_ceil(2), height.div_ceil(2));
+    let mut out = Vec::with_capacity(w*h);
+    for y in 0..h {
+        let (ya, yb) = (2*y, (2*y + 1).min(height - 1));
+        for x in 0..w {
+            let (xa, xb) = (2*x, (2*x + 1).min(width - 1));
+            out.push((pixels[ya*width + xa] + pixels[ya*width + xb]
+                      + pixels[yb*width + xa] + pixels[yb*width + xb]) * 0.25);
+        }
+    }
+    MipLevel { pixels: out, width: w, height: h }
+}
+
+impl Texture for ImageTexture {
+    fn sample(&self, _pos: Vector3, _normal: Vector3, uv: (f64, f64)) -> Vector3 {
+        if self.width == 0 || self.height == 0 {
+            return v3!(0.0, 0.0, 0.0);
+        }
+        match self.filter {
+            FilterMode::NearestNeighbor => nearest(&self.pixels, self.width, self.height, uv, self.wrap),
+            FilterMode::Bilinear => bilinear(&self.pixels, self.width, self.height, uv, self.wrap),
+            FilterMode::Bicubic => bicubic(&self.pixels, self.width, self.height, uv, self.wrap),
+        }
+    }
+
+    /// Only looks at the texture coordinates, so the same as
+    /// `sample_with_differential`.
+    fn sample_footprint(&self, _pos: Vector3, _normal: Vector3, uv: (f64, f64),
+                        footprint: &TextureFootprint) -> Vector3 {
+        let ((dudx, dvdx), (dudy, dvdy)) = (footprint.duvdx, footprint.duvdy);
+        self.sample_with_differential(uv.0, uv.1, dudx, dvdx, dudy, dvdy)
+    }
+
+    /// A footprint under a texel across is sampled with `filter` like
+    /// `sample`. A larger one is covered by up to `MAX_ANISOTROPY`
+    /// trilinear lookups along its longer side, in the pyramid level
+    /// where each spans about a texel, so that it costs at most 32 texel
+    /// reads whatever its size and filter mode. Beyond that anisotropy
+    /// the footprint is blurred across its shorter side as well.
+    fn sample_with_differential(&self, u: f64, v: f64, dudx: f64, dvdx: f64, dudy: f64,
+                                dvdy: f64) -> Vector3 {
+        if self.width == 0 || self.height == 0 {
+            return v3!(0.0, 0.0, 0.0);
+        }
+        let uv = (u, v);
+        let texels = |(du, dv): (f64, f64)| {
+            ((du * self.width as f64).powi(2) + (dv * self.height as f64).powi(2)).sqrt()
+        };
+        let (mut major, mut minor) = ((dudx, dvdx), (dudy, dvdy));
+        if texels(minor) > texels(major) {
+            std::mem::swap(&mut major, &mut minor);
+        }
+        let length = texels(major);
+        if length <= 1.0 {
+            return self.sample(v3!(0.0, 0.0, 0.0), v3!(0.0, 0.0, 0.0), uv);
+        }
+        let taps = (length / texels(minor)).ceil().clamp(1.0, MAX_ANISOTROPY as f64) as usize;
+        let lod = (length / taps as f64).max(1.0).log2();
+        let mut sum = v3!(0.0, 0.0, 0.0);
+        for i in 0..taps {
+            let t = (i as f64 + 0.5) / taps as f64 - 0.5;
+            sum += self.trilinear((uv.0 + major.0*t, uv.1 + major.1*t), lod);
+        }
+        sum / taps as f64
+    }
+}
+
+/// Loads an image file as a repeating texture. The 8-bit colors are
+/// assumed to be gamma encoded with gamma 2.2, and are converted to
+/// linear.
+pub fn load_png_texture(path: &str) -> Result<ImageTexture, ImageError> {
+    let (pixels, width, height) = load_linear(Path::new(path))?;
+    Ok(ImageTexture::new(pixels, width, height, WrapMode::Repeat, FilterMode::Bilinear))
+}
+
+/// Pixels of an 8-bit image file, decoded from gamma 2.2 to linear, and
+/// its width and height.
+fn load_linear(path: &Path) -> Result<(Vec<Vector3>, usize, usize), ImageError> {
+    let image = image::open(path)?.into_rgb8();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let decode = |c: u8| (c as f64 / 255.0).powf(2.2);
+    let pixels = image.pixels()
+        .map(|p| v3!(decode(p[0]), decode(p[1]), decode(p[2])))
+        .collect();
+    Ok((pixels, width, height))
+}
+
+/// Where one image lies in a `TextureAtlas`, in the atlas's texture
+/// coordinates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AtlasRect {
+    pub u0: f64,
+    pub v0: f64,
+    pub u1: f64,
+    pub v1: f64,
+}
+
+/// Many images packed side by side into one, so that a scene with lots of
+/// small textures reads them all from one block of memory. Pixels are
+/// linear colors in row-major order. Each image is looked up by name with
+/// an `AtlasTexture`.
+#[derive(Debug, Clone)]
+pub struct TextureAtlas {
+    pub image: Vec<Vector3>,
+    pub width: usize,
+    pub height: usize,
+    pub entries: HashMap<String, AtlasRect>,
+}
+
+impl TextureAtlas {
+    /// Packs `images`, each a name, its pixels, width and height, into
+    /// shelves: rows as wide as the widest image or the side of a square of
+    /// their total area, filled left to right from the tallest image to
+    /// the shortest. Unused space is black.
+    pub fn pack(images: Vec<(String, Vec<Vector3>, usize, usize)>) -> TextureAtlas {
+        let area: usize = images.iter().map(|&(_, _, w, h)| w*h).sum();
+        let widest = images.iter().map(|&(_, _, w, _)| w).max().unwrap_or(0);
+        let width = widest.max((area as f64).sqrt().ceil() as usize);
+        let mut order: Vec<usize> = (0..images.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(images[i].3));
+
+        // Top left corner of each image.
+        let mut corners = vec![(0, 0); images.len()];
+        let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+        for &i in &order {
+            let (w, h) = (images[i].2, images[i].3);
+            if x + w > width {
+                x = 0;
+                y += shelf_height;
+                shelf_height = 0;
+            }
+            corners[i] = (x, y);
+            x += w;
+            shelf_height = shelf_height.max(h);
+        }
+        let height = y + shelf_height;
+
+        let mut image = vec![v3!(0.0, 0.0, 0.0); width*height];
+        let mut entries = HashMap::new();
+        for ((name, pixels, w, h), (x, y)) in images.into_iter().zip(corners) {
+            for row in 0..h {
+                image[(y + row)*width + x..][..w].copy_from_slice(&pixels[row*w..][..w]);
+            }
+            entries.insert(name, AtlasRect { u0: x as f64 / width as f64,
+                                             v0: y as f64 / height as f64,
+                                             u1: (x + w) as f64 / width as f64,
+                                             v1: (y + h) as f64 / height as f64 });
+        }
+        TextureAtlas { image, width, height, entries }
+    }
+
+    /// Packs every PNG and PPM image in `dir` with `pack`, named by their
+    /// file names without the extension. Colors are decoded from gamma 2.2
+    /// to linear, as by `load_png_texture`.
+    pub fn load_from_dir(dir: &Path) -> Result<TextureAtlas, ImageError> {
+        let mut images = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(ImageError::IoError)? {
+            let path = entry.map_err(ImageError::IoError)?.path();
+            let extension = path.extension().and_then(|e| e.to_str()).map(str::to_lowercase);
+            if !matches!(extension.as_deref(), Some("png") | Some("ppm")) {
+                continue;
+            }
+            let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+            let (pixels, width, height) = load_linear(&path)?;
+            images.push((name, pixels, width, height));
+        }
+        Ok(TextureAtlas::pack(images))
+    }
+}
+
+/// One image of a shared `TextureAtlas`, covering uv in [0, 1] and
+/// repeating outside it like an `ImageTexture`, with v = 0 at the top row.
+/// Lookups are bilinear and wrap around within the image, so they never
+/// blend in its neighbors in the atlas. Not filtered over footprints.
+#[derive(Debug, Clone)]
+pub struct AtlasTexture {
+    atlas: Arc<TextureAtlas>,
+    name: String,
+    /// The image's texels in the atlas: left column, top row, width and
+    /// height.
+    texels: (usize, usize, usize, usize),
+}
+
+impl AtlasTexture {
+    /// The image called `name` in `atlas`, or None if there is no such
+    /// image.
+    pub fn new(atlas: Arc<TextureAtlas>, name: &str) -> Option<AtlasTexture> {
+        let rect = *atlas.entries.get(name)?;
+        let (w, h) = (atlas.width as f64, atlas.height as f64);
+        let (x0, y0) = ((rect.u0 * w).round() as usize, (rect.v0 * h).round() as usize);
+        let texels = (x0, y0, (rect.u1 * w).round() as usize - x0, (rect.v1 * h).round() as usize - y0);
+        Some(AtlasTexture { atlas, name: name.to_string(), texels })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Texture for AtlasTexture {
+    fn sample(&self, _pos: Vector3, _normal: Vector3, uv: (f64, f64)) -> Vector3 {
+        let (x0, y0, width, height) = self.texels;
+        if width == 0 || height == 0 {
+            return v3!(0.0, 0.0, 0.0);
+        }
+        let x = uv.0 * width as f64 - 0.5;
+        let y = uv.1 * height as f64 - 0.5;
+        let (xf, yf) = (x.floor(), y.floor());
+        let (fx, fy) = (x - xf, y - yf);
+        let texel = |i: f64, j: f64| {
+            let column = x0 + wrap_index(i, width, WrapMode::Repeat);
+            let row = y0 + wrap_index(j, height, WrapMode::Repeat);
+            self.atlas.image[row*self.atlas.width + column]
+        };
+        let top = texel(xf, yf).lerp(texel(xf + 1.0, yf), fx);
+        let bottom = texel(xf, yf + 1.0).lerp(texel(xf + 1.0, yf + 1.0), fx);
+        top.lerp(bottom, fy)
+    }
+}
+
+/// Texel `i` of a row or column of `n`, wrapped as `wrap` says.
+fn wrap_index(i: f64, n: usize, wrap: WrapMode) -> usize {
+    match wrap {
+        WrapMode::Repeat => (i as i64).rem_euclid(n as i64) as usize,
+        WrapMode::Clamp => (i as i64).clamp(0, n as i64 - 1) as usize,
+    }
+}
+
+/// Unfiltered lookup of the texel containing `uv`, where the image spans
+/// [0, 1] in both directions.
+fn nearest(pixels: &[Vector3], width: usize, height: usize, uv: (f64, f64),
+           wrap: WrapMode) -> Vector3 {
+    let x = wrap_index((uv.0 * width as f64).floor(), width, wrap);
+    let y = wrap_index((uv.1 * height as f64).floor(), height, wrap);
+    pixels[y*width + x]
+}
+
+/// Bilinearly filtered lookup at `uv`, where the image spans [0, 1] in
+/// both directions.
+fn bilinear(pixels: &[Vector3], width: usize, height: usize, uv: (f64, f64),
+            wrap: WrapMode) -> Vector3 {
+    let x = uv.0 * width as f64 - 0.5;
+    let y = uv.1 * height as f64 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let index = |i: f64, n: usize| wrap_index(i, n, wrap);
+    let (xa, xb) = (index(x0, width), index(x0 + 1.0, width));
+    let (ya, yb) = (index(y0, height), index(y0 + 1.0, height));
+    let top = pixels[ya*width + xa].lerp(pixels[ya*width + xb], fx);
+    let bottom = pixels[yb*width + xa].lerp(pixels[yb*width + xb], fx);
+    top.lerp(bottom, fy)
+}
+
+/// Catmull-Rom weights of the four texels around a lookup a fraction `t`
+/// of the way from the second to the third.
+fn catmull_rom_weights(t: f64) -> [f64; 4] {
+    let (t2, t3) = (t*t, t*t*t);
+    [0.5 * (-t3 + 2.0*t2 - t),
+     0.5 * (3.0*t3 - 5.0*t2 + 2.0),
+     0.5 * (-3.0*t3 + 4.0*t2 + t),
+     0.5 * (t3 - t2)]
+}
+
+/// Bicubically filtered lookup at `uv` through the 4 x 4 texels around
+/// it, where the image spans [0, 1] in both directions. The spline can
+/// overshoot a little past the texels at sharp edges.
+fn bicubic(pixels: &[Vector3], width: usize, height: usize, uv: (f64, f64),
+           wrap: WrapMode) -> Vector3 {
+    let x = uv.0 * width as f64 - 0.5;
+    let y = uv.1 * height as f64 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (wx, wy) = (catmull_rom_weights(x - x0), catmull_rom_weights(y - y0));
+    let mut sum = v3!(0.0, 0.0, 0.0);
+    for (j, wy) in wy.iter().enumerate() {
+        let row = wrap_index(y0 + j as f64 - 1.0, height, wrap) * width;
+        let mut line = v3!(0.0, 0.0, 0.0);
+        for (i, wx) in wx.iter().enumerate() {
+            line += pixels[row + wrap_index(x0 + i as f64 - 1.0, width, wrap)] * *wx;
+        }
+        sum += line * *wy;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rng::Rng;
+
+    #[test]
+    fn filters_return_texels_at_texel_centers() {
+        let (width, height) = (5, 3);
+        let pixels: Vec<Vector3> = (0..width*height)
+            .map(|i| v3!(i as f64, (i*i % 7) as f64, 1.0 / (i + 1) as f64))
+            .collect();
+        for filter in [FilterMode::NearestNeighbor, FilterMode::Bilinear, FilterMode::Bicubic] {
+            for wrap in [WrapMode::Repeat, WrapMode::Clamp] {
+                let texture = ImageTexture::new(pixels.clone(), width, height, wrap, filter);
+                for (i, &texel) in pixels.iter().enumerate() {
+                    let uv = (((i % width) as f64 + 0.5) / width as f64,
+                              ((i / width) as f64 + 0.5) / height as f64);
+                    let color = texture.sample(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), uv);
+                    assert!((color - texel).length() < 1e-12,
+                            "{:?} {:?} texel {} is {:?}, not {:?}", filter, wrap, i, color, texel);
+                }
+            }
+        }
+    }
+    #[test]
+    fn empty_normal_map_is_flat() {
+        let map = NormalMap { pixels: vec![], width: 0, height: 0, scale: 2.0 };
+        assert_eq!(map.sample(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), (0.3, 0.7)), v3!(0.5, 0.5, 1.0));
+    }
+
+    #[test]
+    fn empty_image_samples_as_black() {
+        for filter in [FilterMode::NearestNeighbor, FilterMode::Bilinear, FilterMode::Bicubic] {
+            for (width, height) in [(0, 0), (4, 0), (0, 4)] {
+                let texture = ImageTexture::new(vec![], width, height, WrapMode::Repeat, filter);
+                let footprint = TextureFootprint { dpdx: v3!(1.0, 0.0, 0.0), dpdy: v3!(0.0, 0.0, 1.0),
+                                                   duvdx: (0.5, 0.0), duvdy: (0.0, 0.5) };
+                assert_eq!(texture.sample(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), (0.3, 0.7)),
+                           v3!(0.0, 0.0, 0.0));
+                assert_eq!(texture.sample_footprint(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), (0.3, 0.7),
+                                                    &footprint),
+                           v3!(0.0, 0.0, 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn repeat_tiles_and_clamp_holds_the_edge() {
+        let pixels = vec![v3!(1.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0),
+                          v3!(0.0, 0.0, 1.0), v3!(1.0, 1.0, 0.0)];
+        let sample = |texture: &ImageTexture, u, v| {
+            texture.sample(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), (u, v))
+        };
+        for filter in [FilterMode::NearestNeighbor, FilterMode::Bilinear, FilterMode::Bicubic] {
+            let repeat = ImageTexture::new(pixels.clone(), 2, 2, WrapMode::Repeat, filter);
+            for &(u, v) in &[(0.25, 0.25), (0.6, 0.1), (0.9, 0.75), (0.5, 0.5)] {
+                let color = sample(&repeat, u, v);
+                for &(du, dv) in &[(1.0, 0.0), (0.0, -1.0), (-3.0, 2.0)] {
+                    assert!((sample(&repeat, u + du, v + dv) - color).length() < 1e-9,
+                            "{:?} doesn't repeat at ({}, {})", filter, u + du, v + dv);
+                }
+            }
+            // Past an edge, clamping gives the texels along it.
+            let clamp = ImageTexture { wrap: WrapMode::Clamp, ..repeat };
+            assert!((sample(&clamp, 1.5, 0.25) - pixels[1]).length() < 1e-9);
+            assert!((sample(&clamp, 0.25, 7.0) - pixels[2]).length() < 1e-9);
+            assert!((sample(&clamp, -2.0, -2.0) - pixels[0]).length() < 1e-9);
+            assert!((sample(&clamp, 3.0, 2.5) - pixels[3]).length() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn noise_is_repeatable_bounded_and_continuous() {
+        let mut rng = Rng::new(11);
+        let mut random_point = || {
+            v3!(rng.next_f64(), rng.next_f64(), rng.next_f64()) * 200.0 - v3!(100.0, 100.0, 100.0)
+        };
+        let (mut lowest, mut highest) = (f64::MAX, f64::MIN);
+        for _ in 0..10000 {
+            let p = random_point();
+            for noise in [perlin_noise(p), fractal_noise(p, 4)] {
+                assert!((-1.0..=1.0).contains(&noise), "noise at {:?} is {}", p, noise);
+                lowest = lowest.min(noise);
+                highest = highest.max(noise);
+            }
+            assert_eq!(perlin_noise(p), perlin_noise(p));
+            assert_eq!(fractal_noise(p, 4), fractal_noise(p, 4));
+            // A step of 1e-4 changes the noise by a small multiple of it,
+            // also across cell boundaries.
+            let step = random_point().normalize() * 1e-4;
+            assert!((perlin_noise(p + step) - perlin_noise(p)).abs() < 1e-3);
+            assert!((fractal_noise(p + step, 4) - fractal_noise(p, 4)).abs() < 1e-3);
+            let corner = v3!(p.x.round(), p.y.round(), p.z.round());
+            assert_eq!(perlin_noise(corner), 0.0);
+            assert!(perlin_noise(corner + step).abs() < 1e-3);
+        }
+        assert!(lowest < -0.5 && highest > 0.5, "noise only spans {} to {}", lowest, highest);
+    }
+
+    #[test]
+    fn wide_footprints_average_the_texels_they_cover() {
+        // Texel-sized squares of black and white, 64 across.
+        let size = 64;
+        let pixels: Vec<Vector3> = (0..size*size)
+            .map(|i| if (i % size + i / size) % 2 == 0 { v3!(0.0, 0.0, 0.0) } else { v3!(1.0, 1.0, 1.0) })
+            .collect();
+        let gray = v3!(0.5, 0.5, 0.5);
+        let mut rng = Rng::new(5);
+        for filter in [FilterMode::NearestNeighbor, FilterMode::Bilinear, FilterMode::Bicubic] {
+            let texture = ImageTexture::new(pixels.clone(), size, size, WrapMode::Repeat, filter);
+            // From a few texels across to the whole image many times over,
+            // square and stretched.
+            for &(x, y) in &[(4.0, 4.0), (16.0, 1.0), (1.0, 40.0), (1000.0, 1000.0), (5000.0, 300.0)] {
+                let footprint = TextureFootprint { dpdx: v3!(1.0, 0.0, 0.0), dpdy: v3!(0.0, 0.0, 1.0),
+                                                   duvdx: (x / size as f64, 0.0),
+                                                   duvdy: (0.0, y / size as f64) };
+                for _ in 0..20 {
+                    let uv = (rng.next_f64(), rng.next_f64());
+                    let color = texture.sample_footprint(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), uv,
+                                                         &footprint);
+                    assert!((color - gray).length() < 0.1, "{:?} {} x {} at {:?} is {:?}",
+                            filter, x, y, uv, color);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn differentials_pick_the_footprint_like_sample_footprint() {
+        let size = 16;
+        let pixels: Vec<Vector3> = (0..size*size)
+            .map(|i| if (i % size + i / size) % 2 == 0 { v3!(0.0, 0.0, 0.0) } else { v3!(1.0, 1.0, 1.0) })
+            .collect();
+        let texture = ImageTexture::new(pixels, size, size, WrapMode::Repeat, FilterMode::NearestNeighbor);
+        let texel = 1.0 / size as f64;
+        // Under a texel the texel itself, over many of them their average.
+        assert_eq!(texture.sample_with_differential(1.5*texel, 0.5*texel, 0.5*texel, 0.0, 0.0, 0.5*texel),
+                   v3!(1.0, 1.0, 1.0));
+        let wide = texture.sample_with_differential(0.3, 0.6, 0.4, 0.1, -0.1, 0.4);
+        assert!((wide - v3!(0.5, 0.5, 0.5)).length() < 0.1, "{:?}", wide);
+        let footprint = TextureFootprint { dpdx: v3!(1.0, 0.0, 0.0), dpdy: v3!(0.0, 0.0, 1.0),
+                                           duvdx: (0.4, 0.1), duvdy: (-0.1, 0.4) };
+        assert_eq!(texture.sample_footprint(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), (0.3, 0.6), &footprint), wide);
+    }
+
+    #[test]
+    fn atlas_images_sample_like_their_own_textures() {
+        let mut rng = Rng::new(3);
+        let sizes = [(5, 3), (8, 8), (1, 6), (4, 1), (7, 2)];
+        let images: Vec<(String, Vec<Vector3>, usize, usize)> = sizes.iter().enumerate()
+            .map(|(i, &(w, h))| {
+                let pixels = (0..w*h)
+                    .map(|_| v3!(rng.next_f64(), rng.next_f64(), rng.next_f64()))
+                    .collect();
+                (format!("image{}", i), pixels, w, h)
+            })
+            .collect();
+        let atlas = Arc::new(TextureAtlas::pack(images.clone()));
+        let total: usize = sizes.iter().map(|&(w, h)| w*h).sum();
+        assert!(atlas.width*atlas.height < 3*total, "{} x {} atlas for {} texels",
+                atlas.width, atlas.height, total);
+
+        let (pos, normal) = (v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0));
+        for (name, pixels, w, h) in images {
+            let own = ImageTexture::new(pixels, w, h, WrapMode::Repeat, FilterMode::Bilinear);
+            let texture = AtlasTexture::new(atlas.clone(), &name).unwrap();
+            for _ in 0..200 {
+                // Anywhere, including across the edges, which wrap around
+                // within the image.
+                let uv = (rng.next_f64()*3.0 - 1.0, rng.next_f64()*3.0 - 1.0);
+                let (a, b) = (texture.sample(pos, normal, uv), own.sample(pos, normal, uv));
+                assert!((a - b).length() < 1e-9, "{} at {:?} is {:?}, not {:?}", name, uv, a, b);
+            }
+        }
+        assert!(AtlasTexture::new(atlas, "missing").is_none());
+    }
+
+    #[test]
+    fn atlas_loads_png_and_ppm_files() {
+        let dir = std::env::temp_dir().join(format!("rust_raytracer_atlas_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbImage::from_pixel(4, 2, image::Rgb([255, 0, 0])).save(dir.join("red.png")).unwrap();
+        image::RgbImage::from_pixel(3, 5, image::Rgb([0, 0, 255])).save(dir.join("blue.ppm")).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not an image").unwrap();
+        let atlas = TextureAtlas::load_from_dir(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let atlas = Arc::new(atlas.unwrap());
+        assert_eq!(atlas.entries.len(), 2);
+        let color = |name: &str| {
+            AtlasTexture::new(atlas.clone(), name).unwrap()
+                .sample(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), (0.5, 0.5))
+        };
+        assert_eq!(color("red"), v3!(1.0, 0.0, 0.0));
+        assert_eq!(color("blue"), v3!(0.0, 0.0, 1.0));
+    }
+}
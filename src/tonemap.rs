@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use vector::Vector3;
+
+/// Maps high dynamic range radiance into the displayable [0, 1] range.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ToneMapper {
+    /// Cut off every channel at 1.
+    #[default]
+    Clamp,
+    /// `c / (c + 1)` per channel.
+    Reinhard,
+    /// Narkowicz's curve fit of the ACES filmic tone curve.
+    AcesApprox,
+    /// Leave the radiance untouched, for formats that store HDR values.
+    Linear,
+}
+
+impl ToneMapper {
+    pub fn apply(self, color: Vector3) -> Vector3 {
+        if self == ToneMapper::Linear {
+            return color;
+        }
+        color.map(|c| {
+            let c = c.max(0.0);
+            match self {
+                ToneMapper::Clamp => c.min(1.0),
+                ToneMapper::Reinhard => c / (c + 1.0),
+                ToneMapper::AcesApprox =>
+                    ((c*(2.51*c + 0.03)) / (c*(2.43*c + 0.59) + 0.14)).clamp(0.0, 1.0),
+                ToneMapper::Linear => c,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tone_mappers_compress_into_display_range() {
+        let c = v3!(0.0, 1.0, 4.0);
+        assert_eq!(ToneMapper::Clamp.apply(c), v3!(0.0, 1.0, 1.0));
+        assert_eq!(ToneMapper::Reinhard.apply(c), v3!(0.0, 0.5, 0.8));
+        assert_eq!(ToneMapper::Linear.apply(v3!(-1.0, 2.0, 1e6)), v3!(-1.0, 2.0, 1e6));
+        let aces = ToneMapper::AcesApprox.apply(v3!(0.0, 0.18, 1e6));
+        assert!(aces.x.abs() < 1e-12 && (aces.y - 0.2667).abs() < 1e-3 && aces.z == 1.0, "{:?}", aces);
+        // Every mapper but Linear keeps colors in [0, 1], rising with the
+        // radiance, and clips negative values.
+        for mapper in [ToneMapper::Clamp, ToneMapper::Reinhard, ToneMapper::AcesApprox] {
+            let mapped: Vec<f64> = (0..200).map(|i| mapper.apply(v3!(i as f64 * 0.1, 0.0, 0.0)).x).collect();
+            assert!(mapped.iter().all(|c| (0.0..=1.0).contains(c)), "{:?} left [0, 1]", mapper);
+            assert!(mapped.windows(2).all(|w| w[0] <= w[1]), "{:?} isn't monotonic", mapper);
+            assert_eq!(mapper.apply(v3!(-1.0, -1.0, -1.0)), v3!(0.0, 0.0, 0.0));
+        }
+        // Reinhard never quite reaches white.
+        assert!(ToneMapper::Reinhard.apply(v3!(1e3, 1e3, 1e3)).x < 1.0);
+    }
+}
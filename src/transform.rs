@@ -0,0 +1,474 @@
+use std::sync::Arc;
+
+use acceleration::Aabb;
+use raytracer::{Geometry, Intersection, Material, MaterialId, Ray};
+use vector::{Matrix4, Vector3};
+
+/// Places a geometry with an affine transform, so that one object space
+/// shape can be moved, scaled and rotated without rewriting its
+/// intersection code.
+pub struct Transform {
+    object: Box<dyn Geometry>,
+    matrix: Matrix4,
+    inverse: Matrix4,
+}
+
+impl Transform {
+    /// Panics if `matrix` isn't invertible.
+    pub fn new(matrix: Matrix4, object: Box<dyn Geometry>) -> Transform {
+        let inverse = matrix.invert().expect("transform matrix must be invertible");
+        Transform { object, matrix, inverse }
+    }
+
+    pub fn matrix(&self) -> Matrix4 {
+        self.matrix
+    }
+}
+
+impl Geometry for Transform {
+    fn material(&self) -> Material {
+        self.object.material()
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        self.object.material_id()
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        transformed_bounds(self.object.as_ref(), &self.matrix)
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        transformed_intersection(self.object.as_ref(), &self.matrix, &self.inverse, ray)
+    }
+    fn sample_surface(&self, u1: f64, u2: f64) -> (Vector3, Vector3, f64) {
+        transformed_sample(self.object.as_ref(), &self.matrix, &self.inverse, u1, u2)
+    }
+    fn pdf_surface(&self, from: Vector3, to: Vector3) -> f64 {
+        transformed_pdf(self.object.as_ref(), &self.matrix, &self.inverse, from, to)
+    }
+    fn translate(&mut self, offset: Vector3) -> bool {
+        self.matrix = Matrix4::translate(offset) * self.matrix;
+        self.inverse = self.matrix.invert().expect("transform matrix must be invertible");
+        true
+    }
+}
+
+/// Shared handle to a geometry that can be placed in a scene any number of
+/// times with `Scene::add_instance`, without copying it.
+#[derive(Clone)]
+pub struct InstanceHandle {
+    geometry: Arc<dyn Geometry>,
+}
+
+impl InstanceHandle {
+    pub fn new<T: Geometry + 'static>(geometry: T) -> InstanceHandle {
+        InstanceHandle { geometry: Arc::new(geometry) }
+    }
+
+    pub fn geometry(&self) -> &dyn Geometry {
+        self.geometry.as_ref()
+    }
+}
+
+impl From<Arc<dyn Geometry>> for InstanceHandle {
+    fn from(geometry: Arc<dyn Geometry>) -> InstanceHandle {
+        InstanceHandle { geometry }
+    }
+}
+
+/// One placement of a shared geometry. Like `Transform`, but any number of
+/// instances can refer to the same geometry.
+pub struct Instance {
+    geometry: Arc<dyn Geometry>,
+    matrix: Matrix4,
+    inverse: Matrix4,
+    /// Shades this instance in place of the geometry's own material.
+    material_override: Option<Material>,
+}
+
+impl Instance {
+    /// Panics if `matrix` isn't invertible.
+    pub fn new(handle: &InstanceHandle, matrix: Matrix4) -> Instance {
+        let inverse = matrix.invert().expect("instance matrix must be invertible");
+        Instance { geometry: handle.geometry.clone(), matrix, inverse, material_override: None }
+    }
+
+    /// Shades this instance with `material`, whatever the shared geometry
+    /// is made of, so that one mesh can be placed in many colors.
+    pub fn with_material(mut self, material: Material) -> Instance {
+        self.material_override = Some(material);
+        self
+    }
+
+    pub fn matrix(&self) -> Matrix4 {
+        self.matrix
+    }
+}
+
+impl Geometry for Instance {
+    fn material(&self) -> Material {
+        match self.material_override {
+            Some(ref material) => material.clone(),
+            None => self.geometry.material(),
+        }
+    }
+    fn material_id(&self) -> Option<MaterialId> {
+        match self.material_override {
+            Some(_) => None,
+            None => self.geometry.material_id(),
+        }
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        transformed_bounds(self.geometry.as_ref(), &self.matrix)
+    }
+    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        let isect = transformed_intersection(self.geometry.as_ref(), &self.matrix, &self.inverse, ray);
+        match self.material_override {
+            Some(ref material) => isect.map(|isect| Intersection { material: material.clone(),
+                                                                   material_id: None,
+                                                                   ..isect }),
+            None => isect,
+        }
+    }
+    fn sample_surface(&self, u1: f64, u2: f64) -> (Vector3, Vector3, f64) {
+        transformed_sample(self.geometry.as_ref(), &self.matrix, &self.inverse, u1, u2)
+    }
+    fn pdf_surface(&self, from: Vector3, to: Vector3) -> f64 {
+        transformed_pdf(self.geometry.as_ref(), &self.matrix, &self.inverse, from, to)
+    }
+}
+
+/// Node of a scene graph. Its transform places it relative to its parent,
+/// so moving a node moves everything below it, e.g. the wheels of a car
+/// along with the car.
+pub struct SceneNode {
+    pub transform: Matrix4,
+    pub geometry: Option<Box<dyn Geometry>>,
+    pub children: Vec<SceneNode>,
+}
+
+impl Default for SceneNode {
+    fn default() -> SceneNode {
+        SceneNode::new(Matrix4::identity())
+    }
+}
+
+impl SceneNode {
+    /// Empty node, for grouping children.
+    pub fn new(transform: Matrix4) -> SceneNode {
+        SceneNode { transform, geometry: None, children: vec![] }
+    }
+
+    pub fn with_geometry<T: Geometry + 'static>(mut self, geometry: T) -> SceneNode {
+        self.geometry = Some(Box::new(geometry));
+        self
+    }
+
+    pub fn with_child(mut self, child: SceneNode) -> SceneNode {
+        self.children.push(child);
+        self
+    }
+
+    /// The geometry of this node and all below it, each placed with the
+    /// transforms of its ancestors composed, outermost first, on top of
+    /// `parent`. Panics if a composed transform isn't invertible.
+    pub fn flatten(self, parent: &Matrix4) -> Vec<Instance> {
+        let matrix = *parent * self.transform;
+        let mut instances: Vec<Instance> = self.geometry.into_iter()
+            .map(|geometry| Instance::new(&InstanceHandle::from(Arc::from(geometry)), matrix))
+            .collect();
+        for child in self.children {
+            instances.extend(child.flatten(&matrix));
+        }
+        instances
+    }
+}
+
+/// World space box around the transformed corners of the object's box.
+fn transformed_bounds(object: &dyn Geometry, matrix: &Matrix4) -> Option<Aabb> {
+    let b = object.bounding_box()?;
+    let corners = (0..8).map(|i| {
+        let corner = v3!(if i & 1 == 0 { b.min.x } else { b.max.x },
+                         if i & 2 == 0 { b.min.y } else { b.max.y },
+                         if i & 4 == 0 { b.min.z } else { b.max.z });
+        matrix.transform_point(corner)
+    });
+    corners.fold(None, |acc: Option<Aabb>, p| {
+        let pb = Aabb::from_points(p, p);
+        Some(acc.map_or(pb, |a| a.merge(&pb)))
+    })
+}
+
+/// Intersects the ray, brought into object space, with the object, and
+/// brings the hit back into world space.
+fn transformed_intersection(object: &dyn Geometry, matrix: &Matrix4, inverse: &Matrix4,
+                            ray: &Ray) -> Option<Intersection> {
+    // Shapes expect unit directions, so the object space distance is
+    // scaled back by the direction's length.
+    let dir = inverse.transform_vector(ray.dir);
+    let scale = dir.length();
+    let local = Ray { origin: inverse.transform_point(ray.origin),
+                      dir: dir * (1.0 / scale),
+                      t_max: ray.t_max * scale };
+    object.intersects(&local).map(|isect| {
+        let dist = isect.dist / scale;
+        // The inverse transpose keeps normals on the side of the surface
+        // they came from, so mirroring matrices need no flip.
+        let normal = inverse.transpose().transform_vector(isect.normal).normalize();
+        let tangent = isect.tangent.map(|t| matrix.transform_vector(t).normalize());
+        // Nested transforms keep the innermost object space.
+        let object_pos = Some(isect.object_pos.unwrap_or(isect.pos));
+        Intersection { pos: ray.origin + ray.dir*dist, normal, dist, tangent, object_pos, ..isect }
+    })
+}
+
+/// Samples the object's surface in object space and brings the sample
+/// into world space. The pdf, per unit area, shrinks by as much as the
+/// matrix stretches the surface there.
+fn transformed_sample(object: &dyn Geometry, matrix: &Matrix4, inverse: &Matrix4,
+                      u1: f64, u2: f64) -> (Vector3, Vector3, f64) {
+    let (p, n, pdf) = object.sample_surface(u1, u2);
+    let normal = inverse.transpose().transform_vector(n).normalize();
+    (matrix.transform_point(p), normal, pdf / area_scale(matrix, n.normalize()))
+}
+
+/// Solid angle pdf of `transformed_sample` having picked `to`, seen from
+/// `from`. The object's own pdf is turned back into one per unit area in
+/// object space, which needs the normal at `to`; a ray towards it finds
+/// that.
+fn transformed_pdf(object: &dyn Geometry, matrix: &Matrix4, inverse: &Matrix4,
+                   from: Vector3, to: Vector3) -> f64 {
+    let (local_from, local_to) = (inverse.transform_point(from), inverse.transform_point(to));
+    let d = local_to - local_from;
+    let ray = Ray { origin: local_from, dir: d.normalize(), t_max: f64::INFINITY };
+    let hit = object.intersect_all(&ray).into_iter()
+        .min_by(|a, b| (a.pos - local_to).length().total_cmp(&(b.pos - local_to).length()));
+    let n = match hit {
+        Some(isect) => isect.normal.normalize(),
+        None => return 0.0,
+    };
+    let area_pdf = object.pdf_surface(local_from, local_to) * n.dot(ray.dir).abs() / d.dot(d)
+        / area_scale(matrix, n);
+    let normal = inverse.transpose().transform_vector(n).normalize();
+    let d = to - from;
+    area_pdf * d.dot(d) / normal.dot(d.normalize()).abs()
+}
+
+/// Factor by which `matrix` scales areas on a surface with unit normal
+/// `n`: the area of the image of a unit square in its tangent plane.
+fn area_scale(matrix: &Matrix4, n: Vector3) -> f64 {
+    let axis = if n.x.abs() > 0.9 { v3!(0.0, 1.0, 0.0) } else { v3!(1.0, 0.0, 0.0) };
+    let t1 = axis.cross(&n).normalize();
+    let t2 = n.cross(&t1);
+    matrix.transform_vector(t1).cross(&matrix.transform_vector(t2)).length()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raytracer::{basic_material, raytrace, RenderConfig, SceneBuilder, Sphere, Triangle};
+
+    #[test]
+    fn instances_render_like_separate_spheres() {
+        let material = basic_material(v3!(0.8, 0.6, 0.4));
+        let positions: Vec<Vector3> = (0..1000)
+            .map(|i| v3!((i % 10) as f64 * 3.0 - 13.5, ((i / 10) % 10) as f64 * 3.0 - 13.5,
+                         (i / 100) as f64 * 3.0 + 10.0))
+            .collect();
+        let builder = || SceneBuilder::new().light(v3!(0.0, 20.0, -10.0), v3!(1.0, 1.0, 1.0));
+
+        let mut spheres = builder().build();
+        for &pos in &positions {
+            spheres.add(Sphere { pos, radius: 1.0, material: material.clone().into(), motion: None });
+        }
+        let mut instances = builder().build();
+        let unit: Arc<dyn Geometry> = Arc::new(Sphere { pos: v3!(0.0, 0.0, 0.0), radius: 1.0,
+                                                        material: material.into(), motion: None });
+        let mut handle = InstanceHandle::from(unit);
+        for &pos in &positions {
+            handle = instances.add_instance(handle, Matrix4::translate(pos));
+        }
+        spheres.build_bvh();
+        instances.build_bvh();
+
+        let config = RenderConfig { width: 64, height: 48, print_stats: false, ..RenderConfig::default() };
+        let (expected, image) = (raytrace(&spheres, &config), raytrace(&instances, &config));
+        assert!(expected.iter().any(|p| p.x > 0.5), "no sphere is lit");
+        for (i, (a, b)) in expected.iter().zip(&image).enumerate() {
+            assert!((*a - *b).length() < 1e-6, "pixel {} is {:?}, not {:?}", i, b, a);
+        }
+    }
+
+    #[test]
+    fn instances_take_their_material_overrides() {
+        let registered = basic_material(v3!(1.0, 1.0, 1.0));
+        let mut scene = SceneBuilder::new().light(v3!(0.0, 0.0, -5.0), v3!(1.0, 1.0, 1.0)).build();
+        let id = scene.register_material("white", registered);
+        let unit: Arc<dyn Geometry> = Arc::new(Sphere { pos: v3!(0.0, 0.0, 0.0), radius: 1.5,
+                                                        material: id.into(), motion: None });
+        let handle = InstanceHandle::from(unit);
+        let (red, green) = (basic_material(v3!(1.0, 0.0, 0.0)), basic_material(v3!(0.0, 1.0, 0.0)));
+        scene.add(Instance::new(&handle, Matrix4::translate(v3!(-2.0, 0.0, 8.0))).with_material(red));
+        scene.add(Instance::new(&handle, Matrix4::translate(v3!(2.0, 0.0, 8.0))).with_material(green));
+        scene.add(Instance::new(&handle, Matrix4::translate(v3!(0.0, 3.5, 8.0))));
+
+        let (width, height) = (48, 36);
+        let config = RenderConfig { width, height, print_stats: false, ..RenderConfig::default() };
+        let image = raytrace(&scene, &config);
+        let lit = |x: usize, y: usize| image[y*width + x];
+        // Across the middle row, the left sphere is red and the right one
+        // green; the one above keeps the shared white.
+        let row: Vec<Vector3> = (0..width).map(|x| lit(x, height / 2)).collect();
+        let red = |p: &Vector3| p.x > 0.5 && p.y < 0.2 && p.z < 0.2;
+        let green = |p: &Vector3| p.y > 0.5 && p.x < 0.2 && p.z < 0.2;
+        let (left, right) = row.split_at(width / 2);
+        assert!(left.iter().any(red) && !left.iter().any(green));
+        assert!(right.iter().any(green) && !right.iter().any(red));
+        assert!((0..height/2).any(|y| lit(width / 2, y).min_component() > 0.5));
+    }
+
+    #[test]
+    fn mirrored_instances_keep_outward_normals() {
+        let white = basic_material(v3!(1.0, 1.0, 1.0));
+        let mirror = Matrix4::translate(v3!(0.0, 0.0, 5.0)) * Matrix4::scale(v3!(-1.0, 1.0, 1.0));
+        let unit: Arc<dyn Geometry> = Arc::new(Sphere { pos: v3!(0.5, 0.0, 0.0), radius: 1.0,
+                                                        material: white.clone().into(), motion: None });
+        let sphere = Instance::new(&InstanceHandle::from(unit), mirror);
+        let center = v3!(-0.5, 0.0, 5.0);
+        let mut hits = 0;
+        for i in 0..21 {
+            for j in 0..21 {
+                let target = center + v3!(i as f64 - 10.0, j as f64 - 10.0, 0.0) * 0.09;
+                let dir = target.normalize();
+                let ray = Ray { origin: v3!(0.0, 0.0, 0.0), dir, t_max: f64::INFINITY };
+                if let Some(isect) = sphere.intersects(&ray) {
+                    hits += 1;
+                    assert!(isect.normal.dot(isect.pos - center) > 0.0, "{:?} points inward", isect.normal);
+                    assert!(isect.normal.dot(dir) < 0.0);
+                }
+            }
+        }
+        assert!(hits > 200, "only {} rays hit", hits);
+
+        // A mirrored triangle is seen from the same side as before: its
+        // front face keeps facing the ray that reaches it.
+        let triangle = Triangle::new(v3!(0.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), v3!(1.0, 0.0, 0.0), white);
+        let ray = |x: f64| Ray { origin: v3!(x, 0.2, -1.0), dir: v3!(0.0, 0.0, 1.0), t_max: f64::INFINITY };
+        let local = triangle.intersects(&ray(0.2)).expect("ray misses the triangle");
+        let handle = InstanceHandle::new(triangle);
+        let mirrored = Instance::new(&handle, Matrix4::scale(v3!(-1.0, 1.0, 1.0)));
+        let world = mirrored.intersects(&ray(-0.2)).expect("ray misses the mirrored triangle");
+        assert_eq!(world.normal.as_array(), local.normal.as_array());
+    }
+
+    #[test]
+    fn translating_composes_with_the_matrix() {
+        let sphere = Sphere { pos: v3!(0.0, 0.0, 0.0), radius: 1.0,
+                              material: basic_material(v3!(1.0, 1.0, 1.0)).into(), motion: None };
+        let mut transform = Transform::new(Matrix4::scale(v3!(2.0, 2.0, 2.0)), Box::new(sphere));
+        assert!(transform.translate(v3!(0.0, 0.0, 5.0)));
+        assert!(transform.translate(v3!(1.0, 0.0, 0.0)));
+        // Scaled about the origin first, then moved by both offsets.
+        let p = transform.matrix().transform_point(v3!(1.0, 1.0, 1.0));
+        assert_eq!(p.as_array(), [3.0, 2.0, 7.0]);
+        let ray = Ray { origin: v3!(1.0, 0.0, 0.0), dir: v3!(0.0, 0.0, 1.0), t_max: f64::INFINITY };
+        assert_eq!(transform.intersects(&ray).unwrap().pos, v3!(1.0, 0.0, 3.0));
+    }
+
+    #[test]
+    fn transformed_samples_lie_on_the_surface() {
+        use std::f64::consts::PI;
+        use rng::Rng;
+        let white = basic_material(v3!(1.0, 1.0, 1.0));
+        let unit = Sphere { pos: v3!(0.0, 0.0, 0.0), radius: 1.0, material: white.clone().into(), motion: None };
+        // An ellipsoid with semi-axes 2, 1 and 1 around (0, 0, 5).
+        let matrix = Matrix4::translate(v3!(0.0, 0.0, 5.0)) * Matrix4::scale(v3!(2.0, 1.0, 1.0));
+        let ellipsoid = Transform::new(matrix, Box::new(unit.clone()));
+        let mut rng = Rng::new(3);
+        let n = 20000;
+        let mut area = 0.0;
+        for _ in 0..n {
+            let (p, normal, pdf) = ellipsoid.sample_surface(rng.next_f64(), rng.next_f64());
+            let q = p - v3!(0.0, 0.0, 5.0);
+            assert!((q.x*q.x/4.0 + q.y*q.y + q.z*q.z - 1.0).abs() < 1e-9, "{:?} is off the surface", p);
+            // The same normal a ray hitting that point finds.
+            let ray = Ray { origin: p + normal*0.5, dir: -normal, t_max: f64::INFINITY };
+            let isect = ellipsoid.intersects(&ray).unwrap();
+            assert!((isect.pos - p).length() < 1e-6 && (isect.normal - normal).length() < 1e-6);
+            area += 1.0 / pdf / n as f64;
+        }
+        // Averaging 1 / pdf estimates the area of the prolate spheroid.
+        let e = 3.0f64.sqrt() / 2.0;
+        let expected = 2.0*PI*(1.0 + 2.0 / e * e.asin());
+        assert!((area - expected).abs() < 0.01*expected, "area {} instead of {}", area, expected);
+
+        // Scaling by 3 spreads the samples over 9 times the area.
+        let instance = Instance::new(&InstanceHandle::new(unit), Matrix4::scale(v3!(3.0, 3.0, 3.0)));
+        let (p, _, pdf) = instance.sample_surface(0.3, 0.7);
+        assert!((p.length() - 3.0).abs() < 1e-9);
+        assert!((pdf - 1.0 / (36.0*PI)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn transformed_pdf_matches_the_samples() {
+        use raytracer::Disk;
+        use rng::Rng;
+        let white = basic_material(v3!(1.0, 1.0, 1.0));
+        let unit = Sphere { pos: v3!(0.0, 0.0, 0.0), radius: 1.0, material: white.clone().into(), motion: None };
+        let matrix = Matrix4::translate(v3!(0.0, 0.0, 5.0)) * Matrix4::rotate_y(0.5)
+            * Matrix4::scale(v3!(2.0, 1.0, 0.5));
+        let ellipsoid = Transform::new(matrix, Box::new(unit));
+        let from = v3!(1.0, 4.0, -1.0);
+        let mut rng = Rng::new(8);
+        for _ in 0..100 {
+            // The solid angle pdf is the area pdf times dist^2 / cos.
+            let (p, normal, pdf) = ellipsoid.sample_surface(rng.next_f64(), rng.next_f64());
+            let d = p - from;
+            let expected = pdf * d.dot(d) / normal.dot(d.normalize()).abs();
+            let found = ellipsoid.pdf_surface(from, p);
+            assert!((found - expected).abs() < 1e-6*expected, "pdf {} instead of {}", found, expected);
+        }
+
+        // Moving a disk doesn't change the pdf seen from the moved point.
+        let disk = Disk { center: v3!(0.0, 0.0, 0.0), normal: v3!(0.0, 0.0, 1.0), radius: 1.0,
+                          material: white.into() };
+        let matrix = Matrix4::translate(v3!(1.0, 2.0, 3.0)) * Matrix4::rotate_x(1.0);
+        let moved = Instance::new(&InstanceHandle::new(disk.clone()), matrix);
+        let (from, to) = (v3!(0.2, 0.1, 2.0), v3!(0.5, -0.3, 0.0));
+        let expected = disk.pdf_surface(from, to);
+        let found = moved.pdf_surface(matrix.transform_point(from), matrix.transform_point(to));
+        assert!((found - expected).abs() < 1e-9*expected, "pdf {} instead of {}", found, expected);
+    }
+
+    #[test]
+    fn nested_scene_nodes_compose_their_transforms() {
+        let translate = Matrix4::translate(v3!(1.0, 0.0, 0.0));
+        let scale = Matrix4::scale(v3!(2.0, 2.0, 2.0));
+        let rotate = Matrix4::rotate_y(std::f64::consts::FRAC_PI_2);
+        let sphere = Sphere { pos: v3!(1.0, 0.0, 0.0), radius: 0.5,
+                              material: basic_material(v3!(1.0, 1.0, 1.0)).into(), motion: None };
+        let root = SceneNode::new(translate)
+            .with_child(SceneNode::new(scale)
+                .with_child(SceneNode::new(rotate).with_geometry(sphere)));
+
+        let instances = root.flatten(&Matrix4::identity());
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].matrix(), translate * scale * rotate);
+        // Rotated to (0, 0, -1), scaled to (0, 0, -2), then moved along x.
+        let bounds = instances[0].bounding_box().unwrap();
+        assert_eq!((bounds.min + bounds.max) / 2.0, v3!(1.0, 0.0, -2.0));
+        assert_eq!(bounds.max - bounds.min, v3!(2.0, 2.0, 2.0));
+    }
+
+    #[test]
+    fn scene_graph_is_flattened_into_the_bvh() {
+        let mut scene = SceneBuilder::new().build();
+        let sphere = Sphere { pos: v3!(0.0, 0.0, 0.0), radius: 1.0,
+                              material: basic_material(v3!(1.0, 1.0, 1.0)).into(), motion: None };
+        scene.root.children.push(SceneNode::new(Matrix4::translate(v3!(0.0, 0.0, 5.0)))
+            .with_child(SceneNode::new(Matrix4::translate(v3!(3.0, 0.0, 0.0))).with_geometry(sphere)));
+        scene.build_bvh();
+        assert!(scene.root.children.is_empty());
+        assert_eq!(scene.all_objects().count(), 1);
+        let ray = Ray { origin: v3!(3.0, 0.0, 0.0), dir: v3!(0.0, 0.0, 1.0), t_max: f64::INFINITY };
+        assert_eq!(scene.intersect(&ray).unwrap().pos, v3!(3.0, 0.0, 4.0));
+    }
+}
@@ -1,6 +1,12 @@
-use std::ops::{Add, Sub, Mul};
+use std::error::Error;
+use std::fmt;
+use std::num::ParseFloatError;
+use std::str::FromStr;
+use std::ops::{Add, AddAssign, Sub, SubAssign, Mul, MulAssign, Div, DivAssign, Neg, Index, IndexMut};
 
-#[derive(Copy, Clone)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Vector3 {
     pub x: f64,
     pub y: f64,
@@ -8,12 +14,21 @@ pub struct Vector3 {
 }
 
 
+#[macro_export]
 macro_rules! v3 {
-    ($x:expr, $y:expr, $z:expr) => { Vector3 { x: $x, y: $y, z: $z } }
+    ($x:expr, $y:expr, $z:expr) => { $crate::vector::Vector3 { x: $x, y: $y, z: $z } }
 }
 
 
 impl Vector3 {
+    pub fn from_array(a: [f64; 3]) -> Vector3 {
+        Vector3 { x: a[0], y: a[1], z: a[2] }
+    }
+
+    pub fn as_array(&self) -> [f64; 3] {
+        [self.x, self.y, self.z]
+    }
+
     pub fn cross(&self, other: &Vector3) -> Vector3 {
         Vector3 { x: self.y*other.z-self.z*other.y,
                   y: self.z*other.x-self.x*other.z,
@@ -24,20 +39,243 @@ impl Vector3 {
         self.x*other.x+self.y*other.y+self.z*other.z
     }
 
+    pub fn length(&self) -> f64 {
+        self.length_squared().sqrt()
+    }
+
+    pub fn length_squared(&self) -> f64 {
+        self.dot(*self)
+    }
+
     pub fn normalize(&self) -> Vector3 {
-        let len = (self.x*self.x+self.y*self.y+self.z*self.z).sqrt();
+        let len = self.length();
         Vector3 { x: self.x / len, y: self.y / len, z: self.z / len }
     }
 
-    pub fn to_rgb(&self) -> (u8, u8, u8) {
+    /// Mirror reflection of this direction about the unit `normal`. The
+    /// component along the normal, `normal * self.dot(normal)`, is
+    /// subtracted twice, which flips it and keeps the tangential part.
+    pub fn reflect(&self, normal: Vector3) -> Vector3 {
+        *self - normal*(2.0*self.dot(normal))
+    }
+
+    /// Refraction of this unit direction through a surface with the unit
+    /// `normal` facing against it, where `eta` is the ratio of the refractive
+    /// indices on the incoming and outgoing sides. Snell's law gives the
+    /// outgoing direction's cosine as `sqrt(k)` with
+    /// `k = 1 - eta^2 * (1 - cos_i^2)`; a negative `k` means total internal
+    /// reflection, and None is returned.
+    pub fn refract(&self, normal: Vector3, eta: f64) -> Option<Vector3> {
+        let cos_i = -self.dot(normal);
+        let k = 1.0 - eta*eta*(1.0 - cos_i*cos_i);
+        if k < 0.0 {
+            None
+        } else {
+            Some(*self*eta + normal*(eta*cos_i - k.sqrt()))
+        }
+    }
+
+    /// Linear interpolation, giving `self` at `t = 0` and `other` at `t = 1`.
+    pub fn lerp(&self, other: Vector3, t: f64) -> Vector3 {
+        *self + (other - *self) * t
+    }
+
+    /// Applies `f` to each component.
+    pub fn map(&self, f: impl Fn(f64) -> f64) -> Vector3 {
+        Vector3 { x: f(self.x), y: f(self.y), z: f(self.z) }
+    }
+
+    /// Combines the matching components of `a` and `b` with `f`.
+    pub fn zip_with(a: Vector3, b: Vector3, f: impl Fn(f64, f64) -> f64) -> Vector3 {
+        Vector3 { x: f(a.x, b.x), y: f(a.y, b.y), z: f(a.z, b.z) }
+    }
+
+    pub fn abs(&self) -> Vector3 {
+        self.map(f64::abs)
+    }
+
+    pub fn max_component(&self) -> f64 {
+        self.x.max(self.y).max(self.z)
+    }
+
+    pub fn min_component(&self) -> f64 {
+        self.x.min(self.y).min(self.z)
+    }
+
+    /// Per-component maximum of `self` and `other`.
+    pub fn component_max(&self, other: Vector3) -> Vector3 {
+        Vector3::zip_with(*self, other, f64::max)
+    }
+
+    /// Per-component minimum of `self` and `other`.
+    pub fn component_min(&self, other: Vector3) -> Vector3 {
+        Vector3::zip_with(*self, other, f64::min)
+    }
+
+    pub fn floor(&self) -> Vector3 {
+        self.map(f64::floor)
+    }
+
+    pub fn ceil(&self) -> Vector3 {
+        self.map(f64::ceil)
+    }
+
+    pub fn round(&self) -> Vector3 {
+        self.map(f64::round)
+    }
+
+    pub fn sqrt(&self) -> Vector3 {
+        self.map(f64::sqrt)
+    }
+
+    pub fn exp(&self) -> Vector3 {
+        self.map(f64::exp)
+    }
+
+    pub fn ln(&self) -> Vector3 {
+        self.map(f64::ln)
+    }
+
+    /// Per-component `smoothstep_f64(edge0, edge1, self)`.
+    pub fn smoothstep(&self, edge0: Vector3, edge1: Vector3) -> Vector3 {
+        Vector3 { x: smoothstep_f64(edge0.x, edge1.x, self.x),
+                  y: smoothstep_f64(edge0.y, edge1.y, self.y),
+                  z: smoothstep_f64(edge0.z, edge1.z, self.z) }
+    }
+
+    /// Per-component `smootherstep_f64(edge0, edge1, self)`.
+    pub fn smootherstep(&self, edge0: Vector3, edge1: Vector3) -> Vector3 {
+        Vector3 { x: smootherstep_f64(edge0.x, edge1.x, self.x),
+                  y: smootherstep_f64(edge0.y, edge1.y, self.y),
+                  z: smootherstep_f64(edge0.z, edge1.z, self.z) }
+    }
+
+    /// Inverse of `to_rgb`: each 8-bit channel scaled to [0, 1], with no
+    /// gamma decoding.
+    pub fn from_rgb(r: u8, g: u8, b: u8) -> Vector3 {
+        Vector3 { x: r as f64 / 255.0, y: g as f64 / 255.0, z: b as f64 / 255.0 }
+    }
+
+    /// Color from a packed `0xRRGGBB` value, as written in HTML, e.g.
+    /// `Vector3::from_hex(0xff8000)` for orange. Bits above the lowest 24
+    /// are ignored.
+    pub fn from_hex(hex: u32) -> Vector3 {
+        Vector3::from_rgb((hex >> 16) as u8, (hex >> 8) as u8, hex as u8)
+    }
+
+    /// Approximate color of a black body at `temperature` kelvin, scaled
+    /// so that its brightest channel is 1: about 1900 K for candlelight,
+    /// 2700-3200 K for tungsten bulbs, 6500 K for daylight, which is close
+    /// to white, and bluer above. Uses Tanner Helland's curve fit to the
+    /// Planckian locus, which covers 1000-40000 K; temperatures outside
+    /// that are clamped to it.
+    pub fn from_kelvin(temperature: f64) -> Vector3 {
+        let t = temperature.clamp(1000.0, 40000.0) / 100.0;
+        let red = if t <= 66.0 { 255.0 } else { 329.698727446 * (t - 60.0).powf(-0.1332047592) };
+        let green = if t <= 66.0 {
+            99.4708025861 * t.ln() - 161.1195681661
+        } else {
+            288.1221695283 * (t - 60.0).powf(-0.0755148492)
+        };
+        let blue = if t >= 66.0 {
+            255.0
+        } else if t <= 19.0 {
+            0.0
+        } else {
+            138.5177312231 * (t - 10.0).ln() - 305.0447927307
+        };
+        v3!(red, green, blue).map(|c| c.clamp(0.0, 255.0) / 255.0)
+    }
+
+    /// Linear quantization without gamma encoding. Images meant for display
+    /// should use `to_rgb_gamma` instead.
+    pub fn to_rgb(self) -> (u8, u8, u8) {
         ((self.x * 255.0).min(255.0) as u8,
          (self.y * 255.0).min(255.0) as u8,
          (self.z * 255.0).min(255.0) as u8)
     }
+
+    /// Gamma encodes each channel with `1/gamma` and quantizes it to 8 bits.
+    pub fn to_rgb_gamma(self, gamma: f64) -> (u8, u8, u8) {
+        let encode = |c: f64| (c.clamp(0.0, 1.0).powf(1.0 / gamma) * 255.0).round() as u8;
+        (encode(self.x), encode(self.y), encode(self.z))
+    }
 }
 
 
 
+/// Largest per-component difference for two vectors to compare equal.
+const EQ_EPSILON: f64 = 1e-9;
+
+/// Approximate equality, tolerating rounding errors of up to `EQ_EPSILON`
+/// in each component.
+impl PartialEq for Vector3 {
+    fn eq(&self, other: &Vector3) -> bool {
+        (self.x - other.x).abs() <= EQ_EPSILON
+            && (self.y - other.y).abs() <= EQ_EPSILON
+            && (self.z - other.z).abs() <= EQ_EPSILON
+    }
+}
+
+impl fmt::Display for Vector3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({:.2}, {:.2}, {:.2})", self.x, self.y, self.z)
+    }
+}
+
+/// Error returned when parsing a `Vector3` from an "x,y,z" string fails.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseVector3Error {
+    /// The string did not contain exactly three comma separated parts.
+    ComponentCount(usize),
+    /// One of the components was not a valid float.
+    InvalidFloat(ParseFloatError),
+}
+
+impl fmt::Display for ParseVector3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseVector3Error::ComponentCount(n) =>
+                write!(f, "expected 3 comma separated components, got {}", n),
+            ParseVector3Error::InvalidFloat(ref e) =>
+                write!(f, "invalid vector component: {}", e),
+        }
+    }
+}
+
+impl Error for ParseVector3Error {}
+
+impl FromStr for Vector3 {
+    type Err = ParseVector3Error;
+
+    /// Parses a vector written as "x,y,z", e.g. "0.1,0.4,1.0".
+    fn from_str(s: &str) -> Result<Vector3, ParseVector3Error> {
+        let parts: Vec<&str> = s.split(',').collect();
+        if parts.len() != 3 {
+            return Err(ParseVector3Error::ComponentCount(parts.len()));
+        }
+        let mut c = [0.0; 3];
+        for (i, part) in parts.iter().enumerate() {
+            c[i] = part.trim().parse().map_err(ParseVector3Error::InvalidFloat)?;
+        }
+        Ok(Vector3 { x: c[0], y: c[1], z: c[2] })
+    }
+}
+
+/// Hermite interpolation `3t^2 - 2t^3` of `x` between `edge0` and `edge1`,
+/// clamped to [0, 1].
+pub fn smoothstep_f64(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t*t*(3.0 - 2.0*t)
+}
+
+/// Perlin's `6t^5 - 15t^4 + 10t^3` variant of `smoothstep_f64`, which also
+/// has zero second derivative at the edges.
+pub fn smootherstep_f64(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t*t*t*(t*(t*6.0 - 15.0) + 10.0)
+}
+
 impl Add for Vector3 {
     type Output = Vector3;
 
@@ -61,3 +299,363 @@ impl Mul<f64> for Vector3 {
         Vector3 { x: self.x*scalar, y: self.y*scalar, z: self.z*scalar }
     }
 }
+
+/// Component-wise product, e.g. for modulating a color by another.
+impl Mul for Vector3 {
+    type Output = Vector3;
+
+    fn mul(self, other: Vector3) -> Vector3 {
+        Vector3 { x: self.x*other.x, y: self.y*other.y, z: self.z*other.z }
+    }
+}
+
+impl Div<f64> for Vector3 {
+    type Output = Vector3;
+
+    fn div(self, scalar: f64) -> Vector3 {
+        Vector3 { x: self.x/scalar, y: self.y/scalar, z: self.z/scalar }
+    }
+}
+
+impl Neg for Vector3 {
+    type Output = Vector3;
+
+    fn neg(self) -> Vector3 {
+        Vector3 { x: -self.x, y: -self.y, z: -self.z }
+    }
+}
+
+impl AddAssign for Vector3 {
+    fn add_assign(&mut self, other: Vector3) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Vector3 {
+    fn sub_assign(&mut self, other: Vector3) {
+        *self = *self - other;
+    }
+}
+
+impl MulAssign<f64> for Vector3 {
+    fn mul_assign(&mut self, scalar: f64) {
+        *self = *self * scalar;
+    }
+}
+
+impl DivAssign<f64> for Vector3 {
+    fn div_assign(&mut self, scalar: f64) {
+        *self = *self / scalar;
+    }
+}
+
+/// Component by axis number: 0 is x, 1 is y and 2 is z.
+impl Index<usize> for Vector3 {
+    type Output = f64;
+
+    fn index(&self, axis: usize) -> &f64 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vector3 axis {} out of range, must be 0, 1 or 2", axis),
+        }
+    }
+}
+
+impl IndexMut<usize> for Vector3 {
+    fn index_mut(&mut self, axis: usize) -> &mut f64 {
+        match axis {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            _ => panic!("Vector3 axis {} out of range, must be 0, 1 or 2", axis),
+        }
+    }
+}
+
+/// Row-major 4x4 matrix for affine transforms of points and vectors.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Matrix4 {
+    pub m: [[f64; 4]; 4],
+}
+
+impl Matrix4 {
+    pub fn identity() -> Matrix4 {
+        Matrix4::scale(v3!(1.0, 1.0, 1.0))
+    }
+
+    pub fn translate(v: Vector3) -> Matrix4 {
+        Matrix4 { m: [[1.0, 0.0, 0.0, v.x],
+                      [0.0, 1.0, 0.0, v.y],
+                      [0.0, 0.0, 1.0, v.z],
+                      [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    pub fn scale(v: Vector3) -> Matrix4 {
+        Matrix4 { m: [[v.x, 0.0, 0.0, 0.0],
+                      [0.0, v.y, 0.0, 0.0],
+                      [0.0, 0.0, v.z, 0.0],
+                      [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    /// Rotation by `angle` radians around the x axis.
+    pub fn rotate_x(angle: f64) -> Matrix4 {
+        let (s, c) = angle.sin_cos();
+        Matrix4 { m: [[1.0, 0.0, 0.0, 0.0],
+                      [0.0,   c,  -s, 0.0],
+                      [0.0,   s,   c, 0.0],
+                      [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    /// Rotation by `angle` radians around the y axis.
+    pub fn rotate_y(angle: f64) -> Matrix4 {
+        let (s, c) = angle.sin_cos();
+        Matrix4 { m: [[  c, 0.0,   s, 0.0],
+                      [0.0, 1.0, 0.0, 0.0],
+                      [ -s, 0.0,   c, 0.0],
+                      [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    /// Rotation by `angle` radians around the z axis.
+    pub fn rotate_z(angle: f64) -> Matrix4 {
+        let (s, c) = angle.sin_cos();
+        Matrix4 { m: [[  c,  -s, 0.0, 0.0],
+                      [  s,   c, 0.0, 0.0],
+                      [0.0, 0.0, 1.0, 0.0],
+                      [0.0, 0.0, 0.0, 1.0]] }
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = self.m[j][i];
+            }
+        }
+        Matrix4 { m }
+    }
+
+    /// Inverse by Gauss-Jordan elimination, or None if the matrix is
+    /// singular.
+    pub fn invert(&self) -> Option<Matrix4> {
+        let mut a = self.m;
+        let mut inv = Matrix4::identity().m;
+        for col in 0..4 {
+            // Partial pivoting for stability.
+            let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+            let p = a[col][col];
+            for j in 0..4 {
+                a[col][j] /= p;
+                inv[col][j] /= p;
+            }
+            for i in 0..4 {
+                if i != col {
+                    let f = a[i][col];
+                    for j in 0..4 {
+                        a[i][j] -= f * a[col][j];
+                        inv[i][j] -= f * inv[col][j];
+                    }
+                }
+            }
+        }
+        Some(Matrix4 { m: inv })
+    }
+
+    /// Transforms a point, including the translation.
+    pub fn transform_point(&self, p: Vector3) -> Vector3 {
+        let m = &self.m;
+        v3!(m[0][0]*p.x + m[0][1]*p.y + m[0][2]*p.z + m[0][3],
+            m[1][0]*p.x + m[1][1]*p.y + m[1][2]*p.z + m[1][3],
+            m[2][0]*p.x + m[2][1]*p.y + m[2][2]*p.z + m[2][3])
+    }
+
+    /// Transforms a direction, ignoring the translation.
+    pub fn transform_vector(&self, v: Vector3) -> Vector3 {
+        let m = &self.m;
+        v3!(m[0][0]*v.x + m[0][1]*v.y + m[0][2]*v.z,
+            m[1][0]*v.x + m[1][1]*v.y + m[1][2]*v.z,
+            m[2][0]*v.x + m[2][1]*v.y + m[2][2]*v.z)
+    }
+}
+
+/// Composition: `(a * b)` applies `b` first, then `a`.
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+
+    fn mul(self, other: Matrix4) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = (0..4).map(|k| self.m[i][k] * other.m[k][j]).sum();
+            }
+        }
+        Matrix4 { m }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smoothstep_eases_between_edges() {
+        for step in [smoothstep_f64, smootherstep_f64] {
+            assert_eq!(step(2.0, 4.0, 2.0), 0.0);
+            assert_eq!(step(2.0, 4.0, 4.0), 1.0);
+            assert_eq!(step(2.0, 4.0, 3.0), 0.5);
+            assert_eq!(step(2.0, 4.0, -10.0), 0.0);
+            assert_eq!(step(2.0, 4.0, 10.0), 1.0);
+            // Rising throughout, and flat at both edges.
+            let samples: Vec<f64> = (0..=100).map(|i| step(2.0, 4.0, 2.0 + i as f64 * 0.02)).collect();
+            assert!(samples.windows(2).all(|w| w[0] < w[1]));
+            assert!(step(2.0, 4.0, 2.001) < 1e-5 && step(2.0, 4.0, 3.999) > 1.0 - 1e-5);
+        }
+        assert!((smoothstep_f64(0.0, 1.0, 0.25) - 0.15625).abs() < 1e-12);
+        assert!((smootherstep_f64(0.0, 1.0, 0.25) - 0.103515625).abs() < 1e-12);
+        let v = v3!(0.25, 3.0, -1.0).smoothstep(v3!(0.0, 2.0, 0.0), v3!(1.0, 4.0, 1.0));
+        assert_eq!(v, v3!(0.15625, 0.5, 0.0));
+        let v = v3!(0.25, 3.0, 2.0).smootherstep(v3!(0.0, 2.0, 0.0), v3!(1.0, 4.0, 1.0));
+        assert_eq!(v, v3!(0.103515625, 0.5, 1.0));
+    }
+
+    #[test]
+    fn assign_operators_match_binary_ones() {
+        let (a, b) = (v3!(1.0, -2.0, 3.5), v3!(0.5, 4.0, -1.0));
+        let mut v = a;
+        v += b;
+        assert_eq!(v, a + b);
+        assert_eq!(v, v3!(1.5, 2.0, 2.5));
+        v -= b;
+        assert_eq!(v, a);
+        v *= -2.0;
+        assert_eq!(v, v3!(-2.0, 4.0, -7.0));
+        // Accumulating in a loop, as the render loop does.
+        let mut sum = v3!(0.0, 0.0, 0.0);
+        for i in 0..10 {
+            sum += v3!(i as f64, 1.0, -0.5);
+        }
+        assert_eq!(sum, v3!(45.0, 10.0, -5.0));
+    }
+
+    #[test]
+    fn division_by_a_scalar() {
+        let v = v3!(3.0, -6.0, 1.5);
+        assert_eq!(v / 3.0, v3!(1.0, -2.0, 0.5));
+        let mut w = v;
+        w /= 0.5;
+        assert_eq!(w, v3!(6.0, -12.0, 3.0));
+        // Averaging samples.
+        let samples = [v3!(1.0, 0.0, 0.0), v3!(0.0, 1.0, 0.0), v3!(0.0, 0.0, 1.0), v3!(1.0, 1.0, 1.0)];
+        let mut mean = v3!(0.0, 0.0, 0.0);
+        for s in samples {
+            mean += s;
+        }
+        mean /= samples.len() as f64;
+        assert_eq!(mean, v3!(0.5, 0.5, 0.5));
+        assert!((v / 0.0).x.is_infinite());
+    }
+
+    #[test]
+    fn parses_comma_separated_components() {
+        assert_eq!("0.1,0.4,1.0".parse::<Vector3>(), Ok(v3!(0.1, 0.4, 1.0)));
+        assert_eq!(" -1 , 2.5e1,\t3 ".parse::<Vector3>(), Ok(v3!(-1.0, 25.0, 3.0)));
+        assert_eq!("1,2".parse::<Vector3>(), Err(ParseVector3Error::ComponentCount(2)));
+        assert_eq!("1,2,3,4".parse::<Vector3>(), Err(ParseVector3Error::ComponentCount(4)));
+        match "1,x,3".parse::<Vector3>() {
+            Err(ParseVector3Error::InvalidFloat(_)) => {},
+            other => panic!("parsed {:?}", other),
+        }
+        assert!("".parse::<Vector3>().is_err());
+        let e = "1,2".parse::<Vector3>().unwrap_err();
+        assert_eq!(e.to_string(), "expected 3 comma separated components, got 2");
+    }
+
+    #[test]
+    fn map_and_zip_with_work_per_component() {
+        let v = v3!(1.0, -4.0, 9.0);
+        assert_eq!(v.map(|c| c * 2.0 + 1.0), v3!(3.0, -7.0, 19.0));
+        assert_eq!(v.map(f64::abs).sqrt(), v3!(1.0, 2.0, 3.0));
+        assert_eq!(Vector3::zip_with(v, v3!(2.0, 3.0, 4.0), f64::powf), v3!(1.0, -64.0, 6561.0));
+        assert_eq!(v.map(|c| c), v);
+    }
+
+    #[test]
+    fn component_wise_product_and_negation() {
+        let color = v3!(1.0, 0.5, 0.25);
+        assert_eq!(color * v3!(0.5, 0.5, 4.0), v3!(0.5, 0.25, 1.0));
+        // A white light leaves a color unchanged, black removes it.
+        assert_eq!(color * v3!(1.0, 1.0, 1.0), color);
+        assert_eq!(color * v3!(0.0, 0.0, 0.0), v3!(0.0, 0.0, 0.0));
+        assert_eq!(v3!(1.0, 2.0, 3.0) * v3!(4.0, 5.0, 6.0), v3!(4.0, 5.0, 6.0) * v3!(1.0, 2.0, 3.0));
+        assert_eq!(-color, v3!(-1.0, -0.5, -0.25));
+        assert_eq!(-(-color), color);
+        assert_eq!(color + -color, v3!(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn length_and_lerp() {
+        let v = v3!(2.0, 3.0, 6.0);
+        assert_eq!(v.length_squared(), 49.0);
+        assert_eq!(v.length(), 7.0);
+        assert!((v.normalize().length() - 1.0).abs() < 1e-12);
+        assert_eq!(v3!(0.0, 0.0, 0.0).length(), 0.0);
+        let (a, b) = (v3!(0.0, 10.0, -2.0), v3!(4.0, 0.0, 2.0));
+        assert_eq!(a.lerp(b, 0.0), a);
+        assert_eq!(a.lerp(b, 1.0), b);
+        assert_eq!(a.lerp(b, 0.25), v3!(1.0, 7.5, -1.0));
+        // Extrapolates outside [0, 1].
+        assert_eq!(a.lerp(b, 2.0), v3!(8.0, -10.0, 6.0));
+    }
+
+    #[test]
+    fn abs_and_component_extremes() {
+        let v = v3!(-3.0, 2.0, -0.5);
+        assert_eq!(v.abs(), v3!(3.0, 2.0, 0.5));
+        assert_eq!(v.max_component(), 2.0);
+        assert_eq!(v.min_component(), -3.0);
+        assert_eq!(v.abs().max_component(), 3.0);
+        let w = v3!(1.0, 1.0, -1.0);
+        assert_eq!(v.component_max(w), v3!(1.0, 2.0, -0.5));
+        assert_eq!(v.component_min(w), v3!(-3.0, 1.0, -1.0));
+        for (x, y, z) in [(1.0, 2.0, 3.0), (3.0, 1.0, 2.0), (2.0, 3.0, 1.0)] {
+            assert_eq!(v3!(x, y, z).max_component(), 3.0);
+            assert_eq!(v3!(x, y, z).min_component(), 1.0);
+        }
+    }
+
+    #[test]
+    fn reflect_and_refract() {
+        let n = v3!(0.0, 1.0, 0.0);
+        let d = v3!(1.0, -1.0, 0.0).normalize();
+        assert_eq!(d.reflect(n), v3!(1.0, 1.0, 0.0).normalize());
+        assert_eq!(v3!(0.0, -1.0, 0.0).reflect(n), n);
+        // Passing straight through doesn't bend, whatever the indices.
+        assert_eq!(v3!(0.0, -1.0, 0.0).refract(n, 1.0 / 1.5), Some(v3!(0.0, -1.0, 0.0)));
+        // Snell's law: sin_t = eta * sin_i.
+        let eta = 1.0 / 1.5;
+        let t = d.refract(n, eta).unwrap();
+        assert!((t.length() - 1.0).abs() < 1e-12);
+        assert!((t.x - eta * d.x).abs() < 1e-12 && t.y < 0.0, "refracted to {:?}", t);
+        // Leaving glass at 45 degrees is past the critical angle of 41.8.
+        assert_eq!(d.refract(n, 1.5), None);
+        let steep = v3!(0.5, -1.0, 0.0).normalize();
+        assert!(steep.refract(n, 1.5).is_some());
+    }
+
+    #[test]
+    fn rgb_round_trips_every_value() {
+        for c in 0..=255u8 {
+            assert_eq!(Vector3::from_rgb(c, c, c).to_rgb(), (c, c, c));
+            assert_eq!(Vector3::from_rgb(c, 255 - c, c / 2).to_rgb(), (c, 255 - c, c / 2));
+        }
+        assert_eq!(Vector3::from_rgb(255, 0, 51), v3!(1.0, 0.0, 0.2));
+        assert_eq!(Vector3::from_hex(0xff8000).to_rgb(), (255, 128, 0));
+        assert_eq!(Vector3::from_hex(0x12345678), Vector3::from_rgb(0x34, 0x56, 0x78));
+    }
+}